@@ -0,0 +1,65 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use flo_event::ActorId;
+use flo_storage::inspect::{get_event_steam_data_dir, get_partition_data_dir, determine_existing_partition_dirs, get_segment_files};
+
+/// The server's data directory, along with the event stream and partition that should be looked at
+#[derive(Debug)]
+pub struct DataDir {
+    pub path: PathBuf,
+    pub stream: Option<String>,
+    pub partition: Option<ActorId>,
+}
+
+#[derive(Debug)]
+pub struct SegmentLocation {
+    pub stream: String,
+    pub partition: ActorId,
+    pub segment_num: u64,
+    pub path: PathBuf,
+}
+
+impl DataDir {
+    /// Returns all of the segment files that match the stream and partition, ordered by stream name, then partition
+    /// number, then segment number. Nothing in the data directory is modified.
+    pub fn find_segments(&self) -> io::Result<Vec<SegmentLocation>> {
+        let mut segments = Vec::new();
+        for stream in self.find_streams()? {
+            let stream_dir = get_event_steam_data_dir(&self.path, &stream);
+            for partition in determine_existing_partition_dirs(&stream_dir)? {
+                if self.partition.map(|p| p != partition).unwrap_or(false) {
+                    continue;
+                }
+                for segment_file in get_segment_files(&get_partition_data_dir(&stream_dir, partition))? {
+                    segments.push(SegmentLocation {
+                        stream: stream.clone(),
+                        partition: partition,
+                        segment_num: segment_file.segment_num.value(),
+                        path: segment_file.path,
+                    });
+                }
+            }
+        }
+        Ok(segments)
+    }
+
+    fn find_streams(&self) -> io::Result<Vec<String>> {
+        if let Some(ref stream) = self.stream {
+            return Ok(vec![stream.clone()]);
+        }
+
+        let mut streams = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                if let Ok(name) = entry.file_name().into_string() {
+                    streams.push(name);
+                }
+            }
+        }
+        streams.sort();
+        Ok(streams)
+    }
+}
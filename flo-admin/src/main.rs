@@ -0,0 +1,230 @@
+extern crate flo_storage;
+extern crate flo_event;
+#[macro_use]
+extern crate serde_json;
+
+#[macro_use]
+extern crate clap;
+
+mod data_dir;
+
+use std::path::{Path, PathBuf};
+use std::process;
+
+use clap::{App, Arg, ArgMatches, SubCommand, AppSettings};
+use flo_event::{FloEvent, ActorId};
+use flo_storage::inspect::{PersistentEvent, SegmentInspection, KeyRing, inspect_segment_file, truncate_corrupt_tail};
+use data_dir::{DataDir, SegmentLocation};
+
+
+mod args {
+    //sub-commands
+    pub const LIST: &'static str = "list";
+    pub const DUMP: &'static str = "dump";
+    pub const VERIFY: &'static str = "verify";
+
+    //global options
+    pub const DATA_DIR: &'static str = "data-dir";
+    pub const STREAM: &'static str = "stream";
+    pub const PARTITION: &'static str = "partition";
+    pub const KEY_FILE: &'static str = "key-file";
+
+    //dump options
+    pub const SEGMENT: &'static str = "segment";
+
+    //verify options
+    pub const TRUNCATE: &'static str = "truncate";
+}
+
+fn create_app_args() -> App<'static, 'static> {
+    App::new("flo-admin")
+            .version(crate_version!())
+            .about("Offline tool for inspecting, verifying, and repairing the data directory of a flo server. The server must not be running while this tool is in use")
+            .setting(AppSettings::SubcommandRequired)
+            .setting(AppSettings::VersionlessSubcommands)
+            .arg(Arg::with_name(args::DATA_DIR)
+                    .short("d")
+                    .long("data-dir")
+                    .value_name("DIR")
+                    .help("The data directory of the flo server")
+                    .default_value("."))
+            .arg(Arg::with_name(args::STREAM)
+                    .short("s")
+                    .long("stream")
+                    .takes_value(true)
+                    .help("Only look at the event stream with this name. Defaults to all event streams"))
+            .arg(Arg::with_name(args::PARTITION)
+                    .short("p")
+                    .long("partition")
+                    .takes_value(true)
+                    .help("Only look at the partition with this number. Defaults to all partitions"))
+            .arg(Arg::with_name(args::KEY_FILE)
+                    .short("k")
+                    .long("key-file")
+                    .value_name("FILE")
+                    .help("The encryption key file that the server was started with. This is required to read encrypted segments"))
+            .subcommand(SubCommand::with_name(args::LIST)
+                    .about("Lists the event streams, partitions, and segments in the data directory"))
+            .subcommand(SubCommand::with_name(args::DUMP)
+                    .about("Prints every event as a line of JSON")
+                    .arg(Arg::with_name(args::SEGMENT)
+                            .long("segment")
+                            .takes_value(true)
+                            .help("Only dump events from the segment with this number. Defaults to all segments")))
+            .subcommand(SubCommand::with_name(args::VERIFY)
+                    .about("Validates every event, and reports any segments with a corrupt tail")
+                    .arg(Arg::with_name(args::TRUNCATE)
+                            .long("truncate")
+                            .help("Repair segments with a corrupt tail by zeroing out everything after the last valid event. This permanently removes the corrupt data, along with any events that follow it")))
+}
+
+fn main() {
+    let args = create_app_args().get_matches();
+
+    let data_dir = DataDir {
+        path: PathBuf::from(args.value_of(args::DATA_DIR).unwrap_or(".")),
+        stream: args.value_of(args::STREAM).map(|name| name.to_owned()),
+        partition: parse_opt_or_exit::<ActorId>(args::PARTITION, &args),
+    };
+    let keys = args.value_of(args::KEY_FILE).map(|path| {
+        KeyRing::read_key_file(Path::new(path)).unwrap_or_else(|err| {
+            abort_process(format!("Failed to read key file: {}", err))
+        })
+    });
+    let segments = data_dir.find_segments().unwrap_or_else(|err| {
+        abort_process(format!("Failed to read data directory: {:?}: {}", data_dir.path, err))
+    });
+
+    match args.subcommand() {
+        (args::LIST, Some(_)) => list(&segments, keys.as_ref()),
+        (args::DUMP, Some(dump_args)) => {
+            let segment_num = parse_opt_or_exit::<u64>(args::SEGMENT, dump_args);
+            dump(&segments, keys.as_ref(), segment_num)
+        }
+        (args::VERIFY, Some(verify_args)) => verify(&segments, keys.as_ref(), verify_args.is_present(args::TRUNCATE)),
+        (command, _) => abort_process(format!("unknown command: '{}'", command)),
+    }
+}
+
+fn list(segments: &[SegmentLocation], keys: Option<&KeyRing>) {
+    let mut current_partition = None;
+    for segment in segments {
+        let partition = (segment.stream.as_str(), segment.partition);
+        if current_partition != Some(partition) {
+            println!("stream: '{}', partition: {}", segment.stream, segment.partition);
+            current_partition = Some(partition);
+        }
+
+        match inspect_segment_file(&segment.path, keys, |_| {}) {
+            Ok(inspection) => println!("    {}", describe_segment(segment, &inspection)),
+            Err(err) => println!("    segment: {}, error: {}", segment.segment_num, err),
+        }
+    }
+}
+
+fn dump(segments: &[SegmentLocation], keys: Option<&KeyRing>, segment_num: Option<u64>) {
+    for segment in segments.iter().filter(|s| segment_num.map(|num| s.segment_num == num).unwrap_or(true)) {
+        let result = inspect_segment_file(&segment.path, keys, |event| {
+            println!("{}", event_to_json(event));
+        });
+        if let Err(err) = result {
+            abort_process(format!("Failed to read segment: {:?}: {}", segment.path, err));
+        }
+    }
+}
+
+fn verify(segments: &[SegmentLocation], keys: Option<&KeyRing>, truncate: bool) {
+    let mut corrupt_count = 0;
+    let mut event_count = 0;
+    for segment in segments {
+        let inspection = match inspect_segment_file(&segment.path, keys, |_| {}) {
+            Ok(inspection) => inspection,
+            Err(err) => {
+                println!("{:?}: unreadable: {}", segment.path, err);
+                corrupt_count += 1;
+                continue;
+            }
+        };
+        event_count += inspection.event_count;
+
+        if let Some(tail) = inspection.corrupt_tail {
+            println!("{:?}: {} bytes starting at offset: {} are corrupt: {}", segment.path, tail.len, tail.offset, tail.error);
+            if !truncate {
+                corrupt_count += 1;
+            } else if let Err(err) = truncate_corrupt_tail(&segment.path, keys) {
+                println!("{:?}: failed to truncate: {}", segment.path, err);
+                corrupt_count += 1;
+            } else {
+                println!("{:?}: truncated to end after event counter: {}", segment.path, inspection.last_event_counter.unwrap_or(0));
+            }
+        }
+    }
+
+    println!("Verified {} events in {} segments", event_count, segments.len());
+    if corrupt_count > 0 {
+        abort_process(format!("Found {} corrupt segment(s)", corrupt_count));
+    }
+}
+
+fn describe_segment(segment: &SegmentLocation, inspection: &SegmentInspection) -> String {
+    let counters = match (inspection.first_event_counter, inspection.last_event_counter) {
+        (Some(first), Some(last)) => format!("{}-{}", first, last),
+        _ => "none".to_owned(),
+    };
+    let corrupt = inspection.corrupt_tail.as_ref().map(|tail| {
+        format!(", corrupt tail at offset: {}", tail.offset)
+    }).unwrap_or(String::new());
+    format!("segment: {}, created: {}, ends: {}, events: {}, counters: {}, compressed: {}, file size: {} bytes{}",
+            segment.segment_num,
+            inspection.header.create_time.to_rfc3339(),
+            inspection.header.end_time.to_rfc3339(),
+            inspection.event_count,
+            counters,
+            inspection.header.compressed,
+            inspection.file_len,
+            corrupt)
+}
+
+fn event_to_json(event: &PersistentEvent) -> serde_json::Value {
+    let parent_id = event.parent_id().map(|id| format!("{}", id));
+    // Event data is usually text, but it doesn't have to be, so anything else is written out as hex
+    let text = ::std::str::from_utf8(event.data()).ok();
+    let hex = if text.is_none() { Some(to_hex(event.data())) } else { None };
+    let headers = event.headers().iter().map(|&(ref name, ref value)| {
+        let value_text = ::std::str::from_utf8(value).ok();
+        let value_hex = if value_text.is_none() { Some(to_hex(value)) } else { None };
+        json!({
+            "name": name,
+            "value": value_text,
+            "valueHex": value_hex,
+        })
+    }).collect::<Vec<_>>();
+    json!({
+        "id": format!("{}", event.id()),
+        "parentId": parent_id,
+        "timestamp": event.timestamp().to_rfc3339(),
+        "eventTime": event.event_time().map(|time| time.to_rfc3339()),
+        "namespace": event.namespace(),
+        "headers": headers,
+        "dataLength": event.data_len(),
+        "data": text,
+        "dataHex": hex,
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_opt_or_exit<T: ::std::str::FromStr>(arg_name: &'static str, args: &ArgMatches) -> Option<T> {
+    args.value_of(arg_name).map(|value| {
+        value.parse::<T>().unwrap_or_else(|_| {
+            abort_process(format!("Invalid argument: {}", arg_name))
+        })
+    })
+}
+
+fn abort_process(message: String) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
@@ -1,7 +1,7 @@
 use super::{FloCliCommand, Context as CliContext};
 use flo_client_lib::codec::LossyStringCodec;
 use flo_client_lib::sync::{SyncConnection, HandshakeError, ErrorType};
-use flo_client_lib::{Event, FloEventId, VersionVector};
+use flo_client_lib::{Event, FloEventId, VersionVector, Timestamp};
 
 use std::fmt::{self, Display};
 
@@ -11,6 +11,9 @@ pub struct CliConsumerOptions {
     pub namespace: String,
     //TODO: allow passing multiple start position arguments so we can properly use a VersionVector
     pub start_position: Option<FloEventId>,
+    pub since: Option<Timestamp>,
+    /// If true, `since` is compared with the event time that was supplied by the producer instead of the timestamp
+    pub by_event_time: bool,
     pub limit: Option<u64>,
     pub await: bool,
     pub batch_size: Option<u32>,
@@ -23,7 +26,7 @@ impl FloCliCommand for CliConsumer {
     type Error = ConsumerError;
 
     fn run(input: Self::Input, output: &CliContext) -> Result<(), Self::Error> {
-        let CliConsumerOptions { host, port, namespace, limit, await, start_position, batch_size} = input;
+        let CliConsumerOptions { host, port, namespace, limit, await, start_position, since, by_event_time, batch_size} = input;
 
 
         let address = format!("{}:{}", host, port);
@@ -31,20 +34,29 @@ impl FloCliCommand for CliConsumer {
         output.verbose(format!("Connecting to: {}", &address));
         let connection = SyncConnection::connect_from_str(&address, "flo-client-cli", LossyStringCodec, batch_size)?;
 
-        let mut version_vector = VersionVector::new();
-        if let Some(id) = start_position {
-            version_vector.set(id);
+        let event_iter = if let Some(since) = since {
+            if by_event_time {
+                output.verbose(format!("Consuming events with an event time since: {}", since));
+                connection.into_consumer_since_event_time(namespace, since, limit, await)
+            } else {
+                output.verbose(format!("Consuming events since: {}", since));
+                connection.into_consumer_since(namespace, since, limit, await)
+            }
         } else {
-            // safe unwrap since connect succeeded
-            let current_stream = connection.current_stream().unwrap();
-            // set the version vector to just start at the beginning for all partitions
-            for partition in current_stream.partitions.iter() {
-                version_vector.set(FloEventId::new(partition.partition_num, 0));
+            let mut version_vector = VersionVector::new();
+            if let Some(id) = start_position {
+                version_vector.set(id);
+            } else {
+                // safe unwrap since connect succeeded
+                let current_stream = connection.current_stream().unwrap();
+                // set the version vector to just start at the beginning for all partitions
+                for partition in current_stream.partitions.iter() {
+                    version_vector.set(FloEventId::new(partition.partition_num, 0));
+                }
             }
-        }
-
+            connection.into_consumer(namespace, &version_vector, limit, await)
+        };
 
-        let event_iter = connection.into_consumer(namespace, &version_vector, limit, await);
         for result in event_iter {
             let event = result?;
             print_event(output, event);
@@ -62,11 +74,21 @@ fn print_event(output: &CliContext, event: Event<String>) {
     } else {
         String::new()
     };
-    output.normal(format!("EventId: {}{}\nNamespace: {}\nTimestamp: {}\nBody: {}",
+    let event_time = if let Some(event_time) = event.event_time {
+        format!("\nEventTime: {}", event_time)
+    } else {
+        String::new()
+    };
+    let headers = event.headers.iter().map(|&(ref name, ref value)| {
+        format!("\nHeader: {}: {}", name, String::from_utf8_lossy(value))
+    }).collect::<String>();
+    output.normal(format!("EventId: {}{}\nNamespace: {}\nTimestamp: {}{}{}\nBody: {}",
                           event.id,
                           parent,
                           event.namespace,
                           event.timestamp,
+                          event_time,
+                          headers,
                           event.data));
 }
 
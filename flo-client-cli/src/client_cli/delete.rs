@@ -0,0 +1,37 @@
+use flo_client_lib::sync::SyncConnection;
+use flo_client_lib::codec::RawCodec;
+use flo_client_lib::FloEventId;
+use super::{Context, FloCliCommand};
+
+pub struct DeleteOptions {
+    pub host: String,
+    pub port: u16,
+    pub event_ids: Vec<FloEventId>,
+    pub namespace: Option<String>,
+}
+
+pub struct Delete;
+
+impl FloCliCommand for Delete {
+    type Input = DeleteOptions;
+    type Error = String;
+
+    fn run(DeleteOptions{host, port, event_ids, namespace}: DeleteOptions, output: &Context) -> Result<(), Self::Error> {
+        let server_address = format!("{}:{}", host, port);
+        output.verbose(format!("Attempting connection to: {:?}", &server_address));
+        let id_count = event_ids.len();
+        SyncConnection::connect_from_str(&server_address, "flo-client-cli", RawCodec, None).map_err(|handshake_err| {
+            format!("Error establishing connection to flo server: {}", handshake_err)
+        }).and_then(|mut connection| {
+            output.verbose(format!("connected to {}", &server_address));
+            connection.delete(event_ids, namespace.clone()).map_err(|client_err| {
+                format!("Failed to delete events: {:?}", client_err)
+            })
+        }).map(|()| {
+            match namespace {
+                Some(ref glob) => output.normal(format!("Successfully deleted {} events by id and all events matching '{}'", id_count, glob)),
+                None => output.normal(format!("Successfully deleted {} events", id_count)),
+            }
+        })
+    }
+}
@@ -1,8 +1,12 @@
 mod producer;
 mod consumer;
+mod snapshot;
+mod delete;
 
 pub use self::producer::{Producer, ProduceOptions};
 pub use self::consumer::{CliConsumerOptions, CliConsumer};
+pub use self::snapshot::{Snapshot, SnapshotOptions};
+pub use self::delete::{Delete, DeleteOptions};
 
 use std::io::Write;
 use std::fmt::Display;
@@ -1,6 +1,6 @@
-use flo_client_lib::sync::SyncConnection;
+use flo_client_lib::sync::{SyncConnection, EventToProduce};
 use flo_client_lib::codec::RawCodec;
-use flo_client_lib::{FloEventId, ActorId};
+use flo_client_lib::{FloEventId, ActorId, EventHeader, Timestamp};
 use super::{Context, FloCliCommand};
 
 pub struct ProduceOptions {
@@ -10,6 +10,8 @@ pub struct ProduceOptions {
     pub partition: ActorId,
     pub event_data: Vec<Vec<u8>>,
     pub parent_id: Option<FloEventId>,
+    pub event_time: Option<Timestamp>,
+    pub headers: Vec<EventHeader>,
 }
 
 
@@ -19,7 +21,7 @@ impl FloCliCommand for Producer {
     type Input = ProduceOptions;
     type Error = String;
 
-    fn run(ProduceOptions{host, port, partition, namespace, event_data, parent_id}: ProduceOptions, output: &Context) -> Result<(), Self::Error> {
+    fn run(ProduceOptions{host, port, partition, namespace, event_data, parent_id, event_time, headers}: ProduceOptions, output: &Context) -> Result<(), Self::Error> {
         let server_address = format!("{}:{}", host, port);
         output.verbose(format!("Attempting connection to: {:?}", &server_address));
         SyncConnection::connect_from_str(&server_address, "flo-client-cli", RawCodec, None).map_err(|handshake_err| {
@@ -27,15 +29,30 @@ impl FloCliCommand for Producer {
         }).and_then(|mut connection| {
             output.verbose(format!("connected to {}", &server_address));
 
-            event_data.into_iter().fold(Ok(0), |events_produced, event_data| {
-                events_produced.and_then(|count| {
-                    connection.produce_to(partition, namespace.as_str(), parent_id, event_data).map_err(|client_err| {
+            let events = event_data.into_iter().map(|event_data| {
+                let mut event = EventToProduce::new(partition, namespace.as_str(), parent_id, event_data);
+                event.event_time = event_time;
+                event.headers = headers.clone();
+                event
+            }).collect::<Vec<_>>();
+
+            let result = if events.len() > 1 {
+                // multiple events are sent to the server in batches instead of one at a time
+                connection.produce_all(events).map_err(|client_err| {
+                    format!("Failed to produce events: {:?}", client_err)
+                })
+            } else {
+                events.into_iter().map(|event| {
+                    connection.produce(event).map_err(|client_err| {
                         format!("Failed to produce event: {:?}", client_err)
-                    }).map(|produced_id| {
-                        output.normal(produced_id);
-                        count + 1
                     })
-                })
+                }).collect::<Result<Vec<_>, _>>()
+            };
+            result.map(|produced_ids| {
+                for produced_id in produced_ids.iter() {
+                    output.normal(produced_id);
+                }
+                produced_ids.len()
             })
         }).map(|produced_count| {
             output.normal(format!("Successfully produced {} events to {}", produced_count, namespace));
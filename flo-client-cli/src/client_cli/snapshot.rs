@@ -0,0 +1,34 @@
+use flo_client_lib::sync::SyncConnection;
+use flo_client_lib::codec::RawCodec;
+use super::{Context, FloCliCommand};
+
+pub struct SnapshotOptions {
+    pub host: String,
+    pub port: u16,
+    pub dest_dir: String,
+}
+
+pub struct Snapshot;
+
+impl FloCliCommand for Snapshot {
+    type Input = SnapshotOptions;
+    type Error = String;
+
+    fn run(SnapshotOptions{host, port, dest_dir}: SnapshotOptions, output: &Context) -> Result<(), Self::Error> {
+        let server_address = format!("{}:{}", host, port);
+        output.verbose(format!("Attempting connection to: {:?}", &server_address));
+        SyncConnection::connect_from_str(&server_address, "flo-client-cli", RawCodec, None).map_err(|handshake_err| {
+            format!("Error establishing connection to flo server: {}", handshake_err)
+        }).and_then(|mut connection| {
+            output.verbose(format!("connected to {}", &server_address));
+            connection.snapshot(dest_dir.as_str()).map_err(|client_err| {
+                format!("Failed to create snapshot: {:?}", client_err)
+            })
+        }).map(|complete| {
+            for partition in complete.partitions.iter() {
+                output.verbose(format!("partition: {}, head: {}, active segment offset: {}", partition.partition_num, partition.head, partition.active_segment_offset));
+            }
+            output.normal(format!("Successfully wrote snapshot of event stream '{}' with {} partitions to {}", complete.name, complete.partitions.len(), dest_dir));
+        })
+    }
+}
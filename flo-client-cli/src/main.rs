@@ -7,9 +7,9 @@ extern crate clap;
 mod client_cli;
 
 
-use flo_client_lib::{FloEventId, ActorId};
-use clap::{App, Arg, ArgMatches, SubCommand, AppSettings};
-use client_cli::{Producer, ProduceOptions, Verbosity, Context, Critical, CliConsumer, CliConsumerOptions};
+use flo_client_lib::{FloEventId, ActorId, Timestamp, EventHeader};
+use clap::{App, Arg, ArgGroup, ArgMatches, SubCommand, AppSettings};
+use client_cli::{Producer, ProduceOptions, Verbosity, Context, Critical, CliConsumer, CliConsumerOptions, Snapshot, SnapshotOptions, Delete, DeleteOptions};
 
 use std::str::FromStr;
 
@@ -18,6 +18,8 @@ mod args {
     //sub-commands
     pub const PRODUCE: &'static str = "produce";
     pub const CONSUME: &'static str = "consume";
+    pub const SNAPSHOT: &'static str = "snapshot";
+    pub const DELETE: &'static str = "delete";
 
     //global options
     pub const VERBOSE: &'static str = "verbose";
@@ -29,12 +31,22 @@ mod args {
     pub const EVENT_DATA: &'static str = "event-data";
     pub const PARENT_ID: &'static str = "parent-id";
     pub const PARTITION: &'static str = "partition";
+    pub const HEADER: &'static str = "header";
+    pub const EVENT_TIME: &'static str = "event-time";
 
     //consume options
     pub const CONSUME_LIMIT: &'static str = "consume-limit";
     pub const CONSUME_AWAIT: &'static str = "consume-await";
     pub const CONSUME_START_POSITION: &'static str = "consume-start-position";
+    pub const CONSUME_SINCE: &'static str = "consume-since";
+    pub const CONSUME_BY_EVENT_TIME: &'static str = "consume-by-event-time";
     pub const CONSUME_BATCH: &'static str = "consume-batch";
+
+    //snapshot options
+    pub const SNAPSHOT_DIR: &'static str = "snapshot-dir";
+
+    //delete options
+    pub const DELETE_ID: &'static str = "delete-id";
 }
 
 fn create_app_args() -> App<'static, 'static> {
@@ -81,7 +93,19 @@ fn create_app_args() -> App<'static, 'static> {
                             .long("parent")
                             .takes_value(true)
                             .value_name("PARENT-EVENT-ID")
-                            .help("The parent id of the event to be produced. Defaults to none") ) )
+                            .help("The parent id of the event to be produced. Defaults to none") )
+                    .arg(Arg::with_name(args::HEADER)
+                            .long("header")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .value_name("NAME=VALUE")
+                            .help("A header to add to every event that's produced. May be supplied multiple times") )
+                    .arg(Arg::with_name(args::EVENT_TIME)
+                            .long("event-time")
+                            .takes_value(true)
+                            .value_name("TIMESTAMP")
+                            .help("The time that the events actually happened, given in RFC 3339 format (e.g. 2017-06-01T12:00:00Z). This is stored in addition to the timestamp assigned by the server") ) )
             .subcommand(SubCommand::with_name(args::CONSUME)
                     .about("Used to read events from the event stream")
                     .arg(Arg::with_name(args::NAMESPACE)
@@ -95,6 +119,16 @@ fn create_app_args() -> App<'static, 'static> {
                             .long("start-after")
                             .help("Sets the starting position in the event stream. The first event received will be the on directly AFTER this id")
                             .value_name("EVENT_ID"))
+                    .arg(Arg::with_name(args::CONSUME_SINCE)
+                            .long("since")
+                            .takes_value(true)
+                            .value_name("TIMESTAMP")
+                            .conflicts_with(args::CONSUME_START_POSITION)
+                            .help("Start with the first event in each partition with a timestamp at or after this time, given in RFC 3339 format (e.g. 2017-06-01T12:00:00Z)"))
+                    .arg(Arg::with_name(args::CONSUME_BY_EVENT_TIME)
+                            .long("by-event-time")
+                            .requires(args::CONSUME_SINCE)
+                            .help("Compare --since with the time supplied by the producer instead of the server timestamp. Events without an event time are compared using their timestamp"))
                     .arg(Arg::with_name(args::CONSUME_LIMIT)
                             .short("l")
                             .long("limit")
@@ -110,6 +144,31 @@ fn create_app_args() -> App<'static, 'static> {
                             .short("t")
                             .long("tail")
                             .help("Works like tail -f to continuously await new events. Events will be printed as they are received")))
+            .subcommand(SubCommand::with_name(args::SNAPSHOT)
+                    .about("Writes a consistent snapshot of the event stream to a directory on the server")
+                    .arg(Arg::with_name(args::SNAPSHOT_DIR)
+                            .value_name("DIR")
+                            .help("The directory on the server to write the snapshot to. Start a server with --restore-snapshot to restore it")
+                            .required(true)))
+            .subcommand(SubCommand::with_name(args::DELETE)
+                    .about("Permanently deletes events from the event stream. Deleted events are never returned to consumers again")
+                    .arg(Arg::with_name(args::DELETE_ID)
+                            .short("i")
+                            .long("id")
+                            .takes_value(true)
+                            .value_name("EVENT_ID")
+                            .multiple(true)
+                            .number_of_values(1)
+                            .help("The id of an event to delete. May be supplied multiple times"))
+                    .arg(Arg::with_name(args::NAMESPACE)
+                            .short("n")
+                            .long("namespace")
+                            .takes_value(true)
+                            .help("Deletes every event currently in the stream with a namespace matching this glob"))
+                    .group(ArgGroup::with_name("delete-target")
+                            .args(&[args::DELETE_ID, args::NAMESPACE])
+                            .multiple(true)
+                            .required(true)))
 }
 
 fn main() {
@@ -125,6 +184,8 @@ fn main() {
             let event_data = get_event_data(&produce_args);
             let namespace = produce_args.value_of(args::NAMESPACE).or_abort_with_message("Must supply a namespace", &context).to_owned();
             let partition = parse_opt_or_exit::<ActorId>(args::PARTITION, &produce_args, &context).or_abort_process(&context);
+            let headers = get_headers(&produce_args, &context);
+            let event_time = parse_opt_or_exit::<Timestamp>(args::EVENT_TIME, &produce_args, &context);
             let produce_options = ProduceOptions {
                 host,
                 port,
@@ -132,11 +193,15 @@ fn main() {
                 partition,
                 event_data,
                 parent_id,
+                event_time,
+                headers,
             };
             ::client_cli::run::<Producer>(produce_options, context);
         }
         (args::CONSUME, Some(consume_args)) => {
             let start_position = parse_opt_or_exit::<FloEventId>(args::CONSUME_START_POSITION, &consume_args, &context);
+            let since = parse_opt_or_exit::<Timestamp>(args::CONSUME_SINCE, &consume_args, &context);
+            let by_event_time = consume_args.is_present(args::CONSUME_BY_EVENT_TIME);
             let limit = parse_opt_or_exit::<u64>(args::CONSUME_LIMIT, &consume_args, &context);
             let await = consume_args.is_present(args::CONSUME_AWAIT);
             let namespace = consume_args.value_of(args::NAMESPACE).or_abort_with_message("Must supply a namespace", &context).to_owned();
@@ -147,6 +212,8 @@ fn main() {
                 port: port,
                 namespace: namespace,
                 start_position: start_position,
+                since: since,
+                by_event_time: by_event_time,
                 limit: limit,
                 await: await,
                 batch_size: batch_size,
@@ -154,6 +221,32 @@ fn main() {
 
             ::client_cli::run::<CliConsumer>(consume_opts, context);
         }
+        (args::SNAPSHOT, Some(snapshot_args)) => {
+            let dest_dir = snapshot_args.value_of(args::SNAPSHOT_DIR).or_abort_with_message("Must supply a directory", &context).to_owned();
+            let snapshot_opts = SnapshotOptions {
+                host: host,
+                port: port,
+                dest_dir: dest_dir,
+            };
+            ::client_cli::run::<Snapshot>(snapshot_opts, context);
+        }
+        (args::DELETE, Some(delete_args)) => {
+            let event_ids = delete_args.values_of(args::DELETE_ID).map(|values| {
+                values.map(|value| {
+                    value.parse::<FloEventId>().map_err(|_| {
+                        format!("Invalid argument: {}", args::DELETE_ID)
+                    }).or_abort_process(&context)
+                }).collect::<Vec<_>>()
+            }).unwrap_or_else(Vec::new);
+            let namespace = delete_args.value_of(args::NAMESPACE).map(|ns| ns.to_owned());
+            let delete_opts = DeleteOptions {
+                host: host,
+                port: port,
+                event_ids: event_ids,
+                namespace: namespace,
+            };
+            ::client_cli::run::<Delete>(delete_opts, context);
+        }
         (command, _) => {
             context.abort_process(format!("unknown command: '{}'", command));
         }
@@ -185,6 +278,18 @@ fn get_parent_id(args: &ArgMatches, context: &Context) -> Option<FloEventId> {
     })
 }
 
+fn get_headers(args: &ArgMatches, context: &Context) -> Vec<EventHeader> {
+    args.values_of(args::HEADER).map(|values| {
+        values.map(|value| {
+            let separator = value.find('=').ok_or_else(|| {
+                format!("Invalid header: '{}', headers must be formatted as NAME=VALUE", value)
+            }).or_abort_process(context);
+            let (name, value) = value.split_at(separator);
+            (name.to_owned(), value[1..].as_bytes().to_owned())
+        }).collect::<Vec<_>>()
+    }).unwrap_or_else(Vec::new)
+}
+
 fn get_event_data(args: &ArgMatches) -> Vec<Vec<u8>> {
     args.values_of(args::EVENT_DATA).map(|values| {
         let mut vec = values.map(|str_val| {
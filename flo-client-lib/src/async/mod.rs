@@ -16,11 +16,11 @@ use tokio_core::io::Io;
 use futures::{Stream, Sink};
 
 use protocol::{ProtocolMessage, ErrorMessage};
-use event::{FloEventId, ActorId, VersionVector, OwnedFloEvent};
+use event::{FloEventId, ActorId, VersionVector, OwnedFloEvent, Timestamp, ProducerId};
 use codec::EventCodec;
 use self::recv::MessageRecvStream;
 use self::send::MessageSendSink;
-use self::ops::{ProduceOne, ProduceAll, EventToProduce, Consume, Handshake};
+use self::ops::{ProduceOne, ProduceAll, ProduceTransaction, EventToProduce, Consume, Handshake, Snapshot, Delete};
 
 
 pub use self::tcp_connect::{tcp_connect, tcp_connect_with, AsyncTcpClientConnect};
@@ -70,6 +70,7 @@ impl <D: Debug> AsyncConnection<D> {
     pub fn new(name: String, send: MessageSender, recv: MessageReceiver, codec: Box<EventCodec<EventData=D>>) -> AsyncConnection<D> {
         let inner = AsyncConnectionInner {
             client_name: name,
+            producer_id: None,
             recv_batch_size: None,
             send: Some(send),
             recv: Some(recv),
@@ -94,24 +95,34 @@ impl <D: Debug> AsyncConnection<D> {
     /// Produce a single event on the stream and await acknowledgement that it was persisted. Returns a future that resolves
     /// to a tuple of the `FloEventId` of the produced event and this `AsyncConnection`.
     pub fn produce(self, event: EventToProduce<D>) -> ProduceOne<D> {
-        let EventToProduce{partition, namespace, parent_id, data} = event;
-        self.produce_to(partition, namespace, parent_id, data)
+        ProduceOne::new(self, event)
     }
 
     /// Produces a single event to the specified partition and awaits acknowledgement that it was persisted. Returns a future
     /// that resolves to a tuple of the `FloEventId` of the new event and this `AsyncConnection` for reuse.
     pub fn produce_to<N: Into<String>>(self, partition: ActorId, namespace: N, parent_id: Option<FloEventId>, data: D) -> ProduceOne<D> {
-        ProduceOne::new(self, partition, namespace.into(), parent_id, data)
+        ProduceOne::new(self, EventToProduce::new(partition, namespace, parent_id, data))
     }
 
-    /// Produce each of the events yielded by the iterator. The events are each produced in order. Subsequent operations are not
-    /// begun until the previous event has been acknowledged as being persisted successfully. In the event of a failure, all
-    /// subsequent events are skipped, and the error is returned immediated. The error struct contains the number of events
-    /// that were produced successfully.
+    /// Produce each of the events yielded by the iterator. The events are each produced in order, and consecutive events for
+    /// the same partition are sent to the server together as a single batch. Batches are sent without waiting for the
+    /// previous ones to be acknowledged, up to `DEFAULT_MAX_IN_FLIGHT_BATCHES` at a time, which can be changed using
+    /// `ProduceAll::with_max_in_flight`. In the event of a failure, all subsequent events are skipped, and the error is
+    /// returned once every batch that was already sent has been acknowledged. The error struct contains the ids of the
+    /// events that were acknowledged as being produced successfully before the failure, although batches that were sent
+    /// after the one that failed may still be persisted.
     pub fn produce_all<I: Iterator<Item=EventToProduce<D>>>(self, events: I) -> ProduceAll<D, I> {
         ProduceAll::new(self, events)
     }
 
+    /// Produces all of the events in a single transaction, so that consumers will see either all of them or none of them,
+    /// even if they are for different partitions. The server holds onto the events until they are all committed together.
+    /// Returns a future that resolves to the ids of the events, in the same order as they were given. A transaction may
+    /// contain at most `MAX_TRANSACTION_PRODUCE_OPS` batches of events.
+    pub fn produce_in_transaction<I: IntoIterator<Item=EventToProduce<D>>>(self, events: I) -> ProduceTransaction<D> {
+        ProduceTransaction::new(self, events)
+    }
+
     /// Start consuming events from the server. Returns a `Stream` that yields events continuously until the `event_limit` is reached.
     /// If `event_limit` is `None`, then the resulting `Stream` will never terminate unless there's an error.
     /// The `version_vector` represents the exclusive starting `EventCounter` for each partition on the stream that the consumer
@@ -120,6 +131,42 @@ impl <D: Debug> AsyncConnection<D> {
         Consume::new(self, namespace.into(), version_vector, event_limit, await_new)
     }
 
+    /// Start consuming events from the server, beginning with the first event in each partition that has a timestamp greater than
+    /// or equal to `since`. Otherwise, this behaves exactly the same as `consume`.
+    pub fn consume_since<N: Into<String>>(self, namespace: N, since: Timestamp, event_limit: Option<u64>, await_new: bool) -> Consume<D> {
+        Consume::since(self, namespace.into(), since, event_limit, await_new)
+    }
+
+    /// Start consuming events from the server, receiving only those events whose event time is greater than or equal to
+    /// `since`. Events that were produced without an event time are compared using their timestamp instead. Since event
+    /// times are not ordered, every partition is read from the beginning. Otherwise, this behaves exactly the same as `consume`.
+    pub fn consume_since_event_time<N: Into<String>>(self, namespace: N, since: Timestamp, event_limit: Option<u64>, await_new: bool) -> Consume<D> {
+        Consume::since_event_time(self, namespace.into(), since, event_limit, await_new)
+    }
+
+    /// Asks the server to write a consistent snapshot of the current event stream into `dest_dir`, which is a directory on
+    /// the server's filesystem. Returns a future that resolves to a tuple of the `SnapshotComplete` response and this
+    /// `AsyncConnection`.
+    pub fn snapshot<P: Into<String>>(self, dest_dir: P) -> Snapshot<D> {
+        Snapshot::new(self, dest_dir.into())
+    }
+
+    /// Deletes the events with the given ids, as well as every event that's currently in the stream with a namespace
+    /// matching the `namespace` glob, if one is given. Returns a future that resolves to this `AsyncConnection` once the
+    /// deletion has been persisted by the server.
+    pub fn delete(self, event_ids: Vec<FloEventId>, namespace: Option<String>) -> Delete<D> {
+        Delete::new(self, event_ids, namespace)
+    }
+
+    /// Makes this connection an idempotent producer, using the given `producer_id`. This must be called before the handshake
+    /// with the server. Events that are produced with a `sequence` will be persisted at most once for each sequence number,
+    /// so the same producer id must be used every time a producer connects, and no two producers may use the same id at the
+    /// same time.
+    pub fn with_producer_id(mut self, producer_id: ProducerId) -> AsyncConnection<D> {
+        self.inner.producer_id = Some(producer_id);
+        self
+    }
+
     /// Initiates the handshake with the server. The returned `Future` resolves the this connection, which will then be guaranteed
     /// to have the `current_stream()` return `Some`.
     pub fn connect(self) -> Handshake<D> {
@@ -190,6 +237,7 @@ impl From<io::Error> for ErrorType {
 /// from accessing it.
 struct AsyncConnectionInner<D: Debug> {
     client_name: String,
+    producer_id: Option<ProducerId>,
     recv_batch_size: Option<u32>,
     send: Option<MessageSender>,
     recv: Option<MessageReceiver>,
@@ -376,65 +424,73 @@ mod test {
         results
     }
 
+    fn produce_event(op_id: u32, partition: ActorId, namespace: &str) -> ProduceEvent {
+        ProduceEvent {
+            op_id: op_id,
+            partition: partition,
+            namespace: namespace.to_owned(),
+            parent_id: None,
+            event_time: None,
+            sequence: None,
+            expected_version: None,
+            headers: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
     #[test]
     fn produce_all_produces_multiple_events_in_sequence() {
+        use event::time;
+
+        let mut bar = produce_event(2, 2, "/bar");
+        bar.event_time = Some(time::from_millis_since_epoch(1234));
+        bar.headers = vec![("content-type".to_owned(), b"text/plain".to_vec())];
+        let mut baz = produce_event(3, 3, "/baz");
+        baz.sequence = Some(3);
         let expected_sent = vec![
-            ProtocolMessage::ProduceEvent(ProduceEvent {
+            ProtocolMessage::ProduceBatch(ProduceBatch {
                 op_id: 1,
                 partition: 1,
-                namespace: "/foo".to_owned(),
-                parent_id: None,
-                data: Vec::new(),
+                events: vec![produce_event(1, 1, "/foo")],
             }),
-            ProtocolMessage::ProduceEvent(ProduceEvent {
+            ProtocolMessage::ProduceBatch(ProduceBatch {
                 op_id: 2,
                 partition: 2,
-                namespace: "/bar".to_owned(),
-                parent_id: None,
-                data: Vec::new(),
+                events: vec![bar],
             }),
-            ProtocolMessage::ProduceEvent(ProduceEvent {
+            ProtocolMessage::ProduceBatch(ProduceBatch {
                 op_id: 3,
                 partition: 3,
-                namespace: "/baz".to_owned(),
-                parent_id: None,
-                data: Vec::new(),
+                events: vec![baz],
             })
         ];
         let to_recv = vec![
-            ProtocolMessage::AckEvent(EventAck{
+            ProtocolMessage::AckBatch(BatchAck{
                 op_id: 1,
-                event_id: FloEventId::new(1, 1),
+                first_event_id: FloEventId::new(1, 1),
+                last_event_id: FloEventId::new(1, 1),
+                original_ids: Vec::new(),
             }),
-            ProtocolMessage::AckEvent(EventAck{
+            ProtocolMessage::AckBatch(BatchAck{
                 op_id: 2,
-                event_id: FloEventId::new(2, 2),
+                first_event_id: FloEventId::new(2, 2),
+                last_event_id: FloEventId::new(2, 2),
+                original_ids: Vec::new(),
             }),
-            ProtocolMessage::AckEvent(EventAck{
+            ProtocolMessage::AckBatch(BatchAck{
                 op_id: 3,
-                event_id: FloEventId::new(3, 3),
+                first_event_id: FloEventId::new(3, 3),
+                last_event_id: FloEventId::new(3, 3),
+                original_ids: Vec::new(),
             }),
         ];
 
         let events_to_produce = vec![
-            EventToProduce {
-                partition: 1,
-                namespace: "/foo".to_owned(),
-                parent_id: None,
-                data: String::new()
-            },
-            EventToProduce {
-                partition: 2,
-                namespace: "/bar".to_owned(),
-                parent_id: None,
-                data: String::new()
-            },
-            EventToProduce {
-                partition: 3,
-                namespace: "/baz".to_owned(),
-                parent_id: None,
-                data: String::new()
-            }
+            EventToProduce::new(1, "/foo", None, String::new()),
+            EventToProduce::new(2, "/bar", None, String::new())
+                    .with_event_time(time::from_millis_since_epoch(1234))
+                    .with_header("content-type", "text/plain"),
+            EventToProduce::new(3, "/baz", None, String::new()).with_sequence(3)
         ];
 
         let recv = MockReceiveStream::will_produce(to_recv);
@@ -454,6 +510,139 @@ mod test {
         assert_eq!(expected_ids, result.events_produced);
     }
 
+    #[test]
+    fn produce_all_sends_consecutive_events_for_the_same_partition_in_one_batch() {
+        let expected_sent = vec![
+            ProtocolMessage::ProduceBatch(ProduceBatch {
+                op_id: 1,
+                partition: 1,
+                events: vec![produce_event(1, 1, "/foo"), produce_event(1, 1, "/bar"), produce_event(1, 1, "/baz")],
+            }),
+            ProtocolMessage::ProduceBatch(ProduceBatch {
+                op_id: 2,
+                partition: 2,
+                events: vec![produce_event(2, 2, "/foo")],
+            }),
+        ];
+        let to_recv = vec![
+            ProtocolMessage::AckBatch(BatchAck{
+                op_id: 1,
+                first_event_id: FloEventId::new(1, 5),
+                last_event_id: FloEventId::new(1, 7),
+                original_ids: Vec::new(),
+            }),
+            ProtocolMessage::AckBatch(BatchAck{
+                op_id: 2,
+                first_event_id: FloEventId::new(2, 8),
+                last_event_id: FloEventId::new(2, 8),
+                original_ids: Vec::new(),
+            }),
+        ];
+
+        let events_to_produce = vec![
+            EventToProduce::new(1, "/foo", None, String::new()),
+            EventToProduce::new(1, "/bar", None, String::new()),
+            EventToProduce::new(1, "/baz", None, String::new()),
+            EventToProduce::new(2, "/foo", None, String::new()),
+        ];
+
+        let recv = MockReceiveStream::will_produce(to_recv);
+        let (send, mut send_verify) = MockSendStream::new();
+        let connection = create_client(recv, send);
+
+        let result = run_future(connection.produce_all(events_to_produce.into_iter())).expect("failed to run produce_all");
+
+        assert_eq!(expected_sent, send_verify.get_received());
+        let expected_ids = vec![
+            FloEventId::new(1, 5),
+            FloEventId::new(1, 6),
+            FloEventId::new(1, 7),
+            FloEventId::new(2, 8),
+        ];
+        assert_eq!(expected_ids, result.events_produced);
+    }
+
+    #[test]
+    fn produce_all_sends_batches_without_waiting_for_acks_up_to_the_max_in_flight() {
+        let expected_sent = vec![
+            ProtocolMessage::ProduceBatch(ProduceBatch {
+                op_id: 1,
+                partition: 1,
+                events: vec![produce_event(1, 1, "/foo")],
+            }),
+            ProtocolMessage::ProduceBatch(ProduceBatch {
+                op_id: 2,
+                partition: 2,
+                events: vec![produce_event(2, 2, "/foo")],
+            }),
+        ];
+
+        let events_to_produce = vec![
+            EventToProduce::new(1, "/foo", None, String::new()),
+            EventToProduce::new(2, "/foo", None, String::new()),
+            EventToProduce::new(3, "/foo", None, String::new()),
+        ];
+
+        // the server never responds, so the operation fails once the third batch has to wait for the first ack
+        let recv = MockReceiveStream::empty();
+        let (send, mut send_verify) = MockSendStream::new();
+        let connection = create_client(recv, send);
+
+        let op = connection.produce_all(events_to_produce.into_iter()).with_max_in_flight(2);
+        let err = run_future(op).err().expect("expected produce_all to fail");
+
+        assert_eq!(expected_sent, send_verify.get_received());
+        assert!(err.result.events_produced.is_empty());
+    }
+
+    #[test]
+    fn produce_all_reads_the_remaining_acks_before_returning_an_error_for_a_batch_in_the_middle() {
+        fn ack(op_id: u32, partition: ActorId, counter: u64) -> ClientProtocolMessage {
+            ProtocolMessage::AckBatch(BatchAck{
+                op_id: op_id,
+                first_event_id: FloEventId::new(partition, counter),
+                last_event_id: FloEventId::new(partition, counter),
+                original_ids: Vec::new(),
+            })
+        }
+        let to_recv = vec![
+            ack(1, 1, 1),
+            ProtocolMessage::Error(ErrorMessage {
+                op_id: 2,
+                kind: ErrorKind::StorageEngineError,
+                description: "Persistence Error: disk full".to_owned(),
+            }),
+            ack(3, 3, 1),
+            ProtocolMessage::AckEvent(EventAck {
+                op_id: 4,
+                event_id: FloEventId::new(1, 2),
+            }),
+        ];
+
+        let events_to_produce = vec![
+            EventToProduce::new(1, "/foo", None, String::new()),
+            EventToProduce::new(2, "/foo", None, String::new()),
+            EventToProduce::new(3, "/foo", None, String::new()),
+        ];
+
+        let recv = MockReceiveStream::will_produce(to_recv);
+        let (send, mut send_verify) = MockSendStream::new();
+        let connection = create_client(recv, send);
+
+        let err = run_future(connection.produce_all(events_to_produce.into_iter())).err().expect("expected produce_all to fail");
+        assert_eq!(3, send_verify.get_received().len());
+        match err.error {
+            ErrorType::Server(ref message) => assert_eq!(2, message.op_id),
+            ref other => panic!("expected a server error, got: {:?}", other)
+        }
+        assert_eq!(vec![FloEventId::new(1, 1)], err.result.events_produced);
+
+        // the ack for the third batch was already read, so the next response on the connection is for the next operation
+        let (id, connection) = run_future(err.result.connection.produce_to(1, "/bar", None, String::new())).expect("failed to produce");
+        assert_eq!(FloEventId::new(1, 2), id);
+        assert!(connection.inner.received_message_buffer.is_empty());
+    }
+
     #[test]
     fn produce_all_returns_immediate_success_when_iterator_is_empty() {
         let recv = MockReceiveStream::empty();
@@ -476,6 +665,93 @@ mod test {
         assert!(send_verify.get_received().is_empty());
     }
 
+    #[test]
+    fn produce_returns_concurrency_conflict_when_namespace_is_not_at_the_expected_version() {
+        let mut expected_event = produce_event(1, 1, "/carts/77");
+        expected_event.expected_version = Some(ExpectedVersion::LastEventId(FloEventId::new(1, 5)));
+        let expected_sent = vec![ProtocolMessage::ProduceEvent(expected_event)];
+        let to_recv = vec![ProtocolMessage::Error(ErrorMessage {
+            op_id: 1,
+            kind: ErrorKind::ConcurrencyConflict,
+            description: "expected last event in namespace: /carts/77 to be: 5.1, but the last event was: 6.1".to_owned(),
+        })];
+
+        let recv = MockReceiveStream::will_produce(to_recv);
+        let (send, mut send_verify) = MockSendStream::new();
+        let connection = create_client(recv, send);
+
+        let event = EventToProduce::new(1, "/carts/77", None, String::new())
+                .with_expected_version(ExpectedVersion::LastEventId(FloEventId::new(1, 5)));
+        let err = run_future(connection.produce(event)).err().expect("expected produce to fail");
+
+        assert_eq!(expected_sent, send_verify.get_received());
+        match err.err {
+            ErrorType::Server(ref message) => assert_eq!(ErrorKind::ConcurrencyConflict, message.kind),
+            ref other => panic!("expected a server error, got: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn produce_in_transaction_sends_every_batch_before_committing() {
+        let expected_sent = vec![
+            ProtocolMessage::BeginTransaction(1),
+            ProtocolMessage::ProduceBatch(ProduceBatch {
+                op_id: 2,
+                partition: 1,
+                events: vec![produce_event(2, 1, "/foo"), produce_event(2, 1, "/bar")],
+            }),
+            ProtocolMessage::ProduceBatch(ProduceBatch {
+                op_id: 3,
+                partition: 2,
+                events: vec![produce_event(3, 2, "/foo")],
+            }),
+            ProtocolMessage::CommitTransaction(4),
+        ];
+        let to_recv = vec![
+            ProtocolMessage::AckTransaction(TransactionAck {
+                op_id: 1,
+                produced: Vec::new(),
+            }),
+            ProtocolMessage::AckTransaction(TransactionAck {
+                op_id: 4,
+                produced: vec![
+                    BatchAck {
+                        op_id: 2,
+                        first_event_id: FloEventId::new(1, 5),
+                        last_event_id: FloEventId::new(1, 6),
+                        original_ids: Vec::new(),
+                    },
+                    BatchAck {
+                        op_id: 3,
+                        first_event_id: FloEventId::new(2, 7),
+                        last_event_id: FloEventId::new(2, 7),
+                        original_ids: Vec::new(),
+                    },
+                ],
+            }),
+        ];
+
+        let events_to_produce = vec![
+            EventToProduce::new(1, "/foo", None, String::new()),
+            EventToProduce::new(1, "/bar", None, String::new()),
+            EventToProduce::new(2, "/foo", None, String::new()),
+        ];
+
+        let recv = MockReceiveStream::will_produce(to_recv);
+        let (send, mut send_verify) = MockSendStream::new();
+        let connection = create_client(recv, send);
+
+        let result = run_future(connection.produce_in_transaction(events_to_produce)).expect("failed to run produce_in_transaction");
+
+        assert_eq!(expected_sent, send_verify.get_received());
+        let expected_ids = vec![
+            FloEventId::new(1, 5),
+            FloEventId::new(1, 6),
+            FloEventId::new(2, 7),
+        ];
+        assert_eq!(expected_ids, result.events_produced);
+    }
+
     #[test]
     fn connect_initiates_connection() {
         let to_recv = vec![ProtocolMessage::StreamStatus(EventStreamStatus {
@@ -568,8 +844,10 @@ mod test {
             ProtocolMessage::ReceiveEvent(OwnedFloEvent {
                 id: FloEventId::new(3, 4),
                 timestamp: time::from_millis_since_epoch(8),
+                event_time: Some(time::from_millis_since_epoch(7)),
                 parent_id: None,
                 namespace: "/foo/bar".to_owned(),
+                headers: vec![("content-type".to_owned(), b"text/plain".to_vec())],
                 data: "first event data".as_bytes().to_owned(),
             }),
             ProtocolMessage::EndOfBatch,
@@ -577,8 +855,10 @@ mod test {
             ProtocolMessage::ReceiveEvent(OwnedFloEvent {
                 id: FloEventId::new(3, 5),
                 timestamp: time::from_millis_since_epoch(9),
+                event_time: None,
                 parent_id: Some(FloEventId::new(3, 4)),
                 namespace: "/foo/bar".to_owned(),
+                headers: Vec::new(),
                 data: "second event data".as_bytes().to_owned(),
             }),
         ];
@@ -613,15 +893,19 @@ mod test {
             Event {
                 id: FloEventId::new(3, 4),
                 timestamp: time::from_millis_since_epoch(8),
+                event_time: Some(time::from_millis_since_epoch(7)),
                 parent_id: None,
                 namespace: "/foo/bar".to_owned(),
+                headers: vec![("content-type".to_owned(), b"text/plain".to_vec())],
                 data: "first event data".to_owned(),
             },
             Event {
                 id: FloEventId::new(3, 5),
                 timestamp: time::from_millis_since_epoch(9),
+                event_time: None,
                 parent_id: Some(FloEventId::new(3, 4)),
                 namespace: "/foo/bar".to_owned(),
+                headers: Vec::new(),
                 data: "second event data".to_owned(),
             }
         ];
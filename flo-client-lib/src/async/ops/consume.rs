@@ -4,8 +4,8 @@ use std::io;
 
 use futures::{Future, Async, Poll, Stream};
 
-use event::{VersionVector, OwnedFloEvent};
-use protocol::{ProtocolMessage, NewConsumerStart, CONSUME_UNLIMITED};
+use event::{VersionVector, OwnedFloEvent, Timestamp};
+use protocol::{ProtocolMessage, NewConsumerStart, ConsumeSince, CONSUME_UNLIMITED};
 use async::{AsyncConnection, ErrorType, ClientProtocolMessage};
 use async::ops::{SendMessage, SendError, AwaitResponse, AwaitResponseError, RequestResponse};
 use ::Event;
@@ -33,6 +33,35 @@ impl <D: Debug> Consume<D> {
             namespace: namespace.clone(),
         };
         let message = ProtocolMessage::NewStartConsuming(consumer_start);
+        Consume::with_start_message(connection, op_id, message, namespace, event_limit, await_new)
+    }
+
+    /// Creates a consumer that will start with the first event in each partition that has a timestamp greater than or
+    /// equal to `since`
+    pub fn since(connection: AsyncConnection<D>, namespace: String, since: Timestamp, event_limit: Option<u64>, await_new: bool) -> Consume<D> {
+        Consume::start_since(connection, namespace, since, false, event_limit, await_new)
+    }
+
+    /// Creates a consumer that will receive every event in each partition that has an event time greater than or equal
+    /// to `since`, using the timestamp of any event that doesn't have an event time
+    pub fn since_event_time(connection: AsyncConnection<D>, namespace: String, since: Timestamp, event_limit: Option<u64>, await_new: bool) -> Consume<D> {
+        Consume::start_since(connection, namespace, since, true, event_limit, await_new)
+    }
+
+    fn start_since(mut connection: AsyncConnection<D>, namespace: String, since: Timestamp, by_event_time: bool, event_limit: Option<u64>, await_new: bool) -> Consume<D> {
+        let op_id = connection.next_op_id();
+        let consume_since = ConsumeSince {
+            op_id: op_id,
+            since: since,
+            by_event_time: by_event_time,
+            max_events: event_limit.unwrap_or(CONSUME_UNLIMITED),
+            namespace: namespace.clone(),
+        };
+        let message = ProtocolMessage::StartConsumingSince(consume_since);
+        Consume::with_start_message(connection, op_id, message, namespace, event_limit, await_new)
+    }
+
+    fn with_start_message(connection: AsyncConnection<D>, op_id: u32, message: ClientProtocolMessage, namespace: String, event_limit: Option<u64>, await_new: bool) -> Consume<D> {
         let initial_state = State::RequestStart(SendMessage::new(connection, message));
 
         Consume {
@@ -0,0 +1,78 @@
+use std::fmt::Debug;
+use std::io;
+
+use futures::{Future, Poll, Async};
+
+use event::FloEventId;
+use protocol::{ProtocolMessage, DeleteEvents};
+use async::{AsyncConnection, ErrorType, ClientProtocolMessage};
+use async::ops::{RequestResponse, RequestResponseError};
+
+/// An operation that deletes events from the current event stream, either by their ids or by a namespace glob, or both.
+/// Deleted events are never returned to consumers again, but their ids are never reused. This future resolves to the
+/// connection itself once every affected partition on the server has persisted the deletion.
+#[derive(Debug)]
+#[must_use = "futures must be polled in order to do any work"]
+pub struct Delete<D: Debug> {
+    request_response: RequestResponse<D>,
+}
+
+impl <D: Debug> Delete<D> {
+    pub fn new(mut connection: AsyncConnection<D>, event_ids: Vec<FloEventId>, namespace: Option<String>) -> Delete<D> {
+        let op_id = connection.next_op_id();
+        let request = ProtocolMessage::DeleteEvents(DeleteEvents {
+            op_id: op_id,
+            event_ids: event_ids,
+            namespace: namespace,
+        });
+        Delete {
+            request_response: RequestResponse::new(connection, request),
+        }
+    }
+}
+
+impl <D: Debug> Future for Delete<D> {
+    type Item = AsyncConnection<D>;
+    type Error = DeleteError<D>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let (response, connection) = try_ready!(self.request_response.poll());
+        result_from_response(response, connection)
+    }
+}
+
+fn result_from_response<D: Debug>(response: ClientProtocolMessage, connection: AsyncConnection<D>) -> Poll<AsyncConnection<D>, DeleteError<D>> {
+    match response {
+        ProtocolMessage::EventsDeleted(_) => {
+            Ok(Async::Ready(connection))
+        }
+        ProtocolMessage::Error(err_response) => {
+            Err(DeleteError {
+                connection: connection,
+                err: ErrorType::Server(err_response),
+            })
+        }
+        other @ _ => {
+            let io_err = io::Error::new(io::ErrorKind::InvalidData, format!("Invalid response from server: {:?}", other));
+            Err(DeleteError {
+                connection: connection,
+                err: ErrorType::Io(io_err),
+            })
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DeleteError<D: Debug> {
+    pub connection: AsyncConnection<D>,
+    pub err: ErrorType,
+}
+
+impl <D: Debug> From<RequestResponseError<D>> for DeleteError<D> {
+    fn from(RequestResponseError{connection, error}: RequestResponseError<D>) -> Self {
+        DeleteError {
+            connection: connection,
+            err: ErrorType::Io(error),
+        }
+    }
+}
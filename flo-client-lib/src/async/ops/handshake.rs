@@ -4,12 +4,10 @@ use std::io;
 
 use futures::{Future, Async, Poll};
 
-use protocol::{ProtocolMessage, ClientAnnounce};
+use protocol::{ProtocolMessage, ClientAnnounce, PROTOCOL_VERSION};
 use async::{AsyncConnection, ErrorType, ClientProtocolMessage};
 use async::ops::{RequestResponse, RequestResponseError};
 
-const PROTOCOL_VERSION: u32 = 1;
-
 pub struct Handshake<D: Debug> {
     request_response: RequestResponse<D>
 }
@@ -23,6 +21,7 @@ impl <D: Debug> Handshake<D> {
             op_id: op_id,
             client_name: connection.inner.client_name.clone(),
             consume_batch_size: batch_size,
+            producer_id: connection.inner.producer_id,
         });
         let inner = RequestResponse::new(connection, request);
 
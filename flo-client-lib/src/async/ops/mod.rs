@@ -4,10 +4,16 @@ mod await_response;
 mod consume;
 mod request_response;
 mod handshake;
+mod snapshot;
+mod delete;
+mod transaction;
 
 pub use self::send_message::{SendMessage, SendError};
 pub use self::await_response::{AwaitResponse, AwaitResponseError};
-pub use self::produce::{ProduceOne, ProduceErr, EventToProduce, ProduceAll, ProduceAllError, ProduceAllResult};
+pub use self::produce::{ProduceOne, ProduceErr, EventToProduce, ProduceAll, ProduceAllError, ProduceAllResult, DEFAULT_MAX_IN_FLIGHT_BATCHES};
 pub use self::consume::{Consume, ConsumeError};
 pub use self::request_response::{RequestResponse, RequestResponseError};
 pub use self::handshake::{Handshake, HandshakeError};
+pub use self::snapshot::{Snapshot, SnapshotError};
+pub use self::delete::{Delete, DeleteError};
+pub use self::transaction::ProduceTransaction;
@@ -1,12 +1,16 @@
 use std::fmt::Debug;
 use std::io;
+use std::error::Error;
+use std::collections::VecDeque;
+use std::iter::Fuse;
+use std::mem;
 
 use futures::{Future, Poll, Async};
 
-use event::{FloEventId, ActorId};
-use protocol::{ProtocolMessage, ProduceEvent};
+use event::{FloEventId, ActorId, EventHeader, Timestamp};
+use protocol::{ProtocolMessage, ProduceEvent, ProduceBatch, ExpectedVersion, BUFFER_LENGTH};
 use async::{AsyncConnection, ErrorType, ClientProtocolMessage};
-use async::ops::{RequestResponse, RequestResponseError};
+use async::ops::{RequestResponse, RequestResponseError, SendMessage, SendError, AwaitResponse, AwaitResponseError};
 
 /// An operation that produces a single event on an event stream and waits for it to be acknowledged. This future will
 /// not resolve until acknowledgement is received from the server that either the event has been persisted successfully or
@@ -28,17 +32,10 @@ enum Inner<D: Debug> {
 
 
 impl <D: Debug> ProduceOne<D> {
-    pub fn new(mut connection: AsyncConnection<D>, partition: ActorId, namespace: String, parent_id: Option<FloEventId>, data: D) -> ProduceOne<D> {
+    pub fn new(mut connection: AsyncConnection<D>, event: EventToProduce<D>) -> ProduceOne<D> {
         let op_id = connection.next_op_id();
-        let inner: Inner<D> = match connection.inner.codec.convert_produced(&namespace, data) {
-            Ok(converted) => {
-                let proto_msg = ProduceEvent{
-                    op_id,
-                    partition,
-                    namespace,
-                    parent_id,
-                    data: converted,
-                };
+        let inner: Inner<D> = match to_produce_event(&connection, op_id, event) {
+            Ok(proto_msg) => {
                 Inner::RequestResp(RequestResponse::new(connection, ProtocolMessage::ProduceEvent(proto_msg)))
             }
             Err(codec_err) => {
@@ -108,6 +105,24 @@ impl <D: Debug> Into<AsyncConnection<D>> for ProduceOne<D> {
     }
 }
 
+/// Converts the event into the protocol message, using the connection's codec to convert the data
+pub fn to_produce_event<D: Debug>(connection: &AsyncConnection<D>, op_id: u32, event: EventToProduce<D>) -> Result<ProduceEvent, Box<Error>> {
+    let EventToProduce{partition, namespace, parent_id, event_time, sequence, expected_version, headers, data} = event;
+    connection.inner.codec.convert_produced(&namespace, data).map(|converted| {
+        ProduceEvent{
+            op_id,
+            partition,
+            namespace,
+            parent_id,
+            event_time,
+            sequence,
+            expected_version,
+            headers,
+            data: converted,
+        }
+    })
+}
+
 /// Error type when there is a failure to produce an event. Includes the connection itself, in case it can be reused
 #[derive(Debug)]
 pub struct ProduceErr<D: Debug> {
@@ -133,6 +148,18 @@ pub struct EventToProduce<D: Debug> {
     pub partition: ActorId,
     pub namespace: String,
     pub parent_id: Option<FloEventId>,
+    /// The time that the event actually happened, if it's different from the time that it's produced. This is stored
+    /// alongside the timestamp that the server assigns, and is never used for ordering events
+    pub event_time: Option<Timestamp>,
+    /// The sequence number of this event, which is only used if the connection has a producer id. The server persists at
+    /// most one event for each sequence number from a producer, as long as the original is still in its dedupe window, so
+    /// an event that may or may not have been persisted can safely be re-sent using the same sequence number. Sequence
+    /// numbers must be greater than 0, and it's up to the application to keep track of them.
+    pub sequence: Option<u64>,
+    /// The state that the namespace must be in for this event to be produced. If it's in any other state, then the event
+    /// is rejected with a `ConcurrencyConflict` error, along with any other events that are sent in the same batch.
+    pub expected_version: Option<ExpectedVersion>,
+    pub headers: Vec<EventHeader>,
     pub data: D,
 }
 
@@ -142,50 +169,216 @@ impl <D: Debug> EventToProduce<D> {
             partition,
             namespace: namespace.into(),
             parent_id,
+            event_time: None,
+            sequence: None,
+            expected_version: None,
+            headers: Vec::new(),
             data
         }
     }
 
+    /// Sets the state that the namespace must be in for this event to be produced, either having no events yet or having
+    /// a specific event as its most recent one
+    pub fn with_expected_version(mut self, expected_version: ExpectedVersion) -> EventToProduce<D> {
+        self.expected_version = Some(expected_version);
+        self
+    }
+
+    /// Sets the sequence number that's used to detect if this event gets produced more than once
+    pub fn with_sequence(mut self, sequence: u64) -> EventToProduce<D> {
+        self.sequence = Some(sequence);
+        self
+    }
+
+    /// Sets the time that the event actually happened, for example when backfilling historical data
+    pub fn with_event_time(mut self, event_time: Timestamp) -> EventToProduce<D> {
+        self.event_time = Some(event_time);
+        self
+    }
+
+    /// Adds a header to the event. Headers are sent in the order they were added, and the same name may be used more than once
+    pub fn with_header<N: Into<String>, V: Into<Vec<u8>>>(mut self, name: N, value: V) -> EventToProduce<D> {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
     pub fn witout_parent<N: Into<String>>(partition: ActorId, namespace: N, data: D) -> EventToProduce<D> {
         EventToProduce::new(partition, namespace, None, data)
     }
 }
 
 
-/// An operation that will produce each event from the iterator in sequence. This will wait for acknowledgement of each event
-/// before proceeding to the next, and will short circuit the rest of the iterator when an error is encountered.
+/// The default maximum number of batches that a `ProduceAll` will send before waiting for the first of them to be
+/// acknowledged
+pub const DEFAULT_MAX_IN_FLIGHT_BATCHES: usize = 16;
+
+/// An operation that will produce each event from the iterator in order. Consecutive events for the same partition are
+/// sent together in a single `ProduceBatch` message, as many as will fit in one batch header. Batches are pipelined, so
+/// up to `max_in_flight` of them may be sent before the first one is acknowledged. The server acknowledges them in the
+/// order they were sent. This will short circuit the rest of the iterator when an error is encountered, but batches that
+/// were already sent may still be persisted. If the server rejects a batch, then the acks for the batches that were sent
+/// after it are still read before the error is returned, so the connection can be reused. Only the ids of the events
+/// before the rejected batch are included in the result.
 #[derive(Debug)]
 #[must_use = "futures must be polled in order to do any work"]
 pub struct ProduceAll<D: Debug, I: Iterator<Item=EventToProduce<D>>> {
-    iter: Option<I>,
-    current_op: Option<ProduceOne<D>>,
-    conn: Option<AsyncConnection<D>>,
+    iter: Fuse<I>,
+    /// An event that was taken from the iterator, but was not able to be added to the previous batch
+    next_event: Option<ProduceEvent>,
+    /// An error converting an event, which gets returned once the events before it have been produced
+    next_error: Option<ErrorType>,
+    /// An error response to a batch, which gets returned once the rest of the batches that were sent have been acknowledged
+    failure: Option<ErrorType>,
+    /// The op_id and number of events of each batch that has been sent but not yet acknowledged, in the order they were sent
+    unacknowledged: VecDeque<(u32, usize)>,
+    max_in_flight: usize,
+    state: ProduceAllState<D>,
     produced_ids: Vec<FloEventId>,
 }
 
+#[derive(Debug)]
+enum ProduceAllState<D: Debug> {
+    Idle(AsyncConnection<D>),
+    Sending(SendMessage<D>),
+    AwaitingAck(AwaitResponse<D>),
+    Done,
+}
+
 impl <D: Debug, I: Iterator<Item=EventToProduce<D>>> ProduceAll<D, I> {
     /// Creates a new `ProduceAll` for the given client which will produce the events in the iterator. An empty iterator
     /// is acceptable, and will just immediately return a successful result when the future is polled
-    pub fn new(connection: AsyncConnection<D>, mut iterator: I) -> ProduceAll<D, I> {
-        let first_event = iterator.next();
-
-        if let Some(EventToProduce{partition, namespace, parent_id, data}) = first_event {
-            let first_op = connection.produce_to(partition, namespace, parent_id, data);
-            ProduceAll {
-                iter: Some(iterator),
-                current_op: Some(first_op),
-                conn: None,
-                produced_ids: Vec::new(),
+    pub fn new(connection: AsyncConnection<D>, iterator: I) -> ProduceAll<D, I> {
+        ProduceAll {
+            iter: iterator.fuse(),
+            next_event: None,
+            next_error: None,
+            failure: None,
+            unacknowledged: VecDeque::new(),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT_BATCHES,
+            state: ProduceAllState::Idle(connection),
+            produced_ids: Vec::new(),
+        }
+    }
+
+    /// Sets the maximum number of batches that may be sent before waiting for the first of them to be acknowledged. A
+    /// value of 1 means that each batch is acknowledged before the next one is sent.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> ProduceAll<D, I> {
+        assert!(max_in_flight > 0, "max_in_flight must be greater than 0");
+        self.max_in_flight = max_in_flight;
+        self
+    }
+
+    /// Takes events from the iterator until one of them is for a different partition or would make the batch header too
+    /// large. Returns `None` once the iterator is exhausted.
+    fn next_batch(&mut self, connection: &mut AsyncConnection<D>) -> Result<Option<ProduceBatch>, ErrorType> {
+        if let Some(err) = self.next_error.take() {
+            return Err(err);
+        }
+        let first = match self.next_event.take() {
+            Some(event) => event,
+            None => match self.iter.next() {
+                Some(event) => to_produce_event(connection, 0, event).map_err(ErrorType::Codec)?,
+                None => return Ok(None)
             }
-        } else {
-            // empty produceAll, which will simply yield a result the first time it is polled
-            ProduceAll {
-                iter: None,
-                current_op: None,
-                conn: Some(connection),
-                produced_ids: Vec::new(),
+        };
+
+        let mut batch = ProduceBatch {
+            op_id: connection.next_op_id(),
+            partition: first.partition,
+            events: vec![first],
+        };
+        let mut header_len = batch.header_len();
+        while let Some(event) = self.iter.next() {
+            match to_produce_event(connection, 0, event) {
+                Ok(event) => {
+                    let event_header_len = ProduceBatch::event_header_len(&event);
+                    if event.partition != batch.partition || header_len + event_header_len > BUFFER_LENGTH {
+                        self.next_event = Some(event);
+                        break;
+                    }
+                    header_len += event_header_len;
+                    batch.events.push(event);
+                }
+                Err(codec_err) => {
+                    self.next_error = Some(ErrorType::Codec(codec_err));
+                    break;
+                }
+            }
+        }
+
+        // events are converted before it's known which batch they'll be in
+        for event in batch.events.iter_mut() {
+            event.op_id = batch.op_id;
+        }
+        Ok(Some(batch))
+    }
+
+    /// Determines what to do next once the connection is available, either sending another batch, waiting for the
+    /// oldest unacknowledged batch, or completing successfully
+    fn next_state(&mut self, mut connection: AsyncConnection<D>) -> Poll<ProduceAllResult<D>, ProduceAllError<D>> {
+        if self.failure.is_some() {
+            if let Some(&(op_id, _)) = self.unacknowledged.front() {
+                self.state = ProduceAllState::AwaitingAck(AwaitResponse::new(connection, op_id));
+                return Ok(Async::NotReady);
+            }
+            let failure = self.failure.take().unwrap();
+            return Err(self.error(connection, failure));
+        }
+
+        if self.unacknowledged.len() < self.max_in_flight {
+            match self.next_batch(&mut connection) {
+                Ok(Some(batch)) => {
+                    trace!("Sending batch with op_id: {} with {} events", batch.op_id, batch.events.len());
+                    self.unacknowledged.push_back((batch.op_id, batch.events.len()));
+                    self.state = ProduceAllState::Sending(SendMessage::new(connection, ProtocolMessage::ProduceBatch(batch)));
+                    return Ok(Async::NotReady);
+                }
+                Ok(None) => { }
+                Err(err) => {
+                    if self.unacknowledged.is_empty() {
+                        return Err(self.error(connection, err));
+                    }
+                    // the error gets returned once the batches that were already sent have been acknowledged
+                    self.next_error = Some(err);
+                }
             }
         }
+
+        if let Some(&(op_id, _)) = self.unacknowledged.front() {
+            self.state = ProduceAllState::AwaitingAck(AwaitResponse::new(connection, op_id));
+            Ok(Async::NotReady)
+        } else {
+            debug!("Successfully produced all {} events", self.produced_ids.len());
+            Ok(Async::Ready(ProduceAllResult {
+                connection,
+                events_produced: ::std::mem::replace(&mut self.produced_ids, Vec::new()),
+            }))
+        }
+    }
+
+    /// Handles an error response to a batch. The error is returned right away unless other batches are still waiting to
+    /// be acknowledged, in which case their acks are read first.
+    fn failed(&mut self, connection: AsyncConnection<D>, error: ErrorType) -> Result<AsyncConnection<D>, ProduceAllError<D>> {
+        if self.failure.is_some() {
+            debug!("Ignoring error after a previous batch already failed: {:?}", error);
+            return Ok(connection);
+        }
+        if self.unacknowledged.is_empty() {
+            return Err(self.error(connection, error));
+        }
+        debug!("Batch failed with error: {:?}, waiting for {} more acks before returning it", error, self.unacknowledged.len());
+        self.failure = Some(error);
+        Ok(connection)
+    }
+
+    fn error(&mut self, connection: AsyncConnection<D>, error: ErrorType) -> ProduceAllError<D> {
+        ProduceAllError {
+            error: error,
+            result: ProduceAllResult {
+                connection: connection,
+                events_produced: ::std::mem::replace(&mut self.produced_ids, Vec::new()),
+            },
+        }
     }
 }
 
@@ -211,54 +404,58 @@ impl <D: Debug, I: Iterator<Item=EventToProduce<D>>> Future for ProduceAll<D, I>
     type Error = ProduceAllError<D>;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        {
-            let ProduceAll{ref mut iter, ref mut current_op, ref mut conn, ref mut produced_ids} = *self;
-
-            if current_op.is_some() {
-
-                let (id, connection) = {
-                    let produce = current_op.as_mut().unwrap();
-                    let produce_result = produce.poll().map_err(|err| {
-                        let ProduceErr {connection, err} = err;
-                        let events_produced = ::std::mem::replace(produced_ids, Vec::new());
-                        let res = ProduceAllResult {
-                            connection,
-                            events_produced
-                        };
-                        ProduceAllError {
-                            error: err,
-                            result: res,
+        loop {
+            let connection = match mem::replace(&mut self.state, ProduceAllState::Done) {
+                ProduceAllState::Idle(connection) => connection,
+                ProduceAllState::Sending(mut send) => {
+                    match send.poll() {
+                        Ok(Async::Ready(connection)) => connection,
+                        Ok(Async::NotReady) => {
+                            self.state = ProduceAllState::Sending(send);
+                            return Ok(Async::NotReady);
                         }
-                    });
-                    try_ready!(produce_result)
-                };
-                produced_ids.push(id);
-                debug!("Finished producing event: {} with id: {}", produced_ids.len(), id);
-
-                if let Some(next) = iter.as_mut().expect("attempted to poll ProduceAll after it completed with error").next() {
-                    let EventToProduce {partition, namespace, parent_id, data} = next;
-                    let produce = connection.produce_to(partition, namespace, parent_id, data);
-                    *current_op = Some(produce)
-                } else {
-                    *conn = Some(connection);
+                        Err(SendError {connection, err}) => {
+                            return Err(self.error(connection, ErrorType::Io(err)));
+                        }
+                    }
                 }
-            }
-        }
-
+                ProduceAllState::AwaitingAck(mut await_ack) => {
+                    let (response, connection) = match await_ack.poll() {
+                        Ok(Async::Ready(ready)) => ready,
+                        Ok(Async::NotReady) => {
+                            self.state = ProduceAllState::AwaitingAck(await_ack);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(AwaitResponseError {connection, err}) => {
+                            return Err(self.error(connection, ErrorType::Io(err)));
+                        }
+                    };
+                    let (_, event_count) = self.unacknowledged.pop_front().expect("awaited an ack without an unacknowledged batch");
+
+                    match response {
+                        ProtocolMessage::AckBatch(ack) => {
+                            // retransmitted events are acknowledged with the ids of the originals
+                            if self.failure.is_none() {
+                                self.produced_ids.extend(ack.event_ids(event_count));
+                            }
+                            debug!("Finished producing batch of {} events ending with id: {}", event_count, ack.last_event_id);
+                            connection
+                        }
+                        ProtocolMessage::Error(err_response) => {
+                            self.failed(connection, ErrorType::Server(err_response))?
+                        }
+                        other @ _ => {
+                            self.failed(connection, ErrorType::unexpected_message("BatchAck", other))?
+                        }
+                    }
+                }
+                ProduceAllState::Done => panic!("attempted to poll ProduceAll after it completed"),
+            };
 
-        if let Some(connection) = self.conn.take() {
-            debug!("Successfully produced all {} events", self.produced_ids.len());
-            Ok(Async::Ready(ProduceAllResult {
-                connection,
-                events_produced: ::std::mem::replace(&mut self.produced_ids, Vec::new()),
-            }))
-        } else {
-            self.poll()
+            // `NotReady` here just means that there's more work to do, so the new state gets polled right away
+            if let Async::Ready(result) = self.next_state(connection)? {
+                return Ok(Async::Ready(result));
+            }
         }
-
     }
 }
-
-
-
-
@@ -0,0 +1,76 @@
+use std::fmt::Debug;
+use std::io;
+
+use futures::{Future, Poll, Async};
+
+use protocol::{ProtocolMessage, SnapshotRequest, SnapshotComplete};
+use async::{AsyncConnection, ErrorType, ClientProtocolMessage};
+use async::ops::{RequestResponse, RequestResponseError};
+
+/// An operation that asks the server to write a consistent snapshot of the current event stream to a directory on the
+/// server's filesystem. If successful, this future resolves to the `SnapshotComplete` sent by the server, which describes
+/// the events that were included in the snapshot, along with the connection itself for reuse.
+#[derive(Debug)]
+#[must_use = "futures must be polled in order to do any work"]
+pub struct Snapshot<D: Debug> {
+    request_response: RequestResponse<D>,
+}
+
+impl <D: Debug> Snapshot<D> {
+    pub fn new(mut connection: AsyncConnection<D>, dest_dir: String) -> Snapshot<D> {
+        let op_id = connection.next_op_id();
+        let request = ProtocolMessage::Snapshot(SnapshotRequest {
+            op_id: op_id,
+            dest_dir: dest_dir,
+        });
+        Snapshot {
+            request_response: RequestResponse::new(connection, request),
+        }
+    }
+}
+
+impl <D: Debug> Future for Snapshot<D> {
+    type Item = (SnapshotComplete, AsyncConnection<D>);
+    type Error = SnapshotError<D>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let (response, connection) = try_ready!(self.request_response.poll());
+        result_from_response(response, connection)
+    }
+}
+
+fn result_from_response<D: Debug>(response: ClientProtocolMessage, connection: AsyncConnection<D>) -> Poll<(SnapshotComplete, AsyncConnection<D>), SnapshotError<D>> {
+    match response {
+        ProtocolMessage::SnapshotComplete(complete) => {
+            Ok(Async::Ready((complete, connection)))
+        }
+        ProtocolMessage::Error(err_response) => {
+            Err(SnapshotError {
+                connection: connection,
+                err: ErrorType::Server(err_response),
+            })
+        }
+        other @ _ => {
+            let io_err = io::Error::new(io::ErrorKind::InvalidData, format!("Invalid response from server: {:?}", other));
+            Err(SnapshotError {
+                connection: connection,
+                err: ErrorType::Io(io_err),
+            })
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SnapshotError<D: Debug> {
+    pub connection: AsyncConnection<D>,
+    pub err: ErrorType,
+}
+
+impl <D: Debug> From<RequestResponseError<D>> for SnapshotError<D> {
+    fn from(RequestResponseError{connection, error}: RequestResponseError<D>) -> Self {
+        SnapshotError {
+            connection: connection,
+            err: ErrorType::Io(error),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use std::fmt::Debug;
+use std::io;
+use std::collections::VecDeque;
+use std::mem;
+
+use futures::{Future, Poll, Async};
+
+use event::FloEventId;
+use protocol::{ProtocolMessage, ProduceBatch, BUFFER_LENGTH, MAX_TRANSACTION_PRODUCE_OPS};
+use async::{AsyncConnection, ErrorType, ClientProtocolMessage};
+use async::ops::{RequestResponse, SendMessage, SendError, AwaitResponse, AwaitResponseError};
+use async::ops::produce::{to_produce_event, EventToProduce, ProduceErr, ProduceAllResult};
+
+/// An operation that produces all of the events atomically, even if they are for different partitions. Consumers will
+/// see either all of the events or none of them. Consecutive events for the same partition are sent together in a single
+/// `ProduceBatch`, and the server holds onto every batch until the transaction is committed. If successful, this future
+/// resolves to the ids of all the events, in the same order as the events were given.
+#[derive(Debug)]
+#[must_use = "futures must be polled in order to do any work"]
+pub struct ProduceTransaction<D: Debug> {
+    commit_op_id: u32,
+    /// The batches of events, followed by the commit, which are sent once the server has acknowledged the beginning of the
+    /// transaction
+    to_send: VecDeque<ClientProtocolMessage>,
+    state: TransactionState<D>,
+}
+
+#[derive(Debug)]
+enum TransactionState<D: Debug> {
+    InvalidEvents(AsyncConnection<D>, ErrorType),
+    Beginning(RequestResponse<D>),
+    Sending(SendMessage<D>),
+    AwaitingCommit(AwaitResponse<D>),
+    Done,
+}
+
+impl <D: Debug> ProduceTransaction<D> {
+    /// Creates a new transaction containing all of the events. Every event is converted before anything is sent to the
+    /// server, so a codec error means that the transaction is never started.
+    pub fn new<I: IntoIterator<Item=EventToProduce<D>>>(mut connection: AsyncConnection<D>, events: I) -> ProduceTransaction<D> {
+        let begin_op_id = connection.next_op_id();
+        let batches = to_batches(&mut connection, events);
+        let commit_op_id = connection.next_op_id();
+
+        let (to_send, state) = match batches {
+            Ok(batches) => {
+                let mut to_send = batches.into_iter().map(ProtocolMessage::ProduceBatch).collect::<VecDeque<_>>();
+                to_send.push_back(ProtocolMessage::CommitTransaction(commit_op_id));
+                let begin = RequestResponse::new(connection, ProtocolMessage::BeginTransaction(begin_op_id));
+                (to_send, TransactionState::Beginning(begin))
+            }
+            Err(err) => (VecDeque::new(), TransactionState::InvalidEvents(connection, err))
+        };
+
+        ProduceTransaction {
+            commit_op_id: commit_op_id,
+            to_send: to_send,
+            state: state,
+        }
+    }
+
+    fn send_next(&mut self, connection: AsyncConnection<D>) {
+        self.state = match self.to_send.pop_front() {
+            Some(message) => TransactionState::Sending(SendMessage::new(connection, message)),
+            None => TransactionState::AwaitingCommit(AwaitResponse::new(connection, self.commit_op_id)),
+        };
+    }
+}
+
+impl <D: Debug> Future for ProduceTransaction<D> {
+    type Item = ProduceAllResult<D>;
+    type Error = ProduceErr<D>;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let connection = match mem::replace(&mut self.state, TransactionState::Done) {
+                TransactionState::InvalidEvents(connection, err) => {
+                    return Err(ProduceErr { connection, err });
+                }
+                TransactionState::Beginning(mut request) => {
+                    let (response, connection) = match request.poll() {
+                        Ok(Async::Ready(ready)) => ready,
+                        Ok(Async::NotReady) => {
+                            self.state = TransactionState::Beginning(request);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(err) => return Err(err.into()),
+                    };
+                    match response {
+                        ProtocolMessage::AckTransaction(_) => connection,
+                        ProtocolMessage::Error(err_response) => {
+                            return Err(ProduceErr { connection, err: ErrorType::Server(err_response) });
+                        }
+                        other @ _ => {
+                            return Err(ProduceErr { connection, err: ErrorType::unexpected_message("TransactionAck", other) });
+                        }
+                    }
+                }
+                TransactionState::Sending(mut send) => {
+                    match send.poll() {
+                        Ok(Async::Ready(connection)) => connection,
+                        Ok(Async::NotReady) => {
+                            self.state = TransactionState::Sending(send);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(SendError {connection, err}) => {
+                            return Err(ProduceErr { connection, err: ErrorType::Io(err) });
+                        }
+                    }
+                }
+                TransactionState::AwaitingCommit(mut await_commit) => {
+                    let (response, connection) = match await_commit.poll() {
+                        Ok(Async::Ready(ready)) => ready,
+                        Ok(Async::NotReady) => {
+                            self.state = TransactionState::AwaitingCommit(await_commit);
+                            return Ok(Async::NotReady);
+                        }
+                        Err(AwaitResponseError {connection, err}) => {
+                            return Err(ProduceErr { connection, err: ErrorType::Io(err) });
+                        }
+                    };
+                    return match response {
+                        ProtocolMessage::AckTransaction(ack) => {
+                            // the events of each produce operation are given consecutive ids
+                            let events_produced = ack.produced.iter().flat_map(|batch_ack| {
+                                let actor = batch_ack.first_event_id.actor;
+                                (batch_ack.first_event_id.event_counter..(batch_ack.last_event_id.event_counter + 1)).map(move |counter| {
+                                    FloEventId::new(actor, counter)
+                                })
+                            }).collect::<Vec<_>>();
+                            debug!("Committed transaction with {} events", events_produced.len());
+                            Ok(Async::Ready(ProduceAllResult { connection, events_produced }))
+                        }
+                        ProtocolMessage::Error(err_response) => {
+                            Err(ProduceErr { connection, err: ErrorType::Server(err_response) })
+                        }
+                        other @ _ => {
+                            Err(ProduceErr { connection, err: ErrorType::unexpected_message("TransactionAck", other) })
+                        }
+                    };
+                }
+                TransactionState::Done => panic!("attempted to poll ProduceTransaction after it completed"),
+            };
+
+            self.send_next(connection);
+        }
+    }
+}
+
+/// Converts all of the events and groups them into batches, the same way that `ProduceAll` does
+fn to_batches<D: Debug, I: IntoIterator<Item=EventToProduce<D>>>(connection: &mut AsyncConnection<D>, events: I) -> Result<Vec<ProduceBatch>, ErrorType> {
+    let mut batches: Vec<ProduceBatch> = Vec::new();
+    let mut header_len = 0;
+    for event in events {
+        let event = to_produce_event(connection, 0, event).map_err(ErrorType::Codec)?;
+        let event_header_len = ProduceBatch::event_header_len(&event);
+        let fits_in_last = batches.last().map(|batch| {
+            batch.partition == event.partition && header_len + event_header_len <= BUFFER_LENGTH
+        }).unwrap_or(false);
+
+        if fits_in_last {
+            header_len += event_header_len;
+            batches.last_mut().unwrap().events.push(event);
+        } else {
+            let batch = ProduceBatch {
+                op_id: connection.next_op_id(),
+                partition: event.partition,
+                events: vec![event],
+            };
+            header_len = batch.header_len();
+            batches.push(batch);
+        }
+    }
+
+    if batches.len() > MAX_TRANSACTION_PRODUCE_OPS {
+        let msg = format!("Transaction would require {} produce operations, but the maximum is {}", batches.len(), MAX_TRANSACTION_PRODUCE_OPS);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into());
+    }
+
+    for batch in batches.iter_mut() {
+        let op_id = batch.op_id;
+        for event in batch.events.iter_mut() {
+            event.op_id = op_id;
+        }
+    }
+    Ok(batches)
+}
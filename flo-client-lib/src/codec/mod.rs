@@ -3,7 +3,7 @@ mod serde;
 
 use std::error::Error;
 
-use event::OwnedFloEvent;
+use event::{OwnedFloEvent, EventHeader};
 use ::Event;
 
 #[cfg(feature = "serde-json-codec")]
@@ -11,16 +11,17 @@ pub use self::serde::{SerdeJsonCodec, SerdePrettyJsonCodec};
 
 /// Trait that allows events to be converted to application-specific types. An `EventCodec` is associated with a
 /// connection, and is used to convert all incoming and outgoing events. Note that the types that are produced and consumed
-/// may be different.
+/// may be different. Received events are converted using their headers as well as their namespace, which allows a codec
+/// to decide how to decode an event based on a header such as `content-type`.
 pub trait EventCodec {
     type EventData;
-    fn convert_received(&self, namespace: &str, data: Vec<u8>) -> Result<Self::EventData, Box<Error>>;
+    fn convert_received(&self, namespace: &str, headers: &[EventHeader], data: Vec<u8>) -> Result<Self::EventData, Box<Error>>;
     fn convert_produced(&self, namespace: &str, data: Self::EventData) -> Result<Vec<u8>, Box<Error>>;
 
     fn convert_from_message(&self, input: OwnedFloEvent) -> Result<Event<Self::EventData>, Box<Error>> {
-        let OwnedFloEvent{id, parent_id, namespace, timestamp, data} = input;
+        let OwnedFloEvent{id, parent_id, namespace, timestamp, event_time, headers, data} = input;
         let converted = {
-            self.convert_received(&namespace, data)
+            self.convert_received(&namespace, &headers, data)
         };
 
         converted.map(move |body| {
@@ -28,7 +29,9 @@ pub trait EventCodec {
                 id: id,
                 parent_id: parent_id,
                 timestamp: timestamp,
+                event_time: event_time,
                 namespace: namespace,
+                headers: headers,
                 data: body,
             }
         })
@@ -41,7 +44,7 @@ pub struct RawCodec;
 impl EventCodec for RawCodec {
     type EventData = Vec<u8>;
 
-    fn convert_received(&self, _namespace: &str, data: Vec<u8>) -> Result<Vec<u8>, Box<Error>> {
+    fn convert_received(&self, _namespace: &str, _headers: &[EventHeader], data: Vec<u8>) -> Result<Vec<u8>, Box<Error>> {
         Ok(data)
     }
 
@@ -58,7 +61,7 @@ pub struct StringCodec;
 impl EventCodec for StringCodec {
     type EventData = String;
 
-    fn convert_received(&self, _namespace: &str, data: Vec<u8>) -> Result<String, Box<Error>> {
+    fn convert_received(&self, _namespace: &str, _headers: &[EventHeader], data: Vec<u8>) -> Result<String, Box<Error>> {
         String::from_utf8(data).map_err(|e| Box::new(e) as Box<Error>)
     }
 
@@ -73,7 +76,7 @@ pub struct LossyStringCodec;
 impl EventCodec for LossyStringCodec {
     type EventData = String;
 
-    fn convert_received(&self, _namespace: &str, data: Vec<u8>) -> Result<String, Box<Error>> {
+    fn convert_received(&self, _namespace: &str, _headers: &[EventHeader], data: Vec<u8>) -> Result<String, Box<Error>> {
         Ok(String::from_utf8_lossy(&data).into_owned())
     }
 
@@ -1,4 +1,5 @@
 use super::EventCodec;
+use event::EventHeader;
 
 use serde::{Serialize, Deserialize};
 use serde_json::{Serializer as JsonSerializer, Deserializer as JsonDeserializer};
@@ -23,7 +24,7 @@ impl <T: Serialize + Deserialize> SerdeJsonCodec<T> {
 impl <T> EventCodec for SerdeJsonCodec<T> where T: Serialize + Deserialize {
     type EventData = T;
 
-    fn convert_received(&self, _namespace: &str, data: Vec<u8>) -> Result<T, Box<Error>> {
+    fn convert_received(&self, _namespace: &str, _headers: &[EventHeader], data: Vec<u8>) -> Result<T, Box<Error>> {
         let mut deser = JsonDeserializer::from_slice(&data);
         T::deserialize(&mut deser).map_err(|e| Box::new(e) as Box<Error>)
     }
@@ -50,7 +51,7 @@ impl <T: Serialize + Deserialize> SerdePrettyJsonCodec<T> {
 impl <T> EventCodec for SerdePrettyJsonCodec<T> where T: Serialize + Deserialize {
     type EventData = T;
 
-    fn convert_received(&self, _namespace: &str, data: Vec<u8>) -> Result<T, Box<Error>> {
+    fn convert_received(&self, _namespace: &str, _headers: &[EventHeader], data: Vec<u8>) -> Result<T, Box<Error>> {
         let mut deser = JsonDeserializer::from_slice(&data);
         T::deserialize(&mut deser).map_err(|e| Box::new(e) as Box<Error>)
     }
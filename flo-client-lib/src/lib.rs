@@ -21,7 +21,7 @@ pub mod codec;
 pub mod sync;
 pub mod async;
 
-pub use protocol::{ErrorKind, ErrorMessage};
+pub use protocol::{ErrorKind, ErrorMessage, SnapshotComplete, PartitionSnapshotStatus, ExpectedVersion};
 pub use event::{
     time,
     FloEventId,
@@ -29,7 +29,9 @@ pub use event::{
     ActorId,
     EventCounter,
     Timestamp,
-    OwnedFloEvent
+    OwnedFloEvent,
+    EventHeader,
+    ProducerId
 };
 
 pub const ALL_EVENTS_GLOB: &'static str = "/**/*";
@@ -41,6 +43,17 @@ pub struct Event<T> {
     pub id: FloEventId,
     pub parent_id: Option<FloEventId>,
     pub timestamp: Timestamp,
+    /// The time supplied by the producer, if any. Unlike `timestamp`, this is not assigned by the server
+    pub event_time: Option<Timestamp>,
     pub namespace: String,
+    /// The name/value pairs that the event was produced with. Flo doesn't interpret these in any way
+    pub headers: Vec<EventHeader>,
     pub data: T
 }
+
+impl <T> Event<T> {
+    /// Returns the value of the first header with the given name, if there is one
+    pub fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers.iter().find(|header| header.0 == name).map(|header| header.1.as_slice())
+    }
+}
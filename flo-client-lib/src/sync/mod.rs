@@ -6,10 +6,11 @@ use std::net::SocketAddr;
 use tokio_core::reactor::Core;
 use futures::{Future, Stream};
 
-use event::{FloEventId, ActorId, VersionVector};
+use event::{FloEventId, ActorId, VersionVector, Timestamp};
 use async::{AsyncConnection, tcp_connect_with};
-use async::ops::{ProduceErr, Consume, ConsumeError};
+use async::ops::{ProduceErr, ProduceAllError, ProduceAllResult, Consume, ConsumeError, SnapshotError, DeleteError};
 use codec::EventCodec;
+use protocol::SnapshotComplete;
 use ::Event;
 
 pub use async::{ErrorType, CurrentStreamState};
@@ -99,18 +100,82 @@ impl <D: Debug + 'static> SyncConnection<D> {
         }
     }
 
+    /// Produces all of the events in order, and returns their ids once they have all been persisted. Consecutive events for
+    /// the same partition are sent to the server together as a single batch, which is much faster than producing each one
+    /// separately. If an error is returned, then some of the events may still have been persisted.
+    pub fn produce_all<T: Into<EventToProduce<D>>, I: IntoIterator<Item=T>>(&mut self, events: I) -> Result<Vec<FloEventId>, ErrorType> {
+        let conn = self.async_connection.take().unwrap();
+        let result = run_future(conn.produce_all(events.into_iter().map(|event| event.into())));
+        match result {
+            Ok(ProduceAllResult {connection, events_produced}) => {
+                self.async_connection = Some(connection);
+                Ok(events_produced)
+            }
+            Err(ProduceAllError {error, result}) => {
+                self.async_connection = Some(result.connection);
+                Err(error)
+            }
+        }
+    }
+
+    /// Produces all of the events atomically, so that consumers will see either all of them or none of them, even if they
+    /// are for different partitions. Returns the ids of the events once the transaction has been committed. If an error is
+    /// returned, then none of the events will be seen by consumers.
+    pub fn produce_in_transaction<T: Into<EventToProduce<D>>, I: IntoIterator<Item=T>>(&mut self, events: I) -> Result<Vec<FloEventId>, ErrorType> {
+        let conn = self.async_connection.take().unwrap();
+        let result = run_future(conn.produce_in_transaction(events.into_iter().map(|event| event.into())));
+        match result {
+            Ok(ProduceAllResult {connection, events_produced}) => {
+                self.async_connection = Some(connection);
+                Ok(events_produced)
+            }
+            Err(ProduceErr {connection, err}) => {
+                self.async_connection = Some(connection);
+                Err(err)
+            }
+        }
+    }
+
     /// Produces an event onto the given `partition` with the given `namespace`, `parent_id`, and `data`. Returns the id of
     /// the event once it is successfully persisted, otherwise an error. If this method returns sucessfully, the returned
     /// `FloEventId` will always refer to the same event and is guaranteed to be stable. That is, it will never be re-used to
     /// refer to any other event in the event stream, even if the produce event expires and is no longer part of the event stream.
     pub fn produce_to<N: Into<String>>(&mut self, partition: ActorId, namespace: N, parent_id: Option<FloEventId>, data: D) -> Result<FloEventId, ErrorType> {
-        let to_produce = EventToProduce {
-            partition,
-            namespace: namespace.into(),
-            parent_id,
-            data
-        };
-        self.produce(to_produce)
+        self.produce(EventToProduce::new(partition, namespace, parent_id, data))
+    }
+
+    /// Writes a consistent snapshot of the current event stream into `dest_dir`, which is a directory on the server's
+    /// filesystem. Returns the head of each partition that was included in the snapshot once it's been written.
+    pub fn snapshot<P: Into<String>>(&mut self, dest_dir: P) -> Result<SnapshotComplete, ErrorType> {
+        let conn = self.async_connection.take().unwrap();
+        let result = run_future(conn.snapshot(dest_dir));
+        match result {
+            Ok((complete, conn)) => {
+                self.async_connection = Some(conn);
+                Ok(complete)
+            }
+            Err(SnapshotError {connection, err}) => {
+                self.async_connection = Some(connection);
+                Err(err)
+            }
+        }
+    }
+
+    /// Deletes the events with the given ids, along with every event that's already in the stream with a namespace
+    /// matching the `namespace` glob, if one is given. Deleted events are never returned to consumers again.
+    pub fn delete(&mut self, event_ids: Vec<FloEventId>, namespace: Option<String>) -> Result<(), ErrorType> {
+        let conn = self.async_connection.take().unwrap();
+        let result = run_future(conn.delete(event_ids, namespace));
+        match result {
+            Ok(conn) => {
+                self.async_connection = Some(conn);
+                Ok(())
+            }
+            Err(DeleteError {connection, err}) => {
+                self.async_connection = Some(connection);
+                Err(err)
+            }
+        }
     }
 
     /// Use this connection to consume events from the server. The returned value implements `Iterator`
@@ -151,6 +216,29 @@ impl <D: Debug + 'static> SyncConnection<D> {
         }
     }
 
+    /// Creates a consumer that starts with the first event in each partition that has a timestamp greater than or equal to `since`.
+    /// The `event_limit` and `await_new_events` arguments behave exactly the same as they do for `into_consumer`.
+    pub fn into_consumer_since<N: Into<String>>(mut self, namespace: N, since: Timestamp, event_limit: Option<u64>, await_new_events: bool) -> EventIterator<D> {
+        let connection = self.async_connection.take().unwrap();
+        let consume = connection.consume_since(namespace, since, event_limit, await_new_events);
+        EventIterator {
+            consume: Some(consume),
+            connection: None,
+        }
+    }
+
+    /// Creates a consumer that receives every event whose event time is greater than or equal to `since`. Events without an
+    /// event time are compared using their timestamp. The `event_limit` and `await_new_events` arguments behave exactly the
+    /// same as they do for `into_consumer`.
+    pub fn into_consumer_since_event_time<N: Into<String>>(mut self, namespace: N, since: Timestamp, event_limit: Option<u64>, await_new_events: bool) -> EventIterator<D> {
+        let connection = self.async_connection.take().unwrap();
+        let consume = connection.consume_since_event_time(namespace, since, event_limit, await_new_events);
+        EventIterator {
+            consume: Some(consume),
+            connection: None,
+        }
+    }
+
     /// Returns information on the event stream associated with this connection. Will return `None` if the handshake with the server has not
     /// been performed yet.
     pub fn current_stream(&self) -> Option<&CurrentStreamState> {
@@ -61,6 +61,18 @@ pub type ActorId = u16;
 /// given point in time.
 pub type EventCounter = u64;
 
+/// Identifies a producer across connections, so that the server can recognize when a producer re-sends an event that it
+/// already persisted. Producer ids are chosen by clients, and each one must be used by only one producer at a time.
+pub type ProducerId = u64;
+
+/// The producer id and sequence number that an event was produced with. Sequence numbers are assigned by the producer,
+/// and the server persists any event at most once for each `ProducerSequence`, as long as it's within the dedupe window.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub struct ProducerSequence {
+    pub producer_id: ProducerId,
+    pub sequence: u64,
+}
+
 /// This is the primary key for an event. It is immutable and unique within the entire event stream.
 /// FloEventIds are strictly ordered first based on the `EventCounter` and then on the `ActorId`.
 /// This ordering is exactly the same as the ordering of events in the stream.
@@ -189,6 +201,10 @@ pub trait FloEvent: Debug {
     fn id(&self) -> &FloEventId;
     /// The UTC timestamp (generated by the server) when this event was persisted.
     fn timestamp(&self) -> Timestamp;
+    /// The time that the event actually happened, as supplied by the producer. This is `None` unless the producer set it,
+    /// which is useful when backfilling historical data or replaying events from another system. Unlike the `timestamp`,
+    /// the server never sets or checks this value, so it's not ordered in any way.
+    fn event_time(&self) -> Option<Timestamp>;
     /// Events may optionally have a parent id. This is used to correlate events. A simple example would be a request/response
     /// where the response has it's `parent_id` set to the `id` of the request. It can also be used to trace events through a
     /// complex system of microservices. Clients are encouraged to keep it simple and just always set the `parent_id` to
@@ -202,6 +218,19 @@ pub trait FloEvent: Debug {
     fn data_len(&self) -> u32;
     /// Returns the arbitrary binary data associated with this event.
     fn data(&self) -> &[u8];
+    /// Events may have any number of headers, which are name/value pairs that are set by the producer. They're meant for
+    /// metadata about the event, such as the content type of the data or a trace id. Flo doesn't interpret headers at all,
+    /// and names may be repeated. Headers are kept in the order they were produced in.
+    fn headers(&self) -> &[EventHeader];
+    /// Returns the value of the first header with the given name, if there is one.
+    fn header(&self, name: &str) -> Option<&[u8]> {
+        self.headers().iter().find(|header| header.0 == name).map(|header| header.1.as_slice())
+    }
+    /// The producer id and sequence number that this event was produced with, if it came from an idempotent producer. This
+    /// is only used by the server to detect retransmitted events, so it's never sent to consumers.
+    fn producer_sequence(&self) -> Option<ProducerSequence> {
+        None
+    }
     /// Converts this event into an `OwnedFloEvent`, cloning it in the process.
     fn to_owned(&self) -> OwnedFloEvent {
         let id = *self.id();
@@ -209,13 +238,18 @@ pub trait FloEvent: Debug {
         OwnedFloEvent {
             id: id,
             timestamp: self.timestamp(),
+            event_time: self.event_time(),
             parent_id: self.parent_id(),
             namespace: self.namespace().to_owned(),
+            headers: self.headers().to_vec(),
             data: data,
         }
     }
 }
 
+/// A single header of an event, as a tuple of the header name and its value.
+pub type EventHeader = (String, Vec<u8>);
+
 impl <T> FloEvent for T where T: AsRef<OwnedFloEvent> + Debug {
     fn id(&self) -> &FloEventId {
         self.as_ref().id()
@@ -233,6 +267,10 @@ impl <T> FloEvent for T where T: AsRef<OwnedFloEvent> + Debug {
         self.as_ref().data()
     }
 
+    fn headers(&self) -> &[EventHeader] {
+        self.as_ref().headers()
+    }
+
     fn to_owned(&self) -> OwnedFloEvent {
         self.as_ref().clone()
     }
@@ -244,6 +282,10 @@ impl <T> FloEvent for T where T: AsRef<OwnedFloEvent> + Debug {
     fn timestamp(&self) -> Timestamp {
         self.as_ref().timestamp()
     }
+
+    fn event_time(&self) -> Option<Timestamp> {
+        self.as_ref().event_time()
+    }
 }
 
 /// This is the main `FloEvent` implementation that clients will deal with. All of the fields returned by the `FloEvent`
@@ -252,21 +294,38 @@ impl <T> FloEvent for T where T: AsRef<OwnedFloEvent> + Debug {
 pub struct OwnedFloEvent {
     pub id: FloEventId,
     pub timestamp: Timestamp,
+    pub event_time: Option<Timestamp>,
     pub parent_id: Option<FloEventId>,
     pub namespace: String,
+    pub headers: Vec<EventHeader>,
     pub data: Vec<u8>,
 }
 
 impl OwnedFloEvent {
+    /// Creates a new event without any headers or event time
     pub fn new(id: FloEventId, parent_id: Option<FloEventId>, timestamp: Timestamp, namespace: String, data: Vec<u8>) -> OwnedFloEvent {
         OwnedFloEvent {
             id: id,
             timestamp: timestamp,
+            event_time: None,
             parent_id: parent_id,
             namespace: namespace,
+            headers: Vec::new(),
             data: data,
         }
     }
+
+    /// Returns this event with the given header added after any existing headers
+    pub fn with_header<N: Into<String>, V: Into<Vec<u8>>>(mut self, name: N, value: V) -> OwnedFloEvent {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Returns this event with the given event time
+    pub fn with_event_time(mut self, event_time: Timestamp) -> OwnedFloEvent {
+        self.event_time = Some(event_time);
+        self
+    }
 }
 
 impl FloEvent for OwnedFloEvent {
@@ -286,6 +345,10 @@ impl FloEvent for OwnedFloEvent {
         &self.data
     }
 
+    fn headers(&self) -> &[EventHeader] {
+        &self.headers
+    }
+
     fn to_owned(&self) -> OwnedFloEvent {
         self.clone()
     }
@@ -296,4 +359,8 @@ impl FloEvent for OwnedFloEvent {
     fn timestamp(&self) -> Timestamp {
         self.timestamp
     }
+
+    fn event_time(&self) -> Option<Timestamp> {
+        self.event_time
+    }
 }
@@ -1,4 +1,6 @@
-use chrono::{UTC, TimeZone};
+use std::sync::{Arc, Mutex};
+
+use chrono::{UTC, TimeZone, Duration};
 
 use ::Timestamp; // type alias for DateTime<UTC> defined in lib.rs
 
@@ -22,10 +24,56 @@ pub fn now() -> Timestamp {
     UTC::now()
 }
 
+/// A source of the current time. Anything that makes decisions based on the current time should get it from a `Clock`
+/// so that tests can control how time passes.
+pub trait Clock: Send {
+    fn now(&self) -> Timestamp;
+}
+
+/// The real wall clock
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Timestamp {
+        now()
+    }
+}
+
+/// A clock that only moves when it's told to. Clones all share the same time, so one clone can be given away while the
+/// other is used to advance the time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    time: Arc<Mutex<Timestamp>>,
+}
+
+impl ManualClock {
+    pub fn new(start: Timestamp) -> ManualClock {
+        ManualClock {
+            time: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    pub fn set(&self, time: Timestamp) {
+        *self.time.lock().unwrap() = time;
+    }
+
+    pub fn advance(&self, amount: Duration) {
+        let mut time = self.time.lock().unwrap();
+        *time = *time + amount;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Timestamp {
+        *self.time.lock().unwrap()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use chrono::UTC;
+    use chrono::{UTC, Duration};
 
     #[test]
     fn timestamp_is_converted_from_u64_and_back() {
@@ -41,4 +89,18 @@ mod test {
         let early_timestamp = UTC.ymd(1932, 1, 10).and_hms(9, 30, 05);
         let _ = millis_since_epoch(early_timestamp);
     }
+
+    #[test]
+    fn manual_clock_clones_share_the_same_time() {
+        let start = from_millis_since_epoch(5000);
+        let subject = ManualClock::new(start);
+        let other = subject.clone();
+        assert_eq!(start, other.now());
+
+        subject.advance(Duration::seconds(3));
+        assert_eq!(from_millis_since_epoch(8000), other.now());
+
+        other.set(start);
+        assert_eq!(start, subject.now());
+    }
 }
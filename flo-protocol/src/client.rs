@@ -10,8 +10,8 @@
 //!
 //! All numbers use big endian byte order.
 //! All Strings are newline terminated.
-use nom::{be_u64, be_u32, be_u16};
-use event::{time, OwnedFloEvent, FloEvent, FloEventId, ActorId, EventCounter, Timestamp};
+use nom::{be_u64, be_u32, be_u16, be_u8};
+use event::{time, OwnedFloEvent, FloEvent, FloEventId, ActorId, EventCounter, Timestamp, EventHeader, ProducerId};
 use serializer::Serializer;
 use std::net::SocketAddr;
 
@@ -35,6 +35,17 @@ pub mod headers {
     pub const NEW_START_CONSUMING: u8 = 17;
     pub const SET_EVENT_STREAM: u8 = 18;
     pub const EVENT_STREAM_STATUS: u8 = 19;
+    pub const START_CONSUMING_SINCE: u8 = 20;
+    pub const SNAPSHOT: u8 = 21;
+    pub const SNAPSHOT_COMPLETE: u8 = 22;
+    pub const DELETE_EVENTS: u8 = 23;
+    pub const EVENTS_DELETED: u8 = 24;
+    pub const PRODUCE_BATCH: u8 = 25;
+    pub const BATCH_ACK: u8 = 26;
+    pub const BEGIN_TRANSACTION: u8 = 27;
+    pub const COMMIT_TRANSACTION: u8 = 28;
+    pub const ABORT_TRANSACTION: u8 = 29;
+    pub const TRANSACTION_ACK: u8 = 30;
     pub const CLIENT_ANNOUNCE: u8 = 170;
 }
 
@@ -45,6 +56,25 @@ pub const ERROR_INVALID_CONSUMER_STATE: u8 = 16;
 pub const ERROR_INVALID_VERSION_VECTOR: u8 = 17;
 pub const ERROR_STORAGE_ENGINE_IO: u8 = 18;
 pub const ERROR_NO_STREAM: u8 = 19;
+pub const ERROR_CORRUPT_EVENT: u8 = 20;
+pub const ERROR_INVALID_TRANSACTION_STATE: u8 = 21;
+pub const ERROR_CONCURRENCY_CONFLICT: u8 = 22;
+pub const ERROR_UNSUPPORTED_PROTOCOL_VERSION: u8 = 23;
+
+/// The version of the protocol that's sent in every `ClientAnnounce`. Each version changes the format of some messages,
+/// so the server rejects clients that announce any other version.
+///
+/// - 1: the original protocol
+/// - 2: events carry key/value headers
+/// - 3: events carry an optional event time, which consumers can also start from
+/// - 4: `ClientAnnounce` carries a producer id, and produced events carry a sequence number for deduplication
+/// - 5: `ProduceBatch` produces many events at once, and is acknowledged with an `AckBatch`
+/// - 6: transactions, using `BeginTransaction`, `CommitTransaction`, and `AbortTransaction`
+/// - 7: produced events carry an optional expected version of their namespace
+pub const PROTOCOL_VERSION: u32 = 7;
+
+/// The first protocol version whose `ClientAnnounce` includes the producer id
+const PRODUCER_ID_PROTOCOL_VERSION: u32 = 4;
 
 /// Describes the type of error. This gets serialized a u8
 #[derive(Debug, PartialEq, Clone)]
@@ -59,6 +89,16 @@ pub enum ErrorKind {
     StorageEngineError,
     /// Requested event stream does not exist
     NoSuchStream,
+    /// A persisted event failed checksum verification when it was read, so it could not be sent
+    CorruptEvent,
+    /// Indicates that a transaction could not be begun, committed, or aborted because of the state of the connection's
+    /// current transaction, or that the transaction had too many produce operations
+    InvalidTransactionState,
+    /// Indicates that an event was not produced because its namespace was not in the state that the producer expected.
+    /// The description includes the id of the most recent event in the namespace
+    ConcurrencyConflict,
+    /// Indicates that the client announced a protocol version that the server doesn't speak
+    UnsupportedProtocolVersion,
 }
 
 /// Represents a response to any request that results in an error
@@ -83,6 +123,10 @@ impl ErrorKind {
             ERROR_INVALID_VERSION_VECTOR => Ok(ErrorKind::InvalidVersionVector),
             ERROR_STORAGE_ENGINE_IO => Ok(ErrorKind::StorageEngineError),
             ERROR_NO_STREAM => Ok(ErrorKind::NoSuchStream),
+            ERROR_CORRUPT_EVENT => Ok(ErrorKind::CorruptEvent),
+            ERROR_INVALID_TRANSACTION_STATE => Ok(ErrorKind::InvalidTransactionState),
+            ERROR_CONCURRENCY_CONFLICT => Ok(ErrorKind::ConcurrencyConflict),
+            ERROR_UNSUPPORTED_PROTOCOL_VERSION => Ok(ErrorKind::UnsupportedProtocolVersion),
             other => Err(other)
         }
     }
@@ -95,6 +139,10 @@ impl ErrorKind {
             &ErrorKind::InvalidVersionVector => ERROR_INVALID_VERSION_VECTOR,
             &ErrorKind::StorageEngineError => ERROR_STORAGE_ENGINE_IO,
             &ErrorKind::NoSuchStream => ERROR_NO_STREAM,
+            &ErrorKind::CorruptEvent => ERROR_CORRUPT_EVENT,
+            &ErrorKind::InvalidTransactionState => ERROR_INVALID_TRANSACTION_STATE,
+            &ErrorKind::ConcurrencyConflict => ERROR_CONCURRENCY_CONFLICT,
+            &ErrorKind::UnsupportedProtocolVersion => ERROR_UNSUPPORTED_PROTOCOL_VERSION,
         }
     }
 }
@@ -116,11 +164,41 @@ pub struct ProduceEvent {
     /// The parent id is optional. On the wire, a null parent_id is serialized as an event id where both the counter and the
     /// actor are set to 0.
     pub parent_id: Option<FloEventId>,
+    /// Arbitrary name/value pairs that are stored along with the event. Headers are part of the message header, so the
+    /// serialized headers and namespace together must fit within `BUFFER_LENGTH`.
+    pub headers: Vec<EventHeader>,
+    /// The time that the event actually happened, if it's different from when the server persists it. On the wire, a
+    /// missing event time is serialized as 0.
+    pub event_time: Option<Timestamp>,
+    /// The sequence number of this event, assigned by an idempotent producer. This is only used if the connection announced
+    /// a `producer_id`, in which case the server will persist at most one event for each sequence number that's still in
+    /// its dedupe window, and acknowledge any retransmissions with the id of the original event. Sequence numbers must be
+    /// greater than 0. On the wire, a missing sequence number is serialized as 0.
+    pub sequence: Option<u64>,
+    /// The state that the event's namespace must be in for the event to be appended, which producers can use for optimistic
+    /// concurrency control. Expected versions are checked against the partition that the event is produced onto, before
+    /// any of the events in the same produce operation are appended. On the wire, this is serialized as a u8 tag followed
+    /// by an event id, which is all zeros unless the tag is `EXPECT_LAST_EVENT_ID`.
+    pub expected_version: Option<ExpectedVersion>,
     /// The event payload. As far as the flo server is concerned, this is just an opaque byte array. Note that events with
     /// 0-length bodies are perfectly fine.
     pub data: Vec<u8>,
 }
 
+pub const EXPECT_ANY_VERSION: u8 = 0;
+pub const EXPECT_NO_EVENTS: u8 = 1;
+pub const EXPECT_LAST_EVENT_ID: u8 = 2;
+
+/// The state that a namespace must be in before an event can be appended to it. If it isn't, then the produce operation
+/// fails with a `ConcurrencyConflict` error and none of its events are appended.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ExpectedVersion {
+    /// The namespace must not have any events in the partition
+    NoEvents,
+    /// The most recent event in the namespace must have the given id
+    LastEventId(FloEventId),
+}
+
 /// Sent by the server to the producer of an event to acknowledge that the event was successfully persisted to the stream.
 #[derive(Debug, PartialEq, Clone)]
 pub struct EventAck {
@@ -131,6 +209,81 @@ pub struct EventAck {
     pub event_id: FloEventId,
 }
 
+/// The body of a ProduceBatch `ProtocolMessage`, which produces any number of events onto a single partition using one
+/// request. The server will respond with either a `BatchAck` or an `ErrorMessage`. Events are appended in order, and
+/// either all of them are persisted or none of them are acknowledged.
+///
+/// On the wire, the header of each event is sent along with the batch header, and then the data of each event follows
+/// in the same order. The `op_id` and `partition` of each event are not serialized, and are always set to those of the
+/// batch when it's parsed. The whole batch header must fit within `BUFFER_LENGTH`, which `header_len` can be used to check.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProduceBatch {
+    pub op_id: u32,
+    /// The partition that all of the events are produced onto
+    pub partition: ActorId,
+    pub events: Vec<ProduceEvent>,
+}
+
+/// The length of the batch header without any events: tag, op_id, partition, and event count
+const PRODUCE_BATCH_HEADER_LEN: usize = 1 + 4 + 2 + 2;
+
+impl ProduceBatch {
+    /// Returns the number of bytes that the header for this batch takes up on the wire
+    pub fn header_len(&self) -> usize {
+        PRODUCE_BATCH_HEADER_LEN + self.events.iter().map(ProduceBatch::event_header_len).sum::<usize>()
+    }
+
+    /// Returns the number of bytes that the header for the given event takes up when it's part of a batch
+    pub fn event_header_len(event: &ProduceEvent) -> usize {
+        let headers_len = event.headers.iter().map(|&(ref name, ref value)| 2 + name.len() + 4 + value.len()).sum::<usize>();
+        // namespace, parent id, headers, event time, sequence, expected version, and data length
+        2 + event.namespace.len() + 10 + 2 + headers_len + 8 + 8 + 11 + 4
+    }
+}
+
+/// Sent by the server to acknowledge that every event in a `ProduceBatch` was successfully persisted. The events that were
+/// appended are given consecutive ids, from `first_event_id` to `last_event_id` inclusive. The only exception is when some
+/// of the events were retransmitted by an idempotent producer, since those are not appended again, and are acknowledged
+/// using the ids of the originals instead.
+#[derive(Debug, PartialEq, Clone)]
+pub struct BatchAck {
+    /// This will be set to the `op_id` of the `ProduceBatch`
+    pub op_id: u32,
+    /// The id of the first event in the batch that was appended, or a zero id if they were all retransmissions
+    pub first_event_id: FloEventId,
+    /// The id of the last event in the batch that was appended, or a zero id if they were all retransmissions
+    pub last_event_id: FloEventId,
+    /// The position within the batch of each event that was a retransmission, along with the id of the original event
+    pub original_ids: Vec<(u16, FloEventId)>,
+}
+
+impl BatchAck {
+    /// Returns the id of every event in the batch, in the order they were produced
+    pub fn event_ids(&self, event_count: usize) -> Vec<FloEventId> {
+        let mut next_counter = self.first_event_id.event_counter;
+        (0..event_count).map(|i| {
+            self.original_ids.iter().find(|&&(index, _)| index as usize == i).map(|&(_, id)| id).unwrap_or_else(|| {
+                let id = FloEventId::new(self.first_event_id.actor, next_counter);
+                next_counter += 1;
+                id
+            })
+        }).collect()
+    }
+}
+
+/// The maximum number of produce operations that a single transaction may contain, which ensures that the `TransactionAck`
+/// for the commit fits within `BUFFER_LENGTH`
+pub const MAX_TRANSACTION_PRODUCE_OPS: usize = 256;
+
+/// Sent by the server in response to a `BeginTransaction`, `CommitTransaction`, or `AbortTransaction`. The response to a
+/// commit has one `BatchAck` for each produce operation in the transaction, in the order they were sent, using the
+/// `op_id` of each produce. The responses to the other messages have no acks, since nothing has been produced yet.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TransactionAck {
+    pub op_id: u32,
+    pub produced: Vec<BatchAck>,
+}
+
 /// Sent by a client to the server to begin reading events from the stream.
 #[derive(Debug, PartialEq, Clone)]
 pub struct ConsumerStart {
@@ -155,6 +308,21 @@ pub struct NewConsumerStart {
     pub namespace: String,
 }
 
+/// Sent by a client to the server to begin reading events that were produced at or after a given time. Events are read
+/// from every partition in the event stream. The server resolves the time to a starting point within each partition.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConsumeSince {
+    pub op_id: u32,
+    /// Only events with a timestamp greater than or equal to this will be read
+    pub since: Timestamp,
+    pub max_events: u64,
+    pub namespace: String,
+    /// If true, then `since` is compared to the event time that was supplied by the producer instead of the time that
+    /// the event was persisted. Events without an event time use the time they were persisted. Since event times aren't
+    /// ordered, every event in the stream has to be checked, but only the matching events are sent to the consumer.
+    pub by_event_time: bool,
+}
+
 
 /// Represents information known about a member of the flo cluster from the perspective of whichever member sent the
 /// ClusterState message.
@@ -227,6 +395,53 @@ pub struct SetEventStream{
     pub name: String,
 }
 
+/// Sent by a client to ask the server to write a consistent snapshot of the current event stream to a directory on the
+/// server's filesystem. The server responds with either a `SnapshotComplete` or an `ErrorMessage`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SnapshotRequest {
+    pub op_id: u32,
+    /// The directory on the server to write the snapshot to. Each event stream is written into its own subdirectory
+    pub dest_dir: String,
+}
+
+/// Describes the portion of a single partition that was included in a snapshot. Included as part of `SnapshotComplete`
+#[derive(Debug, PartialEq, Clone)]
+pub struct PartitionSnapshotStatus {
+    pub partition_num: ActorId,
+    /// The counter of the last event in the partition that's included in the snapshot
+    pub head: EventCounter,
+    /// The byte offset just past the last event in the segment that was being written to when the snapshot was taken
+    pub active_segment_offset: u64,
+}
+
+/// Sent by the server once a snapshot has been written successfully
+#[derive(Debug, PartialEq, Clone)]
+pub struct SnapshotComplete {
+    pub op_id: u32,
+    /// The name of the event stream that was written
+    pub name: String,
+    pub partitions: Vec<PartitionSnapshotStatus>,
+}
+
+/// Sent by a client to delete events from the current event stream, for example in order to erase a user's data. Deleted
+/// events are never returned to consumers, and event ids are never reused. The server responds with either an
+/// `EventsDeleted` or an `ErrorMessage`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DeleteEvents {
+    pub op_id: u32,
+    /// The ids of specific events to delete. Each event is deleted from the partition given by the id's actor
+    pub event_ids: Vec<FloEventId>,
+    /// If set, every event currently in the stream with a namespace that matches this glob is deleted from every
+    /// partition. Events that are produced afterwards are unaffected
+    pub namespace: Option<String>,
+}
+
+/// Sent by the server once every partition has recorded the deletion requested by a `DeleteEvents`
+#[derive(Debug, PartialEq, Clone)]
+pub struct EventsDeleted {
+    pub op_id: u32,
+}
+
 /// Sent by the client as the very first message to the server. The server will respond with an `EventStreamStatus` for the current (default) stream
 #[derive(Debug, PartialEq, Clone)]
 pub struct ClientAnnounce {
@@ -234,6 +449,10 @@ pub struct ClientAnnounce {
     pub op_id: u32,
     pub client_name: String,
     pub consume_batch_size: Option<u32>,
+    /// Set by idempotent producers, so that the server can recognize events that they re-send after a failure, even on a
+    /// different connection. On the wire, a missing producer id is serialized as 0. Announcements from clients using
+    /// protocol versions before 4 end before this field, so it's only read and written for later versions.
+    pub producer_id: Option<ProducerId>,
 }
 
 
@@ -248,12 +467,18 @@ pub enum ProtocolMessage<E: FloEvent> {
     SetEventStream(SetEventStream),
     /// Signals a client's intent to publish a new event. The server will respond with either an `EventAck` or an `ErrorMessage`
     ProduceEvent(ProduceEvent),
+    /// Produces several events onto one partition. The server will respond with either a `BatchAck` or an `ErrorMessage`
+    ProduceBatch(ProduceBatch),
     /// This is a complete event as serialized over the wire. This message is sent to to both consumers as well as other servers
     ReceiveEvent(E),
     /// Sent from the server to client to acknowledge that an event was persisted successfully.
     AckEvent(EventAck),
+    /// Sent from the server to client to acknowledge that a whole batch of events was persisted successfully.
+    AckBatch(BatchAck),
     /// New message sent by a client to start reading events from the stream
     NewStartConsuming(NewConsumerStart),
+    /// Sent by a client to start reading events from the stream that were produced at or after a given time
+    StartConsumingSince(ConsumeSince),
     /// send by the server to a client in response to a StartConsuming message to indicate the start of a series of events
     CursorCreated(CursorInfo),
     /// sent by a client to a server to tell the server to stop sending events. This is required in order to reuse the connection for multiple queries
@@ -269,6 +494,24 @@ pub enum ProtocolMessage<E: FloEvent> {
     /// continue to send events as more come in, but this just lets the client know that it may be some time before more
     /// events are available. This message will only be sent at most once to a given consumer.
     AwaitingEvents,
+    /// Sent by a client to write a consistent snapshot of the current event stream into a directory on the server
+    Snapshot(SnapshotRequest),
+    /// Sent by the server in response to a `Snapshot` message once the snapshot has been written
+    SnapshotComplete(SnapshotComplete),
+    /// Sent by a client to delete specific events, or all of the events in the matching namespaces
+    DeleteEvents(DeleteEvents),
+    /// Sent by the server in response to a `DeleteEvents` message once the deletion has been recorded
+    EventsDeleted(EventsDeleted),
+    /// Sent by a client to begin a transaction. Produce operations that are sent before the transaction is committed are
+    /// held by the server, and are acknowledged as part of the `TransactionAck` for the commit instead of individually
+    BeginTransaction(u32),
+    /// Sent by a client to atomically produce every event in the current transaction, which may span any number of
+    /// partitions. Consumers will see either all of the events or none of them
+    CommitTransaction(u32),
+    /// Sent by a client to discard every event in the current transaction
+    AbortTransaction(u32),
+    /// Sent by the server in response to `BeginTransaction`, `CommitTransaction`, and `AbortTransaction` messages
+    AckTransaction(TransactionAck),
     /// Represents an error response to any other message
     Error(ErrorMessage),
 }
@@ -346,6 +589,57 @@ named!{pub parse_event_id<Option<FloEventId>>,
     )
 }
 
+named!{parse_event_header<EventHeader>,
+    chain!(
+        name: parse_str ~
+        value: length_data!(be_u32),
+        || {
+            (name, value.to_vec())
+        }
+    )
+}
+
+named!{pub parse_event_headers<Vec<EventHeader>>,
+    length_count!(be_u16, parse_event_header)
+}
+
+fn to_optional_u64(value: u64) -> Option<u64> {
+    if value > 0 {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+named!{parse_optional_u64<Option<u64>>,
+    map!(be_u64, to_optional_u64)
+}
+
+fn to_optional_timestamp(millis: u64) -> Option<Timestamp> {
+    if millis > 0 {
+        Some(time::from_millis_since_epoch(millis))
+    } else {
+        None
+    }
+}
+
+named!{parse_optional_timestamp<Option<Timestamp>>,
+    map!(be_u64, to_optional_timestamp)
+}
+
+fn to_expected_version((tag, id): (u8, FloEventId)) -> Result<Option<ExpectedVersion>, u8> {
+    match tag {
+        EXPECT_ANY_VERSION => Ok(None),
+        EXPECT_NO_EVENTS => Ok(Some(ExpectedVersion::NoEvents)),
+        EXPECT_LAST_EVENT_ID => Ok(Some(ExpectedVersion::LastEventId(id))),
+        other => Err(other)
+    }
+}
+
+named!{parse_expected_version<Option<ExpectedVersion>>,
+    map_res!(chain!(tag: be_u8 ~ id: parse_zeroable_event_id, || { (tag, id) }), to_expected_version)
+}
+
 named!{pub parse_new_producer_event<ProtocolMessage<OwnedFloEvent>>,
     chain!(
         _tag: tag!(&[PRODUCE_EVENT]) ~
@@ -353,6 +647,10 @@ named!{pub parse_new_producer_event<ProtocolMessage<OwnedFloEvent>>,
         parent_id: parse_event_id ~
         op_id: be_u32 ~
         partition: be_u16 ~
+        headers: parse_event_headers ~
+        event_time: parse_optional_timestamp ~
+        sequence: parse_optional_u64 ~
+        expected_version: parse_expected_version ~
         data_len: be_u32,
         || {
             ProtocolMessage::ProduceEvent(ProduceEvent{
@@ -360,12 +658,62 @@ named!{pub parse_new_producer_event<ProtocolMessage<OwnedFloEvent>>,
                 parent_id: parent_id,
                 op_id: op_id,
                 partition: partition,
+                headers: headers,
+                event_time: event_time,
+                sequence: sequence,
+                expected_version: expected_version,
                 data: Vec::with_capacity(data_len as usize),
             })
         }
     )
 }
 
+named!{parse_batch_event_header<ProduceEvent>,
+    chain!(
+        namespace: parse_str ~
+        parent_id: parse_event_id ~
+        headers: parse_event_headers ~
+        event_time: parse_optional_timestamp ~
+        sequence: parse_optional_u64 ~
+        expected_version: parse_expected_version ~
+        data_len: be_u32,
+        || {
+            ProduceEvent {
+                op_id: 0,
+                partition: 0,
+                namespace: namespace,
+                parent_id: parent_id,
+                headers: headers,
+                event_time: event_time,
+                sequence: sequence,
+                expected_version: expected_version,
+                data: Vec::with_capacity(data_len as usize),
+            }
+        }
+    )
+}
+
+named!{parse_produce_batch<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[PRODUCE_BATCH]) ~
+        op_id: be_u32 ~
+        partition: be_u16 ~
+        events: length_count!(be_u16, parse_batch_event_header),
+        || {
+            let mut events = events;
+            for event in events.iter_mut() {
+                event.op_id = op_id;
+                event.partition = partition;
+            }
+            ProtocolMessage::ProduceBatch(ProduceBatch {
+                op_id: op_id,
+                partition: partition,
+                events: events,
+            })
+        }
+    )
+}
+
 named!{parse_timestamp<Timestamp>,
     map!(be_u64, time::from_millis_since_epoch)
 }
@@ -376,7 +724,9 @@ named!{parse_receive_event_header<ProtocolMessage<OwnedFloEvent>>,
         id: parse_non_zero_event_id ~
         parent_id: parse_event_id ~
         timestamp: parse_timestamp ~
+        event_time: parse_optional_timestamp ~
         namespace: parse_str ~
+        headers: parse_event_headers ~
         data: length_data!(be_u32),
         || {
            ProtocolMessage::ReceiveEvent(OwnedFloEvent {
@@ -384,6 +734,8 @@ named!{parse_receive_event_header<ProtocolMessage<OwnedFloEvent>>,
                 parent_id: parent_id,
                 namespace: namespace,
                 timestamp: timestamp,
+                event_time: event_time,
+                headers: headers,
                 data: data.to_vec(),
             })
         }
@@ -405,6 +757,81 @@ named!{parse_event_ack<ProtocolMessage<OwnedFloEvent>>,
     )
 }
 
+named!{parse_batch_ack<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[BATCH_ACK]) ~
+        ack: parse_batch_ack_body,
+        || {
+            ProtocolMessage::AckBatch(ack)
+        }
+    )
+}
+
+named!{parse_original_id<(u16, FloEventId)>,
+    chain!(
+        index: be_u16 ~
+        id: parse_zeroable_event_id,
+        || {
+            (index, id)
+        }
+    )
+}
+
+named!{parse_begin_transaction<ProtocolMessage<OwnedFloEvent>>, chain!(
+    _tag: tag!(&[BEGIN_TRANSACTION]) ~
+    op_id: be_u32,
+    || {
+        ProtocolMessage::BeginTransaction(op_id)
+    }
+)}
+
+named!{parse_commit_transaction<ProtocolMessage<OwnedFloEvent>>, chain!(
+    _tag: tag!(&[COMMIT_TRANSACTION]) ~
+    op_id: be_u32,
+    || {
+        ProtocolMessage::CommitTransaction(op_id)
+    }
+)}
+
+named!{parse_abort_transaction<ProtocolMessage<OwnedFloEvent>>, chain!(
+    _tag: tag!(&[ABORT_TRANSACTION]) ~
+    op_id: be_u32,
+    || {
+        ProtocolMessage::AbortTransaction(op_id)
+    }
+)}
+
+named!{parse_batch_ack_body<BatchAck>,
+    chain!(
+        op_id: be_u32 ~
+        first_event_id: parse_zeroable_event_id ~
+        last_event_id: parse_zeroable_event_id ~
+        original_ids: length_count!(be_u16, parse_original_id),
+        || {
+            BatchAck {
+                op_id: op_id,
+                first_event_id: first_event_id,
+                last_event_id: last_event_id,
+                original_ids: original_ids,
+            }
+        }
+    )
+}
+
+named!{parse_transaction_ack<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[TRANSACTION_ACK]) ~
+        op_id: be_u32 ~
+        produced: length_count!(be_u16, parse_batch_ack_body),
+        || {
+            ProtocolMessage::AckTransaction(TransactionAck {
+                op_id: op_id,
+                produced: produced,
+            })
+        }
+    )
+}
+
 named!{parse_new_start_consuming<ProtocolMessage<OwnedFloEvent>>,
     chain!(
         _tag: tag!(&[NEW_START_CONSUMING]) ~
@@ -423,6 +850,26 @@ named!{parse_new_start_consuming<ProtocolMessage<OwnedFloEvent>>,
     )
 }
 
+named!{parse_start_consuming_since<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[START_CONSUMING_SINCE]) ~
+        op_id: be_u32 ~
+        since: parse_timestamp ~
+        max_events: be_u64 ~
+        namespace: parse_str ~
+        by_event_time: be_u8,
+        || {
+            ProtocolMessage::StartConsumingSince(ConsumeSince {
+                op_id: op_id,
+                since: since,
+                max_events: max_events,
+                namespace: namespace,
+                by_event_time: by_event_time == 1,
+            })
+        }
+    )
+}
+
 named!{parse_set_event_stream<ProtocolMessage<OwnedFloEvent>>,
     chain!(
         _tag: tag!(&[SET_EVENT_STREAM]) ~
@@ -437,6 +884,79 @@ named!{parse_set_event_stream<ProtocolMessage<OwnedFloEvent>>,
     )
 }
 
+named!{parse_snapshot<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[SNAPSHOT]) ~
+        op_id: be_u32 ~
+        dest_dir: parse_str,
+        || {
+            ProtocolMessage::Snapshot(SnapshotRequest {
+                op_id: op_id,
+                dest_dir: dest_dir,
+            })
+        }
+    )
+}
+
+named!{parse_partition_snapshot_status<PartitionSnapshotStatus>,
+    chain!(
+        partition_num: be_u16 ~
+        head: be_u64 ~
+        active_segment_offset: be_u64,
+        || {
+            PartitionSnapshotStatus {
+                partition_num: partition_num,
+                head: head,
+                active_segment_offset: active_segment_offset,
+            }
+        }
+    )
+}
+
+named!{parse_snapshot_complete<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[SNAPSHOT_COMPLETE]) ~
+        op_id: be_u32 ~
+        name: parse_str ~
+        partitions: length_count!(be_u16, parse_partition_snapshot_status),
+        || {
+            ProtocolMessage::SnapshotComplete(SnapshotComplete {
+                op_id: op_id,
+                name: name,
+                partitions: partitions,
+            })
+        }
+    )
+}
+
+named!{parse_delete_events<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[DELETE_EVENTS]) ~
+        op_id: be_u32 ~
+        event_ids: length_count!(be_u16, parse_zeroable_event_id) ~
+        namespace: parse_str,
+        || {
+            ProtocolMessage::DeleteEvents(DeleteEvents {
+                op_id: op_id,
+                event_ids: event_ids,
+                namespace: if namespace.is_empty() { None } else { Some(namespace) },
+            })
+        }
+    )
+}
+
+named!{parse_events_deleted<ProtocolMessage<OwnedFloEvent>>,
+    chain!(
+        _tag: tag!(&[EVENTS_DELETED]) ~
+        op_id: be_u32,
+        || {
+            ProtocolMessage::EventsDeleted(EventsDeleted {
+                op_id: op_id,
+            })
+        }
+    )
+}
+
 named!{parse_version_vec<Vec<FloEventId>>,
     length_count!(be_u16, parse_zeroable_event_id)
 }
@@ -497,51 +1017,139 @@ named!{parse_client_announce<ProtocolMessage<OwnedFloEvent>>, chain!(
     protocol_version: be_u32 ~
     op_id: be_u32 ~
     client_name: parse_str ~
-    batch_size: be_u32,
+    batch_size: be_u32 ~
+    producer_id: cond!(protocol_version >= PRODUCER_ID_PROTOCOL_VERSION, parse_optional_u64),
     || {
         let batch = if batch_size > 0 { Some(batch_size) } else { None };
+        let producer_id = producer_id.and_then(|id| id);
 
         ProtocolMessage::Announce(ClientAnnounce{
             protocol_version: protocol_version,
             op_id: op_id,
             client_name: client_name,
-            consume_batch_size: batch
+            consume_batch_size: batch,
+            producer_id: producer_id,
         })
     }
 )}
 
 named!{pub parse_any<ProtocolMessage<OwnedFloEvent>>, alt!(
         parse_event_ack |
+        parse_batch_ack |
         parse_receive_event_header |
         parse_error_message |
         parse_awaiting_events |
         parse_new_producer_event |
+        parse_produce_batch |
         parse_set_batch_size |
         parse_next_batch |
         parse_end_of_batch |
         parse_stop_consuming |
         parse_cursor_created |
         parse_new_start_consuming |
+        parse_start_consuming_since |
         parse_set_event_stream |
         parse_event_stream_status |
+        parse_snapshot |
+        parse_snapshot_complete |
+        parse_delete_events |
+        parse_events_deleted |
+        parse_begin_transaction |
+        parse_commit_transaction |
+        parse_abort_transaction |
+        parse_transaction_ack |
         parse_client_announce
 )}
 
+/// Headers are written as a u16 count, followed by each header name as a string and each value prefixed by a u32 length
+fn serialize_event_headers<'a>(serializer: Serializer<'a>, headers: &[EventHeader]) -> Serializer<'a> {
+    serializer.write_u16(headers.len() as u16)
+            .write_many(headers.iter(), |ser, &(ref name, ref value)| {
+                ser.write_string(name)
+                        .write_u32(value.len() as u32)
+                        .write_bytes(value)
+            })
+}
+
+/// The expected version is written as a u8 tag followed by an event id, so that it always has the same length
+fn serialize_expected_version<'a>(serializer: Serializer<'a>, expected_version: Option<ExpectedVersion>) -> Serializer<'a> {
+    let (tag, id) = match expected_version {
+        None => (EXPECT_ANY_VERSION, FloEventId::zero()),
+        Some(ExpectedVersion::NoEvents) => (EXPECT_NO_EVENTS, FloEventId::zero()),
+        Some(ExpectedVersion::LastEventId(id)) => (EXPECT_LAST_EVENT_ID, id),
+    };
+    serializer.write_u8(tag)
+            .write_u64(id.event_counter)
+            .write_u16(id.actor)
+}
+
 fn serialize_new_produce_header(header: &ProduceEvent, buf: &mut [u8]) -> usize {
     let (counter, actor) = header.parent_id.map(|id| {
         (id.event_counter, id.actor)
     }).unwrap_or((0, 0));
 
-    Serializer::new(buf).write_u8(PRODUCE_EVENT)
+    let serializer = Serializer::new(buf).write_u8(PRODUCE_EVENT)
                         .write_string(&header.namespace)
                         .write_u64(counter)
                         .write_u16(actor)
                         .write_u32(header.op_id)
-                        .write_u16(header.partition)
+                        .write_u16(header.partition);
+    let serializer = serialize_event_headers(serializer, &header.headers)
+                        .write_u64(header.event_time.map(time::millis_since_epoch).unwrap_or(0))
+                        .write_u64(header.sequence.unwrap_or(0));
+    serialize_expected_version(serializer, header.expected_version)
                         .write_u32(header.data.len() as u32)
                         .finish()
 }
 
+fn serialize_produce_batch(batch: &ProduceBatch, buf: &mut [u8]) -> usize {
+    Serializer::new(buf).write_u8(PRODUCE_BATCH)
+            .write_u32(batch.op_id)
+            .write_u16(batch.partition)
+            .write_u16(batch.events.len() as u16)
+            .write_many(batch.events.iter(), |ser, event| {
+                let (counter, actor) = event.parent_id.map(|id| {
+                    (id.event_counter, id.actor)
+                }).unwrap_or((0, 0));
+                let ser = ser.write_string(&event.namespace)
+                        .write_u64(counter)
+                        .write_u16(actor);
+                let ser = serialize_event_headers(ser, &event.headers)
+                        .write_u64(event.event_time.map(time::millis_since_epoch).unwrap_or(0))
+                        .write_u64(event.sequence.unwrap_or(0));
+                serialize_expected_version(ser, event.expected_version)
+                        .write_u32(event.data.len() as u32)
+            })
+            .finish()
+}
+
+fn serialize_batch_ack(ack: &BatchAck, buf: &mut [u8]) -> usize {
+    let ser = Serializer::new(buf).write_u8(BATCH_ACK);
+    serialize_batch_ack_body(ser, ack).finish()
+}
+
+fn serialize_batch_ack_body<'a>(serializer: Serializer<'a>, ack: &BatchAck) -> Serializer<'a> {
+    serializer.write_u32(ack.op_id)
+            .write_u64(ack.first_event_id.event_counter)
+            .write_u16(ack.first_event_id.actor)
+            .write_u64(ack.last_event_id.event_counter)
+            .write_u16(ack.last_event_id.actor)
+            .write_u16(ack.original_ids.len() as u16)
+            .write_many(ack.original_ids.iter(), |ser, &(index, id)| {
+                ser.write_u16(index)
+                        .write_u64(id.event_counter)
+                        .write_u16(id.actor)
+            })
+}
+
+fn serialize_transaction_ack(ack: &TransactionAck, buf: &mut [u8]) -> usize {
+    Serializer::new(buf).write_u8(TRANSACTION_ACK)
+            .write_u32(ack.op_id)
+            .write_u16(ack.produced.len() as u16)
+            .write_many(ack.produced.iter(), serialize_batch_ack_body)
+            .finish()
+}
+
 fn serialize_event_ack(ack: &EventAck, buf: &mut [u8]) -> usize {
     Serializer::new(buf).write_u8(ACK_HEADER)
             .write_u32(ack.op_id)
@@ -559,14 +1167,16 @@ fn serialize_error_message(err: &ErrorMessage, buf: &mut [u8]) -> usize {
 }
 
 fn serialize_receive_event_header<E: FloEvent>(event: &E, buf: &mut [u8]) -> usize {
-    Serializer::new(buf)
+    let serializer = Serializer::new(buf)
             .write_u8(::client::headers::RECEIVE_EVENT)
             .write_u64(event.id().event_counter)
             .write_u16(event.id().actor)
             .write_u64(event.parent_id().map(|id| id.event_counter).unwrap_or(0))
             .write_u16(event.parent_id().map(|id| id.actor).unwrap_or(0))
             .write_u64(time::millis_since_epoch(event.timestamp()))
-            .write_string(event.namespace())
+            .write_u64(event.event_time().map(time::millis_since_epoch).unwrap_or(0))
+            .write_string(event.namespace());
+    serialize_event_headers(serializer, event.headers())
             .write_u32(event.data_len())
             .finish()
 }
@@ -586,18 +1196,48 @@ fn serialize_event_stream_status(status: &EventStreamStatus, buf: &mut [u8]) ->
             .finish()
 }
 
+fn serialize_snapshot_complete(complete: &SnapshotComplete, buf: &mut [u8]) -> usize {
+    Serializer::new(buf)
+            .write_u8(SNAPSHOT_COMPLETE)
+            .write_u32(complete.op_id)
+            .write_string(&complete.name)
+            .write_u16(complete.partitions.len() as u16)
+            .write_many(complete.partitions.iter(), |ser, partition| {
+                ser.write_u16(partition.partition_num)
+                        .write_u64(partition.head)
+                        .write_u64(partition.active_segment_offset)
+            })
+            .finish()
+}
+
+fn serialize_delete_events(delete: &DeleteEvents, buf: &mut [u8]) -> usize {
+    Serializer::new(buf)
+            .write_u8(DELETE_EVENTS)
+            .write_u32(delete.op_id)
+            .write_u16(delete.event_ids.len() as u16)
+            .write_many(delete.event_ids.iter(), |ser, id| {
+                ser.write_u64(id.event_counter).write_u16(id.actor)
+            })
+            .write_string(delete.namespace.as_ref().map(|ns| ns.as_str()).unwrap_or(""))
+            .finish()
+}
+
 impl <E: FloEvent> ProtocolMessage<E> {
 
     pub fn serialize(&self, buf: &mut [u8]) -> usize {
         match *self {
             ProtocolMessage::Announce(ref announce) => {
-                Serializer::new(buf)
+                let ser = Serializer::new(buf)
                         .write_u8(CLIENT_ANNOUNCE)
                         .write_u32(announce.protocol_version)
                         .write_u32(announce.op_id)
                         .write_string(&announce.client_name)
-                        .write_u32(announce.consume_batch_size.unwrap_or(0))
-                        .finish()
+                        .write_u32(announce.consume_batch_size.unwrap_or(0));
+                if announce.protocol_version >= PRODUCER_ID_PROTOCOL_VERSION {
+                    ser.write_u64(announce.producer_id.unwrap_or(0)).finish()
+                } else {
+                    ser.finish()
+                }
             }
             ProtocolMessage::StreamStatus(ref status) => {
                 serialize_event_stream_status(status, buf)
@@ -612,6 +1252,46 @@ impl <E: FloEvent> ProtocolMessage<E> {
             ProtocolMessage::ReceiveEvent(ref event) => {
                 serialize_receive_event_header(event, buf)
             }
+            ProtocolMessage::Snapshot(ref snapshot) => {
+                Serializer::new(buf)
+                        .write_u8(SNAPSHOT)
+                        .write_u32(snapshot.op_id)
+                        .write_string(&snapshot.dest_dir)
+                        .finish()
+            }
+            ProtocolMessage::SnapshotComplete(ref complete) => {
+                serialize_snapshot_complete(complete, buf)
+            }
+            ProtocolMessage::DeleteEvents(ref delete) => {
+                serialize_delete_events(delete, buf)
+            }
+            ProtocolMessage::EventsDeleted(ref deleted) => {
+                Serializer::new(buf)
+                        .write_u8(EVENTS_DELETED)
+                        .write_u32(deleted.op_id)
+                        .finish()
+            }
+            ProtocolMessage::BeginTransaction(op_id) => {
+                Serializer::new(buf)
+                        .write_u8(BEGIN_TRANSACTION)
+                        .write_u32(op_id)
+                        .finish()
+            }
+            ProtocolMessage::CommitTransaction(op_id) => {
+                Serializer::new(buf)
+                        .write_u8(COMMIT_TRANSACTION)
+                        .write_u32(op_id)
+                        .finish()
+            }
+            ProtocolMessage::AbortTransaction(op_id) => {
+                Serializer::new(buf)
+                        .write_u8(ABORT_TRANSACTION)
+                        .write_u32(op_id)
+                        .finish()
+            }
+            ProtocolMessage::AckTransaction(ref ack) => {
+                serialize_transaction_ack(ack, buf)
+            }
             ProtocolMessage::CursorCreated(ref info) => {
                 Serializer::new(buf).write_u8(headers::CURSOR_CREATED)
                         .write_u32(info.op_id)
@@ -630,6 +1310,9 @@ impl <E: FloEvent> ProtocolMessage<E> {
             ProtocolMessage::ProduceEvent(ref header) => {
                 serialize_new_produce_header(header, buf)
             }
+            ProtocolMessage::ProduceBatch(ref batch) => {
+                serialize_produce_batch(batch, buf)
+            }
             ProtocolMessage::NewStartConsuming(NewConsumerStart{ref op_id, ref version_vector, ref max_events, ref namespace}) => {
                 let mut serializer = Serializer::new(buf).write_u8(NEW_START_CONSUMING)
                         .write_u32(*op_id)
@@ -641,9 +1324,21 @@ impl <E: FloEvent> ProtocolMessage<E> {
                 serializer.write_u64(*max_events)
                         .write_string(namespace).finish()
             }
+            ProtocolMessage::StartConsumingSince(ref consume_since) => {
+                Serializer::new(buf).write_u8(START_CONSUMING_SINCE)
+                        .write_u32(consume_since.op_id)
+                        .write_u64(time::millis_since_epoch(consume_since.since))
+                        .write_u64(consume_since.max_events)
+                        .write_string(&consume_since.namespace)
+                        .write_bool(consume_since.by_event_time)
+                        .finish()
+            }
             ProtocolMessage::AckEvent(ref ack) => {
                 serialize_event_ack(ack, buf)
             }
+            ProtocolMessage::AckBatch(ref ack) => {
+                serialize_batch_ack(ack, buf)
+            }
             ProtocolMessage::Error(ref err_message) => {
                 serialize_error_message(err_message, buf)
             }
@@ -663,15 +1358,20 @@ impl <E: FloEvent> ProtocolMessage<E> {
         }
     }
 
-    pub fn get_body(&self) -> Option<&[u8]> {
+    /// Returns the portions of the message that are written after the header, in the order they're written. Most messages
+    /// have no body, and a `ProduceBatch` has one for each event.
+    pub fn get_bodies(&self) -> Vec<&[u8]> {
         match *self {
             ProtocolMessage::ProduceEvent(ref produce) => {
-                Some(produce.data.as_slice())
+                vec![produce.data.as_slice()]
+            }
+            ProtocolMessage::ProduceBatch(ref batch) => {
+                batch.events.iter().map(|event| event.data.as_slice()).collect()
             }
             ProtocolMessage::ReceiveEvent(ref event) => {
-                Some(event.data())
+                vec![event.data()]
             }
-            _ => None
+            _ => Vec::new()
         }
     }
 
@@ -679,12 +1379,23 @@ impl <E: FloEvent> ProtocolMessage<E> {
         match *self {
             ProtocolMessage::Announce(ref ann) => ann.op_id,
             ProtocolMessage::ProduceEvent(ref prod) => prod.op_id,
+            ProtocolMessage::ProduceBatch(ref batch) => batch.op_id,
+            ProtocolMessage::AckBatch(ref ack) => ack.op_id,
             ProtocolMessage::CursorCreated(ref info) => info.op_id,
             ProtocolMessage::Error(ref err) => err.op_id,
             ProtocolMessage::AckEvent(ref ack) => ack.op_id,
             ProtocolMessage::StreamStatus(ref status) => status.op_id,
             ProtocolMessage::SetEventStream(ref set) => set.op_id,
+            ProtocolMessage::StartConsumingSince(ref start) => start.op_id,
             ProtocolMessage::StopConsuming(ref op_id) => *op_id,
+            ProtocolMessage::Snapshot(ref snapshot) => snapshot.op_id,
+            ProtocolMessage::SnapshotComplete(ref complete) => complete.op_id,
+            ProtocolMessage::DeleteEvents(ref delete) => delete.op_id,
+            ProtocolMessage::EventsDeleted(ref deleted) => deleted.op_id,
+            ProtocolMessage::BeginTransaction(op_id) => op_id,
+            ProtocolMessage::CommitTransaction(op_id) => op_id,
+            ProtocolMessage::AbortTransaction(op_id) => op_id,
+            ProtocolMessage::AckTransaction(ref ack) => ack.op_id,
             _ => 0
         }
     }
@@ -710,7 +1421,7 @@ mod test {
 
         let mut len = message.serialize(&mut buffer[..]);
         if include_body {
-            if let Some(body) = message.get_body() {
+            for body in message.get_bodies() {
                 (&mut buffer[len..(len + body.len())]).copy_from_slice(body);
                 len += body.len();
             }
@@ -736,14 +1447,37 @@ mod test {
     #[test]
     fn serde_client_announce() {
         let announce = ClientAnnounce {
-            protocol_version: 1,
+            protocol_version: PROTOCOL_VERSION,
             op_id: 765,
             client_name: "nathan".to_owned(),
             consume_batch_size: Some(456),
+            producer_id: Some(8765),
         };
         test_serialize_then_deserialize(&ProtocolMessage::Announce(announce));
     }
 
+    #[test]
+    fn client_announce_from_protocol_version_1_is_parsed_without_a_producer_id() {
+        let announce = ClientAnnounce {
+            protocol_version: 1,
+            op_id: 765,
+            client_name: "nathan".to_owned(),
+            consume_batch_size: Some(456),
+            producer_id: None,
+        };
+        let mut buffer = [0; 128];
+        let len = ProtocolMessage::Announce::<OwnedFloEvent>(announce.clone()).serialize(&mut buffer[..]);
+        assert_eq!(1 + 4 + 4 + 2 + 6 + 4, len);
+
+        match parse_any(&buffer[..len]) {
+            IResult::Done(remaining, result) => {
+                assert!(remaining.is_empty());
+                assert_eq!(ProtocolMessage::Announce(announce), result);
+            }
+            other => panic!("Expected to parse announce, got: {:?}", other)
+        }
+    }
+
     #[test]
     fn serde_event_stream_status() {
         let status = EventStreamStatus {
@@ -786,6 +1520,82 @@ mod test {
         test_serialize_then_deserialize(&ProtocolMessage::SetEventStream(set_stream));
     }
 
+    #[test]
+    fn serde_snapshot() {
+        let snapshot = SnapshotRequest {
+            op_id: 87,
+            dest_dir: "/var/backups/flo".to_owned(),
+        };
+        test_serialize_then_deserialize(&ProtocolMessage::Snapshot(snapshot));
+    }
+
+    #[test]
+    fn serde_snapshot_complete() {
+        let complete = SnapshotComplete {
+            op_id: 87,
+            name: "default".to_owned(),
+            partitions: vec![
+                PartitionSnapshotStatus {
+                    partition_num: 1,
+                    head: 9876,
+                    active_segment_offset: 4096,
+                },
+                PartitionSnapshotStatus {
+                    partition_num: 2,
+                    head: 0,
+                    active_segment_offset: 0,
+                },
+            ],
+        };
+        test_serialize_then_deserialize(&ProtocolMessage::SnapshotComplete(complete));
+    }
+
+    #[test]
+    fn serde_delete_events() {
+        let delete = DeleteEvents {
+            op_id: 5,
+            event_ids: vec![FloEventId::new(1, 77), FloEventId::new(3, 2)],
+            namespace: Some("/users/42/**/*".to_owned()),
+        };
+        test_serialize_then_deserialize(&ProtocolMessage::DeleteEvents(delete));
+
+        let delete = DeleteEvents {
+            op_id: 6,
+            event_ids: vec![FloEventId::new(1, 77)],
+            namespace: None,
+        };
+        test_serialize_then_deserialize(&ProtocolMessage::DeleteEvents(delete));
+    }
+
+    #[test]
+    fn serde_events_deleted() {
+        test_serialize_then_deserialize(&ProtocolMessage::EventsDeleted(EventsDeleted { op_id: 5 }));
+    }
+
+    #[test]
+    fn serde_transaction_messages() {
+        test_serialize_then_deserialize(&ProtocolMessage::BeginTransaction(3));
+        test_serialize_then_deserialize(&ProtocolMessage::CommitTransaction(4));
+        test_serialize_then_deserialize(&ProtocolMessage::AbortTransaction(5));
+        test_serialize_then_deserialize(&ProtocolMessage::AckTransaction(TransactionAck {
+            op_id: 4,
+            produced: vec![
+                BatchAck {
+                    op_id: 1,
+                    first_event_id: FloEventId::new(1, 8),
+                    last_event_id: FloEventId::new(1, 9),
+                    original_ids: Vec::new(),
+                },
+                BatchAck {
+                    op_id: 2,
+                    first_event_id: FloEventId::new(2, 10),
+                    last_event_id: FloEventId::new(2, 10),
+                    original_ids: Vec::new(),
+                },
+            ],
+        }));
+    }
+
     #[test]
     fn serde_new_start_consuming() {
         let version_vec = vec![
@@ -813,13 +1623,37 @@ mod test {
         test_serialize_then_deserialize(&msg);
     }
 
+    #[test]
+    fn serde_start_consuming_since() {
+        test_serialize_then_deserialize(&ProtocolMessage::StartConsumingSince(ConsumeSince {
+            op_id: 4567,
+            since: time::from_millis_since_epoch(1_500_000_000_123),
+            max_events: 55,
+            namespace: "/foo/*".to_owned(),
+            by_event_time: false,
+        }));
+    }
+
+    #[test]
+    fn serde_start_consuming_since_event_time() {
+        test_serialize_then_deserialize(&ProtocolMessage::StartConsumingSince(ConsumeSince {
+            op_id: 4568,
+            since: time::from_millis_since_epoch(1_400_000_000_000),
+            max_events: 5,
+            namespace: "/foo/*".to_owned(),
+            by_event_time: true,
+        }));
+    }
+
     #[test]
     fn serde_receive_event() {
         let event = OwnedFloEvent {
             id: FloEventId::new(4, 5),
             timestamp: time::from_millis_since_epoch(99),
+            event_time: None,
             parent_id: Some(FloEventId::new(4, 3)),
             namespace: "/foo/bar".to_owned(),
+            headers: Vec::new(),
             data: vec![9; 99],
         };
         let message = ProtocolMessage::ReceiveEvent(event.clone());
@@ -827,6 +1661,26 @@ mod test {
         assert_eq!(message, result);
     }
 
+    #[test]
+    fn serde_receive_event_with_headers() {
+        let event = OwnedFloEvent::new(FloEventId::new(4, 5), None, time::from_millis_since_epoch(99), "/foo/bar".to_owned(), vec![9; 20])
+                .with_header("content-type", "application/json")
+                .with_header("trace-id", vec![1, 2, 3, 4])
+                .with_header("empty", Vec::new());
+        let message = ProtocolMessage::ReceiveEvent(event);
+        let result = serde_with_body(&message, true);
+        assert_eq!(message, result);
+    }
+
+    #[test]
+    fn serde_receive_event_with_event_time() {
+        let event = OwnedFloEvent::new(FloEventId::new(4, 5), None, time::from_millis_since_epoch(99_999), "/foo/bar".to_owned(), vec![9; 20])
+                .with_event_time(time::from_millis_since_epoch(12_345));
+        let message = ProtocolMessage::ReceiveEvent(event);
+        let result = serde_with_body(&message, true);
+        assert_eq!(message, result);
+    }
+
     #[test]
     fn stop_consuming_is_serialized_and_parsed() {
         test_serialize_then_deserialize(&ProtocolMessage::StopConsuming(345));
@@ -867,6 +1721,26 @@ mod test {
         test_serialize_then_deserialize(&mut ProtocolMessage::Error(error));
     }
 
+    #[test]
+    fn corrupt_event_error_message_is_parsed() {
+        let error = ErrorMessage {
+            op_id: 6789,
+            kind: ErrorKind::CorruptEvent,
+            description: "checksum mismatch".to_owned(),
+        };
+        test_serialize_then_deserialize(&mut ProtocolMessage::Error(error));
+    }
+
+    #[test]
+    fn concurrency_conflict_error_message_is_parsed() {
+        let error = ErrorMessage {
+            op_id: 4321,
+            kind: ErrorKind::ConcurrencyConflict,
+            description: "expected no events in namespace: /carts/77, but the last event was: 1.5".to_owned(),
+        };
+        test_serialize_then_deserialize(&mut ProtocolMessage::Error(error));
+    }
+
     #[test]
     fn acknowledge_event_message_is_parsed() {
         test_serialize_then_deserialize(&mut ProtocolMessage::AckEvent(EventAck{
@@ -882,6 +1756,10 @@ mod test {
             parent_id: Some(FloEventId::new(123, 456)),
             op_id: 9,
             partition: 7,
+            headers: vec![("content-type".to_owned(), b"text/plain".to_vec())],
+            event_time: Some(time::from_millis_since_epoch(1_400_000_000_000)),
+            sequence: Some(42),
+            expected_version: Some(ExpectedVersion::LastEventId(FloEventId::new(7, 41))),
             data: vec![9; 5]
         };
         let mut message_input = ProtocolMessage::ProduceEvent(input.clone());
@@ -892,6 +1770,10 @@ mod test {
             assert_eq!(input.parent_id, result.parent_id);
             assert_eq!(input.op_id, result.op_id);
             assert_eq!(input.partition, result.partition);
+            assert_eq!(input.headers, result.headers);
+            assert_eq!(input.event_time, result.event_time);
+            assert_eq!(input.sequence, result.sequence);
+            assert_eq!(input.expected_version, result.expected_version);
 
             // The vector must be allocated with the correct capacity, but we haven't actually read all the data
             assert_eq!(input.data.len(), result.data.capacity());
@@ -900,6 +1782,87 @@ mod test {
         }
     }
 
+    fn batch_event(namespace: &str, data: &[u8]) -> ProduceEvent {
+        ProduceEvent {
+            op_id: 6,
+            partition: 3,
+            namespace: namespace.to_owned(),
+            parent_id: None,
+            headers: Vec::new(),
+            event_time: None,
+            sequence: None,
+            expected_version: None,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn parse_produce_batch_parses_the_header_of_every_event_but_not_the_data() {
+        let mut second = batch_event("/bar", b"second");
+        second.parent_id = Some(FloEventId::new(2, 3));
+        second.headers = vec![("content-type".to_owned(), b"text/plain".to_vec())];
+        second.event_time = Some(time::from_millis_since_epoch(1_400_000_000_000));
+        second.sequence = Some(42);
+        second.expected_version = Some(ExpectedVersion::NoEvents);
+        let input = ProduceBatch {
+            op_id: 6,
+            partition: 3,
+            events: vec![batch_event("/foo", b"first"), second, batch_event("/baz", b"")],
+        };
+        let message_input = ProtocolMessage::ProduceBatch(input.clone());
+        let mut buffer = [0; 1024];
+        let len = message_input.serialize(&mut buffer[..]);
+        assert_eq!(input.header_len(), len);
+
+        let message_result = ser_de(&message_input);
+        if let ProtocolMessage::ProduceBatch(result) = message_result {
+            assert_eq!(input.op_id, result.op_id);
+            assert_eq!(input.partition, result.partition);
+            assert_eq!(input.events.len(), result.events.len());
+            for (expected, actual) in input.events.iter().zip(result.events.iter()) {
+                assert_eq!(expected.op_id, actual.op_id);
+                assert_eq!(expected.partition, actual.partition);
+                assert_eq!(expected.namespace, actual.namespace);
+                assert_eq!(expected.parent_id, actual.parent_id);
+                assert_eq!(expected.headers, actual.headers);
+                assert_eq!(expected.event_time, actual.event_time);
+                assert_eq!(expected.sequence, actual.sequence);
+                assert_eq!(expected.expected_version, actual.expected_version);
+                assert_eq!(expected.data.len(), actual.data.capacity());
+            }
+        } else {
+            panic!("expected a ProduceBatch, got: {:?}", message_result);
+        }
+    }
+
+    #[test]
+    fn serde_batch_ack() {
+        test_serialize_then_deserialize(&ProtocolMessage::AckBatch(BatchAck {
+            op_id: 7,
+            first_event_id: FloEventId::new(3, 10),
+            last_event_id: FloEventId::new(3, 12),
+            original_ids: vec![(1, FloEventId::new(3, 4)), (4, FloEventId::new(3, 6))],
+        }));
+    }
+
+    #[test]
+    fn batch_ack_gives_retransmitted_events_the_ids_of_the_originals() {
+        let ack = BatchAck {
+            op_id: 7,
+            first_event_id: FloEventId::new(3, 10),
+            last_event_id: FloEventId::new(3, 12),
+            original_ids: vec![(1, FloEventId::new(3, 4)), (4, FloEventId::new(3, 6))],
+        };
+        let expected = vec![
+            FloEventId::new(3, 10),
+            FloEventId::new(3, 4),
+            FloEventId::new(3, 11),
+            FloEventId::new(3, 12),
+            FloEventId::new(3, 6),
+        ];
+        assert_eq!(expected, ack.event_ids(5));
+    }
+
     #[test]
     fn parse_string_returns_empty_string_string_length_is_0() {
         let input = vec![0, 0, 110, 4, 5, 6, 7];
@@ -927,11 +1890,12 @@ mod test {
             0, 0, 0, 0, 0, 0, 1, 34,  0, 1,
             0, 0, 0, 0, 0, 0, 0, 0,   0, 0,
             0, 0, 1, 93, 77, 45, 214, 26,
+            0, 0, 0, 0, 0, 0, 0, 0,
             47, 101, 118, 101
         ];
 
         let result = parse_any(&input);
-        let expected = IResult::Incomplete(Needed::Size(12164));
+        let expected = IResult::Incomplete(Needed::Size(12172));
         assert_eq!(expected, result);
     }
 }
@@ -238,8 +238,7 @@ impl <E: FloEvent> InProgressMessage<E> {
     }
 
     fn body_bytes_remaining(&mut self) -> usize {
-        let pos = self.body_read_pos;
-        get_body_buffer(&mut self.message).map(|buf| buf.capacity() - pos).unwrap_or(0)
+        get_body_len(&self.message) - self.body_read_pos
     }
 
     fn append_body(&mut self, bytes: &[u8]) -> usize {
@@ -255,9 +254,23 @@ impl <E: FloEvent> InProgressMessage<E> {
     }
 }
 
+/// Returns the total number of bytes in the body of the message, which is the sum of the capacities of its body buffers
+fn get_body_len<E: FloEvent>(message: &ProtocolMessage<E>) -> usize {
+    match *message {
+        ProtocolMessage::ProduceEvent(ref event) => event.data.capacity(),
+        ProtocolMessage::ProduceBatch(ref batch) => batch.events.iter().map(|event| event.data.capacity()).sum::<usize>(),
+        _ => 0
+    }
+}
+
+/// Returns the buffer that the next bytes of the body should be appended to. The body of a batch is the data of each
+/// event, one after another, so this is the first one that isn't full yet
 fn get_body_buffer<E: FloEvent>(message: &mut ProtocolMessage<E>) -> Option<&mut Vec<u8>> {
     match *message {
         ProtocolMessage::ProduceEvent(ref mut event) => Some(&mut event.data),
+        ProtocolMessage::ProduceBatch(ref mut batch) => {
+            batch.events.iter_mut().map(|event| &mut event.data).find(|data| data.len() < data.capacity())
+        }
         _ => None
     }
 }
@@ -303,22 +316,75 @@ impl <E: FloEvent> MessageWriter<E> {
             *header_written = true;
         }
 
-        if let Some(body) = message.get_body() {
-            *body_len = body.len();
-            while *body_position < *body_len {
-                let to_write = &body[*body_position..];
-                match dest.write(to_write) {
-                    Ok(n) => *body_position += n,
-                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {} // ignore and retry
-                    Err(ref e) if cfg!(target_os = "macos") && e.raw_os_error() == Some(41) => {
-                        // osx is weird, and can sometimes return an EPROTOTYPE when writing
-                        debug!(target: "eprototype", "Retrying write due to error: {:?}", e);
-                    }
-                    Err(other) => return Err(other)
+        let bodies = message.get_bodies();
+        *body_len = bodies.iter().map(|body| body.len()).sum();
+        while *body_position < *body_len {
+            let to_write = unwritten_body(&bodies, *body_position);
+            match dest.write(to_write) {
+                Ok(n) => *body_position += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {} // ignore and retry
+                Err(ref e) if cfg!(target_os = "macos") && e.raw_os_error() == Some(41) => {
+                    // osx is weird, and can sometimes return an EPROTOTYPE when writing
+                    debug!(target: "eprototype", "Retrying write due to error: {:?}", e);
                 }
+                Err(other) => return Err(other)
             }
         }
         Ok(())
     }
 }
 
+/// Returns the portion of the bodies that starts at the given position, up to the end of whichever body contains it
+fn unwritten_body<'a>(bodies: &[&'a [u8]], position: usize) -> &'a [u8] {
+    let mut start = position;
+    for body in bodies {
+        if start < body.len() {
+            return &body[start..];
+        }
+        start -= body.len();
+    }
+    &[]
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn batch_event(namespace: &str, data: &[u8]) -> ProduceEvent {
+        ProduceEvent {
+            op_id: 4,
+            partition: 2,
+            namespace: namespace.to_owned(),
+            parent_id: None,
+            headers: Vec::new(),
+            event_time: None,
+            sequence: None,
+            expected_version: None,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn produce_batch_is_written_and_read_back_with_the_data_of_every_event() {
+        let batch = ProtocolMessage::ProduceBatch(ProduceBatch {
+            op_id: 4,
+            partition: 2,
+            events: vec![
+                batch_event("/foo", b"first event"),
+                batch_event("/bar", b""),
+                batch_event("/baz", b"third event"),
+            ],
+        });
+
+        let mut bytes = Vec::new();
+        let mut writer = MessageWriter::new_owned(batch.clone());
+        writer.write(&mut bytes).expect("failed to write batch");
+        assert!(writer.is_done());
+
+        let mut stream: MessageStream<Cursor<Vec<u8>>, OwnedFloEvent> = MessageStream::new(Cursor::new(bytes));
+        let result = stream.read_next().expect("failed to read batch");
+        assert_eq!(batch, result);
+    }
+}
@@ -1,6 +1,7 @@
 //! For running an event stream server in-process and using an in-memory transport for communication with it.
 //! This is especially useful in development and testing, as it allows an application to run without a dependency
-//! on an external server.
+//! on an external server. Setting `storage` to `StorageBackend::Memory` in the `EventStreamOptions` keeps all of the
+//! events in memory as well, so the embedded server never touches the filesystem.
 
 use std::fmt::Debug;
 use std::io;
@@ -15,7 +16,7 @@ use event::FloEvent;
 use engine::{EngineRef, create_client_channels, start_controller, ConnectionHandler, SendProtocolMessage};
 
 pub use engine::ControllerOptions;
-pub use engine::event_stream::EventStreamOptions;
+pub use engine::event_stream::{EventStreamOptions, StorageBackend};
 
 
 #[derive(Clone, Debug)]
@@ -58,14 +59,25 @@ fn message_to_owned(server_msg: SendProtocolMessage) -> ClientProtocolMessage {
         ProtocolMessage::Error(op) => ProtocolMessage::Error(op),
         ProtocolMessage::StreamStatus(op) => ProtocolMessage::StreamStatus(op),
         ProtocolMessage::AckEvent(op) => ProtocolMessage::AckEvent(op),
+        ProtocolMessage::AckBatch(op) => ProtocolMessage::AckBatch(op),
         ProtocolMessage::ProduceEvent(op) => ProtocolMessage::ProduceEvent(op),
+        ProtocolMessage::ProduceBatch(op) => ProtocolMessage::ProduceBatch(op),
         ProtocolMessage::NextBatch => ProtocolMessage::NextBatch,
         ProtocolMessage::EndOfBatch => ProtocolMessage::EndOfBatch,
         ProtocolMessage::SetBatchSize(op) => ProtocolMessage::SetBatchSize(op),
         ProtocolMessage::NewStartConsuming(op) => ProtocolMessage::NewStartConsuming(op),
+        ProtocolMessage::StartConsumingSince(op) => ProtocolMessage::StartConsumingSince(op),
         ProtocolMessage::CursorCreated(op) => ProtocolMessage::CursorCreated(op),
         ProtocolMessage::Announce(op) => ProtocolMessage::Announce(op),
         ProtocolMessage::SetEventStream(op) => ProtocolMessage::SetEventStream(op),
+        ProtocolMessage::Snapshot(op) => ProtocolMessage::Snapshot(op),
+        ProtocolMessage::SnapshotComplete(op) => ProtocolMessage::SnapshotComplete(op),
+        ProtocolMessage::DeleteEvents(op) => ProtocolMessage::DeleteEvents(op),
+        ProtocolMessage::EventsDeleted(op) => ProtocolMessage::EventsDeleted(op),
+        ProtocolMessage::BeginTransaction(op) => ProtocolMessage::BeginTransaction(op),
+        ProtocolMessage::CommitTransaction(op) => ProtocolMessage::CommitTransaction(op),
+        ProtocolMessage::AbortTransaction(op) => ProtocolMessage::AbortTransaction(op),
+        ProtocolMessage::AckTransaction(op) => ProtocolMessage::AckTransaction(op),
     }
 }
 
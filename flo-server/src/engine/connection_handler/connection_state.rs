@@ -2,6 +2,7 @@
 use tokio_core::reactor::Handle;
 
 use protocol::*;
+use event::ProducerId;
 
 use engine::{ConnectionId, ClientSender, EngineRef, SendProtocolMessage};
 use engine::event_stream::EventStreamRef;
@@ -13,6 +14,8 @@ const DEFAULT_CONSUME_BATCH_SIZE: u32 = 10_000;
 #[derive(Debug)]
 pub struct ConnectionState {
     pub client_name: Option<String>,
+    /// Set if the client announced itself as an idempotent producer
+    pub producer_id: Option<ProducerId>,
     pub connection_id: ConnectionId,
     pub client_sender: ClientSender,
     pub engine: EngineRef,
@@ -27,6 +30,7 @@ impl ConnectionState {
         let event_stream = engine.get_default_stream();
         ConnectionState {
             client_name: None,
+            producer_id: None,
             connection_id,
             client_sender,
             engine,
@@ -37,10 +41,26 @@ impl ConnectionState {
     }
 
     pub fn handle_announce_message(&mut self, announce: ClientAnnounce) -> ConnectionHandlerResult {
-        let ClientAnnounce {op_id, client_name, consume_batch_size, ..} = announce;
-        // todo: return error if client name is already set or if protocol version != 1
+        let ClientAnnounce {protocol_version, op_id, client_name, consume_batch_size, producer_id} = announce;
+        // todo: return error if client name is already set
+        if protocol_version != PROTOCOL_VERSION {
+            warn!("Rejecting client: '{}' on connection_id: {} because it uses protocol version: {}", client_name, self.connection_id, protocol_version);
+            let err_message = ErrorMessage {
+                op_id: op_id,
+                kind: ErrorKind::UnsupportedProtocolVersion,
+                description: format!("Unsupported protocol version: {}, the server only supports version: {}", protocol_version, PROTOCOL_VERSION),
+            };
+            // The client can't be expected to understand anything else, so the connection gets closed once the error is sent
+            self.send_to_client(ProtocolMessage::Error(err_message))?;
+            return Err(format!("Closing connection_id: {} because the client uses unsupported protocol version: {}", self.connection_id, protocol_version));
+        }
         self.client_name = Some(client_name);
 
+        if let Some(id) = producer_id {
+            debug!("Using producer_id: {} for connection_id: {}", id, self.connection_id);
+            self.producer_id = Some(id);
+        }
+
         if let Some(batch_size) = consume_batch_size {
             debug!("Using consume batch size of {} for connection_id: {}", batch_size, self.connection_id);
             self.consume_batch_size = batch_size;
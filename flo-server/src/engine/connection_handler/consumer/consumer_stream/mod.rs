@@ -7,8 +7,8 @@ use std::io;
 use futures::{Stream, Poll, Async};
 
 use engine::{ConnectionId, SendProtocolMessage};
-use engine::event_stream::partition::{PartitionReader, PersistentEvent};
-use protocol::{ProtocolMessage};
+use engine::event_stream::partition::{PartitionReader, PersistentEvent, is_checksum_error};
+use protocol::{ProtocolMessage, ErrorMessage, ErrorKind};
 
 pub use self::notifier::{ConsumerTaskSetter};
 pub use self::status_check::{ConsumerStatus, ConsumerStatusChecker, ConsumerStatusSetter, create_status_channel};
@@ -108,7 +108,19 @@ impl Consumer {
         // set the total remaining to 0 to make sure that all future poll calls will return None
         self.total_events_remaining = Some(0);
 
-        Err(err.into())
+        let kind = if is_checksum_error(&err) {
+            ErrorKind::CorruptEvent
+        } else {
+            ErrorKind::StorageEngineError
+        };
+        let message = ProtocolMessage::Error(ErrorMessage {
+            op_id: self.op_id,
+            kind: kind,
+            description: format!("Error reading event: {}", err),
+        });
+
+        // let the client know why the consumer is stopping instead of just dropping the cursor
+        Ok(Async::Ready(Some(message)))
     }
 
 
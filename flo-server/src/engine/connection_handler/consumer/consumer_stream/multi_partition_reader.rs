@@ -5,6 +5,8 @@ use event::{FloEvent, EventCounter};
 use engine::event_stream::partition::{PartitionReader, PersistentEvent};
 
 
+/// Reads events from several partitions in order of their event counters. Each partition's reader stops at any event
+/// from a transaction that hasn't been committed yet, which only holds back the events of that partition.
 pub struct MultiPartitionEventReader {
     readers: Vec<PartReaderInternal>,
 }
@@ -9,7 +9,7 @@ use event::ActorId;
 use protocol::*;
 use engine::connection_handler::ConnectionHandlerResult;
 use engine::connection_handler::connection_state::ConnectionState;
-use engine::event_stream::partition::{PartitionReader, EventFilter};
+use engine::event_stream::partition::{PartitionReader, EventFilter, ConsumeStart};
 
 use self::consumer_stream::{Consumer,
                             ConsumerStatus,
@@ -77,6 +77,28 @@ impl ConsumerConnectionState {
 
     pub fn handle_start_consuming(&mut self, start: NewConsumerStart, connection: &mut ConnectionState) -> ConnectionHandlerResult {
         let NewConsumerStart {op_id, version_vector, namespace, max_events} = start;
+        let partition_starts = version_vector.into_iter().map(|id| {
+            (id.actor, ConsumeStart::Exclusive(id.event_counter))
+        }).collect::<Vec<_>>();
+
+        self.start_consuming(op_id, namespace, max_events, partition_starts, connection)
+    }
+
+    pub fn handle_start_consuming_since(&mut self, start: ConsumeSince, connection: &mut ConnectionState) -> ConnectionHandlerResult {
+        let ConsumeSince {op_id, since, by_event_time, max_events, namespace} = start;
+        let partition_start = if by_event_time {
+            ConsumeStart::SinceEventTime(since)
+        } else {
+            ConsumeStart::Since(since)
+        };
+        let partition_starts = connection.event_stream.partitions().iter().map(|partition| {
+            (partition.partition_num(), partition_start)
+        }).collect::<Vec<_>>();
+
+        self.start_consuming(op_id, namespace, max_events, partition_starts, connection)
+    }
+
+    fn start_consuming(&mut self, op_id: u32, namespace: String, max_events: u64, partition_starts: Vec<(ActorId, ConsumeStart)>, connection: &mut ConnectionState) -> ConnectionHandlerResult {
         let event_limit = if max_events == CONSUME_UNLIMITED {
             None
         } else {
@@ -88,9 +110,7 @@ impl ConsumerConnectionState {
                 let connection_id = connection.connection_id;
                 let mut pending_consume = PendingConsumeOperation::new(op_id, event_limit);
 
-                for id in version_vector {
-                    let start = id.event_counter;
-                    let partition = id.actor;
+                for (partition, start) in partition_starts {
                     let notifier = pending_consume.create_notifier(connection_id);
 
                     let send_result = connection.event_stream.get_partition(partition).unwrap().consume(connection_id,
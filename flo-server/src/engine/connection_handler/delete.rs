@@ -0,0 +1,133 @@
+use std::io;
+use std::fmt::{self, Debug};
+use std::error::Error;
+use std::collections::BTreeMap;
+
+use protocol::*;
+use futures::{Future, Poll, Async};
+use futures::future::{join_all, JoinAll};
+
+use event::{ActorId, EventCounter};
+use engine::event_stream::partition::{DeleteResponseReceiver, DeleteResult, EventDeletion, EventFilter};
+use engine::{ConnectionHandlerResult, SendProtocolMessage};
+use engine::connection_handler::connection_state::ConnectionState;
+
+struct PendingDelete {
+    op_id: u32,
+    partitions: JoinAll<Vec<DeleteResponseReceiver>>,
+}
+
+/// Handles requests to delete events from the connection's current event stream. Event ids are sent only to the
+/// partitions that they belong to, while a namespace is deleted from every partition. The client gets a single response
+/// once every partition has persisted its tombstones.
+pub struct DeleteConnectionState {
+    delete_operation: Option<PendingDelete>,
+}
+
+impl DeleteConnectionState {
+    pub fn new() -> DeleteConnectionState {
+        DeleteConnectionState {
+            delete_operation: None,
+        }
+    }
+
+    pub fn requires_poll_complete(&self) -> bool {
+        self.delete_operation.is_some()
+    }
+
+    pub fn handle_delete(&mut self, delete: DeleteEvents, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
+        let DeleteEvents {op_id, event_ids, namespace} = delete;
+        let connection_id = common_state.connection_id;
+        info!("Deleting events from event stream: '{}' for connection_id: {}, event_ids: {:?}, namespace: {:?}",
+              common_state.event_stream.name(), connection_id, event_ids, namespace);
+
+        if let Some(ref glob) = namespace {
+            if let Err(description) = EventFilter::parse(glob) {
+                return common_state.send_to_client(error_response(op_id, ErrorKind::InvalidNamespaceGlob, description));
+            }
+        }
+
+        let partition_count = common_state.event_stream.get_partition_count();
+        let mut counters_by_partition: BTreeMap<ActorId, Vec<EventCounter>> = BTreeMap::new();
+        for id in event_ids {
+            if id.actor == 0 || id.actor > partition_count {
+                let description = format!("Event stream: '{}' has no partition: {}", common_state.event_stream.name(), id.actor);
+                return common_state.send_to_client(error_response(op_id, ErrorKind::StorageEngineError, description));
+            }
+            counters_by_partition.entry(id.actor).or_insert_with(Vec::new).push(id.event_counter);
+        }
+
+        let mut receivers = Vec::new();
+        for partition_num in 1..(partition_count + 1) {
+            let counters = counters_by_partition.remove(&partition_num).unwrap_or_else(Vec::new);
+            if counters.is_empty() && namespace.is_none() {
+                continue;
+            }
+            let deletion = EventDeletion {
+                counters: counters,
+                namespace: namespace.clone(),
+            };
+            let partition = common_state.event_stream.get_partition(partition_num).unwrap();
+            let receiver = partition.delete(connection_id, deletion).map_err(|err| {
+                format!("Failed to send operation: {:?}", err.0)
+            })?;
+            receivers.push(receiver);
+        }
+
+        self.delete_operation = Some(PendingDelete {
+            op_id: op_id,
+            partitions: join_all(receivers),
+        });
+        Ok(())
+    }
+
+    pub fn poll_delete_complete(&mut self, common_state: &mut ConnectionState) -> Poll<(), io::Error> {
+        let response = match self.delete_operation {
+            Some(ref mut pending) => {
+                let op_id = pending.op_id;
+                let results = try_ready!(pending.partitions.poll().map_err(|recv_err| {
+                    error!("Failed to poll delete operation for client: op_id: {}: {:?}", op_id, recv_err);
+                    io::Error::new(io::ErrorKind::Other, "failed to poll delete operation")
+                }));
+                create_response(op_id, results)
+            },
+            None => return Ok(Async::Ready(()))
+        };
+
+        self.delete_operation = None;
+
+        common_state.send_to_client(response).map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, e)
+        })?;
+
+        Ok(Async::Ready(()))
+    }
+}
+
+fn create_response(op_id: u32, results: Vec<DeleteResult>) -> SendProtocolMessage {
+    for result in results {
+        if let Err(io_err) = result {
+            return error_response(op_id, ErrorKind::StorageEngineError, format!("Delete Error: {}", io_err.description()));
+        }
+    }
+    ProtocolMessage::EventsDeleted(EventsDeleted {
+        op_id: op_id,
+    })
+}
+
+fn error_response(op_id: u32, kind: ErrorKind, description: String) -> SendProtocolMessage {
+    ProtocolMessage::Error(ErrorMessage {
+        op_id: op_id,
+        kind: kind,
+        description: description,
+    })
+}
+
+impl Debug for DeleteConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pending_op_id = self.delete_operation.as_ref().map(|pending| pending.op_id);
+        f.debug_struct("DeleteConnectionState")
+                .field("pending_op_id", &pending_op_id)
+                .finish()
+    }
+}
@@ -1,6 +1,9 @@
 pub mod connection_state;
 mod consumer;
 mod producer;
+mod snapshot;
+mod delete;
+mod transaction;
 
 use std::fmt::{self, Debug};
 use std::io;
@@ -14,12 +17,20 @@ use engine::{ConnectionId, ClientSender, EngineRef, ReceivedProtocolMessage};
 use self::connection_state::ConnectionState;
 use self::consumer::ConsumerConnectionState;
 use self::producer::ProducerConnectionState;
+use self::snapshot::SnapshotConnectionState;
+use self::delete::DeleteConnectionState;
+use self::transaction::TransactionConnectionState;
+
+pub use self::producer::DEFAULT_MAX_IN_FLIGHT_PRODUCES;
 
 
 pub struct ConnectionHandler {
     common_state: ConnectionState,
     consumer_state: ConsumerConnectionState,
     producer_state: ProducerConnectionState,
+    snapshot_state: SnapshotConnectionState,
+    delete_state: DeleteConnectionState,
+    transaction_state: TransactionConnectionState,
 }
 
 
@@ -30,18 +41,40 @@ impl ConnectionHandler {
         ConnectionHandler {
             common_state: ConnectionState::new(connection, client_sender, engine, handle),
             consumer_state: ConsumerConnectionState::new(),
-            producer_state: ProducerConnectionState::new(),
+            producer_state: ProducerConnectionState::new(DEFAULT_MAX_IN_FLIGHT_PRODUCES),
+            snapshot_state: SnapshotConnectionState::new(),
+            delete_state: DeleteConnectionState::new(),
+            transaction_state: TransactionConnectionState::new(),
         }
     }
 
-    pub fn can_process(&self, _message: &ReceivedProtocolMessage) -> bool {
-        !self.producer_state.requires_poll_complete() && !self.consumer_state.requires_poll_complete()
+    /// Sets the number of produce operations that may be in progress at once. Produce messages are processed as soon as
+    /// they're received, up to this limit, and their acks are sent in the order the messages were received
+    pub fn with_max_in_flight_produces(mut self, max_in_flight: usize) -> ConnectionHandler {
+        self.producer_state = ProducerConnectionState::new(max_in_flight);
+        self
+    }
+
+    pub fn can_process(&self, message: &ReceivedProtocolMessage) -> bool {
+        let others_idle = !self.consumer_state.requires_poll_complete() &&
+                !self.snapshot_state.requires_poll_complete() &&
+                !self.delete_state.requires_poll_complete() &&
+                !self.transaction_state.requires_poll_complete();
+
+        match *message {
+            // produce operations can be pipelined, since their acks are always sent in order, and the ones in a
+            // transaction are just held until it's committed
+            ProtocolMessage::ProduceEvent(_) | ProtocolMessage::ProduceBatch(_) => {
+                others_idle && (self.transaction_state.is_open() || self.producer_state.can_start_produce())
+            }
+            _ => others_idle && !self.producer_state.requires_poll_complete()
+        }
     }
 
     pub fn handle_incoming_message(&mut self, message: ReceivedProtocolMessage) -> ConnectionHandlerResult {
         trace!("client: {:?}, received message: {:?}", self.common_state, message);
 
-        let ConnectionHandler{ref mut common_state, ref mut consumer_state, ref mut producer_state } = *self;
+        let ConnectionHandler{ref mut common_state, ref mut consumer_state, ref mut producer_state, ref mut snapshot_state, ref mut delete_state, ref mut transaction_state } = *self;
 
         match message {
             ProtocolMessage::SetEventStream(SetEventStream{op_id, name}) => {
@@ -51,17 +84,46 @@ impl ConnectionHandler {
                 common_state.handle_announce_message(announce)
             },
             ProtocolMessage::ProduceEvent(produce) => {
-                producer_state.handle_produce(produce, common_state)
+                if transaction_state.is_open() {
+                    transaction_state.handle_produce(produce)
+                } else {
+                    producer_state.handle_produce(produce, common_state)
+                }
+            },
+            ProtocolMessage::ProduceBatch(batch) => {
+                if transaction_state.is_open() {
+                    transaction_state.handle_produce_batch(batch)
+                } else {
+                    producer_state.handle_produce_batch(batch, common_state)
+                }
             },
+            ProtocolMessage::BeginTransaction(op_id) => {
+                transaction_state.handle_begin(op_id, common_state)
+            }
+            ProtocolMessage::CommitTransaction(op_id) => {
+                transaction_state.handle_commit(op_id, common_state)
+            }
+            ProtocolMessage::AbortTransaction(op_id) => {
+                transaction_state.handle_abort(op_id, common_state)
+            }
             ProtocolMessage::NewStartConsuming(consumer_start) => {
                 consumer_state.handle_start_consuming(consumer_start, common_state)
             },
+            ProtocolMessage::StartConsumingSince(consume_since) => {
+                consumer_state.handle_start_consuming_since(consume_since, common_state)
+            },
             ProtocolMessage::NextBatch => {
                 consumer_state.handle_next_batch(common_state)
             }
             ProtocolMessage::StopConsuming(op_id) => {
                 consumer_state.stop_consuming(op_id, common_state)
             }
+            ProtocolMessage::Snapshot(snapshot) => {
+                snapshot_state.handle_snapshot(snapshot, common_state)
+            }
+            ProtocolMessage::DeleteEvents(delete) => {
+                delete_state.handle_delete(delete, common_state)
+            }
             _ => unimplemented!()
         }
     }
@@ -75,6 +137,9 @@ impl Sink for ConnectionHandler {
     type SinkError = io::Error;
 
     fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        // Make progress on any operations that are already in progress first. This sends their responses without waiting
+        // for the client to stop sending, and ensures we get notified once there's room for this message
+        let _ = self.poll_complete()?;
         if !self.can_process(&item) {
             return Ok(AsyncSink::NotReady(item));
         }
@@ -87,12 +152,18 @@ impl Sink for ConnectionHandler {
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        let ConnectionHandler {ref mut common_state, ref mut consumer_state, ref mut producer_state} = *self;
+        let ConnectionHandler {ref mut common_state, ref mut consumer_state, ref mut producer_state, ref mut snapshot_state, ref mut delete_state, ref mut transaction_state} = *self;
 
         if producer_state.requires_poll_complete() {
             producer_state.poll_produce_complete(common_state)
         } else if consumer_state.requires_poll_complete() {
             consumer_state.poll_consume_complete(common_state)
+        } else if snapshot_state.requires_poll_complete() {
+            snapshot_state.poll_snapshot_complete(common_state)
+        } else if delete_state.requires_poll_complete() {
+            delete_state.poll_delete_complete(common_state)
+        } else if transaction_state.requires_poll_complete() {
+            transaction_state.poll_commit_complete(common_state)
         } else {
             Ok(Async::Ready(()))
         }
@@ -115,6 +186,9 @@ impl Debug for ConnectionHandler {
                 .field("common_state", &self.common_state)
                 .field("consumer_state", &self.consumer_state)
                 .field("producer_state", &self.producer_state)
+                .field("snapshot_state", &self.snapshot_state)
+                .field("delete_state", &self.delete_state)
+                .field("transaction_state", &self.transaction_state)
                 .finish()
     }
 }
@@ -127,7 +201,7 @@ mod test {
     use tokio_core::reactor::Core;
 
     use super::*;
-    use event::ActorId;
+    use event::{ActorId, FloEventId};
     use engine::{SYSTEM_STREAM_NAME, system_stream_name};
     use engine::event_stream::EventStreamRef;
     use engine::event_stream::partition::*;
@@ -135,7 +209,6 @@ mod test {
     use atomics::{AtomicCounterWriter, AtomicBoolWriter};
 
     struct Fixture {
-        #[allow(dead_code)] // TODO: add more connection handler tests
         partition_receivers: HashMap<(String, ActorId), PartitionReceiver>,
         client_receiver: Option<ClientReceiver>,
         engine: EngineRef,
@@ -175,7 +248,6 @@ mod test {
             (subject, fixture)
         }
 
-        #[allow(dead_code)] // TODO: add more connection handler tests
         fn with_stream(stream_name: &str, partition_count: ActorId) -> (ConnectionHandler, Fixture) {
             let (handler, mut fixutre) = Fixture::create();
             fixutre.add_new_stream(stream_name, partition_count);
@@ -204,7 +276,6 @@ mod test {
             }).unwrap();
         }
 
-        #[allow(dead_code)] // TODO: add more connection handler tests
         fn message_sent_to_partition(&self, event_stream: &str, partition_id: ActorId) -> Operation {
             let key = (event_stream.to_owned(), partition_id);
             let partition_receiver = self.partition_receivers.get(&key).expect("no such partition");
@@ -236,6 +307,15 @@ mod test {
 
     }
 
+    fn appended(op_id: u32, first_event_id: FloEventId, last_event_id: FloEventId) -> BatchAck {
+        BatchAck {
+            op_id: op_id,
+            first_event_id: first_event_id,
+            last_event_id: last_event_id,
+            original_ids: Vec::new(),
+        }
+    }
+
     #[test]
     fn set_event_stream_sets_event_stream_when_the_named_stream_exists() {
         let (mut subject, mut fixture) = Fixture::create();
@@ -277,6 +357,377 @@ mod test {
         fixture.assert_sent_to_client(ProtocolMessage::StreamStatus(expected));
     }
 
+    #[test]
+    fn announce_with_an_unsupported_protocol_version_is_rejected_and_closes_the_connection() {
+        let (mut subject, mut fixture) = Fixture::create();
+
+        let announce = ClientAnnounce {
+            protocol_version: 1,
+            op_id: 3,
+            client_name: "old client".to_owned(),
+            consume_batch_size: None,
+            producer_id: None,
+        };
+        // the error closes the connection, but the client gets sent the reason first
+        subject.handle_incoming_message(ProtocolMessage::Announce(announce)).expect_err("connection should have been closed");
+        assert!(subject.common_state.client_name.is_none());
+
+        let expected = ErrorMessage {
+            op_id: 3,
+            kind: ErrorKind::UnsupportedProtocolVersion,
+            description: format!("Unsupported protocol version: 1, the server only supports version: {}", PROTOCOL_VERSION),
+        };
+        fixture.assert_sent_to_client(ProtocolMessage::Error(expected));
+    }
+
+    #[test]
+    fn snapshot_is_sent_to_every_partition_and_completes_once_they_all_respond() {
+        use std::path::PathBuf;
+        use futures::future::poll_fn;
+
+        let (mut subject, mut fixture) = Fixture::with_stream("foo", 2);
+        subject.common_state.event_stream = fixture.engine.event_streams.lock().unwrap().get("foo").unwrap().clone();
+
+        subject.handle_incoming_message(ProtocolMessage::Snapshot(SnapshotRequest {
+            op_id: 8,
+            dest_dir: "/backups".to_owned(),
+        })).expect("failed to handle message");
+
+        for partition_num in 1..3 {
+            match fixture.message_sent_to_partition("foo", partition_num).op_type {
+                OpType::Snapshot(SnapshotOperation {client, dest_dir}) => {
+                    assert_eq!(PathBuf::from(format!("/backups/foo/{}", partition_num)), dest_dir);
+                    client.send(Ok(PartitionSnapshot {
+                        partition_num: partition_num,
+                        head: 10 * partition_num as u64,
+                        active_segment_offset: 100,
+                        segment_count: 1,
+                    })).unwrap();
+                }
+                other => panic!("expected snapshot operation, got: {:?}", other)
+            }
+        }
+        fixture.reactor.run(poll_fn(|| subject.poll_complete())).expect("failed to complete snapshot");
+
+        let expected = SnapshotComplete {
+            op_id: 8,
+            name: "foo".to_owned(),
+            partitions: vec![
+                PartitionSnapshotStatus {
+                    partition_num: 1,
+                    head: 10,
+                    active_segment_offset: 100,
+                },
+                PartitionSnapshotStatus {
+                    partition_num: 2,
+                    head: 20,
+                    active_segment_offset: 100,
+                },
+            ],
+        };
+        fixture.assert_sent_to_client(ProtocolMessage::SnapshotComplete(expected));
+    }
+
+    #[test]
+    fn delete_sends_event_ids_to_their_own_partitions_and_namespace_to_all_partitions() {
+        use futures::future::poll_fn;
+
+        let (mut subject, mut fixture) = Fixture::with_stream("foo", 3);
+        subject.common_state.event_stream = fixture.engine.event_streams.lock().unwrap().get("foo").unwrap().clone();
+
+        subject.handle_incoming_message(ProtocolMessage::DeleteEvents(DeleteEvents {
+            op_id: 9,
+            event_ids: vec![FloEventId::new(1, 4), FloEventId::new(3, 7), FloEventId::new(1, 5)],
+            namespace: Some("/users/42/**/*".to_owned()),
+        })).expect("failed to handle message");
+
+        let expected_counters = vec![vec![4, 5], vec![], vec![7]];
+        for partition_num in 1..4 {
+            match fixture.message_sent_to_partition("foo", partition_num).op_type {
+                OpType::Delete(DeleteOperation {client, deletion}) => {
+                    let expected = EventDeletion {
+                        counters: expected_counters[partition_num as usize - 1].clone(),
+                        namespace: Some("/users/42/**/*".to_owned()),
+                    };
+                    assert_eq!(expected, deletion);
+                    client.send(Ok(())).unwrap();
+                }
+                other => panic!("expected delete operation, got: {:?}", other)
+            }
+        }
+        fixture.reactor.run(poll_fn(|| subject.poll_complete())).expect("failed to complete delete");
+
+        fixture.assert_sent_to_client(ProtocolMessage::EventsDeleted(EventsDeleted { op_id: 9 }));
+    }
+
+    #[test]
+    fn produce_batch_sends_all_events_in_one_operation_and_acks_with_the_id_range() {
+        use futures::future::poll_fn;
+
+        let (mut subject, mut fixture) = Fixture::with_stream("foo", 2);
+        subject.common_state.event_stream = fixture.engine.event_streams.lock().unwrap().get("foo").unwrap().clone();
+
+        let events = (0..3).map(|i| {
+            ProduceEvent {
+                op_id: 4,
+                partition: 2,
+                namespace: "/foo".to_owned(),
+                parent_id: None,
+                headers: Vec::new(),
+                event_time: None,
+                sequence: None,
+                expected_version: None,
+                data: format!("event {}", i).into_bytes(),
+            }
+        }).collect::<Vec<_>>();
+        subject.handle_incoming_message(ProtocolMessage::ProduceBatch(ProduceBatch {
+            op_id: 4,
+            partition: 2,
+            events: events.clone(),
+        })).expect("failed to handle message");
+
+        match fixture.message_sent_to_partition("foo", 2).op_type {
+            OpType::Produce(produce) => {
+                assert_eq!(4, produce.op_id);
+                assert_eq!(events, produce.events);
+                produce.client.send(Ok(appended(4, FloEventId::new(2, 7), FloEventId::new(2, 9)))).unwrap();
+            }
+            other => panic!("expected produce operation, got: {:?}", other)
+        }
+        fixture.reactor.run(poll_fn(|| subject.poll_complete())).expect("failed to complete produce");
+
+        fixture.assert_sent_to_client(ProtocolMessage::AckBatch(BatchAck {
+            op_id: 4,
+            first_event_id: FloEventId::new(2, 7),
+            last_event_id: FloEventId::new(2, 9),
+            original_ids: Vec::new(),
+        }));
+    }
+
+    #[test]
+    fn produce_operations_are_pipelined_and_acked_in_the_order_they_were_received() {
+        use futures::future::poll_fn;
+
+        let (mut subject, mut fixture) = Fixture::with_stream("foo", 2);
+        subject.common_state.event_stream = fixture.engine.event_streams.lock().unwrap().get("foo").unwrap().clone();
+
+        for &(op_id, partition) in [(1, 1), (2, 2)].iter() {
+            let produce = ProtocolMessage::ProduceEvent(ProduceEvent {
+                op_id: op_id,
+                partition: partition,
+                namespace: "/foo".to_owned(),
+                parent_id: None,
+                headers: Vec::new(),
+                event_time: None,
+                sequence: None,
+                expected_version: None,
+                data: Vec::new(),
+            });
+            assert!(subject.can_process(&produce));
+            subject.handle_incoming_message(produce).expect("failed to handle message");
+        }
+        // other messages must wait until every produce has been acknowledged
+        assert!(!subject.can_process(&ProtocolMessage::NextBatch));
+
+        let mut clients = (1..3).map(|partition_num| {
+            match fixture.message_sent_to_partition("foo", partition_num).op_type {
+                OpType::Produce(produce) => produce.client,
+                other => panic!("expected produce operation, got: {:?}", other)
+            }
+        }).collect::<Vec<_>>();
+        // the second operation finishes first, but its ack must not be sent before the first one's
+        clients.pop().unwrap().send(Ok(appended(2, FloEventId::new(2, 5), FloEventId::new(2, 5)))).unwrap();
+        clients.pop().unwrap().send(Ok(appended(1, FloEventId::new(1, 3), FloEventId::new(1, 3)))).unwrap();
+        fixture.reactor.run(poll_fn(|| subject.poll_complete())).expect("failed to complete produce");
+
+        fixture.assert_sent_to_client(ProtocolMessage::AckEvent(EventAck { op_id: 1, event_id: FloEventId::new(1, 3) }));
+        fixture.assert_sent_to_client(ProtocolMessage::AckEvent(EventAck { op_id: 2, event_id: FloEventId::new(2, 5) }));
+        assert!(subject.can_process(&ProtocolMessage::NextBatch));
+    }
+
+    #[test]
+    fn produce_is_not_processed_once_the_max_in_flight_operations_are_in_progress() {
+        let (subject, fixture) = Fixture::with_stream("foo", 1);
+        let mut subject = subject.with_max_in_flight_produces(1);
+        subject.common_state.event_stream = fixture.engine.event_streams.lock().unwrap().get("foo").unwrap().clone();
+
+        let produce = ProtocolMessage::ProduceEvent(ProduceEvent {
+            op_id: 1,
+            partition: 1,
+            namespace: "/foo".to_owned(),
+            parent_id: None,
+            headers: Vec::new(),
+            event_time: None,
+            sequence: None,
+            expected_version: None,
+            data: Vec::new(),
+        });
+        subject.handle_incoming_message(produce.clone()).expect("failed to handle message");
+        let _ = fixture.message_sent_to_partition("foo", 1);
+        assert!(!subject.can_process(&produce));
+    }
+
+    fn transaction_produce(op_id: u32, partition: ActorId, namespace: &str) -> ProduceEvent {
+        ProduceEvent {
+            op_id: op_id,
+            partition: partition,
+            namespace: namespace.to_owned(),
+            parent_id: None,
+            headers: Vec::new(),
+            event_time: None,
+            sequence: None,
+            expected_version: None,
+            data: Vec::new(),
+        }
+    }
+
+    fn start_transaction(subject: &mut ConnectionHandler, fixture: &mut Fixture) {
+        subject.common_state.event_stream = fixture.engine.event_streams.lock().unwrap().get("foo").unwrap().clone();
+        subject.handle_incoming_message(ProtocolMessage::BeginTransaction(1)).expect("failed to handle message");
+        fixture.assert_sent_to_client(ProtocolMessage::AckTransaction(TransactionAck { op_id: 1, produced: Vec::new() }));
+
+        let messages = vec![
+            ProtocolMessage::ProduceEvent(transaction_produce(2, 1, "/orders/123")),
+            ProtocolMessage::ProduceBatch(ProduceBatch {
+                op_id: 3,
+                partition: 2,
+                events: vec![transaction_produce(3, 2, "/payments/1"), transaction_produce(3, 2, "/payments/2")],
+            }),
+            ProtocolMessage::ProduceEvent(transaction_produce(4, 1, "/orders/124")),
+        ];
+        for message in messages {
+            assert!(subject.can_process(&message));
+            subject.handle_incoming_message(message).expect("failed to handle message");
+        }
+    }
+
+    fn namespaces(prepare: &PrepareTransactionOperation) -> Vec<&str> {
+        prepare.events.iter().map(|event| event.namespace.as_str()).collect()
+    }
+
+    fn poll_pending_commit(subject: &mut ConnectionHandler, fixture: &mut Fixture) {
+        use futures::future::poll_fn;
+
+        let poll_result = fixture.reactor.run(poll_fn(|| -> Poll<Poll<(), io::Error>, ()> {
+            Ok(Async::Ready(subject.poll_complete()))
+        })).unwrap();
+        match poll_result {
+            Ok(Async::NotReady) => { }
+            other => panic!("expected commit to still be in progress, got: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn transaction_commit_prepares_the_events_in_each_partition_before_committing_it_in_every_partition() {
+        use futures::future::poll_fn;
+
+        let (mut subject, mut fixture) = Fixture::with_stream("foo", 2);
+        start_transaction(&mut subject, &mut fixture);
+        // nothing gets produced until the transaction is committed
+        assert!(fixture.partition_receivers.get(&("foo".to_owned(), 1)).unwrap().try_recv().is_err());
+
+        subject.handle_incoming_message(ProtocolMessage::CommitTransaction(5)).expect("failed to handle message");
+
+        let transaction_id = match fixture.message_sent_to_partition("foo", 1).op_type {
+            OpType::PrepareTransaction(prepare) => {
+                assert_eq!(vec!["/orders/123", "/orders/124"], namespaces(&prepare));
+                prepare.client.send(Ok(appended(5, FloEventId::new(1, 3), FloEventId::new(1, 4)))).unwrap();
+                prepare.transaction_id
+            }
+            other => panic!("expected prepare operation, got: {:?}", other)
+        };
+        match fixture.message_sent_to_partition("foo", 2).op_type {
+            OpType::PrepareTransaction(prepare) => {
+                assert_eq!(vec!["/payments/1", "/payments/2"], namespaces(&prepare));
+                assert_eq!(transaction_id, prepare.transaction_id);
+                prepare.client.send(Ok(appended(5, FloEventId::new(2, 1), FloEventId::new(2, 2)))).unwrap();
+            }
+            other => panic!("expected prepare operation, got: {:?}", other)
+        }
+        // the client doesn't get an ack until every partition has persisted its commit marker
+        poll_pending_commit(&mut subject, &mut fixture);
+
+        for partition_num in 1..3 {
+            match fixture.message_sent_to_partition("foo", partition_num).op_type {
+                OpType::CommitTransaction(commit) => {
+                    assert_eq!(transaction_id, commit.transaction_id);
+                    commit.client.send(Ok(())).unwrap();
+                }
+                other => panic!("expected commit operation, got: {:?}", other)
+            }
+        }
+        fixture.reactor.run(poll_fn(|| subject.poll_complete())).expect("failed to complete commit");
+
+        fixture.assert_sent_to_client(ProtocolMessage::AckTransaction(TransactionAck {
+            op_id: 5,
+            produced: vec![
+                BatchAck { op_id: 2, first_event_id: FloEventId::new(1, 3), last_event_id: FloEventId::new(1, 3), original_ids: Vec::new() },
+                BatchAck { op_id: 3, first_event_id: FloEventId::new(2, 1), last_event_id: FloEventId::new(2, 2), original_ids: Vec::new() },
+                BatchAck { op_id: 4, first_event_id: FloEventId::new(1, 4), last_event_id: FloEventId::new(1, 4), original_ids: Vec::new() },
+            ],
+        }));
+    }
+
+    #[test]
+    fn transaction_is_aborted_by_the_other_partitions_when_any_partition_fails_to_prepare_it() {
+        use futures::future::poll_fn;
+
+        let (mut subject, mut fixture) = Fixture::with_stream("foo", 2);
+        start_transaction(&mut subject, &mut fixture);
+        subject.handle_incoming_message(ProtocolMessage::CommitTransaction(5)).expect("failed to handle message");
+
+        let transaction_id = match fixture.message_sent_to_partition("foo", 1).op_type {
+            OpType::PrepareTransaction(prepare) => {
+                prepare.client.send(Ok(appended(5, FloEventId::new(1, 3), FloEventId::new(1, 4)))).unwrap();
+                prepare.transaction_id
+            }
+            other => panic!("expected prepare operation, got: {:?}", other)
+        };
+        match fixture.message_sent_to_partition("foo", 2).op_type {
+            OpType::PrepareTransaction(prepare) => {
+                prepare.client.send(Err(io::Error::new(io::ErrorKind::Other, "disk full"))).unwrap();
+            }
+            other => panic!("expected prepare operation, got: {:?}", other)
+        }
+        // the error can't be sent until the first partition has aborted the transaction
+        poll_pending_commit(&mut subject, &mut fixture);
+
+        match fixture.message_sent_to_partition("foo", 1).op_type {
+            OpType::AbortTransaction(abort) => {
+                assert_eq!(transaction_id, abort.transaction_id);
+                abort.client.send(Ok(())).unwrap();
+            }
+            other => panic!("expected abort operation, got: {:?}", other)
+        }
+        // the partition that failed has already aborted the transaction on its own
+        assert!(fixture.partition_receivers.get(&("foo".to_owned(), 2)).unwrap().try_recv().is_err());
+        fixture.reactor.run(poll_fn(|| subject.poll_complete())).expect("failed to complete abort");
+
+        fixture.assert_sent_to_client(ProtocolMessage::Error(ErrorMessage {
+            op_id: 5,
+            kind: ErrorKind::StorageEngineError,
+            description: "Persistence Error: disk full".to_owned(),
+        }));
+    }
+
+    #[test]
+    fn transaction_abort_discards_the_events_without_producing_them() {
+        let (mut subject, mut fixture) = Fixture::with_stream("foo", 2);
+        start_transaction(&mut subject, &mut fixture);
+
+        subject.handle_incoming_message(ProtocolMessage::AbortTransaction(5)).expect("failed to handle message");
+        fixture.assert_sent_to_client(ProtocolMessage::AckTransaction(TransactionAck { op_id: 5, produced: Vec::new() }));
+        assert!(fixture.partition_receivers.get(&("foo".to_owned(), 1)).unwrap().try_recv().is_err());
+        assert!(fixture.partition_receivers.get(&("foo".to_owned(), 2)).unwrap().try_recv().is_err());
+
+        subject.handle_incoming_message(ProtocolMessage::CommitTransaction(6)).expect("failed to handle message");
+        fixture.assert_sent_to_client(ProtocolMessage::Error(ErrorMessage {
+            op_id: 6,
+            kind: ErrorKind::InvalidTransactionState,
+            description: "There is no transaction in progress".to_owned(),
+        }));
+    }
+
     #[test]
     fn set_event_stream_sends_error_message_when_named_stream_does_not_exist() {
         let (mut subject, mut fixture) = Fixture::create();
@@ -1,82 +1,137 @@
 use std::io;
-use std::error::Error;
+use std::collections::VecDeque;
 
 use protocol::*;
+use event::ActorId;
 use futures::{Future, Poll, Async};
 
-use engine::event_stream::partition::{ProduceResponseReceiver};
-use engine::ConnectionHandlerResult;
+use engine::event_stream::partition::{ProduceResponseReceiver, ProduceResult, is_concurrency_conflict};
+use engine::{ConnectionHandlerResult, SendProtocolMessage};
 use engine::connection_handler::connection_state::ConnectionState;
 
 
+#[derive(Debug)]
+struct PendingProduce {
+    op_id: u32,
+    /// Whether the events were sent as a `ProduceBatch`, which determines the type of ack that the client gets
+    is_batch: bool,
+    response: ProduceResponseReceiver,
+}
+
+/// The number of produce operations that a connection may have in progress at once, unless it's configured otherwise
+pub const DEFAULT_MAX_IN_FLIGHT_PRODUCES: usize = 16;
+
 #[derive(Debug)]
 pub struct ProducerConnectionState {
-    produce_operation: Option<(u32, ProduceResponseReceiver)>,
+    /// Produce operations that have been sent to their partitions, in the order they were received from the client
+    in_flight: VecDeque<PendingProduce>,
+    max_in_flight: usize,
 }
 
 
 impl ProducerConnectionState {
-    pub fn new() -> ProducerConnectionState {
+    pub fn new(max_in_flight: usize) -> ProducerConnectionState {
         ProducerConnectionState {
-            produce_operation: None,
+            in_flight: VecDeque::with_capacity(max_in_flight),
+            max_in_flight: max_in_flight,
         }
     }
 
     pub fn requires_poll_complete(&self) -> bool {
-        self.produce_operation.is_some()
+        !self.in_flight.is_empty()
+    }
+
+    /// Returns true if another produce operation can be started before the ones in progress have completed
+    pub fn can_start_produce(&self) -> bool {
+        self.in_flight.len() < self.max_in_flight
     }
 
 
     pub fn handle_produce(&mut self, produce: ProduceEvent, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
         let op_id = produce.op_id;
+        let partition = produce.partition;
+        self.start_produce(op_id, partition, false, vec![produce], common_state)
+    }
+
+    pub fn handle_produce_batch(&mut self, batch: ProduceBatch, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
+        let ProduceBatch {op_id, partition, events} = batch;
+        self.start_produce(op_id, partition, true, events, common_state)
+    }
+
+    fn start_produce(&mut self, op_id: u32, partition_num: ActorId, is_batch: bool, events: Vec<ProduceEvent>, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
         let connection_id = common_state.connection_id;
+        let producer_id = common_state.producer_id;
 
         let receiver = {
-            let partition = common_state.event_stream.get_partition(produce.partition).unwrap();
-            partition.produce(connection_id, op_id, vec![produce]).map_err(|err| {
+            let partition = common_state.event_stream.get_partition(partition_num).unwrap();
+            partition.produce(connection_id, op_id, producer_id, events).map_err(|err| {
                 format!("Failed to send operation: {:?}", err.0)
             })?
         };
 
-        self.produce_operation = Some((op_id, receiver));
+        self.in_flight.push_back(PendingProduce {
+            op_id: op_id,
+            is_batch: is_batch,
+            response: receiver,
+        });
 
         Ok(())
     }
 
 
+    /// Sends the response for each completed produce operation. Responses are always sent in the same order that the
+    /// operations were received, so an operation that completes early has to wait for all of the ones before it
     pub fn poll_produce_complete(&mut self, common_state: &mut ConnectionState) -> Poll<(), io::Error> {
-        let response = match self.produce_operation {
-            Some((op_id, ref mut pending)) => {
-                let result = try_ready!(pending.poll().map_err(|recv_err| {
-                    error!("Failed to poll produce operation for client: op_id: {}: {:?}", op_id, recv_err);
-                    io::Error::new(io::ErrorKind::Other, "failed to poll produce operation")
-                }));
-
-                match result {
-                    Ok(id) => {
-                        ProtocolMessage::AckEvent(EventAck{
-                            op_id: op_id,
-                            event_id: id,
-                        })
-                    }
-                    Err(io_err) => {
-                        ProtocolMessage::Error(ErrorMessage {
-                            op_id: op_id,
-                            kind: ErrorKind::StorageEngineError,
-                            description: format!("Persistence Error: {}", io_err.description()),
-                        })
-                    }
-                }
-            },
-            None => return Ok(Async::Ready(()))
-        };
-
-        self.produce_operation = None;
+        loop {
+            let response = match self.in_flight.front_mut() {
+                Some(&mut PendingProduce {op_id, is_batch, ref mut response}) => {
+                    let result = try_ready!(response.poll().map_err(|recv_err| {
+                        error!("Failed to poll produce operation for client: op_id: {}: {:?}", op_id, recv_err);
+                        io::Error::new(io::ErrorKind::Other, "failed to poll produce operation")
+                    }));
+                    to_response(op_id, is_batch, result)
+                },
+                None => return Ok(Async::Ready(()))
+            };
+
+            self.in_flight.pop_front();
+
+            common_state.send_to_client(response).map_err(|e| {
+                io::Error::new(io::ErrorKind::Other, e)
+            })?;
+        }
+    }
+}
 
-        common_state.send_to_client(response).map_err(|e| {
-            io::Error::new(io::ErrorKind::Other, e)
-        })?;
+fn to_response(op_id: u32, is_batch: bool, result: ProduceResult) -> SendProtocolMessage {
+    match result {
+        Ok(ack) if is_batch => ProtocolMessage::AckBatch(ack),
+        Ok(ack) => {
+            ProtocolMessage::AckEvent(EventAck{
+                op_id: op_id,
+                event_id: ack.event_ids(1)[0],
+            })
+        }
+        Err(io_err) => {
+            ProtocolMessage::Error(produce_error(op_id, &io_err))
+        }
+    }
+}
 
-        Ok(Async::Ready(()))
+/// Creates the error message for a failed produce operation. Events that were rejected because of their expected
+/// version get a `ConcurrencyConflict`, which describes the actual head of the namespace
+pub fn produce_error(op_id: u32, io_err: &io::Error) -> ErrorMessage {
+    if is_concurrency_conflict(io_err) {
+        ErrorMessage {
+            op_id: op_id,
+            kind: ErrorKind::ConcurrencyConflict,
+            description: format!("Concurrency Conflict: {}", io_err),
+        }
+    } else {
+        ErrorMessage {
+            op_id: op_id,
+            kind: ErrorKind::StorageEngineError,
+            description: format!("Persistence Error: {}", io_err),
+        }
     }
 }
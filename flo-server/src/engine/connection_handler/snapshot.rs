@@ -0,0 +1,121 @@
+use std::io;
+use std::fmt::{self, Debug};
+use std::path::PathBuf;
+use std::error::Error;
+
+use protocol::*;
+use futures::{Future, Poll, Async};
+use futures::future::{join_all, JoinAll};
+
+use engine::event_stream::partition::{SnapshotResponseReceiver, PartitionSnapshot};
+use engine::{ConnectionHandlerResult, SendProtocolMessage};
+use engine::connection_handler::connection_state::ConnectionState;
+use flo_storage::{get_event_steam_data_dir, get_partition_data_dir};
+
+struct PendingSnapshot {
+    op_id: u32,
+    event_stream_name: String,
+    partitions: JoinAll<Vec<SnapshotResponseReceiver>>,
+}
+
+/// Handles requests to snapshot the connection's current event stream. Every partition is asked to write its snapshot
+/// concurrently, and the client gets a single response once all of them have finished.
+pub struct SnapshotConnectionState {
+    snapshot_operation: Option<PendingSnapshot>,
+}
+
+impl SnapshotConnectionState {
+    pub fn new() -> SnapshotConnectionState {
+        SnapshotConnectionState {
+            snapshot_operation: None,
+        }
+    }
+
+    pub fn requires_poll_complete(&self) -> bool {
+        self.snapshot_operation.is_some()
+    }
+
+    pub fn handle_snapshot(&mut self, snapshot: SnapshotRequest, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
+        let SnapshotRequest {op_id, dest_dir} = snapshot;
+        let connection_id = common_state.connection_id;
+        let event_stream_name = common_state.event_stream.name().to_owned();
+        let stream_dir = get_event_steam_data_dir(&PathBuf::from(dest_dir), &event_stream_name);
+        info!("Starting snapshot of event stream: '{}' into {:?} for connection_id: {}", event_stream_name, stream_dir, connection_id);
+
+        let partition_count = common_state.event_stream.get_partition_count();
+        let mut receivers = Vec::with_capacity(partition_count as usize);
+        for partition_num in 1..(partition_count + 1) {
+            let partition = common_state.event_stream.get_partition(partition_num).unwrap();
+            let partition_dir = get_partition_data_dir(&stream_dir, partition_num);
+            let receiver = partition.snapshot(connection_id, partition_dir).map_err(|err| {
+                format!("Failed to send operation: {:?}", err.0)
+            })?;
+            receivers.push(receiver);
+        }
+
+        self.snapshot_operation = Some(PendingSnapshot {
+            op_id: op_id,
+            event_stream_name: event_stream_name,
+            partitions: join_all(receivers),
+        });
+        Ok(())
+    }
+
+    pub fn poll_snapshot_complete(&mut self, common_state: &mut ConnectionState) -> Poll<(), io::Error> {
+        let response = match self.snapshot_operation {
+            Some(ref mut pending) => {
+                let op_id = pending.op_id;
+                let results = try_ready!(pending.partitions.poll().map_err(|recv_err| {
+                    error!("Failed to poll snapshot operation for client: op_id: {}: {:?}", op_id, recv_err);
+                    io::Error::new(io::ErrorKind::Other, "failed to poll snapshot operation")
+                }));
+                create_response(op_id, &pending.event_stream_name, results)
+            },
+            None => return Ok(Async::Ready(()))
+        };
+
+        self.snapshot_operation = None;
+
+        common_state.send_to_client(response).map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, e)
+        })?;
+
+        Ok(Async::Ready(()))
+    }
+}
+
+fn create_response(op_id: u32, event_stream_name: &str, results: Vec<io::Result<PartitionSnapshot>>) -> SendProtocolMessage {
+    let mut partitions = Vec::with_capacity(results.len());
+    for result in results {
+        match result {
+            Ok(snapshot) => {
+                partitions.push(PartitionSnapshotStatus {
+                    partition_num: snapshot.partition_num,
+                    head: snapshot.head,
+                    active_segment_offset: snapshot.active_segment_offset as u64,
+                });
+            }
+            Err(io_err) => {
+                return ProtocolMessage::Error(ErrorMessage {
+                    op_id: op_id,
+                    kind: ErrorKind::StorageEngineError,
+                    description: format!("Snapshot Error: {}", io_err.description()),
+                });
+            }
+        }
+    }
+    ProtocolMessage::SnapshotComplete(SnapshotComplete {
+        op_id: op_id,
+        name: event_stream_name.to_owned(),
+        partitions: partitions,
+    })
+}
+
+impl Debug for SnapshotConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pending_op_id = self.snapshot_operation.as_ref().map(|pending| pending.op_id);
+        f.debug_struct("SnapshotConnectionState")
+                .field("pending_op_id", &pending_op_id)
+                .finish()
+    }
+}
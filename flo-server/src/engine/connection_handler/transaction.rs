@@ -0,0 +1,362 @@
+use std::io;
+use std::fmt::{self, Debug};
+use std::collections::BTreeMap;
+
+use protocol::*;
+use futures::{Future, Poll, Async};
+use futures::future::{join_all, JoinAll};
+
+use event::{ActorId, FloEventId, EventCounter};
+use engine::event_stream::TransactionId;
+use engine::event_stream::partition::{ProduceResponseReceiver, ProduceResult, FinishTransactionResponseReceiver, FinishTransactionResult};
+use engine::{ConnectionHandlerResult, SendProtocolMessage};
+use engine::connection_handler::connection_state::ConnectionState;
+use engine::connection_handler::producer::produce_error;
+
+/// A produce operation that was received while a transaction was open
+#[derive(Debug)]
+struct TransactionProduce {
+    op_id: u32,
+    partition: ActorId,
+    event_count: usize,
+}
+
+#[derive(Debug)]
+struct OpenTransaction {
+    produces: Vec<TransactionProduce>,
+    /// The events from every produce operation, grouped by partition and kept in the order they were received
+    events_by_partition: BTreeMap<ActorId, Vec<ProduceEvent>>,
+    /// Set if a produce operation could not be added to the transaction, in which case it can't be committed
+    error: Option<String>,
+}
+
+enum CommitPhase {
+    /// Each partition is appending all of its events from the transaction, along with a prepare record. Consumers stop at
+    /// the first of those events until the partition either commits or aborts the transaction
+    Preparing(JoinAll<Vec<ProduceResponseReceiver>>),
+    /// Every partition has prepared its events, and is now persisting a commit marker, which makes them visible
+    Committing(TransactionAck, JoinAll<Vec<FinishTransactionResponseReceiver>>),
+    /// At least one partition failed to prepare its events, so the partitions that did prepare them are aborting the
+    /// transaction before the error is returned
+    Aborting(ErrorMessage, JoinAll<Vec<FinishTransactionResponseReceiver>>),
+}
+
+struct PendingCommit {
+    op_id: u32,
+    transaction_id: TransactionId,
+    produces: Vec<TransactionProduce>,
+    /// The partitions that events were sent to, in the same order as the results of the prepare operations
+    partitions: Vec<ActorId>,
+    phase: CommitPhase,
+}
+
+/// Handles transactions, which allow a client to produce events to any number of partitions so that consumers will see
+/// either all of them or none of them. Produce operations that are received while a transaction is open are held here
+/// until it's committed. The commit sends all of the events for each partition in a single prepare operation, and once
+/// every partition has prepared its events, each one persists a commit marker. If any partition fails to prepare its
+/// events, then every other partition aborts the transaction instead. Each partition's consumers only wait on the
+/// transactions that are prepared in that partition, and any transaction that a partition never committed is aborted
+/// when the server restarts.
+pub struct TransactionConnectionState {
+    open_transaction: Option<OpenTransaction>,
+    pending_commit: Option<PendingCommit>,
+}
+
+impl TransactionConnectionState {
+    pub fn new() -> TransactionConnectionState {
+        TransactionConnectionState {
+            open_transaction: None,
+            pending_commit: None,
+        }
+    }
+
+    pub fn requires_poll_complete(&self) -> bool {
+        self.pending_commit.is_some()
+    }
+
+    /// Returns true if produce operations should be added to the current transaction instead of being produced right away
+    pub fn is_open(&self) -> bool {
+        self.open_transaction.is_some()
+    }
+
+    pub fn handle_begin(&mut self, op_id: u32, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
+        if self.open_transaction.is_some() {
+            return common_state.send_to_client(error_response(op_id,
+                                                              ErrorKind::InvalidTransactionState,
+                                                              "A transaction is already in progress".to_owned()));
+        }
+        debug!("Beginning transaction for connection_id: {}", common_state.connection_id);
+        self.open_transaction = Some(OpenTransaction {
+            produces: Vec::new(),
+            events_by_partition: BTreeMap::new(),
+            error: None,
+        });
+        common_state.send_to_client(transaction_ack(op_id, Vec::new()))
+    }
+
+    pub fn handle_produce(&mut self, produce: ProduceEvent) -> ConnectionHandlerResult {
+        let op_id = produce.op_id;
+        let partition = produce.partition;
+        self.add_produce(op_id, partition, vec![produce])
+    }
+
+    pub fn handle_produce_batch(&mut self, batch: ProduceBatch) -> ConnectionHandlerResult {
+        let ProduceBatch {op_id, partition, events} = batch;
+        self.add_produce(op_id, partition, events)
+    }
+
+    fn add_produce(&mut self, op_id: u32, partition: ActorId, events: Vec<ProduceEvent>) -> ConnectionHandlerResult {
+        let transaction = self.open_transaction.as_mut().expect("no transaction is open");
+        if transaction.error.is_some() {
+            return Ok(());
+        }
+        if transaction.produces.len() == MAX_TRANSACTION_PRODUCE_OPS {
+            warn!("Transaction has exceeded the maximum of {} produce operations", MAX_TRANSACTION_PRODUCE_OPS);
+            transaction.error = Some(format!("A transaction may not have more than {} produce operations", MAX_TRANSACTION_PRODUCE_OPS));
+            return Ok(());
+        }
+
+        transaction.produces.push(TransactionProduce {
+            op_id: op_id,
+            partition: partition,
+            event_count: events.len(),
+        });
+        if !events.is_empty() {
+            transaction.events_by_partition.entry(partition).or_insert_with(Vec::new).extend(events);
+        }
+        Ok(())
+    }
+
+    pub fn handle_abort(&mut self, op_id: u32, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
+        if self.open_transaction.take().is_none() {
+            return common_state.send_to_client(error_response(op_id,
+                                                              ErrorKind::InvalidTransactionState,
+                                                              "There is no transaction in progress".to_owned()));
+        }
+        debug!("Aborted transaction for connection_id: {}", common_state.connection_id);
+        common_state.send_to_client(transaction_ack(op_id, Vec::new()))
+    }
+
+    pub fn handle_commit(&mut self, op_id: u32, common_state: &mut ConnectionState) -> ConnectionHandlerResult {
+        let OpenTransaction {produces, events_by_partition, error} = match self.open_transaction.take() {
+            Some(transaction) => transaction,
+            None => {
+                return common_state.send_to_client(error_response(op_id,
+                                                                  ErrorKind::InvalidTransactionState,
+                                                                  "There is no transaction in progress".to_owned()));
+            }
+        };
+        if let Some(description) = error {
+            return common_state.send_to_client(error_response(op_id, ErrorKind::InvalidTransactionState, description));
+        }
+        let partition_count = common_state.event_stream.get_partition_count();
+        if let Some(partition) = events_by_partition.keys().find(|&&partition| partition == 0 || partition > partition_count) {
+            let description = format!("Event stream: '{}' has no partition: {}", common_state.event_stream.name(), partition);
+            return common_state.send_to_client(error_response(op_id, ErrorKind::InvalidTransactionState, description));
+        }
+        if events_by_partition.is_empty() {
+            let produced = produces.iter().map(empty_ack).collect();
+            return common_state.send_to_client(transaction_ack(op_id, produced));
+        }
+
+        let connection_id = common_state.connection_id;
+        let transaction_id = common_state.event_stream.next_transaction_id();
+        info!("Committing transaction: {} with {} produce operations to {} partitions for connection_id: {}",
+              transaction_id, produces.len(), events_by_partition.len(), connection_id);
+
+        let mut partitions = Vec::with_capacity(events_by_partition.len());
+        let mut receivers = Vec::with_capacity(events_by_partition.len());
+        for (partition_num, events) in events_by_partition {
+            let partition = common_state.event_stream.get_partition(partition_num).unwrap();
+            // Events in a transaction are never deduplicated, so that each partition's events get consecutive ids
+            let receiver = partition.prepare_transaction(connection_id, op_id, transaction_id, events).map_err(|err| {
+                format!("Failed to send operation: {:?}", err.0)
+            })?;
+            partitions.push(partition_num);
+            receivers.push(receiver);
+        }
+
+        self.pending_commit = Some(PendingCommit {
+            op_id: op_id,
+            transaction_id: transaction_id,
+            produces: produces,
+            partitions: partitions,
+            phase: CommitPhase::Preparing(join_all(receivers)),
+        });
+        Ok(())
+    }
+
+    pub fn poll_commit_complete(&mut self, common_state: &mut ConnectionState) -> Poll<(), io::Error> {
+        let response = match self.pending_commit {
+            Some(ref mut pending) => try_ready!(pending.poll(common_state)),
+            None => return Ok(Async::Ready(()))
+        };
+
+        self.pending_commit = None;
+
+        common_state.send_to_client(response).map_err(|e| {
+            io::Error::new(io::ErrorKind::Other, e)
+        })?;
+
+        Ok(Async::Ready(()))
+    }
+}
+
+impl PendingCommit {
+    fn poll(&mut self, common_state: &mut ConnectionState) -> Poll<SendProtocolMessage, io::Error> {
+        let op_id = self.op_id;
+        let transaction_id = self.transaction_id;
+        loop {
+            let next_phase = match self.phase {
+                CommitPhase::Preparing(ref mut prepares) => {
+                    let results = try_ready!(prepares.poll().map_err(|recv_err| {
+                        error!("Failed to poll transaction commit for client: op_id: {}: {:?}", op_id, recv_err);
+                        io::Error::new(io::ErrorKind::Other, "failed to poll transaction commit")
+                    }));
+                    self.finish_transaction(results, common_state)?
+                }
+                CommitPhase::Committing(ref ack, ref mut commits) => {
+                    let results = try_ready!(poll_finished(op_id, commits));
+                    let failure = self.partitions.iter().zip(results.iter()).filter_map(|(partition, result)| {
+                        result.as_ref().err().map(|io_err| (*partition, io_err))
+                    }).next().map(|(partition, io_err)| {
+                        ProtocolMessage::Error(commit_error(op_id, transaction_id, partition, io_err))
+                    });
+                    return Ok(Async::Ready(failure.unwrap_or_else(|| ProtocolMessage::AckTransaction(ack.clone()))));
+                }
+                CommitPhase::Aborting(ref error, ref mut aborts) => {
+                    let results = try_ready!(poll_finished(op_id, aborts));
+                    for result in results {
+                        if let Err(io_err) = result {
+                            error!("Failed to abort transaction: {} for op_id: {}: {:?}", self.transaction_id, op_id, io_err);
+                        }
+                    }
+                    return Ok(Async::Ready(ProtocolMessage::Error(error.clone())));
+                }
+            };
+            self.phase = next_phase;
+        }
+    }
+
+    /// Commits the transaction in every partition if they all prepared their events successfully. Otherwise, aborts it in
+    /// each partition that did prepare its events. A partition that fails to prepare its events aborts the transaction
+    /// on its own.
+    fn finish_transaction(&mut self, results: Vec<ProduceResult>, common_state: &mut ConnectionState) -> Result<CommitPhase, io::Error> {
+        let op_id = self.op_id;
+        let transaction_id = self.transaction_id;
+        let failure = results.iter().filter_map(|result| result.as_ref().err()).next().map(|io_err| {
+            produce_error(op_id, io_err)
+        });
+        if let Some(ref error) = failure {
+            warn!("Aborting transaction: {} for op_id: {} after error: {}", transaction_id, op_id, error.description);
+        }
+
+        let connection_id = common_state.connection_id;
+        let mut next_ids = BTreeMap::new();
+        let mut receivers = Vec::with_capacity(self.partitions.len());
+        for (partition_num, result) in self.partitions.iter().zip(results.into_iter()) {
+            if let Ok(ack) = result {
+                next_ids.insert(*partition_num, ack.first_event_id);
+                let partition = common_state.event_stream.get_partition(*partition_num).unwrap();
+                let send_result = if failure.is_some() {
+                    partition.abort_transaction(connection_id, transaction_id)
+                } else {
+                    partition.commit_transaction(connection_id, transaction_id)
+                };
+                let receiver = send_result.map_err(|err| {
+                    io::Error::new(io::ErrorKind::Other, format!("Failed to send operation: {:?}", err.0))
+                })?;
+                receivers.push(receiver);
+            }
+        }
+
+        let phase = match failure {
+            Some(error) => CommitPhase::Aborting(error, join_all(receivers)),
+            None => {
+                let ack = TransactionAck {
+                    op_id: op_id,
+                    produced: ack_each_produce(&self.produces, next_ids),
+                };
+                CommitPhase::Committing(ack, join_all(receivers))
+            }
+        };
+        Ok(phase)
+    }
+}
+
+/// Creates the error message for a transaction that was prepared in every partition, but failed to persist its commit
+/// marker in at least one of them. The transaction can no longer be aborted, since other partitions may have committed
+/// it already. The partition keeps retrying the commit, and consumers of that partition can't read past the
+/// transaction until it succeeds. If the server restarts first, the transaction is committed during startup.
+fn commit_error(op_id: u32, transaction_id: TransactionId, partition: ActorId, io_err: &io::Error) -> ErrorMessage {
+    ErrorMessage {
+        op_id: op_id,
+        kind: ErrorKind::StorageEngineError,
+        description: format!("Persistence Error: partition {} failed to commit transaction {}: {}. The transaction will \
+                             not be aborted, and it will be committed once the partition succeeds in retrying the \
+                             commit or the server restarts. Until then, consumers of partition {} are held back at the \
+                             start of the transaction", partition, transaction_id, io_err, partition),
+    }
+}
+
+fn poll_finished(op_id: u32, finishes: &mut JoinAll<Vec<FinishTransactionResponseReceiver>>) -> Poll<Vec<FinishTransactionResult>, io::Error> {
+    finishes.poll().map_err(|recv_err| {
+        error!("Failed to poll the end of a transaction for client: op_id: {}: {:?}", op_id, recv_err);
+        io::Error::new(io::ErrorKind::Other, "failed to poll the end of a transaction")
+    })
+}
+
+/// Assigns ids to each produce operation. Each partition appended all of its events at once, so they have consecutive
+/// ids starting with the first one, in the same order as the produce operations
+fn ack_each_produce(produces: &[TransactionProduce], mut next_ids: BTreeMap<ActorId, FloEventId>) -> Vec<BatchAck> {
+    produces.iter().map(|produce| {
+        if produce.event_count == 0 {
+            return empty_ack(produce);
+        }
+        let next_id = next_ids.get_mut(&produce.partition).unwrap();
+        let first = *next_id;
+        let last = FloEventId::new(first.actor, first.event_counter + produce.event_count as EventCounter - 1);
+        *next_id = FloEventId::new(first.actor, last.event_counter + 1);
+        BatchAck {
+            op_id: produce.op_id,
+            first_event_id: first,
+            last_event_id: last,
+            original_ids: Vec::new(),
+        }
+    }).collect()
+}
+
+fn empty_ack(produce: &TransactionProduce) -> BatchAck {
+    BatchAck {
+        op_id: produce.op_id,
+        first_event_id: FloEventId::new(produce.partition, 0),
+        last_event_id: FloEventId::new(produce.partition, 0),
+        original_ids: Vec::new(),
+    }
+}
+
+fn transaction_ack(op_id: u32, produced: Vec<BatchAck>) -> SendProtocolMessage {
+    ProtocolMessage::AckTransaction(TransactionAck {
+        op_id: op_id,
+        produced: produced,
+    })
+}
+
+fn error_response(op_id: u32, kind: ErrorKind, description: String) -> SendProtocolMessage {
+    ProtocolMessage::Error(ErrorMessage {
+        op_id: op_id,
+        kind: kind,
+        description: description,
+    })
+}
+
+impl Debug for TransactionConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let open_produce_count = self.open_transaction.as_ref().map(|transaction| transaction.produces.len());
+        let committing_op_id = self.pending_commit.as_ref().map(|pending| pending.op_id);
+        f.debug_struct("TransactionConnectionState")
+                .field("open_produce_count", &open_produce_count)
+                .field("committing_op_id", &committing_op_id)
+                .finish()
+    }
+}
@@ -9,13 +9,17 @@ use tokio_core::reactor::Remote;
 use engine::{EngineRef, system_stream_name};
 use engine::event_stream::{EventStreamRef,
                                EventStreamOptions,
+                               StorageBackend,
                                init_existing_event_stream,
                                init_new_event_stream};
 
 #[derive(Debug, PartialEq)]
 pub struct ControllerOptions {
+    /// Event streams that use `StorageBackend::Memory` never read from or write to this directory
     pub storage_dir: PathBuf,
     pub default_stream_options: EventStreamOptions,
+    /// If set, the event streams in this snapshot directory are copied into `storage_dir` before any streams are opened
+    pub restore_snapshot: Option<PathBuf>,
 }
 
 
@@ -36,7 +40,12 @@ pub fn start_controller(options: ControllerOptions, remote: Remote) -> io::Resul
 
     debug!("Starting Flo Controller with: {:?}", options);
 
-    let ControllerOptions{storage_dir, default_stream_options} = options;
+    let ControllerOptions{storage_dir, default_stream_options, restore_snapshot} = options;
+
+    if let Some(ref snapshot_dir) = restore_snapshot {
+        let restored = ::flo_storage::restore_snapshot(snapshot_dir, &storage_dir)?;
+        info!("Restored event streams: {:?} from snapshot: {:?}", restored, snapshot_dir);
+    }
 
     // for now, we'll just create a default "system" stream. This is temporary.
     // Once we start work on clustering, the system stream will be used exclusively for cluster communication
@@ -46,7 +55,8 @@ pub fn start_controller(options: ControllerOptions, remote: Remote) -> io::Resul
     let status_writer = AtomicBoolWriter::with_value(true);
 
     let system_stream_dir = storage_dir.join(&default_stream_options.name);
-    let event_stream_ref = if system_stream_dir.exists() {
+    // in-memory event streams always start out empty, regardless of what's in the storage directory
+    let event_stream_ref = if default_stream_options.storage == StorageBackend::Mmap && system_stream_dir.exists() {
         init_existing_event_stream(system_stream_dir, default_stream_options, status_writer.reader(), remote)?
     } else {
         init_new_event_stream(system_stream_dir, default_stream_options, status_writer.reader(), remote)?
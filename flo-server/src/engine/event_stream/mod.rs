@@ -1,7 +1,7 @@
 pub mod partition;
-mod highest_counter;
+mod transaction;
 
-use std::path::{PathBuf, Path};
+use std::path::PathBuf;
 use std::io;
 
 use tokio_core::reactor::Remote;
@@ -9,18 +9,40 @@ use futures::{Sink, Async, AsyncSink, StartSend, Poll};
 use chrono::Duration;
 
 use event::ActorId;
+use flo_storage::{self, PartitionOptions};
 use self::partition::{PartitionRef, initialize_existing_partition, initialize_new_partition};
 use atomics::AtomicBoolReader;
 
-pub use self::highest_counter::HighestCounter;
+pub use flo_storage::{HighestCounter, FsyncPolicy, get_event_steam_data_dir, determine_existing_partition_dirs};
+pub use self::transaction::{Transactions, TransactionId};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct EventStreamOptions {
     pub name: String,
     pub num_partitions: u16,
     pub event_retention: Duration,
     pub max_segment_duration: Duration,
     pub segment_max_size_bytes: usize,
+    /// The oldest segments in a partition are dropped once the total size of its segments exceeds this many bytes
+    pub max_bytes_per_partition: usize,
+    /// The oldest segments in a partition are dropped once it holds more than this many events
+    pub max_events_per_partition: u64,
+    /// If set, then sealed segments are periodically compacted so that only the newest event is kept for each namespace
+    /// that matches this glob
+    pub compacted_namespaces: Option<String>,
+    /// If true, then sealed segments are compressed in the background
+    pub compress_sealed_segments: bool,
+    /// If set, then segments that are dropped due to retention or size limits get moved to an archive instead of being
+    /// deleted
+    pub archive: Option<ArchiveOptions>,
+    /// If set, then new segments are encrypted using the newest key in this file. See `flo_storage::KeyRing` for the
+    /// format of the file
+    pub encryption_key_file: Option<PathBuf>,
+    pub fsync_policy: FsyncPolicy,
+    pub storage: StorageBackend,
+    /// The number of recent events from idempotent producers that each partition remembers, so that retransmitted events
+    /// can be recognized and acknowledged without being appended again
+    pub dedupe_window_events: usize,
 }
 
 
@@ -32,14 +54,64 @@ impl Default for EventStreamOptions {
             event_retention: Duration::max_value(),     // For-ev-er
             max_segment_duration: Duration::days(1),    // 24 hours
             segment_max_size_bytes: 1024 * 1024 * 1024, // 1GB
+            max_bytes_per_partition: ::std::usize::MAX, // no limit
+            max_events_per_partition: ::std::u64::MAX,  // no limit
+            compacted_namespaces: None,
+            compress_sealed_segments: false,
+            archive: None,
+            encryption_key_file: None,
+            fsync_policy: FsyncPolicy::default(),
+            storage: StorageBackend::Mmap,
+            dedupe_window_events: 10_000,
         }
     }
 }
 
+/// Determines where the events in an event stream are kept
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StorageBackend {
+    /// Events are persisted to memory mapped segment files within the storage directory
+    Mmap,
+    /// Events are only kept in memory, and are gone once the server stops. Nothing is written to the storage directory,
+    /// and the compaction, compression, archive, and encryption options are ignored
+    Memory,
+}
+
+/// Determines where archived segments are kept, and for how long
+#[derive(Debug, PartialEq, Clone)]
+pub struct ArchiveOptions {
+    /// Each partition gets its own directory within this one, at `<dir>/<event stream name>/<partition number>`
+    pub dir: PathBuf,
+    /// Archived segments are deleted once this amount of time has passed since their end time
+    pub retention: Duration,
+}
+
 impl EventStreamOptions {
     pub fn get_tick_interval(&self) -> Duration {
         self.max_segment_duration / 3
     }
+
+    /// Returns the options for the storage of a single partition in this event stream
+    pub fn partition_options(&self, partition_num: ActorId) -> PartitionOptions {
+        let archive = self.archive.as_ref().map(|archive| {
+            flo_storage::ArchiveOptions {
+                dir: archive.dir.join(&self.name).join(format!("{}", partition_num)),
+                retention: archive.retention,
+            }
+        });
+        PartitionOptions {
+            segment_max_size_bytes: self.segment_max_size_bytes,
+            max_segment_duration: self.max_segment_duration,
+            event_retention: self.event_retention,
+            max_bytes: self.max_bytes_per_partition,
+            max_events: self.max_events_per_partition,
+            compacted_namespaces: self.compacted_namespaces.clone(),
+            compress_sealed_segments: self.compress_sealed_segments,
+            archive: archive,
+            fsync_policy: self.fsync_policy,
+            encryption_key_file: self.encryption_key_file.clone(),
+        }
+    }
 }
 
 
@@ -50,6 +122,12 @@ pub fn init_existing_event_stream(event_stream_storage_dir: PathBuf, options: Ev
     let partition_numbers = determine_existing_partition_dirs(&event_stream_storage_dir)?;
     debug!("Initializing {} partition(s)", partition_numbers.len());
 
+    // A transaction that any partition committed has to be committed by all of them before they're initialized
+    let partition_dirs = partition_numbers.iter().map(|&partition_num| {
+        flo_storage::get_partition_data_dir(&event_stream_storage_dir, partition_num)
+    }).collect::<Vec<_>>();
+    flo_storage::resolve_transactions(&partition_dirs)?;
+
     let highest_counter = HighestCounter::zero();
 
     let mut partition_refs = Vec::with_capacity(partition_numbers.len());
@@ -64,6 +142,7 @@ pub fn init_existing_event_stream(event_stream_storage_dir: PathBuf, options: Ev
     let event_stream = EventStreamRef {
         name: options.name,
         partitions: partition_refs,
+        transactions: Transactions::new(),
     };
 
     start_tick_timer(remote, event_stream.clone(), tick_interval);
@@ -75,7 +154,9 @@ pub fn init_new_event_stream(event_stream_storage_dir: PathBuf, options: EventSt
 
     debug!("Starting initialization of new event stream with: {:?}", &options);
     let partition_count = options.num_partitions;
-    ::std::fs::create_dir_all(&event_stream_storage_dir)?;
+    if options.storage == StorageBackend::Mmap {
+        ::std::fs::create_dir_all(&event_stream_storage_dir)?;
+    }
 
     let mut partition_refs: Vec<PartitionRef> = Vec::with_capacity(partition_count as usize);
     let highest_counter = HighestCounter::zero();
@@ -93,42 +174,18 @@ pub fn init_new_event_stream(event_stream_storage_dir: PathBuf, options: EventSt
     let event_stream = EventStreamRef {
         name: name,
         partitions: partition_refs,
+        transactions: Transactions::new(),
     };
     start_tick_timer(remote, event_stream.clone(), tick_interval);
     Ok(event_stream)
 }
 
 
-pub fn get_event_steam_data_dir(server_storage_dir: &Path, event_stream_name: &str) -> PathBuf {
-    server_storage_dir.join(event_stream_name)
-}
-
-//TODO: Just save a file that contains the state of all the event streams and their partition directories instead of trying to figure it out based on conventions
-fn determine_existing_partition_dirs(event_stream_dir: &Path) -> io::Result<Vec<ActorId>> {
-    let files = ::std::fs::read_dir(event_stream_dir)?;
-    let mut partition_numbers = Vec::with_capacity(files.size_hint().0);
-    for entry_result in files {
-        let dir_entry = entry_result?;
-        if dir_entry.file_type()?.is_dir() {
-            let partition_number = dir_entry.file_name().into_string().ok().and_then(|name| {
-                name.parse::<ActorId>().ok()
-            });
-
-            if let Some(partition) = partition_number {
-                partition_numbers.push(partition);
-            }
-        }
-    }
-    // this sort isn't really important, but it just makes partition initialization follow a deterministic order to make debugging easier
-    partition_numbers.sort();
-    Ok(partition_numbers)
-}
-
-
 #[derive(Clone, Debug)]
 pub struct EventStreamRef {
     name: String,
     partitions: Vec<PartitionRef>,
+    transactions: Transactions,
 }
 
 impl EventStreamRef {
@@ -136,6 +193,7 @@ impl EventStreamRef {
         EventStreamRef {
             name: name,
             partitions: partitions,
+            transactions: Transactions::new(),
         }
     }
 
@@ -154,6 +212,11 @@ impl EventStreamRef {
     pub fn get_partition(&mut self, partition: ActorId) -> Option<&mut PartitionRef> {
         self.partitions.get_mut(partition as usize - 1)
     }
+
+    /// Returns a new id for a transaction that's about to be committed to this event stream
+    pub fn next_transaction_id(&self) -> TransactionId {
+        self.transactions.next_id()
+    }
 }
 
 
@@ -0,0 +1,89 @@
+use std::collections::{HashMap, VecDeque};
+
+use event::{FloEventId, ProducerSequence};
+
+/// Remembers the ids of the most recent events that were produced by idempotent producers, so that an event that gets
+/// re-sent can be acknowledged with the id of the original instead of being appended again. Once the window is full, the
+/// oldest entries are forgotten, so a retransmission is only detected if the original is still within the window.
+#[derive(Debug)]
+pub struct DedupeWindow {
+    max_entries: usize,
+    ids: HashMap<ProducerSequence, FloEventId>,
+    order: VecDeque<ProducerSequence>,
+}
+
+impl DedupeWindow {
+    pub fn new(max_entries: usize) -> DedupeWindow {
+        DedupeWindow {
+            max_entries: max_entries,
+            ids: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Returns the id of the event that was already appended with the given producer sequence, if it's in the window
+    pub fn get(&self, producer_sequence: &ProducerSequence) -> Option<FloEventId> {
+        self.ids.get(producer_sequence).cloned()
+    }
+
+    pub fn add(&mut self, producer_sequence: ProducerSequence, id: FloEventId) {
+        if self.max_entries == 0 {
+            return;
+        }
+        if self.ids.insert(producer_sequence, id).is_none() {
+            self.order.push_back(producer_sequence);
+        }
+        while self.order.len() > self.max_entries {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+    }
+
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn seq(producer_id: u64, sequence: u64) -> ProducerSequence {
+        ProducerSequence {
+            producer_id: producer_id,
+            sequence: sequence,
+        }
+    }
+
+    #[test]
+    fn ids_are_returned_for_producer_sequences_in_the_window() {
+        let mut subject = DedupeWindow::new(10);
+        subject.add(seq(1, 1), FloEventId::new(1, 5));
+        subject.add(seq(2, 1), FloEventId::new(1, 6));
+
+        assert_eq!(Some(FloEventId::new(1, 5)), subject.get(&seq(1, 1)));
+        assert_eq!(Some(FloEventId::new(1, 6)), subject.get(&seq(2, 1)));
+        assert_eq!(None, subject.get(&seq(1, 2)));
+    }
+
+    #[test]
+    fn oldest_entries_are_forgotten_once_the_window_is_full() {
+        let mut subject = DedupeWindow::new(2);
+        subject.add(seq(1, 1), FloEventId::new(1, 1));
+        subject.add(seq(1, 2), FloEventId::new(1, 2));
+        subject.add(seq(1, 3), FloEventId::new(1, 3));
+
+        assert_eq!(None, subject.get(&seq(1, 1)));
+        assert_eq!(Some(FloEventId::new(1, 2)), subject.get(&seq(1, 2)));
+        assert_eq!(Some(FloEventId::new(1, 3)), subject.get(&seq(1, 3)));
+    }
+
+    #[test]
+    fn nothing_is_remembered_when_the_window_size_is_zero() {
+        let mut subject = DedupeWindow::new(0);
+        subject.add(seq(1, 1), FloEventId::new(1, 1));
+        assert_eq!(None, subject.get(&seq(1, 1)));
+    }
+}
@@ -1,42 +1,50 @@
-mod util;
 mod consumer_manager;
+mod dedupe;
+mod namespace_heads;
 
 use std::io;
-use std::collections::VecDeque;
 use std::path::PathBuf;
-
-use chrono::{Duration};
+use std::collections::HashMap;
 
 use atomics::{AtomicCounterWriter, AtomicCounterReader, AtomicBoolReader};
-use protocol::ProduceEvent;
-use event::{ActorId, FloEventId, EventCounter, FloEvent, Timestamp, time};
-use super::{SharedReaderRefsMut, Operation, OpType, ProduceOperation, ConsumeOperation, PartitionReader, EventFilter, SegmentNum};
-use super::segment::Segment;
-use super::index::{PartitionIndex, IndexEntry};
-use engine::event_stream::{EventStreamOptions, HighestCounter};
+use protocol::{ProduceEvent, BatchAck};
+use event::{ActorId, FloEventId, EventCounter, FloEvent, ProducerId, ProducerSequence};
+use event::time::Clock;
+use flo_storage::{Partition, MemoryPartition, PartitionStorage, NewEvent};
+use super::{Operation, OpType, ProduceOperation, ConsumeOperation, SnapshotOperation, DeleteOperation, PrepareTransactionOperation, FinishTransactionOperation, ConsumeStart, PartitionReader, EventFilter};
+use engine::event_stream::{EventStreamOptions, StorageBackend, HighestCounter, TransactionId};
 use engine::ConnectionId;
-use self::util::get_segment_files;
 use self::consumer_manager::ConsumerManager;
+use self::dedupe::DedupeWindow;
+use self::namespace_heads::NamespaceHeads;
 
-const FIRST_SEGMENT_NUM: SegmentNum = SegmentNum(1);
+pub use self::namespace_heads::is_concurrency_conflict;
 
+/// Runs a single partition of an event stream. This handles the operations that get sent to the partition, and uses a
+/// `PartitionStorage` to actually persist and read the events. Which storage is used depends on the `StorageBackend` in
+/// the event stream options.
 pub struct PartitionImpl {
     event_stream_name: String,
-    partition_num: ActorId,
-    partition_dir: PathBuf,
-    max_segment_size: usize,
-    max_segment_duration: Duration,
-    segments: VecDeque<Segment>,
-    index: PartitionIndex,
-    event_stream_highest_counter: HighestCounter,
+    partition: Box<PartitionStorage>,
     partition_highest_counter: AtomicCounterWriter,
     primary: AtomicBoolReader,
 
-    /// new segments each have a reader added here. The readers are then accessed as needed by the EventReader
-    reader_refs: SharedReaderRefsMut,
-
     /// consumers each have a notifier added here
     consumer_manager: ConsumerManager,
+
+    /// the most recent events from idempotent producers, used to detect events that get re-sent
+    dedupe_window: DedupeWindow,
+
+    /// the id of the most recent event in each namespace, used to check the expected versions of produced events
+    namespace_heads: NamespaceHeads,
+
+    /// the namespace and id of each event in the transactions that have been prepared, which become the heads of their
+    /// namespaces once the transaction is committed
+    prepared_transactions: HashMap<TransactionId, Vec<(String, FloEventId)>>,
+
+    /// transactions that failed to persist their commit markers, which get retried on every tick. Consumers of this
+    /// partition can't read past the events of these transactions until they are committed
+    failed_commits: Vec<TransactionId>,
 }
 
 impl PartitionImpl {
@@ -45,73 +53,47 @@ impl PartitionImpl {
                          partition_data_dir: PathBuf,
                          options: &EventStreamOptions,
                          status_reader: AtomicBoolReader,
-                         highest_counter: HighestCounter) -> io::Result<PartitionImpl> {
-
-        let start_time = ::std::time::Instant::now();
-        debug!("Starting to init partition: {} with directory: {:?}, and options: {:?}", partition_num, partition_data_dir, options);
-
-        let mut index = PartitionIndex::new(partition_num);
-
-        let segment_files = get_segment_files(&partition_data_dir)?;
-        let mut initialized_segments = VecDeque::with_capacity(segment_files.len());
-        let reader_refs = SharedReaderRefsMut::with_capacity(segment_files.len());
-        for segment_file in segment_files {
-            let segment = segment_file.init_segment(&mut index)?;
-            let reader = segment.iter_from_start();
-            initialized_segments.push_front(segment);
-            reader_refs.add(reader);
-        }
-        let current_greatest_id = index.greatest_event_counter();
-        highest_counter.set_if_greater(current_greatest_id);
-        let partition_id_counter = AtomicCounterWriter::with_value(current_greatest_id as usize);
-
-        // TODO: factor out a more legit method of timing and logging perf stats
-        let init_time = start_time.elapsed();
-        let time_in_millis = (init_time.as_secs() * 1000) +
-            (init_time.subsec_nanos() as u64 / 1_000_000);
-        info!("Initialized existing partition: {} with directory: {:?}, and options: {:?} in {} milliseconds",
-              partition_num,
-              partition_data_dir,
-              options,
-              time_in_millis);
+                         highest_counter: HighestCounter,
+                         clock: Box<Clock>) -> io::Result<PartitionImpl> {
 
-        Ok(PartitionImpl {
-            event_stream_name: options.name.clone(),
-            partition_num: partition_num,
-            partition_dir: partition_data_dir,
-            max_segment_size: options.segment_max_size_bytes,
-            max_segment_duration: options.max_segment_duration,
-            segments: initialized_segments,
-            index: index,
-            event_stream_highest_counter: highest_counter,
-            partition_highest_counter: partition_id_counter,
-            primary: status_reader,
-            reader_refs: reader_refs,
-            consumer_manager: ConsumerManager::new(),
-        })
+        let partition_options = options.partition_options(partition_num);
+        let partition = Partition::init_existing(partition_num, partition_data_dir, &partition_options, highest_counter, clock)?;
+        PartitionImpl::new(options, Box::new(partition), status_reader)
     }
 
     pub fn init_new(partition_num: ActorId,
                     partition_data_dir: PathBuf,
                     options: &EventStreamOptions,
                     status_reader: AtomicBoolReader,
-                    highest_counter: HighestCounter) -> io::Result<PartitionImpl> {
+                    highest_counter: HighestCounter,
+                    clock: Box<Clock>) -> io::Result<PartitionImpl> {
 
-        ::std::fs::create_dir_all(&partition_data_dir)?;
+        let partition_options = options.partition_options(partition_num);
+        let partition: Box<PartitionStorage> = match options.storage {
+            StorageBackend::Mmap => {
+                Box::new(Partition::init_new(partition_num, partition_data_dir, &partition_options, highest_counter, clock)?)
+            }
+            StorageBackend::Memory => {
+                Box::new(MemoryPartition::new(partition_num, &partition_options, highest_counter, clock))
+            }
+        };
+        PartitionImpl::new(options, partition, status_reader)
+    }
 
+    fn new(options: &EventStreamOptions, partition: Box<PartitionStorage>, status_reader: AtomicBoolReader) -> io::Result<PartitionImpl> {
+        let greatest_counter = partition.greatest_event_counter();
+        let dedupe_window = rebuild_dedupe_window(&*partition, options.dedupe_window_events)?;
+        let namespace_heads = rebuild_namespace_heads(&*partition)?;
         Ok(PartitionImpl {
-            event_stream_name: options.name.to_owned(),
-            partition_num: partition_num,
-            partition_dir: partition_data_dir,
-            max_segment_duration: options.max_segment_duration,
-            max_segment_size: options.segment_max_size_bytes,
-            segments: VecDeque::with_capacity(4),
-            index: PartitionIndex::new(partition_num),
-            event_stream_highest_counter: highest_counter,
-            partition_highest_counter: AtomicCounterWriter::zero(),
+            event_stream_name: options.name.clone(),
+            partition: partition,
+            partition_highest_counter: AtomicCounterWriter::with_value(greatest_counter as usize),
             primary: status_reader,
-            reader_refs: SharedReaderRefsMut::new(),
             consumer_manager: ConsumerManager::new(),
+            dedupe_window: dedupe_window,
+            namespace_heads: namespace_heads,
+            prepared_transactions: HashMap::new(),
+            failed_commits: Vec::new(),
         })
     }
 
@@ -128,11 +110,11 @@ impl PartitionImpl {
     }
 
     pub fn partition_num(&self) -> ActorId {
-        self.partition_num
+        self.partition.partition_num()
     }
 
     pub fn process(&mut self, operation: Operation) -> io::Result<()> {
-        trace!("Partition: {}, got operation: {:?}", self.partition_num, operation);
+        trace!("Partition: {}, got operation: {:?}", self.partition_num(), operation);
 
         // TODO: time handling and log it
         let Operation{connection_id, op_type, ..} = operation;
@@ -148,40 +130,76 @@ impl PartitionImpl {
                 self.consumer_manager.remove(connection_id);
                 Ok(())
             }
+            OpType::Snapshot(snapshot_op) => {
+                self.handle_snapshot(snapshot_op)
+            }
+            OpType::Delete(delete_op) => {
+                self.handle_delete(delete_op)
+            }
+            OpType::PrepareTransaction(prepare_op) => {
+                self.handle_prepare_transaction(prepare_op)
+            }
+            OpType::CommitTransaction(commit_op) => {
+                self.handle_finish_transaction(commit_op, true)
+            }
+            OpType::AbortTransaction(abort_op) => {
+                self.handle_finish_transaction(abort_op, false)
+            }
             OpType::Tick => {
-                self.expire_old_events();
+                self.partition.tick();
+                self.retry_failed_commits();
                 Ok(())
             }
         }
     }
 
-    fn expire_old_events(&mut self) {
-        let now = time::now();
-        let expired_segment_index = self.segments.iter().enumerate().take_while(|&(_, ref segment)| {
-            segment.is_expired(now)
-        }).last().map(|(ref index, _)| *index);
-        if let Some(drop_through_index) = expired_segment_index {
-            self.drop_segments_through_index(drop_through_index);
+    fn handle_snapshot(&mut self, snapshot: SnapshotOperation) -> io::Result<()> {
+        let SnapshotOperation {client, dest_dir} = snapshot;
+        let result = self.partition.snapshot(&dest_dir);
+        if let Err(e) = result.as_ref() {
+            error!("Failed to snapshot partition: {} of event stream: '{}' to {:?}: {:?}", self.partition_num(), self.event_stream_name, dest_dir, e);
         }
+        // A failed snapshot doesn't affect the partition itself, so the error only gets returned to the client
+        let _ = client.send(result);
+        Ok(())
     }
 
-    fn drop_segments_through_index(&mut self, segment_index: usize) {
-        info!("Dropping first {} segment(s)", segment_index + 1);
-        let PartitionImpl { ref mut segments, ref mut index, ref mut reader_refs, .. } = *self;
-
-        segments.drain(..(segment_index + 1)).for_each(|mut drop_segment| {
-            info!("Removing Segment: {:?} with highest_event counter: {}", drop_segment.segment_num, drop_segment.get_highest_event_counter());
-            reader_refs.remove_through(drop_segment.segment_num);
-            index.remove_through(drop_segment.get_highest_event_counter());
-            drop_segment.delete_on_drop();
-        });
+    fn handle_delete(&mut self, delete: DeleteOperation) -> io::Result<()> {
+        let DeleteOperation {client, deletion} = delete;
+        info!("Deleting events from partition: {} of event stream: '{}': {:?}", self.partition_num(), self.event_stream_name, deletion);
+        let mut result = self.partition.delete(deletion);
+        if let Err(e) = result.as_ref() {
+            warn!("Failed to delete events from partition: {} of event stream: '{}': {:?}", self.partition_num(), self.event_stream_name, e);
+        }
+        // deleted events are no longer the head of their namespace, so the heads have to be found again
+        if result.is_ok() {
+            match rebuild_namespace_heads(&*self.partition) {
+                Ok(mut heads) => {
+                    // the rebuilt heads leave out prepared transactions, so their namespaces are reserved again
+                    for &(ref namespace, _) in self.prepared_transactions.values().flat_map(|prepared| prepared.iter()) {
+                        heads.reserve(namespace);
+                    }
+                    self.namespace_heads = heads;
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+        // Nothing is changed if the deletion fails, so the error only gets returned to the client
+        let _ = client.send(result);
+        Ok(())
     }
 
     fn handle_produce(&mut self, produce: ProduceOperation) -> io::Result<()> {
-        let ProduceOperation {client, op_id, events} = produce;
-        let result = self.append_all(events);
-        if let Err(e) = result.as_ref() {
-            error!("Failed to handle produce operation for op_id: {}, err: {:?}", op_id, e);
+        let ProduceOperation {client, op_id, producer_id, events} = produce;
+        let result = self.append_all(op_id, producer_id, events);
+        match result.as_ref() {
+            Err(e) if is_concurrency_conflict(e) => {
+                debug!("Rejected produce operation for op_id: {} on partition: {}: {}", op_id, self.partition_num(), e);
+            }
+            Err(e) => {
+                error!("Failed to handle produce operation for op_id: {}, err: {:?}", op_id, e);
+            }
+            Ok(_) => { }
         }
         // No biggie if the receiving end has hung up already. The operation will still be considered complete and successful
         // TODO: Consider logging this if the receiving end has hung up already?
@@ -189,104 +207,174 @@ impl PartitionImpl {
         Ok(())
     }
 
-    fn append_all(&mut self, events: Vec<ProduceEvent>) -> io::Result<FloEventId> {
-        let event_count = events.len();
-        // reserve the range of ids for the events
-        let new_highest = self.event_stream_highest_counter.increment_and_get(event_count as u64);
+    /// Appends the events and returns the ids of the first and last ones that were appended. Events that were
+    /// retransmitted by an idempotent producer are not appended again, and are acknowledged with the ids of the originals
+    /// instead. If the namespace of any new event is not in the state that it expects, then none of the events are appended
+    fn append_all(&mut self, op_id: u32, producer_id: Option<ProducerId>, events: Vec<ProduceEvent>) -> io::Result<BatchAck> {
+        let mut original_ids = Vec::new();
+        let mut new_events = Vec::with_capacity(events.len());
+        for (index, event) in events.into_iter().enumerate() {
+            let expected_version = event.expected_version;
+            let event = to_new_event(producer_id, event);
+            let original_id = event.producer_sequence.and_then(|seq| self.dedupe_window.get(&seq));
+            if let Some(id) = original_id {
+                debug!("Partition: {} ignoring retransmitted event with producer sequence: {:?}, original id: {}",
+                       self.partition_num(), event.producer_sequence, id);
+                original_ids.push((index as u16, id));
+            } else {
+                new_events.push((event, expected_version));
+            }
+        }
+        self.namespace_heads.check_all(new_events.iter().map(|&(ref event, expected)| (event.namespace.as_str(), expected)))?;
+        let new_events = new_events.into_iter().map(|(event, _)| event).collect::<Vec<_>>();
+        // If every event was a retransmission, then nothing is appended and the producer only gets the ids of the originals
+        if new_events.is_empty() && !original_ids.is_empty() {
+            let none_appended = FloEventId::new(self.partition_num(), 0);
+            return Ok(BatchAck {
+                op_id: op_id,
+                first_event_id: none_appended,
+                last_event_id: none_appended,
+                original_ids: original_ids,
+            });
+        }
 
-        let timestamp = time::now();
-        let mut event_counter = new_highest - event_count as u64;
-        for produce_event in events {
-            event_counter += 1;
-            let event = EventToProduce {
-                id: FloEventId::new(self.partition_num, event_counter),
-                ts: timestamp,
-                produce: produce_event,
-            };
-            // early return if creating segment fails or if appending fails
-            self.append(&event)?;
+        let event_count = new_events.len();
+        let producer_sequences = new_events.iter().map(|event| event.producer_sequence).collect::<Vec<_>>();
+        let namespaces = new_events.iter().map(|event| event.namespace.clone()).collect::<Vec<_>>();
+        let id = self.partition.append_all(new_events)?;
+
+        // events in a batch always get consecutive counters, ending with the returned id
+        let first_counter = (id.event_counter + 1) - event_count as EventCounter;
+        for (i, (producer_sequence, namespace)) in producer_sequences.into_iter().zip(namespaces.iter()).enumerate() {
+            let new_id = FloEventId::new(id.actor, first_counter + i as EventCounter);
+            if let Some(seq) = producer_sequence {
+                self.dedupe_window.add(seq, new_id);
+            }
+            self.namespace_heads.set(namespace, new_id);
         }
-        debug!("partition: {} finished appending {} events ending with counter: {}", self.partition_num, event_count, event_counter);
+
         // now increment our counter and notify consumers
         self.partition_highest_counter.increment_and_get_relaxed(event_count);
         ::std::sync::atomic::fence(::std::sync::atomic::Ordering::SeqCst);
         self.consumer_manager.notify_uncommitted();
-        Ok(FloEventId::new(self.partition_num, event_counter))
+
+        Ok(BatchAck {
+            op_id: op_id,
+            first_event_id: FloEventId::new(id.actor, first_counter),
+            last_event_id: id,
+            original_ids: original_ids,
+        })
     }
 
-    fn append(&mut self, event: &EventToProduce) -> io::Result<()> {
-        use super::SegmentNum;
-        use super::segment::AppendResult;
+    fn handle_prepare_transaction(&mut self, prepare: PrepareTransactionOperation) -> io::Result<()> {
+        let PrepareTransactionOperation {client, op_id, transaction_id, events} = prepare;
+        let result = self.prepare_transaction(op_id, transaction_id, events);
+        if let Err(e) = result.as_ref() {
+            warn!("Failed to prepare transaction: {} for op_id: {} on partition: {}: {}", transaction_id, op_id, self.partition_num(), e);
+        }
+        let _ = client.send(result);
+        Ok(())
+    }
 
-        let mut byte_offset: usize = 0;
-        let mut segment_num: SegmentNum = SegmentNum(0);
+    /// Appends the events from a transaction, which consumers can't read until it's committed. Events in a transaction are
+    /// never deduplicated, but their expected versions are checked the same as any other batch. Their namespaces are then
+    /// reserved until the transaction is resolved, so that no other event can be produced with an expected version for them
+    fn prepare_transaction(&mut self, op_id: u32, transaction_id: TransactionId, events: Vec<ProduceEvent>) -> io::Result<BatchAck> {
+        self.namespace_heads.check_all(events.iter().map(|event| (event.namespace.as_str(), event.expected_version)))?;
+        let new_events = events.into_iter().map(|event| to_new_event(None, event)).collect::<Vec<_>>();
+        let event_count = new_events.len();
+        let namespaces = new_events.iter().map(|event| event.namespace.clone()).collect::<Vec<_>>();
+        let id = self.partition.prepare_transaction(transaction_id, new_events)?;
+
+        let first_counter = (id.event_counter + 1) - event_count as EventCounter;
+        let prepared = namespaces.into_iter().enumerate().map(|(i, namespace)| {
+            self.namespace_heads.reserve(&namespace);
+            (namespace, FloEventId::new(id.actor, first_counter + i as EventCounter))
+        }).collect();
+        self.prepared_transactions.insert(transaction_id, prepared);
+
+        // Consumers aren't notified, since they can't read any of these events until the transaction is committed
+        self.partition_highest_counter.increment_and_get_relaxed(event_count);
+        Ok(BatchAck {
+            op_id: op_id,
+            first_event_id: FloEventId::new(id.actor, first_counter),
+            last_event_id: id,
+            original_ids: Vec::new(),
+        })
+    }
 
-        if let Some(ref mut segment) = self.segments.front_mut() {
-            match segment.append(event) {
-                AppendResult::Success(offset) => {
-                    byte_offset = offset;
-                    segment_num = segment.segment_num;
-                }
-                AppendResult::IoError(kind) => {
-                    return Err(kind.into());
-                }
-                other @ _ => {
-                    debug!("Event {} does not fit into {:?} due to: {:?}", event.id(), segment.segment_num, other);
-                }
-            }
+    fn handle_finish_transaction(&mut self, finish: FinishTransactionOperation, commit: bool) -> io::Result<()> {
+        let FinishTransactionOperation {client, transaction_id} = finish;
+        let result = if commit {
+            self.partition.commit_transaction(transaction_id)
+        } else {
+            self.partition.abort_transaction(transaction_id)
+        };
+        if let Err(e) = result.as_ref() {
+            error!("Failed to {} transaction: {} on partition: {}: {:?}", if commit { "commit" } else { "abort" }, transaction_id, self.partition_num(), e);
         }
 
-        if byte_offset == 0 {
-            // we weren't able to append to the last segment, so we need to create a new one
-            segment_num = self.segments.front().map(|s: &Segment| {
-                s.segment_num.next()
-            }).unwrap_or(FIRST_SEGMENT_NUM);
+        // A transaction that fails to commit remains prepared until a retry succeeds, but one that fails to abort is
+        // aborted anyway
+        if commit && result.is_err() {
+            self.failed_commits.push(transaction_id);
+        } else {
+            self.finish_prepared(transaction_id, commit);
+        }
 
-            let segment_end_time = time::now() + self.max_segment_duration;
-            let new_segment = Segment::init_new(&self.partition_dir,
-                                                segment_num,
-                                                self.max_segment_size,
-                                                segment_end_time)?;
-            self.reader_refs.add(new_segment.range_iter(0));
-            self.segments.push_front(new_segment);
+        let _ = client.send(result);
+        Ok(())
+    }
 
-            match self.segments.front_mut().unwrap().append(event) {
-                AppendResult::Success(offset) => {
-                    byte_offset = offset;
+    /// Tries again to commit each transaction that failed to persist its commit marker. The transaction was already
+    /// committed in the other partitions, so it can't be aborted here.
+    fn retry_failed_commits(&mut self) {
+        let transaction_ids = ::std::mem::replace(&mut self.failed_commits, Vec::new());
+        for transaction_id in transaction_ids {
+            match self.partition.commit_transaction(transaction_id) {
+                Ok(()) => {
+                    info!("Committed transaction: {} on partition: {} after retrying", transaction_id, self.partition_num());
+                    self.finish_prepared(transaction_id, true);
                 }
-                AppendResult::IoError(kind) => {
-                    return Err(kind.into());
-                }
-                other @ _ => {
-                    error!("Event {} can't fit into new segment: {:?} due to: {:?}", event.id(), segment_num, other);
-                    return Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", other)));
+                Err(e) => {
+                    error!("Failed to retry commit of transaction: {} on partition: {}: {:?}", transaction_id, self.partition_num(), e);
+                    self.failed_commits.push(transaction_id);
                 }
             }
         }
-
-        let index_entry = IndexEntry {
-            counter: event.id().event_counter,
-            segment: segment_num,
-            file_offset: byte_offset,
-        };
-        self.index.append(index_entry);
-        Ok(())
     }
 
-    pub fn fsync(&mut self) -> io::Result<()> {
-        for segment in self.segments.iter_mut() {
-            segment.fsync()?
+    /// Releases the namespaces of a transaction that has been committed or aborted, and lets consumers read past it
+    fn finish_prepared(&mut self, transaction_id: TransactionId, committed: bool) {
+        let prepared = self.prepared_transactions.remove(&transaction_id).unwrap_or_default();
+        for (namespace, id) in prepared {
+            self.namespace_heads.release(&namespace);
+            if committed {
+                self.namespace_heads.set(&namespace, id);
+            }
         }
-        Ok(())
+        // Consumers may be waiting on the transaction either way, since they stop at the first of its events
+        ::std::sync::atomic::fence(::std::sync::atomic::Ordering::SeqCst);
+        self.consumer_manager.notify_uncommitted();
     }
 
-    fn current_segment_num(&self) -> SegmentNum {
-        self.segments.front().map(|s| s.segment_num).unwrap_or(SegmentNum(0))
+    pub fn fsync(&mut self) -> io::Result<()> {
+        self.partition.fsync()
     }
 
     fn handle_consume(&mut self, connection_id: ConnectionId, consume: ConsumeOperation) -> io::Result<()> {
-        let ConsumeOperation {client_sender, filter, start_exclusive, notifier} = consume;
-        let reader = self.create_reader(connection_id, filter, start_exclusive);
+        let ConsumeOperation {client_sender, filter, start, notifier} = consume;
+        let reader = match start {
+            ConsumeStart::Exclusive(counter) => self.create_reader(connection_id, filter, counter),
+            ConsumeStart::Since(since) => {
+                let start_exclusive = self.partition.get_start_counter_since(since)?;
+                self.create_reader(connection_id, filter, start_exclusive)
+            }
+            ConsumeStart::SinceEventTime(since) => {
+                let start_exclusive = self.partition.get_start_counter_since_event_time(since)?;
+                self.create_reader(connection_id, filter, start_exclusive).with_min_event_time(since)
+            }
+        };
 
         // We don't really care if the receiving end has hung up already
         // but we don't want to actually add the notifier to the consumer manager in that case
@@ -297,66 +385,73 @@ impl PartitionImpl {
         Ok(())
     }
 
-    fn create_reader(&mut self, connection_id: ConnectionId, filter: EventFilter, start_exclusive: EventCounter) -> PartitionReader {
-        let current_segment_num = self.current_segment_num();
-        let index_entry: Option<IndexEntry> = self.index.get_next_entry(start_exclusive);
-        let readers = self.reader_refs.get_reader_refs();
-
-        let current_segment = match index_entry {
-            Some(entry) => {
-                readers.get_next_segment(entry.segment.previous()).map(|mut segment| {
-                    segment.set_offset(entry.file_offset);
-                    segment
-                })
-            }
-            None => {
-                // The requested event counter comes after the end of the stream, so just return the current segment
-                let mut reader = readers.get_segment(current_segment_num);
-                reader.as_mut().map(|r| r.set_offset_to_end());
-                reader
-            }
-        };
-
-        PartitionReader::new(connection_id, self.partition_num, filter, current_segment, self.reader_refs.get_reader_refs())
+    fn create_reader(&self, connection_id: ConnectionId, filter: EventFilter, start_exclusive: EventCounter) -> PartitionReader {
+        self.partition.create_reader(connection_id, filter, start_exclusive)
     }
-
-
-}
-
-#[derive(Debug, PartialEq)]
-struct EventToProduce {
-    id: FloEventId,
-    ts: Timestamp,
-    produce: ProduceEvent,
 }
 
-impl FloEvent for EventToProduce {
-    fn id(&self) -> &FloEventId {
-        &self.id
-    }
-
-    fn timestamp(&self) -> Timestamp {
-        self.ts
-    }
-
-    fn parent_id(&self) -> Option<FloEventId> {
-        self.produce.parent_id
+/// The dedupe window is rebuilt from the most recent events in the partition, since each event is persisted along with
+/// the producer sequence that it was produced with. Readers skip deleted events, so their producer sequences come from
+/// the tombstones instead, and a producer that re-sends a deleted event still gets the original id back. Event counters
+/// are shared by every partition in the stream, so the range of counters that's read is doubled until it holds
+/// `max_entries` of this partition's events, or it reaches back to the start of the partition.
+fn rebuild_dedupe_window(partition: &PartitionStorage, max_entries: usize) -> io::Result<DedupeWindow> {
+    let mut window = DedupeWindow::new(max_entries);
+    if window.max_entries() == 0 {
+        return Ok(window);
     }
-
-    fn namespace(&self) -> &str {
-        &self.produce.namespace
+    let greatest_counter = partition.greatest_event_counter();
+    let mut lookback = max_entries as EventCounter;
+    loop {
+        let start_exclusive = greatest_counter.saturating_sub(lookback);
+        let mut sequences = partition.deleted_producer_sequences(start_exclusive);
+        let mut event_count = sequences.len();
+        for result in partition.create_reader(0, EventFilter::All, start_exclusive) {
+            let event = result?;
+            event_count += 1;
+            if let Some(producer_sequence) = event.producer_sequence() {
+                sequences.push((*event.id(), producer_sequence));
+            }
+        }
+        if event_count >= max_entries || start_exclusive == 0 {
+            // The window evicts the oldest entries first, so they're added in the order the events were appended
+            sequences.sort_by_key(|&(id, _)| id);
+            for (id, producer_sequence) in sequences {
+                window.add(producer_sequence, id);
+            }
+            return Ok(window);
+        }
+        lookback = lookback.saturating_mul(2);
     }
+}
 
-    fn data_len(&self) -> u32 {
-        self.produce.data.len() as u32
+/// The storage persists the namespace heads whenever it seals a segment, so only the events after that need to be read.
+/// Events in transactions that are still prepared are left out, since they become heads when the transaction commits.
+fn rebuild_namespace_heads(partition: &PartitionStorage) -> io::Result<NamespaceHeads> {
+    let partition_num = partition.partition_num();
+    let mut heads = NamespaceHeads::new();
+    for (namespace, counter) in partition.namespace_heads()? {
+        heads.set(&namespace, FloEventId::new(partition_num, counter));
     }
+    Ok(heads)
+}
 
-    fn data(&self) -> &[u8] {
-        &self.produce.data
+fn to_new_event(producer_id: Option<ProducerId>, produce: ProduceEvent) -> NewEvent {
+    let ProduceEvent {namespace, parent_id, event_time, sequence, headers, data, ..} = produce;
+    let producer_sequence = match (producer_id, sequence) {
+        (Some(producer_id), Some(sequence)) => Some(ProducerSequence { producer_id, sequence }),
+        _ => None
+    };
+    NewEvent {
+        namespace: namespace,
+        parent_id: parent_id,
+        event_time: event_time,
+        producer_sequence: producer_sequence,
+        headers: headers,
+        data: data,
     }
 }
 
-
 #[cfg(test)]
 mod test {
     use chrono::Duration;
@@ -366,9 +461,11 @@ mod test {
     use super::*;
     use protocol::ProduceEvent;
     use engine::event_stream::partition::{ProduceOperation, EventFilter, PartitionReader};
-    use engine::event_stream::{EventStreamOptions, HighestCounter};
+    use engine::event_stream::{EventStreamOptions, StorageBackend, HighestCounter, FsyncPolicy};
     use engine::ConnectionId;
     use atomics::AtomicBoolWriter;
+    use event::FloEvent;
+    use event::time::SystemClock;
 
     const PARTITION_NUM: ActorId = 1;
     const CONNECTION: ConnectionId = 55;
@@ -384,6 +481,15 @@ mod test {
             event_retention: Duration::seconds(20),
             max_segment_duration: Duration::seconds(5),
             segment_max_size_bytes: 256,
+            max_bytes_per_partition: ::std::usize::MAX,
+            max_events_per_partition: ::std::u64::MAX,
+            compacted_namespaces: None,
+            compress_sealed_segments: false,
+            archive: None,
+            encryption_key_file: None,
+            fsync_policy: FsyncPolicy::EveryMillis(1000),
+            storage: StorageBackend::Mmap,
+            dedupe_window_events: 100,
         };
         let tempdir = TempDir::new("partition_persist_events_and_read_them_back").unwrap();
 
@@ -393,19 +499,25 @@ mod test {
                                                         tempdir.path().to_owned(),
                                                         &options,
                                                         status.reader(),
-                                                        HighestCounter::zero()).unwrap();
+                                                        HighestCounter::zero(),
+                                                        Box::new(SystemClock)).unwrap();
 
             let (client_tx, _client_rx) = oneshot::channel();
 
             let produce = ProduceOperation {
                 client: client_tx,
                 op_id: 3,
+                producer_id: None,
                 events: vec![
                     ProduceEvent {
                         op_id: 3,
                         partition: PARTITION_NUM,
                         namespace: "/foo/bar".to_owned(),
                         parent_id: None,
+                        event_time: None,
+                        sequence: None,
+                        expected_version: None,
+                        headers: Vec::new(),
                         data: "the quick".to_owned().into_bytes(),
                     },
                     ProduceEvent {
@@ -413,12 +525,17 @@ mod test {
                         partition: PARTITION_NUM,
                         namespace: "/foo/bar".to_owned(),
                         parent_id: None,
+                        event_time: None,
+                        sequence: None,
+                        expected_version: None,
+                        headers: Vec::new(),
                         data: "brown fox".to_owned().into_bytes(),
                     }
                 ],
             };
 
             partition.handle_produce(produce).unwrap();
+            assert_eq!(2, partition.event_counter_reader().load_relaxed());
 
             let mut reader: PartitionReader = partition.create_reader(CONNECTION, EventFilter::All, 0);
             let event = reader.next_matching().expect("read_next returned None").expect("read_next returned error");
@@ -433,6 +550,10 @@ mod test {
                     partition: PARTITION_NUM,
                     namespace: "/boo/hoo".to_owned(),
                     parent_id: None,
+                    event_time: None,
+                    sequence: None,
+                    expected_version: None,
+                    headers: Vec::new(),
                     data: "stew".to_owned().into_bytes()
                 }
             }).collect::<Vec<_>>();
@@ -442,6 +563,7 @@ mod test {
             partition.handle_produce(ProduceOperation {
                 client: client_tx,
                 op_id: 4,
+                producer_id: None,
                 events: moar_events,
             }).expect("failed to persist large batch");
 
@@ -455,8 +577,9 @@ mod test {
         }
 
         // now try to initialize the partition from an existing file
-        let result = PartitionImpl::init_existing(PARTITION_NUM, tempdir.path().to_owned(), &options, status.reader(), HighestCounter::zero());
-        let mut partition = result.expect("Failed to init partitionImpl");
+        let result = PartitionImpl::init_existing(PARTITION_NUM, tempdir.path().to_owned(), &options, status.reader(), HighestCounter::zero(), Box::new(SystemClock));
+        let partition = result.expect("Failed to init partitionImpl");
+        assert_eq!(102, partition.event_counter_reader().load_relaxed());
 
         let reader = partition.create_reader(77, EventFilter::All, 0);
         let count = reader.map(|read_result| {
@@ -464,4 +587,577 @@ mod test {
         }).count();
         assert_eq!(102, count);
     }
+
+    #[test]
+    fn retransmitted_events_are_acknowledged_with_the_original_id_and_not_appended_again() {
+        let _ = ::env_logger::init();
+
+        let status = AtomicBoolWriter::with_value(true);
+        let options = EventStreamOptions {
+            name: "dedupe".to_owned(),
+            dedupe_window_events: 10,
+            ..Default::default()
+        };
+        let tempdir = TempDir::new("retransmitted_events_are_not_appended_again").unwrap();
+        let producer_id = Some(7);
+
+        fn produce(partition: &mut PartitionImpl, producer_id: Option<ProducerId>, sequence: Option<u64>) -> FloEventId {
+            use futures::Future;
+
+            let (client_tx, client_rx) = oneshot::channel();
+            partition.handle_produce(ProduceOperation {
+                client: client_tx,
+                op_id: 1,
+                producer_id: producer_id,
+                events: vec![ProduceEvent {
+                    op_id: 1,
+                    partition: PARTITION_NUM,
+                    namespace: "/foo/bar".to_owned(),
+                    parent_id: None,
+                    event_time: None,
+                    sequence: sequence,
+                    expected_version: None,
+                    headers: Vec::new(),
+                    data: "the quick".to_owned().into_bytes(),
+                }],
+            }).unwrap();
+            // the response is always sent before handle_produce returns, so this never blocks
+            client_rx.wait().expect("produce was cancelled").expect("failed to produce").event_ids(1)[0]
+        }
+
+        {
+            let mut partition = PartitionImpl::init_new(PARTITION_NUM,
+                                                        tempdir.path().to_owned(),
+                                                        &options,
+                                                        status.reader(),
+                                                        HighestCounter::zero(),
+                                                        Box::new(SystemClock)).unwrap();
+
+            assert_eq!(FloEventId::new(PARTITION_NUM, 1), produce(&mut partition, producer_id, Some(1)));
+            assert_eq!(FloEventId::new(PARTITION_NUM, 2), produce(&mut partition, producer_id, Some(2)));
+            assert_eq!(FloEventId::new(PARTITION_NUM, 1), produce(&mut partition, producer_id, Some(1)));
+            // the same sequence from a different producer, or without a producer id, is a different event
+            assert_eq!(FloEventId::new(PARTITION_NUM, 3), produce(&mut partition, Some(8), Some(1)));
+            assert_eq!(FloEventId::new(PARTITION_NUM, 4), produce(&mut partition, None, Some(1)));
+            assert_eq!(4, partition.event_counter_reader().load_relaxed());
+            partition.fsync().expect("failed to fsync");
+        }
+
+        let mut partition = PartitionImpl::init_existing(PARTITION_NUM,
+                                                         tempdir.path().to_owned(),
+                                                         &options,
+                                                         status.reader(),
+                                                         HighestCounter::zero(),
+                                                         Box::new(SystemClock)).expect("failed to init partition");
+        assert_eq!(FloEventId::new(PARTITION_NUM, 2), produce(&mut partition, producer_id, Some(2)));
+        assert_eq!(FloEventId::new(PARTITION_NUM, 5), produce(&mut partition, producer_id, Some(3)));
+        assert_eq!(5, partition.event_counter_reader().load_relaxed());
+    }
+
+    #[test]
+    fn a_deleted_event_that_is_retransmitted_after_a_restart_is_acknowledged_with_the_original_id() {
+        use futures::Future;
+        use engine::event_stream::partition::{DeleteOperation, EventDeletion};
+
+        let _ = ::env_logger::init();
+
+        let status = AtomicBoolWriter::with_value(true);
+        let options = EventStreamOptions {
+            name: "dedupe_deleted".to_owned(),
+            dedupe_window_events: 10,
+            ..Default::default()
+        };
+        let tempdir = TempDir::new("deleted_event_is_retransmitted").unwrap();
+
+        fn produce(partition: &mut PartitionImpl, sequence: u64) -> FloEventId {
+            let (client_tx, client_rx) = oneshot::channel();
+            partition.handle_produce(ProduceOperation {
+                client: client_tx,
+                op_id: 1,
+                producer_id: Some(7),
+                events: vec![ProduceEvent {
+                    op_id: 1,
+                    partition: PARTITION_NUM,
+                    namespace: "/foo/bar".to_owned(),
+                    parent_id: None,
+                    event_time: None,
+                    sequence: Some(sequence),
+                    expected_version: None,
+                    headers: Vec::new(),
+                    data: Vec::new(),
+                }],
+            }).unwrap();
+            client_rx.wait().expect("produce was cancelled").expect("failed to produce").event_ids(1)[0]
+        }
+
+        {
+            let mut partition = PartitionImpl::init_new(PARTITION_NUM,
+                                                        tempdir.path().to_owned(),
+                                                        &options,
+                                                        status.reader(),
+                                                        HighestCounter::zero(),
+                                                        Box::new(SystemClock)).unwrap();
+            assert_eq!(FloEventId::new(PARTITION_NUM, 1), produce(&mut partition, 1));
+            assert_eq!(FloEventId::new(PARTITION_NUM, 2), produce(&mut partition, 2));
+            let (client_tx, client_rx) = oneshot::channel();
+            partition.handle_delete(DeleteOperation {
+                client: client_tx,
+                deletion: EventDeletion {
+                    counters: vec![1],
+                    namespace: None,
+                },
+            }).unwrap();
+            client_rx.wait().expect("delete was cancelled").expect("failed to delete");
+            partition.fsync().expect("failed to fsync");
+        }
+
+        let mut partition = PartitionImpl::init_existing(PARTITION_NUM,
+                                                         tempdir.path().to_owned(),
+                                                         &options,
+                                                         status.reader(),
+                                                         HighestCounter::zero(),
+                                                         Box::new(SystemClock)).expect("failed to init partition");
+        assert_eq!(FloEventId::new(PARTITION_NUM, 1), produce(&mut partition, 1));
+        assert_eq!(FloEventId::new(PARTITION_NUM, 2), produce(&mut partition, 2));
+        assert_eq!(2, partition.event_counter_reader().load_relaxed());
+    }
+
+    #[test]
+    fn dedupe_window_is_rebuilt_from_this_partitions_events_when_the_stream_has_other_partitions() {
+        use futures::Future;
+
+        let _ = ::env_logger::init();
+
+        let status = AtomicBoolWriter::with_value(true);
+        let options = EventStreamOptions {
+            name: "dedupe_rebuild".to_owned(),
+            dedupe_window_events: 3,
+            ..Default::default()
+        };
+        let tempdir = TempDir::new("dedupe_window_is_rebuilt").unwrap();
+        let highest_counter = HighestCounter::zero();
+
+        fn produce(partition: &mut PartitionImpl, sequences: &[u64]) -> BatchAck {
+            let events = sequences.iter().map(|&sequence| {
+                ProduceEvent {
+                    op_id: 1,
+                    partition: PARTITION_NUM,
+                    namespace: "/foo/bar".to_owned(),
+                    parent_id: None,
+                    event_time: None,
+                    sequence: Some(sequence),
+                    expected_version: None,
+                    headers: Vec::new(),
+                    data: Vec::new(),
+                }
+            }).collect();
+            let (client_tx, client_rx) = oneshot::channel();
+            partition.handle_produce(ProduceOperation {
+                client: client_tx,
+                op_id: 1,
+                producer_id: Some(7),
+                events: events,
+            }).unwrap();
+            client_rx.wait().expect("produce was cancelled").expect("failed to produce")
+        }
+
+        fn id(counter: EventCounter) -> FloEventId {
+            FloEventId::new(PARTITION_NUM, counter)
+        }
+
+        {
+            let mut partition = PartitionImpl::init_new(PARTITION_NUM,
+                                                        tempdir.path().to_owned(),
+                                                        &options,
+                                                        status.reader(),
+                                                        highest_counter.clone(),
+                                                        Box::new(SystemClock)).unwrap();
+            // other partitions in the stream use up counters in between the events in this one
+            for sequence in 1..4 {
+                highest_counter.increment_and_get(10);
+                produce(&mut partition, &[sequence]);
+            }
+            partition.fsync().expect("failed to fsync");
+        }
+
+        let mut partition = PartitionImpl::init_existing(PARTITION_NUM,
+                                                         tempdir.path().to_owned(),
+                                                         &options,
+                                                         status.reader(),
+                                                         highest_counter.clone(),
+                                                         Box::new(SystemClock)).expect("failed to init partition");
+        let ack = produce(&mut partition, &[1, 4, 3]);
+        assert_eq!(vec![(0, id(11)), (2, id(33))], ack.original_ids);
+        assert_eq!(id(34), ack.first_event_id);
+        assert_eq!(id(34), ack.last_event_id);
+        assert_eq!(vec![id(11), id(34), id(33)], ack.event_ids(3));
+
+        let ack = produce(&mut partition, &[2, 4]);
+        assert_eq!(FloEventId::new(PARTITION_NUM, 0), ack.first_event_id);
+        assert_eq!(vec![id(22), id(34)], ack.event_ids(2));
+    }
+
+    #[test]
+    fn produce_responds_with_the_ids_of_the_first_and_last_events_in_the_batch() {
+        use futures::Future;
+
+        let status = AtomicBoolWriter::with_value(true);
+        let options = EventStreamOptions {
+            name: "batches".to_owned(),
+            ..Default::default()
+        };
+        let tempdir = TempDir::new("produce_responds_with_first_and_last_ids").unwrap();
+        let mut partition = PartitionImpl::init_new(PARTITION_NUM,
+                                                    tempdir.path().to_owned(),
+                                                    &options,
+                                                    status.reader(),
+                                                    HighestCounter::zero(),
+                                                    Box::new(SystemClock)).unwrap();
+
+        let events = (0..3).map(|i| {
+            ProduceEvent {
+                op_id: 1,
+                partition: PARTITION_NUM,
+                namespace: "/foo/bar".to_owned(),
+                parent_id: None,
+                event_time: None,
+                sequence: None,
+                expected_version: None,
+                headers: Vec::new(),
+                data: format!("event {}", i).into_bytes(),
+            }
+        }).collect::<Vec<_>>();
+        let (client_tx, client_rx) = oneshot::channel();
+        partition.handle_produce(ProduceOperation {
+            client: client_tx,
+            op_id: 1,
+            producer_id: None,
+            events: events,
+        }).unwrap();
+
+        let ack = client_rx.wait().expect("produce was cancelled").expect("failed to produce");
+        assert_eq!(FloEventId::new(PARTITION_NUM, 1), ack.first_event_id);
+        assert_eq!(FloEventId::new(PARTITION_NUM, 3), ack.last_event_id);
+        assert!(ack.original_ids.is_empty());
+    }
+
+    #[test]
+    fn events_are_only_appended_when_their_namespaces_are_at_the_expected_versions() {
+        use protocol::ExpectedVersion;
+        use engine::event_stream::partition::{DeleteOperation, EventDeletion};
+
+        let _ = ::env_logger::init();
+
+        let status = AtomicBoolWriter::with_value(true);
+        let options = EventStreamOptions {
+            name: "expected_versions".to_owned(),
+            ..Default::default()
+        };
+        let tempdir = TempDir::new("events_are_only_appended_at_expected_versions").unwrap();
+
+        fn event(namespace: &str, expected_version: Option<ExpectedVersion>) -> ProduceEvent {
+            ProduceEvent {
+                op_id: 1,
+                partition: PARTITION_NUM,
+                namespace: namespace.to_owned(),
+                parent_id: None,
+                event_time: None,
+                sequence: None,
+                expected_version: expected_version,
+                headers: Vec::new(),
+                data: Vec::new(),
+            }
+        }
+
+        fn produce(partition: &mut PartitionImpl, events: Vec<ProduceEvent>) -> io::Result<(FloEventId, FloEventId)> {
+            use futures::Future;
+
+            let (client_tx, client_rx) = oneshot::channel();
+            partition.handle_produce(ProduceOperation {
+                client: client_tx,
+                op_id: 1,
+                producer_id: None,
+                events: events,
+            }).unwrap();
+            client_rx.wait().expect("produce was cancelled").map(|ack| (ack.first_event_id, ack.last_event_id))
+        }
+
+        fn id(counter: EventCounter) -> FloEventId {
+            FloEventId::new(PARTITION_NUM, counter)
+        }
+
+        {
+            let mut partition = PartitionImpl::init_new(PARTITION_NUM,
+                                                        tempdir.path().to_owned(),
+                                                        &options,
+                                                        status.reader(),
+                                                        HighestCounter::zero(),
+                                                        Box::new(SystemClock)).unwrap();
+
+            let result = produce(&mut partition, vec![event("/carts/77", Some(ExpectedVersion::NoEvents))]);
+            assert_eq!((id(1), id(1)), result.unwrap());
+
+            let err = produce(&mut partition, vec![event("/carts/77", Some(ExpectedVersion::NoEvents))]).unwrap_err();
+            assert!(is_concurrency_conflict(&err));
+
+            // every event is checked before any of them are appended
+            let result = produce(&mut partition, vec![
+                event("/carts/77", Some(ExpectedVersion::LastEventId(id(1)))),
+                event("/carts/78", Some(ExpectedVersion::NoEvents)),
+                event("/carts/78", None),
+            ]);
+            assert_eq!((id(2), id(4)), result.unwrap());
+
+            let err = produce(&mut partition, vec![
+                event("/carts/79", Some(ExpectedVersion::NoEvents)),
+                event("/carts/78", Some(ExpectedVersion::LastEventId(id(3)))),
+            ]).unwrap_err();
+            assert!(is_concurrency_conflict(&err));
+
+            // each event becomes the head of its namespace for the rest of the batch
+            let err = produce(&mut partition, vec![
+                event("/carts/77", Some(ExpectedVersion::LastEventId(id(2)))),
+                event("/carts/77", Some(ExpectedVersion::LastEventId(id(2)))),
+            ]).unwrap_err();
+            assert!(is_concurrency_conflict(&err));
+            assert_eq!(4, partition.event_counter_reader().load_relaxed());
+            partition.fsync().expect("failed to fsync");
+        }
+
+        let mut partition = PartitionImpl::init_existing(PARTITION_NUM,
+                                                         tempdir.path().to_owned(),
+                                                         &options,
+                                                         status.reader(),
+                                                         HighestCounter::zero(),
+                                                         Box::new(SystemClock)).expect("failed to init partition");
+        let result = produce(&mut partition, vec![event("/carts/78", Some(ExpectedVersion::LastEventId(id(4))))]);
+        assert_eq!((id(5), id(5)), result.unwrap());
+
+        // once the last event in a namespace is deleted, the one before it is the head again
+        let (client_tx, _client_rx) = oneshot::channel();
+        partition.handle_delete(DeleteOperation {
+            client: client_tx,
+            deletion: EventDeletion {
+                counters: vec![5],
+                namespace: None,
+            },
+        }).unwrap();
+        let result = produce(&mut partition, vec![event("/carts/78", Some(ExpectedVersion::LastEventId(id(4))))]);
+        assert_eq!((id(6), id(6)), result.unwrap());
+    }
+
+    #[test]
+    fn events_in_a_transaction_are_only_read_and_become_namespace_heads_once_it_is_committed() {
+        use futures::Future;
+        use protocol::ExpectedVersion;
+        use engine::event_stream::partition::{PrepareTransactionOperation, FinishTransactionOperation};
+
+        let status = AtomicBoolWriter::with_value(true);
+        let options = EventStreamOptions {
+            name: "transactions".to_owned(),
+            storage: StorageBackend::Memory,
+            ..Default::default()
+        };
+        let mut partition = PartitionImpl::init_new(PARTITION_NUM,
+                                                    PathBuf::new(),
+                                                    &options,
+                                                    status.reader(),
+                                                    HighestCounter::zero(),
+                                                    Box::new(SystemClock)).unwrap();
+
+        fn event(expected_version: Option<ExpectedVersion>) -> ProduceEvent {
+            ProduceEvent {
+                op_id: 1,
+                partition: PARTITION_NUM,
+                namespace: "/carts/1".to_owned(),
+                parent_id: None,
+                event_time: None,
+                sequence: None,
+                expected_version: expected_version,
+                headers: Vec::new(),
+                data: Vec::new(),
+            }
+        }
+
+        fn id(counter: EventCounter) -> FloEventId {
+            FloEventId::new(PARTITION_NUM, counter)
+        }
+
+        fn prepare(partition: &mut PartitionImpl, transaction_id: TransactionId) -> FloEventId {
+            let (client_tx, client_rx) = oneshot::channel();
+            partition.handle_prepare_transaction(PrepareTransactionOperation {
+                client: client_tx,
+                op_id: 1,
+                transaction_id: transaction_id,
+                events: vec![event(None)],
+            }).unwrap();
+            client_rx.wait().unwrap().expect("failed to prepare transaction").last_event_id
+        }
+
+        fn finish(partition: &mut PartitionImpl, transaction_id: TransactionId, commit: bool) {
+            let (client_tx, client_rx) = oneshot::channel();
+            partition.handle_finish_transaction(FinishTransactionOperation {
+                client: client_tx,
+                transaction_id: transaction_id,
+            }, commit).unwrap();
+            client_rx.wait().unwrap().expect("failed to finish transaction");
+        }
+
+        fn produce(partition: &mut PartitionImpl, expected_version: Option<ExpectedVersion>) -> io::Result<BatchAck> {
+            let (client_tx, client_rx) = oneshot::channel();
+            partition.handle_produce(ProduceOperation {
+                client: client_tx,
+                op_id: 1,
+                producer_id: None,
+                events: vec![event(expected_version)],
+            }).unwrap();
+            client_rx.wait().unwrap()
+        }
+
+        fn read_counters(partition: &PartitionImpl) -> Vec<EventCounter> {
+            partition.create_reader(CONNECTION, EventFilter::All, 0).map(|result| {
+                result.expect("failed to read event").id().event_counter
+            }).collect()
+        }
+
+        assert_eq!(id(1), prepare(&mut partition, 1));
+        assert_eq!(Vec::<EventCounter>::new(), read_counters(&partition));
+        finish(&mut partition, 1, true);
+        assert_eq!(vec![1], read_counters(&partition));
+        assert_eq!(id(2), produce(&mut partition, Some(ExpectedVersion::LastEventId(id(1)))).unwrap().last_event_id);
+
+        assert_eq!(id(3), prepare(&mut partition, 2));
+        finish(&mut partition, 2, false);
+        assert_eq!(vec![1, 2], read_counters(&partition));
+        let err = produce(&mut partition, Some(ExpectedVersion::LastEventId(id(3)))).unwrap_err();
+        assert!(is_concurrency_conflict(&err));
+        assert_eq!(id(4), produce(&mut partition, Some(ExpectedVersion::LastEventId(id(2)))).unwrap().last_event_id);
+
+        // while a transaction is prepared, its namespaces conflict with any expected version, even the current head
+        assert_eq!(id(5), prepare(&mut partition, 3));
+        let err = produce(&mut partition, Some(ExpectedVersion::LastEventId(id(4)))).unwrap_err();
+        assert!(is_concurrency_conflict(&err));
+        assert_eq!(id(6), produce(&mut partition, None).unwrap().last_event_id);
+
+        // committing the transaction doesn't replace the newer event as the head of the namespace
+        finish(&mut partition, 3, true);
+        let err = produce(&mut partition, Some(ExpectedVersion::LastEventId(id(5)))).unwrap_err();
+        assert!(is_concurrency_conflict(&err));
+        assert_eq!(id(7), produce(&mut partition, Some(ExpectedVersion::LastEventId(id(6)))).unwrap().last_event_id);
+    }
+
+    #[test]
+    fn a_commit_that_fails_to_persist_is_retried_on_each_tick_until_it_succeeds() {
+        use std::path::Path;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use futures::Future;
+        use event::Timestamp;
+        use flo_storage::{PartitionSnapshot, EventDeletion};
+        use engine::event_stream::partition::{Operation, PrepareTransactionOperation, FinishTransactionOperation};
+
+        /// Fails to commit transactions for as long as the flag is set
+        struct FailingCommits(MemoryPartition, Arc<AtomicBool>);
+
+        impl PartitionStorage for FailingCommits {
+            fn partition_num(&self) -> ActorId { self.0.partition_num() }
+            fn greatest_event_counter(&self) -> EventCounter { self.0.greatest_event_counter() }
+            fn tick(&mut self) { self.0.tick() }
+            fn append_all(&mut self, events: Vec<NewEvent>) -> io::Result<FloEventId> { self.0.append_all(events) }
+            fn prepare_transaction(&mut self, transaction_id: TransactionId, events: Vec<NewEvent>) -> io::Result<FloEventId> {
+                self.0.prepare_transaction(transaction_id, events)
+            }
+            fn commit_transaction(&mut self, transaction_id: TransactionId) -> io::Result<()> {
+                if self.1.load(Ordering::SeqCst) {
+                    return Err(io::Error::new(io::ErrorKind::Other, "disk is full"));
+                }
+                self.0.commit_transaction(transaction_id)
+            }
+            fn abort_transaction(&mut self, transaction_id: TransactionId) -> io::Result<()> { self.0.abort_transaction(transaction_id) }
+            fn fsync(&mut self) -> io::Result<()> { self.0.fsync() }
+            fn delete(&mut self, deletion: EventDeletion) -> io::Result<()> { self.0.delete(deletion) }
+            fn deleted_producer_sequences(&self, start_exclusive: EventCounter) -> Vec<(FloEventId, ProducerSequence)> {
+                self.0.deleted_producer_sequences(start_exclusive)
+            }
+            fn namespace_heads(&self) -> io::Result<HashMap<String, EventCounter>> { self.0.namespace_heads() }
+            fn snapshot(&mut self, dest_dir: &Path) -> io::Result<PartitionSnapshot> { self.0.snapshot(dest_dir) }
+            fn get_start_counter_since(&self, since: Timestamp) -> io::Result<EventCounter> { self.0.get_start_counter_since(since) }
+            fn get_start_counter_since_event_time(&self, since: Timestamp) -> io::Result<EventCounter> {
+                self.0.get_start_counter_since_event_time(since)
+            }
+            fn create_reader(&self, connection_id: ConnectionId, filter: EventFilter, start_exclusive: EventCounter) -> PartitionReader {
+                self.0.create_reader(connection_id, filter, start_exclusive)
+            }
+        }
+
+        let status = AtomicBoolWriter::with_value(true);
+        let options = EventStreamOptions {
+            name: "failing_commits".to_owned(),
+            storage: StorageBackend::Memory,
+            ..Default::default()
+        };
+        let failing = Arc::new(AtomicBool::new(true));
+        let storage = MemoryPartition::new(PARTITION_NUM, &options.partition_options(PARTITION_NUM), HighestCounter::zero(), Box::new(SystemClock));
+        let mut partition = PartitionImpl::new(&options, Box::new(FailingCommits(storage, failing.clone())), status.reader()).unwrap();
+
+        fn read_counters(partition: &PartitionImpl) -> Vec<EventCounter> {
+            partition.create_reader(CONNECTION, EventFilter::All, 0).map(|result| {
+                result.expect("failed to read event").id().event_counter
+            }).collect()
+        }
+
+        let (client_tx, client_rx) = oneshot::channel();
+        partition.handle_prepare_transaction(PrepareTransactionOperation {
+            client: client_tx,
+            op_id: 1,
+            transaction_id: 1,
+            events: vec![ProduceEvent {
+                op_id: 1,
+                partition: PARTITION_NUM,
+                namespace: "/carts/1".to_owned(),
+                parent_id: None,
+                event_time: None,
+                sequence: None,
+                expected_version: None,
+                headers: Vec::new(),
+                data: Vec::new(),
+            }],
+        }).unwrap();
+        client_rx.wait().unwrap().expect("failed to prepare transaction");
+
+        let (client_tx, client_rx) = oneshot::channel();
+        partition.handle_finish_transaction(FinishTransactionOperation {
+            client: client_tx,
+            transaction_id: 1,
+        }, true).unwrap();
+        client_rx.wait().unwrap().expect_err("commit should have failed");
+
+        partition.process(Operation::tick()).unwrap();
+        assert_eq!(Vec::<EventCounter>::new(), read_counters(&partition));
+
+        failing.store(false, Ordering::SeqCst);
+        partition.process(Operation::tick()).unwrap();
+        assert_eq!(vec![1], read_counters(&partition));
+        assert!(partition.failed_commits.is_empty());
+        assert!(partition.prepared_transactions.is_empty());
+    }
+
+    #[test]
+    fn partition_options_put_each_partition_in_its_own_archive_directory() {
+        use std::path::PathBuf;
+        use engine::event_stream::ArchiveOptions;
+
+        let options = EventStreamOptions {
+            name: "archived".to_owned(),
+            archive: Some(ArchiveOptions {
+                dir: PathBuf::from("/archive"),
+                retention: Duration::days(1),
+            }),
+            ..Default::default()
+        };
+        let partition_options = options.partition_options(3);
+        let archive = partition_options.archive.expect("archive options were not set");
+        assert_eq!(PathBuf::from("/archive/archived/3"), archive.dir);
+        assert_eq!(Duration::days(1), archive.retention);
+    }
 }
@@ -0,0 +1,191 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::io;
+
+use event::FloEventId;
+use protocol::ExpectedVersion;
+
+/// Keeps track of the id of the most recent event in each namespace of a partition, so that the expected versions of
+/// produced events can be checked without reading the partition. Namespaces that have events in a transaction that's
+/// prepared but not yet committed are also tracked, since their heads aren't known until the transaction is resolved.
+#[derive(Debug)]
+pub struct NamespaceHeads {
+    heads: HashMap<String, FloEventId>,
+    /// the number of events in each namespace that belong to prepared transactions
+    pending: HashMap<String, usize>,
+}
+
+impl NamespaceHeads {
+    pub fn new() -> NamespaceHeads {
+        NamespaceHeads {
+            heads: HashMap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, namespace: &str) -> Option<FloEventId> {
+        self.heads.get(namespace).cloned()
+    }
+
+    /// Sets the head of the namespace, unless it already has a newer event
+    pub fn set(&mut self, namespace: &str, id: FloEventId) {
+        if let Some(head) = self.heads.get_mut(namespace) {
+            if head.event_counter < id.event_counter {
+                *head = id;
+            }
+            return;
+        }
+        self.heads.insert(namespace.to_owned(), id);
+    }
+
+    /// Records an event in the namespace that belongs to a prepared transaction. Events with an expected version conflict
+    /// with the namespace until `release` is called for it, once the transaction is either committed or aborted.
+    pub fn reserve(&mut self, namespace: &str) {
+        *self.pending.entry(namespace.to_owned()).or_insert(0) += 1;
+    }
+
+    pub fn release(&mut self, namespace: &str) {
+        let remaining = self.pending.get_mut(namespace).map(|count| {
+            *count -= 1;
+            *count
+        });
+        if remaining == Some(0) {
+            self.pending.remove(namespace);
+        }
+    }
+
+    /// Returns an error if the namespace is not in the expected state, or if it has events in a prepared transaction
+    pub fn check(&self, namespace: &str, expected: ExpectedVersion) -> Result<(), ConcurrencyConflict> {
+        self.check_with_uncommitted(namespace, expected, self.pending.contains_key(namespace))
+    }
+
+    /// Checks the expected version of each event in a batch, in order. Each event that's accepted becomes the head of its
+    /// namespace for the events after it, so a later event in the same namespace conflicts if it has an expected version,
+    /// since the id of the earlier event isn't known until the batch is appended.
+    pub fn check_all<'a, I>(&self, events: I) -> Result<(), ConcurrencyConflict> where I: IntoIterator<Item=(&'a str, Option<ExpectedVersion>)> {
+        let mut batch_namespaces = HashSet::new();
+        for (namespace, expected_version) in events {
+            if let Some(expected) = expected_version {
+                let uncommitted = batch_namespaces.contains(namespace) || self.pending.contains_key(namespace);
+                self.check_with_uncommitted(namespace, expected, uncommitted)?;
+            }
+            batch_namespaces.insert(namespace);
+        }
+        Ok(())
+    }
+
+    fn check_with_uncommitted(&self, namespace: &str, expected: ExpectedVersion, uncommitted: bool) -> Result<(), ConcurrencyConflict> {
+        let actual = self.get(namespace);
+        let matches = match expected {
+            ExpectedVersion::NoEvents => actual.is_none(),
+            ExpectedVersion::LastEventId(id) => actual == Some(id),
+        };
+        if matches && !uncommitted {
+            Ok(())
+        } else {
+            Err(ConcurrencyConflict {
+                namespace: namespace.to_owned(),
+                expected: expected,
+                actual: actual,
+                uncommitted: uncommitted,
+            })
+        }
+    }
+}
+
+/// Returns true if the given error was caused by an event whose namespace was not in the state that its producer expected
+pub fn is_concurrency_conflict(err: &io::Error) -> bool {
+    err.get_ref().map(|inner| inner.is::<ConcurrencyConflict>()).unwrap_or(false)
+}
+
+/// The error returned when producing an event whose expected version does not match the head of its namespace
+#[derive(Debug)]
+pub struct ConcurrencyConflict {
+    pub namespace: String,
+    pub expected: ExpectedVersion,
+    /// The id of the most recent event in the namespace, or `None` if it doesn't have any events
+    pub actual: Option<FloEventId>,
+    /// true if the namespace also has newer events that haven't been committed yet, either in a prepared transaction or
+    /// earlier in the same batch
+    pub uncommitted: bool,
+}
+
+impl Display for ConcurrencyConflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.expected {
+            ExpectedVersion::NoEvents => write!(f, "expected no events in namespace: {}", self.namespace)?,
+            ExpectedVersion::LastEventId(id) => write!(f, "expected last event in namespace: {} to be: {}", self.namespace, id)?,
+        }
+        match self.actual {
+            Some(id) => write!(f, ", but the last event was: {}", id)?,
+            None => write!(f, ", but it has no events")?,
+        }
+        if self.uncommitted {
+            write!(f, " and it has newer events that haven't been committed yet")?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConcurrencyConflict {
+    fn description(&self) -> &str {
+        "concurrency conflict"
+    }
+}
+
+impl From<ConcurrencyConflict> for io::Error {
+    fn from(conflict: ConcurrencyConflict) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, conflict)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expected_versions_are_checked_against_the_last_event_in_the_namespace() {
+        let mut subject = NamespaceHeads::new();
+        assert!(subject.check("/carts/77", ExpectedVersion::NoEvents).is_ok());
+
+        subject.set("/carts/77", FloEventId::new(1, 5));
+        subject.set("/carts/78", FloEventId::new(1, 6));
+        assert!(subject.check("/carts/77", ExpectedVersion::LastEventId(FloEventId::new(1, 5))).is_ok());
+
+        let conflict = subject.check("/carts/77", ExpectedVersion::NoEvents).unwrap_err();
+        assert_eq!(Some(FloEventId::new(1, 5)), conflict.actual);
+
+        let conflict = subject.check("/carts/78", ExpectedVersion::LastEventId(FloEventId::new(1, 5))).unwrap_err();
+        assert_eq!(Some(FloEventId::new(1, 6)), conflict.actual);
+
+        let io_err: io::Error = conflict.into();
+        assert!(is_concurrency_conflict(&io_err));
+
+        // an older event never replaces a newer head
+        subject.set("/carts/77", FloEventId::new(1, 4));
+        assert_eq!(Some(FloEventId::new(1, 5)), subject.get("/carts/77"));
+    }
+
+    #[test]
+    fn namespaces_with_uncommitted_events_conflict_with_every_expected_version() {
+        let mut subject = NamespaceHeads::new();
+        subject.set("/carts/77", FloEventId::new(1, 5));
+        let expected = ExpectedVersion::LastEventId(FloEventId::new(1, 5));
+
+        subject.reserve("/carts/77");
+        subject.reserve("/carts/77");
+        assert!(subject.check("/carts/77", expected).unwrap_err().uncommitted);
+        subject.release("/carts/77");
+        assert!(subject.check("/carts/77", expected).is_err());
+        subject.release("/carts/77");
+        assert!(subject.check("/carts/77", expected).is_ok());
+
+        // a later event in the same batch can't expect the same version as an earlier one
+        assert!(subject.check_all(vec![("/carts/77", Some(expected)), ("/carts/78", Some(ExpectedVersion::NoEvents))]).is_ok());
+        let conflict = subject.check_all(vec![("/carts/77", None), ("/carts/77", Some(expected))]).unwrap_err();
+        assert!(conflict.uncommitted);
+        assert_eq!(Some(FloEventId::new(1, 5)), conflict.actual);
+    }
+}
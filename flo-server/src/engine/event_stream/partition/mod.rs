@@ -1,28 +1,34 @@
-mod segment;
-mod index;
-mod event_reader;
 mod ops;
 pub mod controller;
 
-use std::fmt::{self, Debug, Display};
 use std::path::{Path, PathBuf};
-use std::collections::VecDeque;
-use std::sync::{Arc, RwLock};
 use std::thread;
 use std::io;
 
 use atomics::{AtomicCounterReader, AtomicBoolReader};
 use engine::ConnectionId;
-use engine::event_stream::{EventStreamOptions, HighestCounter};
+use engine::event_stream::{EventStreamOptions, HighestCounter, TransactionId};
 use protocol::{ProduceEvent};
-use event::{EventCounter, ActorId};
-use self::segment::SegmentReader;
+use event::{EventCounter, ActorId, ProducerId};
+use event::time::SystemClock;
+use flo_storage::get_partition_data_dir;
 use self::controller::PartitionImpl;
 
 pub use self::ops::{OpType,
                     Operation,
+                    ConsumeStart,
                     ProduceOperation,
                     ConsumeOperation,
+                    SnapshotOperation,
+                    SnapshotResult,
+                    SnapshotResponseReceiver,
+                    DeleteOperation,
+                    DeleteResult,
+                    DeleteResponseReceiver,
+                    PrepareTransactionOperation,
+                    FinishTransactionOperation,
+                    FinishTransactionResult,
+                    FinishTransactionResponseReceiver,
                     ProduceResult,
                     ProduceResponder,
                     ProduceResponseReceiver,
@@ -30,8 +36,8 @@ pub use self::ops::{OpType,
                     ConsumeResponder,
                     ConsumerNotifier,
 };
-pub use self::event_reader::{PartitionReader, EventFilter};
-pub use self::segment::PersistentEvent;
+pub use flo_storage::{PartitionReader, EventFilter, PersistentEvent, PartitionSnapshot, EventDeletion, is_checksum_error};
+pub use self::controller::is_concurrency_conflict;
 
 pub type PartitionSender = ::std::sync::mpsc::Sender<Operation>;
 pub type PartitionReceiver = ::std::sync::mpsc::Receiver<Operation>;
@@ -45,117 +51,11 @@ pub struct PartitionSendError(pub Operation);
 
 pub type PartitionSendResult = Result<(), PartitionSendError>;
 
-pub const DATA_FILE_EXTENSION: &'static str = ".events";
-
-fn get_events_file(partition_dir: &Path, segment_num: SegmentNum) -> PathBuf {
-    let filename = format!("{}{}", segment_num.0, DATA_FILE_EXTENSION);
-    partition_dir.join(filename)
-}
-
-
-/// A 1-based monotonically incrementing counter used to identify segments.
-/// SegmentNums CANNOT BE 0! Since these are used all over in rather memory-sensitive areas, we sometimes
-/// use 0 as a sentinal value to indicate the lack of a segment;
-#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct SegmentNum(u64);
-
-impl SegmentNum {
-    /// returns true if this segment is non-zero
-    pub fn is_set(&self) -> bool {
-        self.0 > 0
-    }
-
-    pub fn next(&self) -> SegmentNum {
-        SegmentNum(self.0 + 1)
-    }
-
-    pub fn previous(&self) -> SegmentNum {
-        // TODO: think about this
-        SegmentNum(self.0.saturating_sub(1))
-    }
-}
-
-impl Display for SegmentNum {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "SegmentNum({})", self.0)
-    }
-}
-
-#[derive(Clone)]
-pub struct SharedReaderRefsMut {
-    inner: Arc<RwLock<VecDeque<SegmentReader>>>
-}
-
-impl SharedReaderRefsMut {
-    pub fn new() -> SharedReaderRefsMut {
-        SharedReaderRefsMut::with_capacity(4)
-    }
-
-    pub fn with_capacity(init_capacity: usize) -> SharedReaderRefsMut {
-        SharedReaderRefsMut {
-            inner: Arc::new(RwLock::new(VecDeque::with_capacity(init_capacity)))
-        }
-    }
-
-    pub fn add(&self, reader: SegmentReader) {
-        let mut locked = self.inner.write().unwrap();
-        locked.push_back(reader);
-    }
-
-    pub fn remove_through(&self, segment: SegmentNum) {
-        let mut locked = self.inner.write().unwrap();
-        while locked.front().map(|r| r.segment_id <= segment).unwrap_or(false) {
-            let removed = locked.pop_front().unwrap();
-            debug!("removing: {} from shared reader refs", removed.segment_id);
-        }
-    }
-
-    pub fn get_reader_refs(&self) -> SharedReaderRefs {
-        SharedReaderRefs {
-            inner: self.inner.clone()
-        }
-    }
-}
-
-pub struct SharedReaderRefs {
-    inner: Arc<RwLock<VecDeque<SegmentReader>>>
-}
-
-impl Debug for SharedReaderRefs {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "SharedReaderRefs(").and_then(|()| {
-            ::std::fmt::Pointer::fmt(&self.inner, f).and_then(|()| {
-                write!(f, ")")
-            })
-        })
-    }
-}
-
-impl SharedReaderRefs {
-    pub fn get_next_segment(&self, previous: SegmentNum) -> Option<SegmentReader> {
-        let locked = self.inner.read().unwrap();
-        locked.front().map(|r| r.segment_id).and_then(|front_segment| {
-            let target_index = (previous.0 + 1).saturating_sub(front_segment.0);
-            locked.get(target_index as usize).cloned()
-        })
-    }
-
-    pub fn get_segment(&self, segment: SegmentNum) -> Option<SegmentReader> {
-        if let Some(seg) =  self.get_next_segment(SegmentNum(segment.0.saturating_sub(1))) {
-            if seg.segment_id == segment {
-                Some(seg)
-            } else {
-                None
-            }
-        } else {
-            None
-        }
-    }
-}
-
-
 pub type AsyncProduceResult = Result<ProduceResponseReceiver, PartitionSendError>;
 pub type AsyncConsumeResult = Result<ConsumeResponseReceiver, PartitionSendError>;
+pub type AsyncSnapshotResult = Result<SnapshotResponseReceiver, PartitionSendError>;
+pub type AsyncDeleteResult = Result<DeleteResponseReceiver, PartitionSendError>;
+pub type AsyncFinishTransactionResult = Result<FinishTransactionResponseReceiver, PartitionSendError>;
 
 #[derive(Clone, Debug)]
 pub struct PartitionRef {
@@ -192,7 +92,7 @@ impl PartitionRef {
         &self.event_stream_name
     }
 
-    pub fn consume(&mut self, connection_id: ConnectionId, _op_id: u32, notifier: Box<ConsumerNotifier>, filter: EventFilter, start: EventCounter) -> AsyncConsumeResult {
+    pub fn consume(&mut self, connection_id: ConnectionId, _op_id: u32, notifier: Box<ConsumerNotifier>, filter: EventFilter, start: ConsumeStart) -> AsyncConsumeResult {
         let (op, rx) = Operation::consume(connection_id, notifier, filter, start);
         self.send(op).map(|()| rx)
     }
@@ -202,8 +102,39 @@ impl PartitionRef {
         let _ = self.send(op);
     }
 
-    pub fn produce(&mut self, connection_id: ConnectionId, op_id: u32, events: Vec<ProduceEvent>) -> AsyncProduceResult {
-        let (op, rx) = Operation::produce(connection_id, op_id, events);
+    pub fn produce(&mut self, connection_id: ConnectionId, op_id: u32, producer_id: Option<ProducerId>, events: Vec<ProduceEvent>) -> AsyncProduceResult {
+        let (op, rx) = Operation::produce(connection_id, op_id, producer_id, events);
+        self.send(op).map(|()| rx)
+    }
+
+    /// Asks the partition to write a consistent snapshot of all of its events into `dest_dir`
+    pub fn snapshot(&mut self, connection_id: ConnectionId, dest_dir: PathBuf) -> AsyncSnapshotResult {
+        let (op, rx) = Operation::snapshot(connection_id, dest_dir);
+        self.send(op).map(|()| rx)
+    }
+
+    /// Asks the partition to delete events. The receiver completes once the deletion has been persisted
+    pub fn delete(&mut self, connection_id: ConnectionId, deletion: EventDeletion) -> AsyncDeleteResult {
+        let (op, rx) = Operation::delete(connection_id, deletion);
+        self.send(op).map(|()| rx)
+    }
+
+    /// Asks the partition to append its events from a transaction. Consumers won't see the events until the transaction
+    /// is committed
+    pub fn prepare_transaction(&mut self, connection_id: ConnectionId, op_id: u32, transaction_id: TransactionId, events: Vec<ProduceEvent>) -> AsyncProduceResult {
+        let (op, rx) = Operation::prepare_transaction(connection_id, op_id, transaction_id, events);
+        self.send(op).map(|()| rx)
+    }
+
+    /// Asks the partition to persist a commit marker for a transaction that it prepared, which lets consumers see its events
+    pub fn commit_transaction(&mut self, connection_id: ConnectionId, transaction_id: TransactionId) -> AsyncFinishTransactionResult {
+        let (op, rx) = Operation::commit_transaction(connection_id, transaction_id);
+        self.send(op).map(|()| rx)
+    }
+
+    /// Asks the partition to abort a transaction that it prepared, so that consumers skip its events
+    pub fn abort_transaction(&mut self, connection_id: ConnectionId, transaction_id: TransactionId) -> AsyncFinishTransactionResult {
+        let (op, rx) = Operation::abort_transaction(connection_id, transaction_id);
         self.send(op).map(|()| rx)
     }
 
@@ -227,7 +158,7 @@ pub fn initialize_existing_partition(partition_num: ActorId,
                                      highest_counter: HighestCounter) -> io::Result<PartitionRef> {
 
     let partition_data_dir = get_partition_data_dir(event_stream_data_dir, partition_num);
-    let partition_impl = PartitionImpl::init_existing(partition_num, partition_data_dir, event_stream_options, status_reader, highest_counter)?;
+    let partition_impl = PartitionImpl::init_existing(partition_num, partition_data_dir, event_stream_options, status_reader, highest_counter, Box::new(SystemClock))?;
     run_partition(partition_impl)
 }
 
@@ -238,7 +169,7 @@ pub fn initialize_new_partition(partition_num: ActorId,
                                 highest_counter: HighestCounter) -> io::Result<PartitionRef> {
 
     let partition_data_dir = get_partition_data_dir(event_stream_data_dir, partition_num);
-    let partition_impl = PartitionImpl::init_new(partition_num, partition_data_dir, &event_stream_options, status_reader, highest_counter)?;
+    let partition_impl = PartitionImpl::init_new(partition_num, partition_data_dir, &event_stream_options, status_reader, highest_counter, Box::new(SystemClock))?;
     run_partition(partition_impl)
 }
 
@@ -281,9 +212,5 @@ fn get_partition_thread_name(event_stream_name: &str, partition_num: ActorId) ->
     format!("partition_{}_{}", event_stream_name, partition_num)
 }
 
-pub fn get_partition_data_dir(event_stream_dir: &Path, partition_num: ActorId) -> PathBuf {
-    event_stream_dir.join(format!("{}", partition_num))
-}
-
 
 
@@ -1,29 +1,64 @@
 
 use std::io;
 use std::fmt::{self, Debug};
+use std::path::PathBuf;
 use std::time::Instant;
 
 use futures::sync::oneshot;
 
-use engine::event_stream::partition::{EventFilter, PartitionReader};
+use engine::event_stream::partition::{EventFilter, PartitionReader, PartitionSnapshot, EventDeletion};
 use engine::ConnectionId;
-use protocol::ProduceEvent;
-use event::{FloEventId, EventCounter};
+use protocol::{ProduceEvent, BatchAck};
+use event::{EventCounter, Timestamp, ProducerId};
+use engine::event_stream::TransactionId;
 
-pub type ProduceResult = Result<FloEventId, io::Error>;
+/// The result of a successful produce operation acknowledges every one of its events, whether it was a batch or not
+pub type ProduceResult = Result<BatchAck, io::Error>;
 pub type ProduceResponder = oneshot::Sender<ProduceResult>;
 pub type ProduceResponseReceiver = oneshot::Receiver<ProduceResult>;
 
 pub struct ProduceOperation {
-    pub client: oneshot::Sender<io::Result<FloEventId>>,
+    pub client: ProduceResponder,
     pub op_id: u32,
+    /// The producer id that the connection announced, if any. Events are only deduplicated if this is set
+    pub producer_id: Option<ProducerId>,
     pub events: Vec<ProduceEvent>,
 }
 
 
 impl Debug for ProduceOperation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ProduceOperation {{ op_id: {}, events: {:?} }}", self.op_id, self.events)
+        write!(f, "ProduceOperation {{ op_id: {}, producer_id: {:?}, events: {:?} }}", self.op_id, self.producer_id, self.events)
+    }
+}
+
+/// Appends one partition's events from a transaction, without letting consumers see them until the transaction is
+/// committed. The result is the same as for a regular produce operation
+pub struct PrepareTransactionOperation {
+    pub client: ProduceResponder,
+    pub op_id: u32,
+    pub transaction_id: TransactionId,
+    pub events: Vec<ProduceEvent>,
+}
+
+impl Debug for PrepareTransactionOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PrepareTransactionOperation {{ op_id: {}, transaction_id: {}, events: {:?} }}", self.op_id, self.transaction_id, self.events)
+    }
+}
+
+pub type FinishTransactionResult = io::Result<()>;
+pub type FinishTransactionResponseReceiver = oneshot::Receiver<FinishTransactionResult>;
+
+/// Either commits or aborts a transaction that was prepared in the partition, depending on the `OpType`
+pub struct FinishTransactionOperation {
+    pub client: oneshot::Sender<FinishTransactionResult>,
+    pub transaction_id: TransactionId,
+}
+
+impl Debug for FinishTransactionOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FinishTransactionOperation {{ transaction_id: {} }}", self.transaction_id)
     }
 }
 
@@ -40,16 +75,57 @@ pub trait ConsumerNotifier: Send {
     fn connection_id(&self) -> ConnectionId;
 }
 
+/// Determines where in the partition a new consumer will start reading
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsumeStart {
+    /// Start reading with the first event after the given counter
+    Exclusive(EventCounter),
+    /// Start reading with the first event that has a timestamp greater than or equal to the given one
+    Since(Timestamp),
+    /// Only return events whose event time is greater than or equal to the given one. Events without an event time are
+    /// compared using their timestamp. Reading starts with the oldest segment that may have any such events
+    SinceEventTime(Timestamp),
+}
+
 pub struct ConsumeOperation {
     pub client_sender: oneshot::Sender<PartitionReader>,
     pub filter: EventFilter,
-    pub start_exclusive: EventCounter,
+    pub start: ConsumeStart,
     pub notifier: Box<ConsumerNotifier>,
 }
 
 impl Debug for ConsumeOperation {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "ConsumeOperation {{ filter: {:?}, start_exclusive: {} }}", self.filter, self.start_exclusive)
+        write!(f, "ConsumeOperation {{ filter: {:?}, start: {:?} }}", self.filter, self.start)
+    }
+}
+
+pub type SnapshotResult = io::Result<PartitionSnapshot>;
+pub type SnapshotResponseReceiver = oneshot::Receiver<SnapshotResult>;
+
+pub struct SnapshotOperation {
+    pub client: oneshot::Sender<SnapshotResult>,
+    /// The directory to write this partition's segment files into
+    pub dest_dir: PathBuf,
+}
+
+impl Debug for SnapshotOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SnapshotOperation {{ dest_dir: {:?} }}", self.dest_dir)
+    }
+}
+
+pub type DeleteResult = io::Result<()>;
+pub type DeleteResponseReceiver = oneshot::Receiver<DeleteResult>;
+
+pub struct DeleteOperation {
+    pub client: oneshot::Sender<DeleteResult>,
+    pub deletion: EventDeletion,
+}
+
+impl Debug for DeleteOperation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DeleteOperation {{ deletion: {:?} }}", self.deletion)
     }
 }
 
@@ -58,6 +134,11 @@ pub enum OpType {
     Produce(ProduceOperation),
     Consume(ConsumeOperation),
     StopConsumer,
+    Snapshot(SnapshotOperation),
+    Delete(DeleteOperation),
+    PrepareTransaction(PrepareTransactionOperation),
+    CommitTransaction(FinishTransactionOperation),
+    AbortTransaction(FinishTransactionOperation),
     Tick,
 }
 
@@ -70,12 +151,12 @@ pub struct Operation {
 }
 
 impl Operation {
-    pub fn consume(connection_id: ConnectionId, notifier: Box<ConsumerNotifier>, filter: EventFilter, start_exclusive: EventCounter) -> (Operation, ConsumeResponseReceiver) {
+    pub fn consume(connection_id: ConnectionId, notifier: Box<ConsumerNotifier>, filter: EventFilter, start: ConsumeStart) -> (Operation, ConsumeResponseReceiver) {
         let (tx, rx) = oneshot::channel();
         let consume = ConsumeOperation {
             client_sender: tx,
             filter: filter,
-            start_exclusive: start_exclusive,
+            start: start,
             notifier: notifier,
         };
         let op = Operation {
@@ -94,11 +175,12 @@ impl Operation {
         }
     }
 
-    pub fn produce(connection_id: ConnectionId, op_id: u32, events: Vec<ProduceEvent>) -> (Operation, ProduceResponseReceiver) {
+    pub fn produce(connection_id: ConnectionId, op_id: u32, producer_id: Option<ProducerId>, events: Vec<ProduceEvent>) -> (Operation, ProduceResponseReceiver) {
         let (tx, rx) = oneshot::channel();
         let produce = ProduceOperation {
             client: tx,
             op_id: op_id,
+            producer_id: producer_id,
             events: events
         };
         let op = Operation {
@@ -109,6 +191,78 @@ impl Operation {
         (op, rx)
     }
 
+    pub fn snapshot(connection_id: ConnectionId, dest_dir: PathBuf) -> (Operation, SnapshotResponseReceiver) {
+        let (tx, rx) = oneshot::channel();
+        let snapshot = SnapshotOperation {
+            client: tx,
+            dest_dir: dest_dir,
+        };
+        let op = Operation {
+            connection_id: connection_id,
+            client_message_recv_time: Instant::now(),
+            op_type: OpType::Snapshot(snapshot),
+        };
+        (op, rx)
+    }
+
+    pub fn delete(connection_id: ConnectionId, deletion: EventDeletion) -> (Operation, DeleteResponseReceiver) {
+        let (tx, rx) = oneshot::channel();
+        let delete = DeleteOperation {
+            client: tx,
+            deletion: deletion,
+        };
+        let op = Operation {
+            connection_id: connection_id,
+            client_message_recv_time: Instant::now(),
+            op_type: OpType::Delete(delete),
+        };
+        (op, rx)
+    }
+
+    pub fn prepare_transaction(connection_id: ConnectionId, op_id: u32, transaction_id: TransactionId, events: Vec<ProduceEvent>) -> (Operation, ProduceResponseReceiver) {
+        let (tx, rx) = oneshot::channel();
+        let prepare = PrepareTransactionOperation {
+            client: tx,
+            op_id: op_id,
+            transaction_id: transaction_id,
+            events: events,
+        };
+        let op = Operation {
+            connection_id: connection_id,
+            client_message_recv_time: Instant::now(),
+            op_type: OpType::PrepareTransaction(prepare),
+        };
+        (op, rx)
+    }
+
+    pub fn commit_transaction(connection_id: ConnectionId, transaction_id: TransactionId) -> (Operation, FinishTransactionResponseReceiver) {
+        let (tx, rx) = oneshot::channel();
+        let finish = FinishTransactionOperation {
+            client: tx,
+            transaction_id: transaction_id,
+        };
+        let op = Operation {
+            connection_id: connection_id,
+            client_message_recv_time: Instant::now(),
+            op_type: OpType::CommitTransaction(finish),
+        };
+        (op, rx)
+    }
+
+    pub fn abort_transaction(connection_id: ConnectionId, transaction_id: TransactionId) -> (Operation, FinishTransactionResponseReceiver) {
+        let (tx, rx) = oneshot::channel();
+        let finish = FinishTransactionOperation {
+            client: tx,
+            transaction_id: transaction_id,
+        };
+        let op = Operation {
+            connection_id: connection_id,
+            client_message_recv_time: Instant::now(),
+            op_type: OpType::AbortTransaction(finish),
+        };
+        (op, rx)
+    }
+
     pub fn tick() -> Operation {
         Operation {
             connection_id: 0,
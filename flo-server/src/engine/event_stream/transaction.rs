@@ -0,0 +1,25 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub use flo_storage::TransactionId;
+
+/// Hands out ids for the transactions that are committed to an event stream. Each partition records the id in its
+/// transaction log when it prepares its part of a transaction, and every transaction that a partition hasn't resolved is
+/// either committed or aborted when the server starts. The logs are rewritten without any committed transactions at
+/// that point, so ids only need to be unique until the server restarts.
+#[derive(Clone, Debug)]
+pub struct Transactions {
+    next_id: Arc<AtomicUsize>,
+}
+
+impl Transactions {
+    pub fn new() -> Transactions {
+        Transactions {
+            next_id: Arc::new(AtomicUsize::new(1)),
+        }
+    }
+
+    pub fn next_id(&self) -> TransactionId {
+        self.next_id.fetch_add(1, Ordering::SeqCst) as TransactionId
+    }
+}
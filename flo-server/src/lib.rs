@@ -1,6 +1,7 @@
 extern crate flo_event as event;
 extern crate flo_protocol as protocol;
 extern crate flo_client_lib;
+extern crate flo_storage;
 
 #[macro_use]
 extern crate log;
@@ -10,12 +11,9 @@ extern crate futures;
 
 extern crate tokio_core;
 extern crate chrono;
-extern crate glob;
-extern crate memmap;
 extern crate clap;
 extern crate log4rs;
 extern crate num_cpus;
-extern crate byteorder;
 
 
 #[cfg(test)]
@@ -30,6 +30,7 @@ use clap::{App, Arg, ArgMatches};
 use std::str::FromStr;
 use std::path::{PathBuf, Path};
 use server::{ServerOptions, MemoryLimit, MemoryUnit};
+use engine::event_stream::FsyncPolicy;
 use std::net::{SocketAddr, ToSocketAddrs};
 
 const FLO_VERSION: &'static str = env!("CARGO_PKG_VERSION");
@@ -92,6 +93,52 @@ fn app_args() -> App<'static, 'static> {
                     .long("max-io-threads")
                     .takes_value(true)
                     .help("The maximum number of threads to spawn for handling client connections. The actual number of threads used may be less"))
+            .arg(Arg::with_name("fsync")
+                    .long("fsync")
+                    .value_name("policy")
+                    .help("When to fsync newly written events to disk. One of 'always', 'millis:<n>' to fsync at most every n milliseconds, or 'bytes:<n>' to fsync after every n bytes written. Defaults to 'millis:1000'"))
+            .arg(Arg::with_name("max-partition-size")
+                    .long("max-partition-size")
+                    .value_name("megabytes")
+                    .help("The maximum size of each partition on disk. Once a partition exceeds this size, its oldest events will be dropped. If unspecified, then partitions may grow without limit"))
+            .arg(Arg::with_name("compact-namespaces")
+                    .long("compact-namespaces")
+                    .value_name("glob")
+                    .help("Periodically compact the event stream so that only the newest event is kept for each namespace matching the given glob. Use '/**/*' to compact the entire stream. If unspecified, then events are never compacted"))
+            .arg(Arg::with_name("compress-segments")
+                    .long("compress-segments")
+                    .help("Compress segments once they're no longer being written to. Compressed segments use much less disk space, at the cost of some CPU when reading older events"))
+            .arg(Arg::with_name("archive-dir")
+                    .long("archive-dir")
+                    .value_name("DIR")
+                    .help("Move segments to this directory instead of deleting them once they exceed the event retention period or partition size limits. Consumers can still read archived events, but more slowly. If unspecified, then old segments are deleted"))
+            .arg(Arg::with_name("archive-retention-days")
+                    .long("archive-retention-days")
+                    .requires("archive-dir")
+                    .value_name("days")
+                    .help("The number of days to keep archived segments, measured from the end of each segment. If unspecified, then archived segments are kept forever"))
+            .arg(Arg::with_name("encryption-key-file")
+                    .long("encryption-key-file")
+                    .value_name("FILE")
+                    .help("Encrypt new segments using the newest key in this file. Each line of the file has the form '<id>:<key>', where the id is a positive integer and the key is 32 bytes written as 64 hex characters. Keys are rotated by adding a new key with a greater id, and older keys must be kept for as long as any segment uses them. Encrypted segments are never compressed"))
+            .arg(Arg::with_name("restore-snapshot")
+                    .long("restore-snapshot")
+                    .value_name("DIR")
+                    .help("Copy the event streams from a snapshot that was created using 'flo-client snapshot' into the data directory before starting. The data directory must not already contain any of the event streams in the snapshot"))
+            .arg(Arg::with_name("max-partition-events")
+                    .long("max-partition-events")
+                    .value_name("count")
+                    .help("The maximum number of events to retain in each partition. Once a partition exceeds this number, its oldest events will be dropped. If unspecified, then partitions may grow without limit"))
+            .arg(Arg::with_name("dedupe-window")
+                    .long("dedupe-window")
+                    .value_name("count")
+                    .default_value("10000")
+                    .help("The number of recent events from idempotent producers that each partition remembers. An event that's re-sent with the same producer id and sequence number as one of these is acknowledged with the id of the original event instead of being appended again"))
+            .arg(Arg::with_name("max-in-flight-produces")
+                    .long("max-in-flight-produces")
+                    .value_name("count")
+                    .default_value("16")
+                    .help("The number of produce operations that each connection may have in progress at once. Producers can send more events without waiting for the previous ones to be acknowledged, and acks are always sent in the order that the events were received"))
 }
 
 fn main() {
@@ -128,6 +175,23 @@ fn main() {
     let default_eviction_period = ::std::cmp::min(retention_duration.num_hours() / 6, MAX_SEGMENT_PERIOD_HOURS);
     let eviction_period_hours = parse_arg_or_exit(&args, "eviction-period", default_eviction_period);
 
+    let fsync_policy = args.value_of("fsync").map(|value| {
+        value.parse::<FsyncPolicy>().or_bail()
+    }).unwrap_or(FsyncPolicy::default());
+
+    let max_partition_megabytes = parse_arg_or_exit(&args, "max-partition-size", ::std::usize::MAX);
+    let max_bytes_per_partition = max_partition_megabytes.saturating_mul(1024 * 1024);
+    let max_events_per_partition = parse_arg_or_exit(&args, "max-partition-events", ::std::u64::MAX);
+    let dedupe_window_events = parse_arg_or_exit(&args, "dedupe-window", 10_000usize);
+    let max_in_flight_produces = parse_arg_or_exit(&args, "max-in-flight-produces", 16usize);
+
+    let archive_retention_days = parse_arg_or_exit(&args, "archive-retention-days", ::std::i64::MAX);
+    let archive_retention_duration = if archive_retention_days == ::std::i64::MAX {
+        Duration::max_value()
+    } else {
+        Duration::days(archive_retention_days)
+    };
+
     let server_options = ServerOptions {
         event_retention_duration: retention_duration,
         event_eviction_period: Duration::hours(eviction_period_hours),
@@ -137,6 +201,17 @@ fn main() {
         cluster_addresses: cluster_addresses,
         actor_id: actor_id,
         max_io_threads: max_io_threads,
+        fsync_policy: fsync_policy,
+        max_bytes_per_partition: max_bytes_per_partition,
+        max_events_per_partition: max_events_per_partition,
+        compacted_namespaces: args.value_of("compact-namespaces").map(|glob| glob.to_owned()),
+        compress_sealed_segments: args.is_present("compress-segments"),
+        archive_dir: args.value_of("archive-dir").map(PathBuf::from),
+        archive_retention_duration: archive_retention_duration,
+        encryption_key_file: args.value_of("encryption-key-file").map(PathBuf::from),
+        restore_snapshot: args.value_of("restore-snapshot").map(PathBuf::from),
+        dedupe_window_events: dedupe_window_events,
+        max_in_flight_produces: max_in_flight_produces,
     };
 
     server_options.validate().or_bail();
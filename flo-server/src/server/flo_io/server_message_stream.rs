@@ -32,6 +32,13 @@ impl ServerMessageStream {
         }
     }
 
+    /// Stops accepting new messages for the client, but keeps writing the ones that were already sent. The future completes
+    /// once they've all been written, so that a client whose connection is being closed can see why.
+    pub fn finish_pending(mut self) -> ServerMessageStream {
+        self.server_receiver.close();
+        self
+    }
+
     fn needs_next_message(&self) -> bool {
         self.current_message.as_ref().map(|m| m.is_done()).unwrap_or(true)
     }
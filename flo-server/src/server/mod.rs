@@ -2,6 +2,7 @@ mod flo_io;
 mod server_options;
 
 use futures::{Stream, Sink, Future};
+use futures::future::{Either, ok};
 use tokio_core::net::{TcpStream, TcpListener};
 
 use event_loops;
@@ -21,7 +22,7 @@ pub fn run(options: ServerOptions) -> io::Result<()> {
                      system_stream_name,
                      create_client_channels,
                      ConnectionHandler};
-    use engine::event_stream::EventStreamOptions;
+    use engine::event_stream::{EventStreamOptions, ArchiveOptions, StorageBackend};
     use self::flo_io::{ProtocolMessageStream, ServerMessageStream};
 
     const ONE_GB: usize = 1024 * 1024 * 1024;
@@ -36,12 +37,28 @@ pub fn run(options: ServerOptions) -> io::Result<()> {
             event_retention: options.event_retention_duration,
             max_segment_duration: options.event_eviction_period,
             segment_max_size_bytes: ONE_GB,
+            max_bytes_per_partition: options.max_bytes_per_partition,
+            max_events_per_partition: options.max_events_per_partition,
+            compacted_namespaces: options.compacted_namespaces.clone(),
+            compress_sealed_segments: options.compress_sealed_segments,
+            archive: options.archive_dir.as_ref().map(|dir| {
+                ArchiveOptions {
+                    dir: dir.clone(),
+                    retention: options.archive_retention_duration,
+                }
+            }),
+            encryption_key_file: options.encryption_key_file.clone(),
+            fsync_policy: options.fsync_policy,
+            storage: StorageBackend::Mmap,
+            dedupe_window_events: options.dedupe_window_events,
         },
+        restore_snapshot: options.restore_snapshot.clone(),
     };
 
     let engine_ref = start_controller(controller_options, event_loop_handles.next_handle())?;
 
     let server_port = options.port;
+    let max_in_flight_produces = options.max_in_flight_produces;
     let address: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), server_port));
     let listener = ::std::net::TcpListener::bind(address)?;
 
@@ -81,18 +98,29 @@ pub fn run(options: ServerOptions) -> io::Result<()> {
                     connection_id,
                     client_tx.clone(),
                     client_engine_ref,
-                     client_handle.clone());
+                     client_handle.clone()).with_max_in_flight_produces(max_in_flight_produces);
 
                 let client_to_server = connection_handler
                         .send_all(client_message_stream)
                         .map(|_| ());
 
-                client_to_server.select(server_to_client).then(move |res| {
-                    if let Err((err, _)) = res {
-                        warn!("Closing connection: {} due to err: {:?}", connection_id, err);
-                    }
-                    info!("Closed connection_id: {} to address: {}", connection_id, client_addr);
-                    Ok(())
+                client_to_server.select2(server_to_client).then(move |res| {
+                    let remaining: Box<Future<Item=(), Error=io::Error>> = match res {
+                        Err(Either::A((err, server_to_client))) => {
+                            // the client may have been sent an error that explains why the connection is being closed
+                            warn!("Closing connection: {} due to err: {:?}", connection_id, err);
+                            Box::new(server_to_client.finish_pending())
+                        }
+                        Err(Either::B((err, _))) => {
+                            warn!("Closing connection: {} due to err: {:?}", connection_id, err);
+                            Box::new(ok(()))
+                        }
+                        Ok(_) => Box::new(ok(())),
+                    };
+                    remaining.then(move |_| {
+                        info!("Closed connection_id: {} to address: {}", connection_id, client_addr);
+                        Ok(())
+                    })
                 })
 
             });
@@ -3,6 +3,9 @@ use chrono::Duration;
 use std::net::SocketAddr;
 
 use event::ActorId;
+use engine::event_stream::FsyncPolicy;
+use engine::event_stream::partition::EventFilter;
+use flo_storage::KeyRing;
 
 
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -48,6 +51,21 @@ pub struct ServerOptions {
     pub cluster_addresses: Option<Vec<SocketAddr>>,
     pub actor_id: ActorId,
     pub max_io_threads: Option<usize>,
+    pub fsync_policy: FsyncPolicy,
+    pub max_bytes_per_partition: usize,
+    pub max_events_per_partition: u64,
+    pub compacted_namespaces: Option<String>,
+    pub compress_sealed_segments: bool,
+    pub archive_dir: Option<PathBuf>,
+    pub archive_retention_duration: Duration,
+    /// If set, then new segments are encrypted using the newest key in this file
+    pub encryption_key_file: Option<PathBuf>,
+    /// If set, then the event streams in this snapshot directory are copied into the data directory before the server starts
+    pub restore_snapshot: Option<PathBuf>,
+    /// The number of recent events from idempotent producers that each partition checks for retransmissions
+    pub dedupe_window_events: usize,
+    /// The number of produce operations that each connection may have in progress at once
+    pub max_in_flight_produces: usize,
 }
 
 
@@ -60,6 +78,22 @@ impl ServerOptions {
                                self.event_retention_duration.num_hours()));
         }
 
+        if self.max_bytes_per_partition == 0 || self.max_events_per_partition == 0 {
+            return Err(format!("Partition size limits must be greater than 0"));
+        }
+
+        if self.max_in_flight_produces == 0 {
+            return Err(format!("The maximum number of in-flight produce operations must be greater than 0"));
+        }
+
+        if let Some(ref glob) = self.compacted_namespaces {
+            EventFilter::parse(glob)?;
+        }
+
+        if let Some(ref key_file) = self.encryption_key_file {
+            KeyRing::read_key_file(key_file).map_err(|err| format!("{}", err))?;
+        }
+
         Ok(())
     }
 }
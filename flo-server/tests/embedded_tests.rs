@@ -16,7 +16,7 @@ use std::time::Duration;
 use tokio_core::reactor::Core;
 use futures::{Stream, Future};
 
-use flo_server::embedded::{EmbeddedFloServer, ControllerOptions, EventStreamOptions, run_embedded_server};
+use flo_server::embedded::{EmbeddedFloServer, ControllerOptions, EventStreamOptions, StorageBackend, run_embedded_server};
 
 use flo_client_lib::{VersionVector, FloEventId, Event, EventCounter, ActorId};
 use flo_client_lib::codec::{EventCodec, StringCodec};
@@ -30,9 +30,20 @@ fn codec() -> Box<EventCodec<EventData=String>> {
 }
 
 
+/// Runs the test once with events stored in segment files, and then again with the events stored only in memory
 fn integration_test<F>(test_name: &'static str, stream_opts: EventStreamOptions, fun: F) where F: Fn(EmbeddedFloServer, Core) {
+    for storage in vec![StorageBackend::Mmap, StorageBackend::Memory] {
+        let options = EventStreamOptions {
+            storage: storage,
+            ..stream_opts.clone()
+        };
+        integration_test_with_backend(test_name, options, &fun);
+    }
+}
+
+fn integration_test_with_backend<F>(test_name: &'static str, stream_opts: EventStreamOptions, fun: &F) where F: Fn(EmbeddedFloServer, Core) {
     let _ = env_logger::init();
-    println!("starting test: {}", test_name);
+    println!("starting test: {} with storage: {:?}", test_name, stream_opts.storage);
 
     let dir_name = test_name.replace("\\w", "-");
     let tmp_dir = tempdir::TempDir::new(&dir_name).expect("failed to create temp dir");
@@ -40,6 +51,7 @@ fn integration_test<F>(test_name: &'static str, stream_opts: EventStreamOptions,
     let controller_options = ControllerOptions {
         storage_dir: tmp_dir.path().to_owned(),
         default_stream_options: stream_opts,
+        restore_snapshot: None,
     };
     let reactor = Core::new().expect("failed to create reactor");
     let embedded_server = run_embedded_server(controller_options, reactor.remote()).expect("failed to run embedded server");
@@ -349,4 +361,71 @@ fn startup_embedded_server_and_connect_async_client() {
     });
 }
 
+#[test]
+fn idempotent_producer_gets_the_original_id_when_an_event_is_produced_again() {
+    use flo_client_lib::async::ops::EventToProduce;
+
+    integration_test("idempotent producer", default_test_options(), |server, mut reactor| {
+        let client = server.connect_client::<String>("idempotent producer".to_owned(), codec(), reactor.handle())
+                .with_producer_id(42);
+        let client = reactor.run(client.connect()).expect("failed to connect client");
+
+        let event = EventToProduce::new(1, "/foo/bar", None, "my data".to_owned()).with_sequence(1);
+        let (first_id, client) = run_future(&mut reactor, client.produce(event));
+        let event = EventToProduce::new(1, "/foo/bar", None, "my data".to_owned()).with_sequence(1);
+        let (second_id, client) = run_future(&mut reactor, client.produce(event));
+        assert_eq!(first_id, second_id);
+
+        let event = EventToProduce::new(1, "/foo/bar", None, "more data".to_owned()).with_sequence(2);
+        let (third_id, client) = run_future(&mut reactor, client.produce(event));
+        assert_eq!(FloEventId::new(1, 2), third_id);
 
+        let mut version_vec = VersionVector::new();
+        version_vec.set(FloEventId::new(1, 0));
+        let consume_future = client.consume("/foo/*", &version_vec, Some(2), false);
+        let events = run_future(&mut reactor, consume_future.collect());
+        let data = events.into_iter().map(|event| event.data).collect::<Vec<_>>();
+        assert_eq!(vec!["my data".to_owned(), "more data".to_owned()], data);
+    });
+}
+
+#[test]
+fn server_starts_with_the_events_from_a_restored_snapshot() {
+    let _ = env_logger::init();
+    let tempdir = tempdir::TempDir::new("server_restores_snapshot").unwrap();
+    let snapshot_dir = tempdir.path().join("snapshot");
+    let stream_options = || EventStreamOptions {
+        storage: StorageBackend::Mmap,
+        ..Default::default()
+    };
+    let mut reactor = Core::new().expect("failed to create reactor");
+
+    let original_server = run_embedded_server(ControllerOptions {
+        storage_dir: tempdir.path().join("original"),
+        default_stream_options: stream_options(),
+        restore_snapshot: None,
+    }, reactor.remote()).expect("failed to run original server");
+    let mut connection = original_server.connect_client::<String>("snapshotter".to_owned(), codec(), reactor.handle());
+    connection = reactor.run(connection.connect()).expect("failed to connect client");
+    for i in 0..3 {
+        let (_, c) = run_future(&mut reactor, connection.produce_to(1, "/foo", None, format!("event {}", i)));
+        connection = c;
+    }
+    let (_, connection) = run_future(&mut reactor, connection.snapshot(snapshot_dir.to_str().unwrap()));
+    // events produced after the snapshot must not show up in the restored server
+    run_future(&mut reactor, connection.produce_to(1, "/foo", None, "after snapshot".to_owned()));
+
+    let restored_server = run_embedded_server(ControllerOptions {
+        storage_dir: tempdir.path().join("restored"),
+        default_stream_options: stream_options(),
+        restore_snapshot: Some(snapshot_dir),
+    }, reactor.remote()).expect("failed to run restored server");
+    let mut connection = restored_server.connect_client::<String>("consumer".to_owned(), codec(), reactor.handle());
+    connection = reactor.run(connection.connect()).expect("failed to connect client");
+
+    let mut version_vec = VersionVector::new();
+    version_vec.set(FloEventId::new(1, 0));
+    let events = run_future(&mut reactor, connection.consume("/foo", &version_vec, None, false).collect());
+    let data = events.into_iter().map(|event| event.data).collect::<Vec<_>>();
+    assert_eq!(vec!["event 0".to_owned(), "event 1".to_owned(), "event 2".to_owned()], data);
+}
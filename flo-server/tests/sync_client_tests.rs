@@ -20,12 +20,7 @@ fn localhost(port: u16) -> SocketAddrV4 {
 }
 
 fn simple_event<N: Into<String>, D: Debug, E: Into<D>>(namespace: N, data: E) -> EventToProduce<D> {
-    EventToProduce {
-        partition: 1,
-        namespace: namespace.into(),
-        parent_id: None,
-        data: data.into()
-    }
+    EventToProduce::new(1, namespace, None, data.into())
 }
 
 fn test_with_server<F: FnOnce(u16)>(test_name: &'static str, flo_server_args: Vec<&str>, test_fun: F) {
@@ -118,6 +113,47 @@ fn produce_many_events_then_consume_them_in_batches() {
     });
 }
 
+#[test]
+fn produce_all_sends_events_in_batches_and_returns_the_id_of_each_one() {
+    test_with_server("produce all events in batches", Vec::new(), |port| {
+        let mut connection = SyncConnection::connect(localhost(port), "produceAll", StringCodec, None).expect("failed to connect");
+
+        // enough events that they won't all fit in a single batch
+        let event_count = 500;
+        let events = (0..event_count).map(|i| {
+            EventToProduce::new(1, "/foo", None, format!("event {} data", i))
+        });
+        let ids = connection.produce_all(events).expect("failed to produce events");
+        let expected_ids = (1..(event_count + 1)).map(|counter| FloEventId::new(1, counter)).collect::<Vec<_>>();
+        assert_eq!(expected_ids, ids);
+
+        let iter = connection.into_consumer("/*", &vv_from_start(), None, false);
+        let received = iter.map(|result| result.unwrap().data).collect::<Vec<_>>();
+        let expected_data = (0..event_count).map(|i| format!("event {} data", i)).collect::<Vec<_>>();
+        assert_eq!(expected_data, received);
+    });
+}
+
+#[test]
+fn produce_in_transaction_returns_the_id_of_each_event_once_committed() {
+    test_with_server("produce events in a transaction", Vec::new(), |port| {
+        let mut connection = SyncConnection::connect(localhost(port), "produceInTransaction", StringCodec, None).expect("failed to connect");
+
+        let events: Vec<EventToProduce<String>> = vec![
+            simple_event("/orders/123", "created"),
+            simple_event("/orders/123", "paid"),
+            simple_event("/orders/124", "created"),
+        ];
+        let ids = connection.produce_in_transaction(events).expect("failed to commit transaction");
+        let expected_ids = (1..4).map(|counter| FloEventId::new(1, counter)).collect::<Vec<_>>();
+        assert_eq!(expected_ids, ids);
+
+        let iter = connection.into_consumer("/orders/*", &vv_from_start(), None, false);
+        let received = iter.map(|result| result.unwrap().data).collect::<Vec<_>>();
+        assert_eq!(vec!["created".to_owned(), "paid".to_owned(), "created".to_owned()], received);
+    });
+}
+
 #[test]
 fn consumer_receives_events_as_they_are_produced() {
     test_with_server("receive events as they are produced", Vec::new(), |port| {
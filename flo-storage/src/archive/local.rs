@@ -0,0 +1,177 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, BigEndian};
+
+use event::time;
+use partition::{SegmentNum, DATA_FILE_EXTENSION};
+use segment::get_index_file;
+use super::{ArchiveStore, ArchivedSegment};
+
+/// Each archived segment has a small metadata file, which is written only after the rest of the segment's files
+const METADATA_FILE_EXTENSION: &'static str = "archived";
+
+// 8 each for the segment number, first counter, highest counter, end time, and size
+const METADATA_LEN: usize = 40;
+
+/// An `ArchiveStore` that keeps segments in a directory on the local filesystem, which would typically be on a larger
+/// and slower disk than the data directory. The files are stored using the same names as in the partition directory.
+#[derive(Debug)]
+pub struct LocalArchiveStore {
+    dir: PathBuf,
+}
+
+impl LocalArchiveStore {
+    pub fn new(dir: PathBuf) -> io::Result<LocalArchiveStore> {
+        fs::create_dir_all(&dir)?;
+        Ok(LocalArchiveStore {
+            dir: dir,
+        })
+    }
+
+    fn events_file(&self, segment_num: SegmentNum) -> PathBuf {
+        self.dir.join(format!("{}{}", segment_num.value(), DATA_FILE_EXTENSION))
+    }
+
+    fn metadata_file(&self, segment_num: SegmentNum) -> PathBuf {
+        self.events_file(segment_num).with_extension(METADATA_FILE_EXTENSION)
+    }
+}
+
+impl ArchiveStore for LocalArchiveStore {
+    fn store(&self, segment: &ArchivedSegment, events_file: &Path, index_file: &Path) -> io::Result<()> {
+        let archived_events_file = self.events_file(segment.segment_num);
+        copy_file(events_file, &archived_events_file)?;
+        let archived_index_file = get_index_file(&archived_events_file);
+        if index_file.exists() {
+            copy_file(index_file, &archived_index_file)?;
+        } else {
+            remove_if_exists(&archived_index_file)?;
+        }
+
+        let mut metadata = [0; METADATA_LEN];
+        BigEndian::write_u64(&mut metadata[0..8], segment.segment_num.value());
+        BigEndian::write_u64(&mut metadata[8..16], segment.first_counter);
+        BigEndian::write_u64(&mut metadata[16..24], segment.highest_counter);
+        BigEndian::write_u64(&mut metadata[24..32], time::millis_since_epoch(segment.end_time));
+        BigEndian::write_u64(&mut metadata[32..40], segment.size_bytes as u64);
+
+        let metadata_file = self.metadata_file(segment.segment_num);
+        let temp_file = metadata_file.with_extension("tmp");
+        {
+            let mut file = File::create(&temp_file)?;
+            file.write_all(&metadata)?;
+            file.sync_all()?;
+        }
+        fs::rename(&temp_file, &metadata_file)
+    }
+
+    fn fetch(&self, segment_num: SegmentNum, events_dest: &Path, index_dest: &Path) -> io::Result<()> {
+        copy_file(&self.events_file(segment_num), events_dest)?;
+        let archived_index_file = get_index_file(&self.events_file(segment_num));
+        if archived_index_file.exists() {
+            copy_file(&archived_index_file, index_dest)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, segment_num: SegmentNum) -> io::Result<()> {
+        // The metadata goes first, so that a partially removed segment is never listed
+        let events_file = self.events_file(segment_num);
+        remove_if_exists(&self.metadata_file(segment_num))?;
+        remove_if_exists(&get_index_file(&events_file))?;
+        remove_if_exists(&events_file)
+    }
+
+    fn list(&self) -> io::Result<Vec<ArchivedSegment>> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if !path.extension().map(|ext| ext == METADATA_FILE_EXTENSION).unwrap_or(false) {
+                continue;
+            }
+
+            let mut metadata = Vec::with_capacity(METADATA_LEN);
+            File::open(&path)?.read_to_end(&mut metadata)?;
+            if metadata.len() != METADATA_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid archive metadata file: {:?}", path)));
+            }
+            segments.push(ArchivedSegment {
+                segment_num: SegmentNum::new(BigEndian::read_u64(&metadata[0..8])),
+                first_counter: BigEndian::read_u64(&metadata[8..16]),
+                highest_counter: BigEndian::read_u64(&metadata[16..24]),
+                end_time: time::from_millis_since_epoch(BigEndian::read_u64(&metadata[24..32])),
+                size_bytes: BigEndian::read_u64(&metadata[32..40]) as usize,
+            });
+        }
+        segments.sort_by_key(|s| s.segment_num);
+        Ok(segments)
+    }
+}
+
+/// Copies the file and then syncs the copy to disk
+fn copy_file(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::copy(src, dst)?;
+    File::open(dst)?.sync_all()
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn archived(segment_num: u64) -> ArchivedSegment {
+        ArchivedSegment {
+            segment_num: SegmentNum::new(segment_num),
+            first_counter: segment_num * 10,
+            highest_counter: segment_num * 10 + 9,
+            end_time: time::from_millis_since_epoch(segment_num * 1000),
+            size_bytes: 1234,
+        }
+    }
+
+    fn write_file(path: &Path, contents: &[u8]) {
+        File::create(path).unwrap().write_all(contents).unwrap();
+    }
+
+    fn read_file(path: &Path) -> Vec<u8> {
+        let mut contents = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut contents).unwrap();
+        contents
+    }
+
+    #[test]
+    fn segments_are_stored_listed_fetched_and_removed() {
+        let src_dir = TempDir::new("local_archive_src").unwrap();
+        let archive_dir = TempDir::new("local_archive").unwrap();
+        let subject = LocalArchiveStore::new(archive_dir.path().join("stream/1")).expect("failed to create archive store");
+
+        for segment_num in vec![2, 1] {
+            let events_file = src_dir.path().join(format!("{}.events", segment_num));
+            write_file(&events_file, format!("events {}", segment_num).as_bytes());
+            write_file(&get_index_file(&events_file), format!("index {}", segment_num).as_bytes());
+            subject.store(&archived(segment_num), &events_file, &get_index_file(&events_file)).expect("failed to store segment");
+        }
+        assert_eq!(vec![archived(1), archived(2)], subject.list().unwrap());
+
+        let events_dest = src_dir.path().join("fetched.events");
+        let index_dest = src_dir.path().join("fetched.index");
+        subject.fetch(SegmentNum::new(2), &events_dest, &index_dest).expect("failed to fetch segment");
+        assert_eq!(b"events 2".to_vec(), read_file(&events_dest));
+        assert_eq!(b"index 2".to_vec(), read_file(&index_dest));
+
+        subject.remove(SegmentNum::new(1)).expect("failed to remove segment");
+        assert_eq!(vec![archived(2)], subject.list().unwrap());
+        assert!(!archive_dir.path().join("stream/1/1.events").exists());
+        assert!(subject.fetch(SegmentNum::new(1), &events_dest, &index_dest).is_err());
+    }
+}
@@ -0,0 +1,187 @@
+mod local;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use chrono::Duration;
+
+use event::{ActorId, EventCounter, Timestamp};
+use partition::{SegmentNum, ArchiveOptions, DATA_FILE_EXTENSION};
+use segment::{Segment, SegmentReader, KeyRing, get_index_file};
+use index::PartitionIndex;
+
+pub use self::local::LocalArchiveStore;
+
+/// Archived segments are copied into this directory within the partition directory when a consumer needs to read them
+const FETCH_DIR_NAME: &'static str = "fetched";
+
+/// Used to give every fetched copy of a segment a unique file name, since several consumers may be reading the same
+/// archived segment at once
+static FETCH_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Describes a segment that's been moved into an `ArchiveStore`
+#[derive(Debug, PartialEq, Clone)]
+pub struct ArchivedSegment {
+    pub segment_num: SegmentNum,
+    /// No event in the segment has a counter lower than this one
+    pub first_counter: EventCounter,
+    pub highest_counter: EventCounter,
+    pub end_time: Timestamp,
+    pub size_bytes: usize,
+}
+
+/// Long term storage for the segments of a single partition, which is slower and cheaper than the partition's data
+/// directory. Segments are moved into the archive once they would otherwise be dropped from the partition, and are
+/// copied back out on demand whenever a consumer reads that far back.
+pub trait ArchiveStore: Send + Sync {
+    /// Copies a sealed segment's events file, along with its index file if there is one, into the archive. The segment
+    /// must not be included in `list` until all of its files have been stored.
+    fn store(&self, segment: &ArchivedSegment, events_file: &Path, index_file: &Path) -> io::Result<()>;
+
+    /// Copies an archived segment's events file and index file to the given paths
+    fn fetch(&self, segment_num: SegmentNum, events_dest: &Path, index_dest: &Path) -> io::Result<()>;
+
+    /// Permanently deletes a segment from the archive
+    fn remove(&self, segment_num: SegmentNum) -> io::Result<()>;
+
+    /// Returns all the segments in the archive, ordered from oldest to newest
+    fn list(&self) -> io::Result<Vec<ArchivedSegment>>;
+}
+
+/// Opens the archive for a partition, if the partition is configured to use one. The keys are needed to read any archived
+/// segments that were encrypted.
+pub fn open_archive(options: Option<&ArchiveOptions>, partition_num: ActorId, partition_dir: &Path, keys: Option<KeyRing>) -> io::Result<Option<ArchiveRef>> {
+    let archive_options = match options {
+        Some(archive_options) => archive_options,
+        None => return Ok(None),
+    };
+
+    let store = LocalArchiveStore::new(archive_options.dir.clone())?;
+    ArchiveRef::open(Arc::new(store), partition_num, partition_dir.join(FETCH_DIR_NAME), archive_options.retention, keys).map(Some)
+}
+
+/// The archived segments of a partition. This is shared between the partition, which moves segments into the archive
+/// and expires them, and the readers for that partition, which fetch segments back out of it.
+#[derive(Clone)]
+pub struct ArchiveRef {
+    store: Arc<ArchiveStore>,
+    partition_num: ActorId,
+    fetch_dir: PathBuf,
+    retention: Duration,
+    keys: Option<KeyRing>,
+    /// ordered from oldest to newest
+    segments: Arc<RwLock<Vec<ArchivedSegment>>>,
+}
+
+impl ArchiveRef {
+    pub fn open(store: Arc<ArchiveStore>, partition_num: ActorId, fetch_dir: PathBuf, retention: Duration, keys: Option<KeyRing>) -> io::Result<ArchiveRef> {
+        // Any segments that were fetched before a restart are no longer being read
+        if fetch_dir.exists() {
+            fs::remove_dir_all(&fetch_dir)?;
+        }
+        fs::create_dir_all(&fetch_dir)?;
+
+        let segments = store.list()?;
+        debug!("Opened archive for partition: {} with {} segment(s)", partition_num, segments.len());
+        Ok(ArchiveRef {
+            store: store,
+            partition_num: partition_num,
+            fetch_dir: fetch_dir,
+            retention: retention,
+            keys: keys,
+            segments: Arc::new(RwLock::new(segments)),
+        })
+    }
+
+    pub fn contains(&self, segment_num: SegmentNum) -> bool {
+        self.segments.read().unwrap().iter().any(|s| s.segment_num == segment_num)
+    }
+
+    pub fn last_segment_num(&self) -> Option<SegmentNum> {
+        self.segments.read().unwrap().last().map(|s| s.segment_num)
+    }
+
+    pub fn greatest_event_counter(&self) -> EventCounter {
+        self.segments.read().unwrap().last().map(|s| s.highest_counter).unwrap_or(0)
+    }
+
+    /// Moves a segment into the archive. The segment is sealed first, if it isn't already. The local copy of the segment
+    /// is left alone, so it's up to the caller to delete it once it's been removed from the partition.
+    pub fn archive(&self, segment: &mut Segment) -> io::Result<()> {
+        segment.seal()?;
+        let archived = ArchivedSegment {
+            segment_num: segment.segment_num,
+            first_counter: segment.get_first_event_counter(),
+            highest_counter: segment.get_highest_event_counter(),
+            end_time: segment.get_end_time(),
+            size_bytes: segment.get_size_bytes(),
+        };
+        self.store.store(&archived, segment.file_path(), segment.index_file_path())?;
+        info!("Archived {} of partition: {} with highest event counter: {}", archived.segment_num, self.partition_num, archived.highest_counter);
+        self.segments.write().unwrap().push(archived);
+        Ok(())
+    }
+
+    /// Deletes the archived segments that have been kept for longer than the archive retention period, measured from
+    /// their end time. Segments are always removed from oldest to newest.
+    pub fn expire_segments(&self, now: Timestamp) -> io::Result<()> {
+        let retention = self.retention;
+        let mut segments = self.segments.write().unwrap();
+        while segments.first().map(|s| now > s.end_time && now - s.end_time > retention).unwrap_or(false) {
+            let segment_num = segments[0].segment_num;
+            self.store.remove(segment_num)?;
+            info!("Removed expired {} from the archive for partition: {}", segment_num, self.partition_num);
+            segments.remove(0);
+        }
+        Ok(())
+    }
+
+    /// Returns the first archived segment that contains any events with a counter greater than `start_exclusive`
+    pub fn find_segment_after_counter(&self, start_exclusive: EventCounter) -> Option<SegmentNum> {
+        self.segments.read().unwrap().iter().find(|s| s.highest_counter > start_exclusive).map(|s| s.segment_num)
+    }
+
+    /// Fetches the first archived segment that comes after `previous`, returning a reader starting at the beginning of
+    /// it. Returns `None` if there are no archived segments after `previous`.
+    pub fn get_next_segment(&self, previous: SegmentNum) -> Option<SegmentReader> {
+        let next = self.segments.read().unwrap().iter().find(|s| s.segment_num > previous).cloned();
+        next.and_then(|archived| {
+            self.fetch(&archived, 0).map_err(|err| {
+                error!("Failed to fetch archived {} for partition: {}: {:?}", archived.segment_num, self.partition_num, err);
+            }).ok()
+        })
+    }
+
+    /// Fetches an archived segment, and returns a reader that's positioned at the first event with a counter greater
+    /// than `start_exclusive`
+    pub fn fetch_from(&self, segment_num: SegmentNum, start_exclusive: EventCounter) -> io::Result<SegmentReader> {
+        let archived = self.segments.read().unwrap().iter().find(|s| s.segment_num == segment_num).cloned();
+        let archived = archived.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{} is not in the archive for partition: {}", segment_num, self.partition_num))
+        })?;
+        self.fetch(&archived, start_exclusive)
+    }
+
+    fn fetch(&self, archived: &ArchivedSegment, start_exclusive: EventCounter) -> io::Result<SegmentReader> {
+        let fetch_num = FETCH_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let events_file = self.fetch_dir.join(format!("{}-{}{}", archived.segment_num.value(), fetch_num, DATA_FILE_EXTENSION));
+        let index_file = get_index_file(&events_file);
+        debug!("Fetching archived {} for partition: {} into {:?}", archived.segment_num, self.partition_num, events_file);
+        self.store.fetch(archived.segment_num, &events_file, &index_file)?;
+
+        let mut index = PartitionIndex::starting_at(self.partition_num, archived.first_counter);
+        let mut segment = Segment::init_from_existing_file(&events_file, archived.segment_num, 0, self.keys.as_ref(), &mut index)?;
+        let mut reader = segment.iter_from_start();
+        if let Some(entry) = index.get_next_entry(start_exclusive) {
+            reader.set_offset(entry.file_offset);
+        } else {
+            reader.set_offset_to_end();
+        }
+        // The fetched copy is only needed for as long as something is reading from it
+        segment.delete_on_drop();
+        Ok(reader)
+    }
+}
@@ -0,0 +1,99 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use event::ActorId;
+
+pub fn get_event_steam_data_dir(server_storage_dir: &Path, event_stream_name: &str) -> PathBuf {
+    server_storage_dir.join(event_stream_name)
+}
+
+pub fn get_partition_data_dir(event_stream_dir: &Path, partition_num: ActorId) -> PathBuf {
+    event_stream_dir.join(format!("{}", partition_num))
+}
+
+//TODO: Just save a file that contains the state of all the event streams and their partition directories instead of trying to figure it out based on conventions
+pub fn determine_existing_partition_dirs(event_stream_dir: &Path) -> io::Result<Vec<ActorId>> {
+    let files = ::std::fs::read_dir(event_stream_dir)?;
+    let mut partition_numbers = Vec::with_capacity(files.size_hint().0);
+    for entry_result in files {
+        let dir_entry = entry_result?;
+        if dir_entry.file_type()?.is_dir() {
+            let partition_number = dir_entry.file_name().into_string().ok().and_then(|name| {
+                name.parse::<ActorId>().ok()
+            });
+
+            if let Some(partition) = partition_number {
+                partition_numbers.push(partition);
+            }
+        }
+    }
+    // this sort isn't really important, but it just makes partition initialization follow a deterministic order to make debugging easier
+    partition_numbers.sort();
+    Ok(partition_numbers)
+}
+
+/// Copies every event stream in a snapshot directory written by `Partition::snapshot` into `server_storage_dir`, so that
+/// the server will start with the events from the snapshot. The files are copied rather than linked, so that the snapshot
+/// remains intact once the server starts appending to it. Each event stream is copied into a temporary directory first,
+/// and only renamed into place once all of its partitions have been copied, so a failed restore never leaves behind a
+/// partial event stream for the server to open. Returns an error without copying anything if any of the event streams in
+/// the snapshot already exist in `server_storage_dir`. Returns the names of the event streams that were restored.
+pub fn restore_snapshot(snapshot_dir: &Path, server_storage_dir: &Path) -> io::Result<Vec<String>> {
+    let mut event_streams = Vec::new();
+    for entry in ::std::fs::read_dir(snapshot_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let name = entry.file_name().into_string().map_err(|name| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Invalid event stream directory name: {:?}", name))
+            })?;
+            if get_event_steam_data_dir(server_storage_dir, &name).exists() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                          format!("Cannot restore event stream: '{}' because it already exists in {:?}", name, server_storage_dir)));
+            }
+            event_streams.push(name);
+        }
+    }
+    event_streams.sort();
+
+    for name in event_streams.iter() {
+        let snapshot_stream_dir = get_event_steam_data_dir(snapshot_dir, name);
+        let stream_dir = get_event_steam_data_dir(server_storage_dir, name);
+        let temp_dir = get_restore_temp_dir(server_storage_dir, name);
+        if temp_dir.exists() {
+            warn!("Removing incomplete restore of event stream: '{}' at {:?}", name, temp_dir);
+            ::std::fs::remove_dir_all(&temp_dir)?;
+        }
+
+        if let Err(err) = copy_event_stream(&snapshot_stream_dir, &temp_dir, name) {
+            if let Err(remove_err) = ::std::fs::remove_dir_all(&temp_dir) {
+                error!("Failed to remove incomplete restore of event stream: '{}' at {:?}: {:?}", name, temp_dir, remove_err);
+            }
+            return Err(err);
+        }
+        ::std::fs::rename(&temp_dir, &stream_dir)?;
+    }
+    Ok(event_streams)
+}
+
+/// The directory that an event stream is copied into while it's being restored. The name starts with a '.', so it can
+/// never be the same as the directory of an event stream
+fn get_restore_temp_dir(server_storage_dir: &Path, event_stream_name: &str) -> PathBuf {
+    server_storage_dir.join(format!(".{}.restoring", event_stream_name))
+}
+
+fn copy_event_stream(snapshot_stream_dir: &Path, dest_stream_dir: &Path, name: &str) -> io::Result<()> {
+    ::std::fs::create_dir_all(dest_stream_dir)?;
+    for partition_num in determine_existing_partition_dirs(snapshot_stream_dir)? {
+        let source_dir = get_partition_data_dir(snapshot_stream_dir, partition_num);
+        let dest_dir = get_partition_data_dir(dest_stream_dir, partition_num);
+        ::std::fs::create_dir_all(&dest_dir)?;
+        for file in ::std::fs::read_dir(&source_dir)? {
+            let file = file?;
+            if file.file_type()?.is_file() {
+                ::std::fs::copy(file.path(), dest_dir.join(file.file_name()))?;
+            }
+        }
+        debug!("Restored partition: {} of event stream: '{}' from {:?}", partition_num, name, source_dir);
+    }
+    Ok(())
+}
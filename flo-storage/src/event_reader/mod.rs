@@ -2,14 +2,16 @@ mod namespace;
 
 use std::io;
 
-use event::{FloEvent, ActorId};
+use event::{FloEvent, ActorId, Timestamp};
 
-use engine::ConnectionId;
-use engine::event_stream::partition::{SharedReaderRefs, SegmentNum};
-use engine::event_stream::partition::segment::{SegmentReader, PersistentEvent};
+use partition::{SharedReaderRefs, SegmentNum};
+use segment::{SegmentReader, PersistentEvent};
 
 pub use self::namespace::NamespaceGlob;
 
+/// Identifies the connection that a reader belongs to. This is only used for logging
+pub type ConnectionId = usize;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum EventFilter {
     All,
@@ -40,6 +42,9 @@ pub struct PartitionReader {
     filter: EventFilter,
     current_segment_reader: Option<SegmentReader>,
     segment_readers_ref: SharedReaderRefs,
+    min_event_time: Option<Timestamp>,
+    /// an event that couldn't be returned yet because it comes after the start of an uncommitted transaction
+    held_back: Option<PersistentEvent>,
     returned_error: bool,
 }
 
@@ -53,26 +58,52 @@ impl PartitionReader {
             filter: filter,
             current_segment_reader: current_reader,
             segment_readers_ref: segment_refs,
+            min_event_time: None,
+            held_back: None,
             returned_error: false,
         }
     }
 
+    /// Returns this reader, changed to skip any events whose event time is before the given time. Event times are
+    /// supplied by producers and are not ordered, so this filters events rather than changing where the reader starts.
+    /// Events that don't have an event time are compared using their timestamp instead.
+    pub fn with_min_event_time(mut self, min_event_time: Timestamp) -> PartitionReader {
+        self.min_event_time = Some(min_event_time);
+        self
+    }
+
+    /// Returns the next event that matches the filter, or `None` if there are no more events that can be read right now.
+    /// Once the reader gets to an event from a transaction that hasn't been committed yet, it returns `None` until the
+    /// transaction is resolved, so that events are still returned in order.
     pub fn next_matching(&mut self) -> Option<io::Result<PersistentEvent>> {
         let mut next = self.read_next();
         while self.should_skip(&next) {
             next = self.read_next();
         }
+        if let Some(Ok(event)) = next {
+            if self.segment_readers_ref.is_uncommitted(&event) {
+                self.held_back = Some(event);
+                return None;
+            }
+            return Some(Ok(event));
+        }
         next
     }
 
     fn should_skip(&self, result: &Option<Result<PersistentEvent, io::Error>>) -> bool {
         if let Some(Ok(ref event)) = *result {
-            !self.filter.matches(event)
+            !self.filter.matches(event) || self.is_before_min_event_time(event) || self.segment_readers_ref.is_deleted(event)
         } else {
             false
         }
     }
 
+    fn is_before_min_event_time(&self, event: &PersistentEvent) -> bool {
+        self.min_event_time.map(|min| {
+            event.event_time().unwrap_or_else(|| event.timestamp()) < min
+        }).unwrap_or(false)
+    }
+
     fn current_reader_is_exhausted(&self) -> bool {
         self.current_segment_reader.as_ref().map(|reader| {
             reader.is_exhausted()
@@ -80,18 +111,21 @@ impl PartitionReader {
     }
 
     fn current_reader_segment_id(&self) -> u64 {
-        self.current_segment_reader.as_ref().map(|r| r.segment_id.0).unwrap_or(0)
+        self.current_segment_reader.as_ref().map(|r| r.segment_id.value()).unwrap_or(0)
     }
 
     fn read_next(&mut self) -> Option<io::Result<PersistentEvent>> {
+        if let Some(event) = self.held_back.take() {
+            return Some(Ok(event));
+        }
         if self.returned_error {
             return None;
         }
 
         if self.current_reader_is_exhausted() {
             let current_segment_id = self.current_reader_segment_id();
-            if let Some(next_segment) = self.segment_readers_ref.get_next_segment(SegmentNum(current_segment_id)) {
-                if next_segment.segment_id.0 - current_segment_id > 1 {
+            if let Some(next_segment) = self.segment_readers_ref.get_next_segment(SegmentNum::new(current_segment_id)) {
+                if next_segment.segment_id.value() - current_segment_id > 1 {
                     warn!("Consumer for connection_id: {} skipped from {} to {}", self.connection_id, current_segment_id, next_segment.segment_id);
                 } else {
                     debug!("Advanced segment for connection_id: {} to {}", self.connection_id, next_segment.segment_id);
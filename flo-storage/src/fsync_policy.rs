@@ -0,0 +1,101 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+
+/// Determines when a partition will fsync newly appended events to disk. The fsync always happens _before_ the
+/// producer is sent an `EventAck`, so a producer that receives an ack knows that the event is durable according to
+/// this policy. Events are always written to the page cache immediately, regardless of the policy.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FsyncPolicy {
+    /// fsync after every produce operation
+    Always,
+    /// fsync before acknowledging a produce operation if at least this many milliseconds have elapsed since the last fsync.
+    /// Partitions also check this on every tick, so the last events appended to an idle partition still get synced.
+    EveryMillis(u64),
+    /// fsync before acknowledging a produce operation if at least this many bytes have been written since the last fsync
+    EveryBytes(usize),
+}
+
+impl Default for FsyncPolicy {
+    fn default() -> Self {
+        FsyncPolicy::EveryMillis(1000)
+    }
+}
+
+impl FsyncPolicy {
+    /// Returns true if an fsync is required, given the time elapsed and the number of bytes written since the last one.
+    pub fn requires_fsync(&self, since_last_fsync: Duration, unsynced_bytes: usize) -> bool {
+        if unsynced_bytes == 0 {
+            return false;
+        }
+
+        match *self {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::EveryMillis(millis) => {
+                let elapsed_millis = since_last_fsync.as_secs() * 1000 + (since_last_fsync.subsec_nanos() / 1_000_000) as u64;
+                elapsed_millis >= millis
+            }
+            FsyncPolicy::EveryBytes(bytes) => unsynced_bytes >= bytes,
+        }
+    }
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    /// Parses a policy from either `always`, `millis:<n>`, or `bytes:<n>`
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        if input == "always" {
+            return Ok(FsyncPolicy::Always);
+        }
+
+        let mut parts = input.splitn(2, ':');
+        let kind = parts.next().unwrap_or("");
+        let amount = parts.next().and_then(|value| value.parse::<u64>().ok());
+
+        match (kind, amount) {
+            ("millis", Some(millis)) => Ok(FsyncPolicy::EveryMillis(millis)),
+            ("bytes", Some(bytes)) => Ok(FsyncPolicy::EveryBytes(bytes as usize)),
+            _ => Err(format!("Invalid fsync policy: '{}', must be one of 'always', 'millis:<n>', or 'bytes:<n>'", input))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn always_requires_fsync_whenever_bytes_have_been_written() {
+        let subject = FsyncPolicy::Always;
+        assert!(subject.requires_fsync(Duration::from_millis(0), 1));
+        assert!(!subject.requires_fsync(Duration::from_millis(5000), 0));
+    }
+
+    #[test]
+    fn every_millis_requires_fsync_once_the_interval_has_elapsed() {
+        let subject = FsyncPolicy::EveryMillis(200);
+        assert!(!subject.requires_fsync(Duration::from_millis(199), 9999));
+        assert!(subject.requires_fsync(Duration::from_millis(200), 1));
+        assert!(subject.requires_fsync(Duration::new(3, 0), 1));
+    }
+
+    #[test]
+    fn every_bytes_requires_fsync_once_the_threshold_is_reached() {
+        let subject = FsyncPolicy::EveryBytes(4096);
+        assert!(!subject.requires_fsync(Duration::new(999, 0), 4095));
+        assert!(subject.requires_fsync(Duration::from_millis(0), 4096));
+    }
+
+    #[test]
+    fn policies_are_parsed_from_strings() {
+        assert_eq!(Ok(FsyncPolicy::Always), "always".parse::<FsyncPolicy>());
+        assert_eq!(Ok(FsyncPolicy::EveryMillis(250)), "millis:250".parse::<FsyncPolicy>());
+        assert_eq!(Ok(FsyncPolicy::EveryBytes(65536)), "bytes:65536".parse::<FsyncPolicy>());
+        assert!("millis".parse::<FsyncPolicy>().is_err());
+        assert!("bytes:lots".parse::<FsyncPolicy>().is_err());
+        assert!("sometimes".parse::<FsyncPolicy>().is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use event::{EventCounter, ActorId};
-use super::SegmentNum;
+use partition::SegmentNum;
 
 
 #[derive(Debug, PartialEq, Clone)]
@@ -41,6 +41,14 @@ impl PartitionIndex {
         PartitionIndex::with_capacity(partition_num, 1024)
     }
 
+    /// Creates an empty index for events with counters of at least `lowest_counter`. This avoids allocating entries for all
+    /// of the events before it, which matters when only a single segment from far into the partition is being indexed.
+    pub fn starting_at(partition_num: ActorId, lowest_counter: EventCounter) -> PartitionIndex {
+        let mut index = PartitionIndex::new(partition_num);
+        index.lowest_counter = lowest_counter;
+        index
+    }
+
     fn with_capacity(partition_num: ActorId, capacity: usize) -> PartitionIndex {
         assert!(capacity.is_power_of_two());
         PartitionIndex {
@@ -83,6 +91,17 @@ impl PartitionIndex {
         }
     }
 
+    /// Removes the entry for a single event, leaving a gap in its place. This is used when an event has been compacted
+    /// away, so the entries for the events around it are unaffected.
+    pub fn remove_entry(&mut self, counter: EventCounter) {
+        if counter < self.lowest_counter || counter > self.highest_counter {
+            return;
+        }
+        if let Some(index) = self.get_read_index(counter) {
+            self.entries[index] = InternalEntry::default();
+        }
+    }
+
     pub fn get_next_entry(&self, start_exclusive: EventCounter) -> Option<IndexEntry> {
         let maybe_index = self.get_read_index(start_exclusive + 1);
         if maybe_index.is_none() {
@@ -244,6 +263,30 @@ mod test {
         assert_eq!(Some(entry(2)), result);
     }
 
+    #[test]
+    fn remove_entry_leaves_a_gap_for_a_single_event() {
+        let mut index = PartitionIndex::new(5);
+        index.append(entry(1));
+        index.append(entry(2));
+        index.append(entry(3));
+
+        index.remove_entry(2);
+        index.remove_entry(99);
+        assert_next_counter_equals(&index, 0, 1);
+        assert_next_counter_equals(&index, 1, 3);
+    }
+
+    #[test]
+    fn index_starting_at_a_high_counter_does_not_allocate_entries_for_lower_counters() {
+        let mut index = PartitionIndex::starting_at(5, 1_000_000);
+        for counter in 1_000_000..1_000_010 {
+            index.append(entry(counter));
+        }
+        assert_eq!(1024, index.cap());
+        assert_next_counter_equals(&index, 0, 1_000_000);
+        assert_next_counter_equals(&index, 1_000_004, 1_000_005);
+    }
+
     #[test]
     fn remove_range_removes_inclusive_range() {
         let mut index = PartitionIndex::new(5);
@@ -349,7 +392,7 @@ mod test {
     }
 
     fn entry(counter: EventCounter) -> IndexEntry {
-        IndexEntry::new(counter, SegmentNum(2), counter as usize)
+        IndexEntry::new(counter, SegmentNum::new(2), counter as usize)
     }
 
 }
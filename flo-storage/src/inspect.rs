@@ -0,0 +1,6 @@
+//! Read-only access to the segment files in a partition directory. This is meant for tools that examine a data directory
+//! while the server isn't running, so none of it goes through a `Partition`.
+
+pub use segment::{SegmentHeader, PersistentEvent, SegmentInspection, CorruptTail, KeyRing, inspect_segment_file, truncate_corrupt_tail};
+pub use partition::{SegmentFile, get_segment_files};
+pub use data_dir::{get_event_steam_data_dir, get_partition_data_dir, determine_existing_partition_dirs};
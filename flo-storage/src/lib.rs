@@ -0,0 +1,44 @@
+//! The storage engine for flo event streams. A `Partition` owns a directory of segment files, and provides a synchronous
+//! api for appending events to them and reading them back. Everything here is independent of the server's networking and
+//! threading, so a partition can be used directly by anything that needs to read or write flo's on-disk format. A
+//! `MemoryPartition` provides the same api without touching the filesystem, and both implement `PartitionStorage`.
+
+extern crate flo_event as event;
+
+#[macro_use]
+extern crate log;
+
+extern crate chrono;
+extern crate glob;
+extern crate memmap;
+extern crate byteorder;
+extern crate crc;
+extern crate snap;
+extern crate libc;
+extern crate ring;
+
+#[cfg(test)]
+extern crate env_logger;
+#[cfg(test)]
+extern crate tempdir;
+
+mod segment;
+mod index;
+mod event_reader;
+mod archive;
+mod partition;
+mod data_dir;
+mod highest_counter;
+mod fsync_policy;
+mod tombstone;
+mod transaction;
+mod namespace_heads;
+pub mod inspect;
+
+pub use partition::{Partition, MemoryPartition, PartitionStorage, PartitionOptions, ArchiveOptions, NewEvent, SegmentNum, PartitionSnapshot, EventDeletion};
+pub use event_reader::{PartitionReader, EventFilter, NamespaceGlob, ConnectionId};
+pub use segment::{PersistentEvent, KeyRing, KeyId, EncryptionKey, is_checksum_error};
+pub use data_dir::{get_event_steam_data_dir, get_partition_data_dir, determine_existing_partition_dirs, restore_snapshot};
+pub use highest_counter::HighestCounter;
+pub use fsync_policy::FsyncPolicy;
+pub use transaction::{TransactionId, resolve_transactions};
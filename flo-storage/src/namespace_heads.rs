@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+use crc::crc32::checksum_ieee;
+
+use event::EventCounter;
+use tombstone::{Tombstones, BufferReader};
+
+const NAMESPACE_HEADS_FILE_NAME: &'static str = "namespace_heads";
+const NAMESPACE_HEADS_FILE_MARKER: &'static [u8] = b"FLO_NSH\n";
+const CHECKSUM_LEN: usize = 4;
+
+/// The counter of the newest event in each namespace of a partition, counting only the events up through
+/// `through_counter`. A partition writes this to a file whenever it seals a segment, so that the heads can be found after
+/// a restart by reading only the events that were appended since then. Heads are kept even after their events expire or
+/// are dropped from the partition, since the namespace still had those events.
+#[derive(Debug, PartialEq, Clone)]
+pub struct NamespaceHeads {
+    through_counter: EventCounter,
+    heads: HashMap<String, EventCounter>,
+}
+
+impl NamespaceHeads {
+    pub fn new() -> NamespaceHeads {
+        NamespaceHeads {
+            through_counter: 0,
+            heads: HashMap::new(),
+        }
+    }
+
+    pub fn through_counter(&self) -> EventCounter {
+        self.through_counter
+    }
+
+    pub fn heads(&self) -> &HashMap<String, EventCounter> {
+        &self.heads
+    }
+
+    pub fn into_heads(self) -> HashMap<String, EventCounter> {
+        self.heads
+    }
+
+    /// Records an event, which becomes the head of its namespace unless the namespace already has a newer one
+    pub fn set(&mut self, namespace: &str, counter: EventCounter) {
+        if let Some(head) = self.heads.get_mut(namespace) {
+            if *head < counter {
+                *head = counter;
+            }
+            return;
+        }
+        self.heads.insert(namespace.to_owned(), counter);
+    }
+
+    /// Marks every event up through the given counter as having been recorded
+    pub fn set_through_counter(&mut self, counter: EventCounter) {
+        self.through_counter = counter;
+    }
+
+    /// Removes the heads whose events have been deleted. Returns the namespaces that may still have older events, which
+    /// need to be read again to find their new heads. Namespaces that were deleted as a whole have no events left.
+    pub fn remove_deleted(&mut self, tombstones: &Tombstones) -> Vec<String> {
+        let deleted = self.heads.iter().filter(|&(namespace, counter)| {
+            tombstones.contains(*counter, namespace)
+        }).map(|(namespace, counter)| (namespace.to_owned(), *counter)).collect::<Vec<_>>();
+
+        let mut remaining = Vec::new();
+        for (namespace, counter) in deleted {
+            self.heads.remove(&namespace);
+            if !tombstones.is_namespace_deleted(counter, &namespace) {
+                remaining.push(namespace);
+            }
+        }
+        remaining
+    }
+}
+
+pub fn get_namespace_heads_file(partition_dir: &Path) -> PathBuf {
+    partition_dir.join(NAMESPACE_HEADS_FILE_NAME)
+}
+
+/// Writes the namespace heads for a partition. The data is written to a temporary file first and then renamed, so that a
+/// partially written file never replaces a complete one.
+pub fn write_namespace_heads_file(path: &Path, heads: &NamespaceHeads) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(NAMESPACE_HEADS_FILE_MARKER);
+    buffer.write_u64::<BigEndian>(heads.through_counter)?;
+    buffer.write_u64::<BigEndian>(heads.heads.len() as u64)?;
+    for (namespace, counter) in heads.heads.iter() {
+        buffer.write_u64::<BigEndian>(*counter)?;
+        buffer.write_u32::<BigEndian>(namespace.len() as u32)?;
+        buffer.extend_from_slice(namespace.as_bytes());
+    }
+    let checksum = checksum_ieee(&buffer);
+    buffer.write_u32::<BigEndian>(checksum)?;
+
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, path)
+}
+
+/// Reads the namespace heads file at the given path. Returns empty heads, which don't include any events, if there is no
+/// file, and an error if the file is corrupted.
+pub fn read_namespace_heads_file(path: &Path) -> io::Result<NamespaceHeads> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(NamespaceHeads::new()),
+        Err(err) => return Err(err),
+    };
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    parse_namespace_heads(&buffer).map_err(|description| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("Invalid namespace heads file: {:?}: {}", path, description))
+    })
+}
+
+fn parse_namespace_heads(buffer: &[u8]) -> Result<NamespaceHeads, String> {
+    if buffer.len() < NAMESPACE_HEADS_FILE_MARKER.len() + CHECKSUM_LEN || &buffer[..NAMESPACE_HEADS_FILE_MARKER.len()] != NAMESPACE_HEADS_FILE_MARKER {
+        return Err("missing namespace heads file marker".to_owned());
+    }
+    let data_end = buffer.len() - CHECKSUM_LEN;
+    if checksum_ieee(&buffer[..data_end]) != BigEndian::read_u32(&buffer[data_end..]) {
+        return Err("checksum mismatch".to_owned());
+    }
+
+    let mut reader = BufferReader::new(&buffer[..data_end], NAMESPACE_HEADS_FILE_MARKER.len());
+    let mut heads = NamespaceHeads::new();
+    heads.through_counter = reader.read_u64()?;
+    let head_count = reader.read_u64()?;
+    for _ in 0..head_count {
+        let counter = reader.read_u64()?;
+        let namespace_len = reader.read_u32()? as usize;
+        let namespace = String::from_utf8(reader.read_bytes(namespace_len)?.to_vec()).map_err(|err| {
+            format!("invalid namespace: {}", err)
+        })?;
+        heads.heads.insert(namespace, counter);
+    }
+    Ok(heads)
+}
+
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn deleted_heads_are_removed_and_namespaces_with_older_events_are_returned() {
+        let mut subject = NamespaceHeads::new();
+        subject.set("/carts/1", 3);
+        subject.set("/carts/1", 2);
+        subject.set("/carts/2", 4);
+        subject.set("/users/1", 5);
+        assert_eq!(Some(&3), subject.heads().get("/carts/1"));
+
+        let mut tombstones = Tombstones::new();
+        tombstones.add_counter(3);
+        tombstones.add_namespace("/users/*", 5).expect("failed to add namespace");
+        assert_eq!(vec!["/carts/1".to_owned()], subject.remove_deleted(&tombstones));
+        assert_eq!(1, subject.heads().len());
+        assert_eq!(Some(&4), subject.heads().get("/carts/2"));
+    }
+
+    #[test]
+    fn namespace_heads_are_written_and_read_back() {
+        let tempdir = TempDir::new("namespace_heads_are_written_and_read_back").unwrap();
+        let path = get_namespace_heads_file(tempdir.path());
+        assert_eq!(NamespaceHeads::new(), read_namespace_heads_file(&path).expect("failed to read missing file"));
+
+        let mut heads = NamespaceHeads::new();
+        heads.set("/carts/1", 8);
+        heads.set("/carts/2", 9);
+        heads.set_through_counter(12);
+        write_namespace_heads_file(&path, &heads).expect("failed to write namespace heads");
+        assert_eq!(heads, read_namespace_heads_file(&path).expect("failed to read namespace heads"));
+
+        let mut bytes = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes[10] ^= 0xFF;
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, read_namespace_heads_file(&path).unwrap_err().kind());
+    }
+}
@@ -0,0 +1,104 @@
+use std::collections::{HashMap, HashSet};
+
+use event::{FloEvent, EventCounter};
+use event_reader::EventFilter;
+use super::SegmentNum;
+
+/// Keeps track of which segments hold events that compaction would remove, so that each tick only needs to rewrite those
+/// segments instead of reading through the whole partition. A segment is marked as compactable when a newer event is
+/// appended to one of its compacted namespaces, and all segments are marked when events are deleted.
+pub struct CompactionTracker {
+    filter: Option<EventFilter>,
+    /// the newest event for each namespace that matches the filter, along with the segment that it was appended to
+    latest_events: HashMap<String, (EventCounter, SegmentNum)>,
+    compactable_segments: HashSet<SegmentNum>,
+}
+
+impl CompactionTracker {
+    pub fn new(filter: Option<EventFilter>) -> CompactionTracker {
+        CompactionTracker {
+            filter: filter,
+            latest_events: HashMap::new(),
+            compactable_segments: HashSet::new(),
+        }
+    }
+
+    pub fn has_compactable_segments(&self) -> bool {
+        !self.compactable_segments.is_empty()
+    }
+
+    pub fn is_compactable(&self, segment: SegmentNum) -> bool {
+        self.compactable_segments.contains(&segment)
+    }
+
+    /// Records an event that was appended to (or read back from) the given segment. If it supersedes an older event, then
+    /// the segment holding the older event is marked as compactable.
+    pub fn event_appended<E: FloEvent>(&mut self, event: &E, segment: SegmentNum) {
+        let matches = self.filter.as_ref().map(|filter| filter.matches(event)).unwrap_or(false);
+        if !matches {
+            return;
+        }
+        let counter = event.id().event_counter;
+        if let Some(&(previous_counter, previous_segment)) = self.latest_events.get(event.namespace()) {
+            if previous_counter > counter {
+                return;
+            }
+            self.compactable_segments.insert(previous_segment);
+        }
+        self.latest_events.insert(event.namespace().to_owned(), (counter, segment));
+    }
+
+    /// Marks every one of the given segments as compactable, since any of them might hold events that were just deleted
+    pub fn events_deleted<I: Iterator<Item=SegmentNum>>(&mut self, segments: I) {
+        self.compactable_segments.extend(segments);
+    }
+
+    /// Returns true if the event has been superseded by a newer event in the same compacted namespace
+    pub fn is_superseded<E: FloEvent>(&self, event: &E) -> bool {
+        self.filter.as_ref().map(|filter| {
+            filter.matches(event) && self.latest_events.get(event.namespace()).map(|&(latest, _)| {
+                latest > event.id().event_counter
+            }).unwrap_or(false)
+        }).unwrap_or(false)
+    }
+
+    /// Called once a segment has been compacted or dropped, since it no longer holds anything that can be removed
+    pub fn segment_removed(&mut self, segment: SegmentNum) {
+        self.compactable_segments.remove(&segment);
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::{OwnedFloEvent, FloEventId, time};
+
+    fn event(counter: EventCounter, namespace: &str) -> OwnedFloEvent {
+        OwnedFloEvent::new(FloEventId::new(1, counter), None, time::now(), namespace.to_owned(), Vec::new())
+    }
+
+    #[test]
+    fn only_segments_holding_superseded_events_are_compactable() {
+        let mut subject = CompactionTracker::new(Some(EventFilter::parse("/a/*").unwrap()));
+        subject.event_appended(&event(1, "/a/1"), SegmentNum::new(1));
+        subject.event_appended(&event(2, "/b/1"), SegmentNum::new(1));
+        subject.event_appended(&event(3, "/b/1"), SegmentNum::new(2));
+        assert!(!subject.has_compactable_segments());
+
+        subject.event_appended(&event(4, "/a/2"), SegmentNum::new(2));
+        subject.event_appended(&event(5, "/a/1"), SegmentNum::new(3));
+        assert!(subject.is_compactable(SegmentNum::new(1)));
+        assert!(!subject.is_compactable(SegmentNum::new(2)));
+        assert!(subject.is_superseded(&event(1, "/a/1")));
+        assert!(!subject.is_superseded(&event(5, "/a/1")));
+        assert!(!subject.is_superseded(&event(2, "/b/1")));
+
+        subject.segment_removed(SegmentNum::new(1));
+        assert!(!subject.has_compactable_segments());
+
+        subject.events_deleted(vec![SegmentNum::new(2), SegmentNum::new(3)].into_iter());
+        assert!(subject.is_compactable(SegmentNum::new(2)));
+        assert!(subject.is_compactable(SegmentNum::new(3)));
+    }
+}
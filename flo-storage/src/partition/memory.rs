@@ -0,0 +1,454 @@
+use std::io;
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+
+use chrono::Duration;
+
+use event::{ActorId, FloEventId, EventCounter, FloEvent, Timestamp, ProducerSequence};
+use event::time::Clock;
+use event_reader::{PartitionReader, EventFilter, ConnectionId};
+use segment::{MemorySegment, SegmentHeader, Segment, PersistentEvent, AppendResult};
+use index::{PartitionIndex, IndexEntry};
+use highest_counter::HighestCounter;
+use tombstone::{Tombstones, get_tombstone_file, write_tombstone_file};
+use transaction::{TransactionState, TransactionId, get_transaction_log_file, write_transaction_log};
+use namespace_heads::NamespaceHeads;
+use super::{SharedReaderRefsMut, PartitionOptions, PartitionSnapshot, EventDeletion, NewEvent, SegmentNum, EventToProduce, get_segment_files, add_tombstones, add_deleted_sequences, transaction_not_prepared};
+
+/// A partition that keeps all of its events in memory instead of in a directory of segment files. Events are stored in the
+/// same format and read using the same `PartitionReader` as a `Partition`, and segments are still dropped according to
+/// the retention period and size limits. Nothing survives once the partition is dropped, though, so this is mostly
+/// useful for tests and for embedded servers that don't need their events to be durable. Compaction, compression,
+/// archiving, and encryption are not supported, and those options are ignored. Deleted events and the events of aborted
+/// transactions are skipped by readers, but they remain in memory until their segment is dropped.
+pub struct MemoryPartition {
+    partition_num: ActorId,
+    max_segment_size: usize,
+    max_segment_duration: Duration,
+    event_retention: Duration,
+    max_bytes: usize,
+    max_events: u64,
+    /// ordered from newest to oldest, so the front segment is the one being appended to
+    segments: VecDeque<MemorySegment>,
+    /// the number of the most recently created segment, so that numbers are never reused after segments are dropped
+    newest_segment_num: SegmentNum,
+    index: PartitionIndex,
+    tombstones: Tombstones,
+    transactions: TransactionState,
+    event_stream_highest_counter: HighestCounter,
+    clock: Box<Clock>,
+    reader_refs: SharedReaderRefsMut,
+}
+
+impl MemoryPartition {
+
+    pub fn new(partition_num: ActorId, options: &PartitionOptions, highest_counter: HighestCounter, clock: Box<Clock>) -> MemoryPartition {
+        if options.compacted_namespaces.is_some() || options.compress_sealed_segments || options.archive.is_some() || options.encryption_key_file.is_some() {
+            warn!("partition: {} is stored in memory, so compaction, compression, archive, and encryption options will be ignored", partition_num);
+        }
+        debug!("Initialized in-memory partition: {} with options: {:?}", partition_num, options);
+
+        MemoryPartition {
+            partition_num: partition_num,
+            max_segment_size: options.segment_max_size_bytes,
+            max_segment_duration: options.max_segment_duration,
+            event_retention: options.event_retention,
+            max_bytes: options.max_bytes,
+            max_events: options.max_events,
+            segments: VecDeque::with_capacity(4),
+            newest_segment_num: SegmentNum::default(),
+            index: PartitionIndex::new(partition_num),
+            tombstones: Tombstones::new(),
+            transactions: TransactionState::new(),
+            event_stream_highest_counter: highest_counter,
+            clock: clock,
+            reader_refs: SharedReaderRefsMut::new(None),
+        }
+    }
+
+    pub fn partition_num(&self) -> ActorId {
+        self.partition_num
+    }
+
+    pub fn greatest_event_counter(&self) -> EventCounter {
+        self.index.greatest_event_counter()
+    }
+
+    /// Drops any segments that have expired or that put the partition over its size limits
+    pub fn tick(&mut self) {
+        self.expire_old_events();
+        self.drop_segments_over_size_limit();
+    }
+
+    /// The newest segment is never dropped, since it's the one that events are being appended to. Once it expires, its
+    /// events are hidden from readers, and it gets dropped after a newer segment has been created.
+    fn expire_old_events(&mut self) {
+        let now = self.clock.now();
+        let retention = self.event_retention;
+        let droppable_count = self.segments.len().saturating_sub(1);
+        let expired_count = self.segments.iter().rev().take(droppable_count).take_while(|segment| segment.is_expired(now, retention)).count();
+        if expired_count > 0 {
+            self.drop_oldest_segments(expired_count);
+        }
+
+        if let Some(active_segment) = self.segments.front() {
+            if active_segment.is_expired(now, retention) && self.reader_refs.contains(active_segment.segment_num) {
+                self.reader_refs.remove_through(active_segment.segment_num);
+                if active_segment.get_event_count() > 0 {
+                    self.index.remove_through(active_segment.get_highest_event_counter());
+                }
+            }
+        }
+    }
+
+    /// Drops the oldest segments until the partition is back within its limits on the total number of bytes and events.
+    /// The newest segment is never dropped, since it's the one that events are being appended to.
+    fn drop_segments_over_size_limit(&mut self) {
+        let mut total_bytes: usize = self.segments.iter().map(|s| s.get_size_bytes()).sum();
+        let mut total_events: u64 = self.segments.iter().map(|s| s.get_event_count()).sum();
+        let droppable_count = self.segments.len().saturating_sub(1);
+
+        let mut drop_count = 0;
+        for segment in self.segments.iter().rev().take(droppable_count) {
+            if total_bytes <= self.max_bytes && total_events <= self.max_events {
+                break;
+            }
+            total_bytes -= segment.get_size_bytes();
+            total_events -= segment.get_event_count();
+            drop_count += 1;
+        }
+
+        if drop_count > 0 {
+            info!("partition: {} is over its size limit, dropping the oldest {} segment(s). Remaining size: {} bytes, {} events",
+                  self.partition_num, drop_count, total_bytes, total_events);
+            self.drop_oldest_segments(drop_count);
+        }
+    }
+
+    /// Readers that are still using a dropped segment keep its memory alive until they're done with it
+    fn drop_oldest_segments(&mut self, count: usize) {
+        for _ in 0..count {
+            if let Some(segment) = self.segments.pop_back() {
+                info!("Removing in-memory Segment: {:?} with highest_event counter: {}", segment.segment_num, segment.get_highest_event_counter());
+                self.reader_refs.remove_through(segment.segment_num);
+                if segment.get_event_count() > 0 {
+                    self.index.remove_through(segment.get_highest_event_counter());
+                }
+            }
+        }
+    }
+
+    /// Appends all of the events to the partition, and returns the id of the last one. The events are visible to every
+    /// reader as soon as this returns.
+    pub fn append_all(&mut self, events: Vec<NewEvent>) -> io::Result<FloEventId> {
+        let event_count = events.len();
+        let new_highest = self.event_stream_highest_counter.increment_and_get(event_count as u64);
+
+        let timestamp = self.clock.now();
+        let mut event_counter = new_highest - event_count as u64;
+        for new_event in events {
+            event_counter += 1;
+            let event = EventToProduce {
+                id: FloEventId::new(self.partition_num, event_counter),
+                ts: timestamp,
+                event: new_event,
+            };
+            self.append(&event)?;
+        }
+        debug!("in-memory partition: {} finished appending {} events ending with counter: {}", self.partition_num, event_count, event_counter);
+        self.drop_segments_over_size_limit();
+        Ok(FloEventId::new(self.partition_num, event_counter))
+    }
+
+    fn append(&mut self, event: &EventToProduce) -> io::Result<()> {
+        let event_len = PersistentEvent::get_repr_length(event) as usize;
+        if event_len > Segment::max_event_size(self.max_segment_size, false) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Event {} is {} bytes, which is larger than the max segment size of {} bytes",
+                                              event.id(), event_len, self.max_segment_size)));
+        }
+
+        if let Some(segment) = self.segments.front_mut() {
+            match segment.append(event) {
+                AppendResult::Success(offset) => {
+                    self.index.append(IndexEntry::new(event.id().event_counter, segment.segment_num, offset));
+                    return Ok(());
+                }
+                AppendResult::IoError(kind) => {
+                    return Err(kind.into());
+                }
+                other @ _ => {
+                    debug!("Event {} does not fit into {:?} due to: {:?}", event.id(), segment.segment_num, other);
+                }
+            }
+        }
+
+        let segment_num = self.newest_segment_num.next();
+        let segment_create_time = self.clock.now();
+        let header = SegmentHeader::new(self.partition_num,
+                                        event.id().event_counter,
+                                        segment_create_time,
+                                        segment_create_time + self.max_segment_duration);
+        let mut new_segment = MemorySegment::new(segment_num, self.max_segment_size, header)?;
+        let offset = match new_segment.append(event) {
+            AppendResult::Success(offset) => offset,
+            AppendResult::IoError(kind) => return Err(kind.into()),
+            other @ _ => {
+                error!("Event {} can't fit into new segment: {:?} due to: {:?}", event.id(), segment_num, other);
+                return Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", other)));
+            }
+        };
+        self.reader_refs.add(new_segment.range_iter(0));
+        self.segments.push_front(new_segment);
+        self.newest_segment_num = segment_num;
+        self.index.append(IndexEntry::new(event.id().event_counter, segment_num, offset));
+        Ok(())
+    }
+
+    /// There's nothing to flush for an in-memory partition, so this always succeeds right away
+    pub fn fsync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Marks events as deleted, so that readers stop returning them right away. See `Partition::delete`
+    pub fn delete(&mut self, deletion: EventDeletion) -> io::Result<()> {
+        let head = self.greatest_event_counter();
+        let mut tombstones = self.tombstones.clone();
+        add_tombstones(&mut tombstones, deletion, head)?;
+        add_deleted_sequences(&mut tombstones, &self.tombstones, self.segments.iter().rev().map(|segment| segment.iter_from_start()))?;
+        self.reader_refs.set_tombstones(tombstones.clone());
+        self.tombstones = tombstones;
+        Ok(())
+    }
+
+    /// Returns the ids and producer sequences of the deleted events with counters greater than `start_exclusive`, in order
+    pub fn deleted_producer_sequences(&self, start_exclusive: EventCounter) -> Vec<(FloEventId, ProducerSequence)> {
+        let partition_num = self.partition_num;
+        self.tombstones.sequences_after(start_exclusive).into_iter().map(|(counter, producer_sequence)| {
+            (FloEventId::new(partition_num, counter), producer_sequence)
+        }).collect()
+    }
+
+    /// Returns the counter of the newest event in each namespace, leaving out events that have been deleted or that belong
+    /// to a transaction that hasn't been committed yet. Nothing is persisted, so every event in memory gets read.
+    pub fn namespace_heads(&self) -> io::Result<HashMap<String, EventCounter>> {
+        let mut heads = NamespaceHeads::new();
+        for segment in self.segments.iter().rev() {
+            for result in segment.iter_from_start() {
+                let event = result?;
+                let removed = self.tombstones.is_deleted(&event) || self.transactions.is_aborted(&event) || self.transactions.is_pending(&event);
+                if !removed {
+                    heads.set(event.namespace(), event.id().event_counter);
+                }
+            }
+        }
+        Ok(heads.into_heads())
+    }
+
+    /// Appends the events for one partition of a transaction, which readers won't see until it's committed. See
+    /// `Partition::prepare_transaction`
+    pub fn prepare_transaction(&mut self, transaction_id: TransactionId, events: Vec<NewEvent>) -> io::Result<FloEventId> {
+        let after_counter = self.greatest_event_counter();
+        self.transactions.prepare(transaction_id, after_counter, events.len() as u64);
+        self.reader_refs.set_transactions(self.transactions.clone());
+        match self.append_all(events) {
+            Ok(id) => {
+                self.transactions.prepared_through(transaction_id, id.event_counter);
+                Ok(id)
+            }
+            Err(err) => {
+                warn!("in-memory partition: {} failed to append events for transaction: {}, so it will be aborted: {:?}", self.partition_num, transaction_id, err);
+                self.abort_transaction(transaction_id)?;
+                Err(err)
+            }
+        }
+    }
+
+    /// Lets readers continue on to the events of a prepared transaction
+    pub fn commit_transaction(&mut self, transaction_id: TransactionId) -> io::Result<()> {
+        if !self.transactions.commit(transaction_id) {
+            return Err(transaction_not_prepared(self.partition_num, transaction_id));
+        }
+        self.reader_refs.set_transactions(self.transactions.clone());
+        Ok(())
+    }
+
+    /// Makes readers skip the events of a prepared transaction
+    pub fn abort_transaction(&mut self, transaction_id: TransactionId) -> io::Result<()> {
+        let head = self.greatest_event_counter();
+        if self.transactions.abort(transaction_id, head).is_none() {
+            return Err(transaction_not_prepared(self.partition_num, transaction_id));
+        }
+        self.reader_refs.set_transactions(self.transactions.clone());
+        Ok(())
+    }
+
+    /// Writes every event in the partition into segment files in `dest_dir`, which can later be opened as a regular
+    /// `Partition` using `init_existing`. Returns an error if `dest_dir` already contains segment files.
+    pub fn snapshot(&mut self, dest_dir: &Path) -> io::Result<PartitionSnapshot> {
+        ::std::fs::create_dir_all(dest_dir)?;
+        if !get_segment_files(dest_dir)?.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{:?} already contains segment files", dest_dir)));
+        }
+
+        let mut active_segment_offset = 0;
+        for segment in self.segments.iter().rev() {
+            active_segment_offset = segment.write_to(dest_dir)?;
+        }
+        if !self.tombstones.is_empty() {
+            write_tombstone_file(&get_tombstone_file(dest_dir), &self.tombstones)?;
+        }
+        let transaction_records = self.transactions.to_records();
+        if !transaction_records.is_empty() {
+            write_transaction_log(&get_transaction_log_file(dest_dir), &transaction_records)?;
+        }
+        let snapshot = PartitionSnapshot {
+            partition_num: self.partition_num,
+            head: self.index.greatest_event_counter(),
+            active_segment_offset: active_segment_offset,
+            segment_count: self.segments.len(),
+        };
+        info!("Created snapshot of in-memory partition: {} in {:?}: {:?}", self.partition_num, dest_dir, snapshot);
+        Ok(snapshot)
+    }
+
+    /// Returns the exclusive starting counter for a consumer that wants to read all the events with a timestamp greater
+    /// than or equal to `since`
+    pub fn get_start_counter_since(&self, since: Timestamp) -> io::Result<EventCounter> {
+        for segment in self.segments.iter().rev() {
+            if let Some(counter) = segment.find_first_event_since(since)? {
+                return Ok(counter - 1);
+            }
+        }
+        Ok(self.index.greatest_event_counter())
+    }
+
+    /// Returns the exclusive starting counter for a consumer that wants to read all the events with an event time greater
+    /// than or equal to `since`, same as `Partition::get_start_counter_since_event_time`
+    pub fn get_start_counter_since_event_time(&self, since: Timestamp) -> io::Result<EventCounter> {
+        for segment in self.segments.iter().rev() {
+            if segment.may_have_event_time_since(since) {
+                return Ok(segment.get_first_event_counter().saturating_sub(1));
+            }
+        }
+        Ok(self.index.greatest_event_counter())
+    }
+
+    /// Returns a reader that starts with the first event after `start_exclusive`, same as `Partition::create_reader`
+    pub fn create_reader(&self, connection_id: ConnectionId, filter: EventFilter, start_exclusive: EventCounter) -> PartitionReader {
+        let current_segment_num = self.segments.front().map(|s| s.segment_num).unwrap_or(SegmentNum(0));
+        let readers = self.reader_refs.get_reader_refs();
+
+        let current_segment = match self.index.get_next_entry(start_exclusive) {
+            Some(entry) => {
+                readers.get_next_segment(entry.segment.previous()).map(|mut segment| {
+                    segment.set_offset(entry.file_offset);
+                    segment
+                })
+            }
+            None => {
+                let mut reader = readers.get_segment(current_segment_num);
+                reader.as_mut().map(|r| r.set_offset_to_end());
+                reader
+            }
+        };
+
+        PartitionReader::new(connection_id, self.partition_num, filter, current_segment, self.reader_refs.get_reader_refs())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use tempdir::TempDir;
+
+    use super::*;
+    use partition::Partition;
+    use event::time::{self, SystemClock, ManualClock};
+
+    const PARTITION_NUM: ActorId = 1;
+    const CONNECTION: ConnectionId = 55;
+
+    #[test]
+    fn events_are_read_back_from_memory_and_old_segments_are_dropped() {
+        let _ = ::env_logger::init();
+        // each event is 61 bytes, so 4 of them fit into a segment
+        let options = PartitionOptions {
+            segment_max_size_bytes: 300,
+            max_events: 10,
+            event_retention: Duration::seconds(20),
+            max_segment_duration: Duration::seconds(5),
+            ..Default::default()
+        };
+        let clock = ManualClock::new(time::now());
+        let mut partition = MemoryPartition::new(PARTITION_NUM, &options, HighestCounter::zero(), Box::new(clock.clone()));
+
+        let events = (0..9).map(|_| NewEvent::new("/foo/bar", None, "x")).collect();
+        let id = partition.append_all(events).expect("failed to append events");
+        assert_eq!(FloEventId::new(PARTITION_NUM, 9), id);
+        assert_eq!(3, partition.segments.len());
+        assert_eq!((1..10).collect::<Vec<_>>(), read_counters(&partition, 0));
+        assert_eq!(vec![7, 8, 9], read_counters(&partition, 6));
+
+        let events = (0..3).map(|_| NewEvent::new("/foo/bar", None, "x")).collect();
+        partition.append_all(events).expect("failed to append events");
+        assert_eq!((5..13).collect::<Vec<_>>(), read_counters(&partition, 0));
+
+        // the newest segment is kept after it expires, but its events are no longer readable
+        clock.advance(Duration::seconds(26));
+        partition.tick();
+        assert_eq!(1, partition.segments.len());
+        assert_eq!(Vec::<EventCounter>::new(), read_counters(&partition, 0));
+
+        partition.append_all(vec![NewEvent::new("/foo/bar", None, "x")]).expect("failed to append events");
+        assert_eq!(SegmentNum(4), partition.segments.front().unwrap().segment_num);
+        partition.tick();
+        assert_eq!(vec![13], read_counters(&partition, 0));
+    }
+
+    #[test]
+    fn snapshot_of_memory_partition_can_be_opened_as_a_regular_partition() {
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("memory_partition_snapshot").unwrap();
+        let options = PartitionOptions {
+            segment_max_size_bytes: 300,
+            ..Default::default()
+        };
+        let mut partition = MemoryPartition::new(PARTITION_NUM, &options, HighestCounter::zero(), Box::new(SystemClock));
+        let events = (0..6).map(|_| NewEvent::new("/foo/bar", None, "x")).collect();
+        partition.append_all(events).expect("failed to append events");
+
+        let snapshot = partition.snapshot(tempdir.path()).expect("failed to snapshot partition");
+        assert_eq!(6, snapshot.head);
+        assert_eq!(2, snapshot.segment_count);
+        assert_eq!(SegmentHeader::get_repr_length() + 2 * 61, snapshot.active_segment_offset);
+
+        let restored = Partition::init_existing(PARTITION_NUM,
+                                                tempdir.path().to_owned(),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).expect("failed to init partition");
+        let counters = restored.create_reader(CONNECTION, EventFilter::All, 0).map(|result| {
+            result.expect("failed to read event").id().event_counter
+        }).collect::<Vec<_>>();
+        assert_eq!((1..7).collect::<Vec<_>>(), counters);
+    }
+
+    #[test]
+    fn events_of_a_transaction_are_hidden_until_it_is_committed() {
+        let mut partition = MemoryPartition::new(PARTITION_NUM, &PartitionOptions::default(), HighestCounter::zero(), Box::new(SystemClock));
+        partition.prepare_transaction(1, vec![NewEvent::new("/foo/bar", None, "x")]).expect("failed to prepare transaction");
+        partition.prepare_transaction(2, vec![NewEvent::new("/foo/bar", None, "x")]).expect("failed to prepare transaction");
+        assert_eq!(Vec::<EventCounter>::new(), read_counters(&partition, 0));
+
+        partition.abort_transaction(1).expect("failed to abort transaction");
+        partition.commit_transaction(2).expect("failed to commit transaction");
+        assert_eq!(vec![2], read_counters(&partition, 0));
+    }
+
+    fn read_counters(partition: &MemoryPartition, start_exclusive: EventCounter) -> Vec<EventCounter> {
+        partition.create_reader(CONNECTION, EventFilter::All, start_exclusive).map(|result| {
+            result.expect("failed to read event").id().event_counter
+        }).collect()
+    }
+}
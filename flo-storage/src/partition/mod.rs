@@ -0,0 +1,2154 @@
+mod util;
+mod memory;
+mod storage;
+mod compaction;
+
+use std::io;
+use std::fmt::{self, Debug, Display};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration as StdDuration;
+
+use chrono::Duration;
+
+use event::{ActorId, FloEventId, EventCounter, FloEvent, Timestamp, EventHeader, ProducerSequence};
+use event::time::Clock;
+use event_reader::{PartitionReader, EventFilter, ConnectionId};
+use segment::{Segment, SegmentHeader, SegmentReader, PersistentEvent, KeyRing};
+use index::{PartitionIndex, IndexEntry};
+use archive::{ArchiveRef, open_archive};
+use highest_counter::HighestCounter;
+use fsync_policy::FsyncPolicy;
+use tombstone::{Tombstones, get_tombstone_file, read_tombstone_file, write_tombstone_file};
+use transaction::{TransactionState, TransactionRecord, TransactionLog, TransactionId, get_transaction_log_file, read_transaction_log, write_transaction_log};
+use namespace_heads::{NamespaceHeads, get_namespace_heads_file, read_namespace_heads_file, write_namespace_heads_file};
+use self::compaction::CompactionTracker;
+use self::util::{remove_incomplete_segment_file, remove_incomplete_temp_files, remove_archived_segment_files, migrate_segment_files};
+
+pub use self::util::{SegmentFile, get_segment_files};
+pub use self::memory::MemoryPartition;
+pub use self::storage::PartitionStorage;
+
+
+pub const DATA_FILE_EXTENSION: &'static str = ".events";
+
+pub fn get_events_file(partition_dir: &Path, segment_num: SegmentNum) -> PathBuf {
+    let filename = format!("{}{}", segment_num.0, DATA_FILE_EXTENSION);
+    partition_dir.join(filename)
+}
+
+
+/// A 1-based monotonically incrementing counter used to identify segments.
+/// SegmentNums CANNOT BE 0! Since these are used all over in rather memory-sensitive areas, we sometimes
+/// use 0 as a sentinal value to indicate the lack of a segment;
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SegmentNum(u64);
+
+impl SegmentNum {
+    pub fn new(value: u64) -> SegmentNum {
+        SegmentNum(value)
+    }
+
+    /// returns true if this segment is non-zero
+    pub fn is_set(&self) -> bool {
+        self.0 > 0
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
+    pub fn next(&self) -> SegmentNum {
+        SegmentNum(self.0 + 1)
+    }
+
+    pub fn previous(&self) -> SegmentNum {
+        // TODO: think about this
+        SegmentNum(self.0.saturating_sub(1))
+    }
+}
+
+impl Display for SegmentNum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SegmentNum({})", self.0)
+    }
+}
+
+#[derive(Clone)]
+pub struct SharedReaderRefsMut {
+    inner: Arc<RwLock<VecDeque<SegmentReader>>>,
+    archive: Option<ArchiveRef>,
+    tombstones: Arc<RwLock<Tombstones>>,
+    transactions: Arc<RwLock<TransactionState>>,
+}
+
+impl SharedReaderRefsMut {
+    pub fn new(archive: Option<ArchiveRef>) -> SharedReaderRefsMut {
+        SharedReaderRefsMut::with_capacity(4, archive)
+    }
+
+    pub fn with_capacity(init_capacity: usize, archive: Option<ArchiveRef>) -> SharedReaderRefsMut {
+        SharedReaderRefsMut {
+            inner: Arc::new(RwLock::new(VecDeque::with_capacity(init_capacity))),
+            archive: archive,
+            tombstones: Arc::new(RwLock::new(Tombstones::new())),
+            transactions: Arc::new(RwLock::new(TransactionState::new())),
+        }
+    }
+
+    /// Replaces the tombstones that every reader checks before returning an event
+    pub fn set_tombstones(&self, tombstones: Tombstones) {
+        let mut locked = self.tombstones.write().unwrap();
+        *locked = tombstones;
+    }
+
+    /// Replaces the transactions that every reader checks before returning an event
+    pub fn set_transactions(&self, transactions: TransactionState) {
+        let mut locked = self.transactions.write().unwrap();
+        *locked = transactions;
+    }
+
+    pub fn add(&self, reader: SegmentReader) {
+        let mut locked = self.inner.write().unwrap();
+        locked.push_back(reader);
+    }
+
+    /// Replaces the reader for a segment whose file has been rewritten. Anything that already has a reader for the previous
+    /// version of the segment can keep using it.
+    pub fn replace(&self, reader: SegmentReader) {
+        let mut locked = self.inner.write().unwrap();
+        if let Some(existing) = locked.iter_mut().find(|r| r.segment_id == reader.segment_id) {
+            *existing = reader;
+        }
+    }
+
+    /// Returns true if new readers are still able to read the given segment
+    pub fn contains(&self, segment: SegmentNum) -> bool {
+        self.inner.read().unwrap().iter().any(|r| r.segment_id == segment)
+    }
+
+    pub fn remove_through(&self, segment: SegmentNum) {
+        let mut locked = self.inner.write().unwrap();
+        while locked.front().map(|r| r.segment_id <= segment).unwrap_or(false) {
+            let removed = locked.pop_front().unwrap();
+            debug!("removing: {} from shared reader refs", removed.segment_id);
+        }
+    }
+
+    pub fn get_reader_refs(&self) -> SharedReaderRefs {
+        SharedReaderRefs {
+            inner: self.inner.clone(),
+            archive: self.archive.clone(),
+            tombstones: self.tombstones.clone(),
+            transactions: self.transactions.clone(),
+        }
+    }
+}
+
+pub struct SharedReaderRefs {
+    inner: Arc<RwLock<VecDeque<SegmentReader>>>,
+    archive: Option<ArchiveRef>,
+    tombstones: Arc<RwLock<Tombstones>>,
+    transactions: Arc<RwLock<TransactionState>>,
+}
+
+impl Debug for SharedReaderRefs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedReaderRefs(").and_then(|()| {
+            ::std::fmt::Pointer::fmt(&self.inner, f).and_then(|()| {
+                write!(f, ")")
+            })
+        })
+    }
+}
+
+impl SharedReaderRefs {
+    /// Returns true if the event has been deleted from the partition or belongs to an aborted transaction, even if it
+    /// hasn't yet been removed from its segment
+    pub fn is_deleted(&self, event: &PersistentEvent) -> bool {
+        self.tombstones.read().unwrap().is_deleted(event) || self.transactions.read().unwrap().is_aborted(event)
+    }
+
+    /// Returns true if the event can't be read yet because a transaction that it may belong to hasn't been committed
+    pub fn is_uncommitted(&self, event: &PersistentEvent) -> bool {
+        self.transactions.read().unwrap().is_uncommitted(event)
+    }
+
+    pub fn get_next_segment(&self, previous: SegmentNum) -> Option<SegmentReader> {
+        // Archived segments are always older than the ones still in the partition, so a reader that's working its way
+        // through the archive keeps going there until it runs out of archived segments. Readers only start reading from
+        // the archive when they're explicitly positioned there, though, so a reader without a segment skips it.
+        if previous.is_set() {
+            if let Some(reader) = self.archive.as_ref().and_then(|archive| archive.get_next_segment(previous)) {
+                return Some(reader);
+            }
+        }
+
+        let locked = self.inner.read().unwrap();
+        locked.front().map(|r| r.segment_id).and_then(|front_segment| {
+            let target_index = (previous.0 + 1).saturating_sub(front_segment.0);
+            locked.get(target_index as usize).cloned()
+        })
+    }
+
+    pub fn get_segment(&self, segment: SegmentNum) -> Option<SegmentReader> {
+        if let Some(seg) =  self.get_next_segment(SegmentNum(segment.0.saturating_sub(1))) {
+            if seg.segment_id == segment {
+                Some(seg)
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+}
+
+/// Configuration for a single `Partition`
+#[derive(Debug, PartialEq, Clone)]
+pub struct PartitionOptions {
+    pub segment_max_size_bytes: usize,
+    pub max_segment_duration: Duration,
+    pub event_retention: Duration,
+    /// The oldest segments are dropped once the total size of the partition's segments exceeds this many bytes
+    pub max_bytes: usize,
+    /// The oldest segments are dropped once the partition holds more than this many events
+    pub max_events: u64,
+    /// If set, then sealed segments are periodically compacted so that only the newest event is kept for each namespace
+    /// that matches this glob
+    pub compacted_namespaces: Option<String>,
+    /// If true, then sealed segments are compressed in the background
+    pub compress_sealed_segments: bool,
+    /// If set, then segments that are dropped due to retention or size limits get moved to an archive instead of being
+    /// deleted
+    pub archive: Option<ArchiveOptions>,
+    pub fsync_policy: FsyncPolicy,
+    /// If set, then every new segment is encrypted using the newest key in this file. Existing segments are decrypted
+    /// using whichever key in the file they were encrypted with. Encrypted segments are never compressed.
+    pub encryption_key_file: Option<PathBuf>,
+}
+
+impl Default for PartitionOptions {
+    fn default() -> Self {
+        PartitionOptions {
+            segment_max_size_bytes: 1024 * 1024 * 1024, // 1GB
+            max_segment_duration: Duration::days(1),    // 24 hours
+            event_retention: Duration::max_value(),     // For-ev-er
+            max_bytes: ::std::usize::MAX,               // no limit
+            max_events: ::std::u64::MAX,                // no limit
+            compacted_namespaces: None,
+            compress_sealed_segments: false,
+            archive: None,
+            fsync_policy: FsyncPolicy::default(),
+            encryption_key_file: None,
+        }
+    }
+}
+
+/// Determines where a partition's archived segments are kept, and for how long
+#[derive(Debug, PartialEq, Clone)]
+pub struct ArchiveOptions {
+    /// The directory that holds the archived segments for this partition. This must not be shared with any other partition
+    pub dir: PathBuf,
+    /// Archived segments are deleted once this amount of time has passed since their end time
+    pub retention: Duration,
+}
+
+/// An event that's ready to be appended to a partition. The partition assigns the id and timestamp when it's appended
+#[derive(Debug, PartialEq, Clone)]
+pub struct NewEvent {
+    pub namespace: String,
+    pub parent_id: Option<FloEventId>,
+    /// The time supplied by the producer, if any. This is stored as-is, and is never used for ordering events
+    pub event_time: Option<Timestamp>,
+    /// Set if the event came from an idempotent producer. This is persisted with the event so that duplicates can still
+    /// be detected after a restart
+    pub producer_sequence: Option<ProducerSequence>,
+    pub headers: Vec<EventHeader>,
+    pub data: Vec<u8>,
+}
+
+impl NewEvent {
+    /// Creates a new event without any headers, event time, or producer sequence
+    pub fn new<N: Into<String>, D: Into<Vec<u8>>>(namespace: N, parent_id: Option<FloEventId>, data: D) -> NewEvent {
+        NewEvent {
+            namespace: namespace.into(),
+            parent_id: parent_id,
+            event_time: None,
+            producer_sequence: None,
+            headers: Vec::new(),
+            data: data.into(),
+        }
+    }
+
+    /// Returns this event with the given producer id and sequence number
+    pub fn with_producer_sequence(mut self, producer_sequence: ProducerSequence) -> NewEvent {
+        self.producer_sequence = Some(producer_sequence);
+        self
+    }
+
+    /// Returns this event with the given producer supplied event time
+    pub fn with_event_time(mut self, event_time: Timestamp) -> NewEvent {
+        self.event_time = Some(event_time);
+        self
+    }
+
+    /// Returns this event with the given header added after any existing headers
+    pub fn with_header<N: Into<String>, V: Into<Vec<u8>>>(mut self, name: N, value: V) -> NewEvent {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+}
+
+/// Identifies events to delete from a partition. Deleted events are skipped by readers right away, and are physically
+/// removed once the segment that holds them is rewritten. Event ids are never reused.
+#[derive(Debug, PartialEq, Clone)]
+pub struct EventDeletion {
+    /// The counters of specific events to delete
+    pub counters: Vec<EventCounter>,
+    /// If set, then every event that's currently in the partition with a namespace matching this glob is deleted. Events
+    /// that are appended afterwards are unaffected
+    pub namespace: Option<String>,
+}
+
+/// Describes the consistent cut of a partition that was written by `Partition::snapshot`
+#[derive(Debug, PartialEq, Clone)]
+pub struct PartitionSnapshot {
+    pub partition_num: ActorId,
+    /// The counter of the last event included in the snapshot
+    pub head: EventCounter,
+    /// The offset just past the end of the last event in the segment that was being appended to
+    pub active_segment_offset: usize,
+    /// The number of segment files that were written
+    pub segment_count: usize,
+}
+
+/// The storage for a single partition of an event stream. Events are appended to a sequence of segment files within the
+/// partition directory, and are read back using a `PartitionReader`. A partition isn't thread safe, so all appends and
+/// maintenance need to happen on one thread, but readers can be sent to other threads and are safe to use concurrently
+/// with appends.
+pub struct Partition {
+
+    partition_num: ActorId,
+    partition_dir: PathBuf,
+    max_segment_size: usize,
+    max_segment_duration: Duration,
+    event_retention: Duration,
+    max_bytes: usize,
+    max_events: u64,
+    /// determines which sealed segments get compacted to keep only the newest event for each matching namespace
+    compaction: CompactionTracker,
+    /// events that have been deleted. Sealed segments that hold any of these events get rewritten without them
+    tombstones: Tombstones,
+    /// transactions that are prepared but not yet resolved, along with the ones that were aborted
+    transactions: TransactionState,
+    /// opened the first time that a transaction is prepared
+    transaction_log: Option<TransactionLog>,
+    /// the newest event in each namespace as of the newest sealed segment, which gets persisted each time a segment is sealed
+    namespace_heads: NamespaceHeads,
+    /// if true, sealed segments are compressed on each tick
+    compress_segments: bool,
+    /// if set, new segments are encrypted using the current key
+    encryption_keys: Option<KeyRing>,
+    /// ordered from newest to oldest, so the front segment is the one being appended to
+    segments: VecDeque<Segment>,
+    /// the number of the most recently created segment. New segments are always numbered after this one, so that a number
+    /// is never reused while a reader may still have the dropped segment with that number mapped
+    newest_segment_num: SegmentNum,
+    /// if set, segments are moved here instead of being deleted when they're dropped from the partition
+    archive: Option<ArchiveRef>,
+    index: PartitionIndex,
+    event_stream_highest_counter: HighestCounter,
+
+    /// the source of the current time for event timestamps and segment expiration
+    clock: Box<Clock>,
+
+    /// determines when appended events get flushed to disk
+    fsync_policy: FsyncPolicy,
+    last_fsync: Timestamp,
+    unsynced_bytes: usize,
+
+    /// new segments each have a reader added here. The readers are then accessed as needed by the EventReader
+    reader_refs: SharedReaderRefsMut,
+}
+
+
+impl Partition {
+
+    /// Initializes a partition from the segment files that already exist in `partition_data_dir`. Any partially written
+    /// events or incomplete segment files are removed, and segments that have expired are dropped.
+    pub fn init_existing(partition_num: ActorId,
+                         partition_data_dir: PathBuf,
+                         options: &PartitionOptions,
+                         highest_counter: HighestCounter,
+                         clock: Box<Clock>) -> io::Result<Partition> {
+
+        let start_time = ::std::time::Instant::now();
+        debug!("Starting to init partition: {} with directory: {:?}, and options: {:?}", partition_num, partition_data_dir, options);
+
+        let mut index = PartitionIndex::new(partition_num);
+        let mut compaction = CompactionTracker::new(parse_compaction_filter(options)?);
+
+        remove_incomplete_temp_files(&partition_data_dir)?;
+        let encryption_keys = read_encryption_keys(options)?;
+        let archive = open_archive(options.archive.as_ref(), partition_num, &partition_data_dir, encryption_keys.clone())?;
+        let mut segment_files = get_segment_files(&partition_data_dir)?;
+        if let Some(ref archive) = archive {
+            remove_archived_segment_files(&mut segment_files, archive)?;
+        }
+        remove_incomplete_segment_file(&mut segment_files)?;
+        migrate_segment_files(&segment_files, partition_num)?;
+
+        let tombstones = read_tombstone_file(&get_tombstone_file(&partition_data_dir))?;
+        // The namespace heads can always be found again by reading the events, so a bad file isn't fatal
+        let mut namespace_heads = read_namespace_heads_file(&get_namespace_heads_file(&partition_data_dir)).unwrap_or_else(|err| {
+            warn!("Reading every event to find the namespace heads for partition: {} since they couldn't be read: {:?}", partition_num, err);
+            NamespaceHeads::new()
+        });
+        let mut initialized_segments = VecDeque::with_capacity(segment_files.len());
+        let reader_refs = SharedReaderRefsMut::with_capacity(segment_files.len(), archive.clone());
+        reader_refs.set_tombstones(tombstones.clone());
+        for segment_file in segment_files {
+            let segment = segment_file.init_segment(options.segment_max_size_bytes, encryption_keys.as_ref(), &mut index)?;
+            let reader = segment.iter_from_start();
+            initialized_segments.push_front(segment);
+            reader_refs.add(reader);
+        }
+
+        // The newest event in each compacted namespace is only known by reading the segments. This only happens once, at
+        // startup, and then it's kept up to date as events are appended
+        if options.compacted_namespaces.is_some() {
+            for segment in initialized_segments.iter().rev() {
+                for result in segment.iter_from_start() {
+                    compaction.event_appended(&result?, segment.segment_num);
+                }
+            }
+        }
+        if !tombstones.is_empty() {
+            compaction.events_deleted(initialized_segments.iter().map(|s| s.segment_num));
+        }
+
+        // Older segments that are missing an index file had to be scanned, so write their index files now so that the
+        // next startup is faster
+        for segment in initialized_segments.iter_mut().skip(1).filter(|s| !s.is_sealed()) {
+            if let Err(err) = segment.seal() {
+                warn!("Failed to write index file for {} in partition: {}: {:?}", segment.segment_num, partition_num, err);
+            }
+        }
+
+        // Only the newest segment is ever appended to, so it's the only one that could have a partially written event
+        if let Some(active_segment) = initialized_segments.front_mut() {
+            let discarded = active_segment.recover_tail()?;
+            if discarded > 0 {
+                warn!("Recovered partition: {} by discarding {} bytes from the end of {}",
+                      partition_num,
+                      discarded,
+                      active_segment.segment_num);
+            }
+
+            // If encryption was turned on or the key was rotated, then sealing the active segment ensures that new events
+            // go into a new segment that uses the current key
+            let current_key_id = encryption_keys.as_ref().map(|keys| keys.current_key().id());
+            if active_segment.get_key_id() != current_key_id {
+                info!("Sealing {} in partition: {} since it does not use the current encryption key", active_segment.segment_num, partition_num);
+                active_segment.seal()?;
+            }
+        }
+
+        // If every segment has been archived, then numbering continues on from the newest archived segment
+        let newest_segment_num = initialized_segments.front().map(|s: &Segment| s.segment_num)
+                .or(archive.as_ref().and_then(|a| a.last_segment_num()))
+                .unwrap_or_default();
+
+        let archived_greatest_id = archive.as_ref().map(|a| a.greatest_event_counter()).unwrap_or(0);
+        let current_greatest_id = ::std::cmp::max(index.greatest_event_counter(), archived_greatest_id);
+        highest_counter.set_if_greater(current_greatest_id);
+        if namespace_heads.through_counter() > current_greatest_id {
+            warn!("Reading every event to find the namespace heads for partition: {} since they include events through: {}, which is past the end of the partition",
+                  partition_num, namespace_heads.through_counter());
+            namespace_heads = NamespaceHeads::new();
+        }
+
+        // TODO: factor out a more legit method of timing and logging perf stats
+        let init_time = start_time.elapsed();
+        let time_in_millis = (init_time.as_secs() * 1000) +
+            (init_time.subsec_nanos() as u64 / 1_000_000);
+        info!("Initialized existing partition: {} with directory: {:?}, and options: {:?} in {} milliseconds",
+              partition_num,
+              partition_data_dir,
+              options,
+              time_in_millis);
+
+        let mut partition = Partition {
+            partition_num: partition_num,
+            partition_dir: partition_data_dir,
+            max_segment_size: options.segment_max_size_bytes,
+            max_segment_duration: options.max_segment_duration,
+            event_retention: options.event_retention,
+            max_bytes: options.max_bytes,
+            max_events: options.max_events,
+            compaction: compaction,
+            tombstones: tombstones,
+            transactions: TransactionState::new(),
+            transaction_log: None,
+            namespace_heads: namespace_heads,
+            compress_segments: options.compress_sealed_segments,
+            encryption_keys: encryption_keys,
+            segments: initialized_segments,
+            newest_segment_num: newest_segment_num,
+            archive: archive,
+            index: index,
+            event_stream_highest_counter: highest_counter,
+            last_fsync: clock.now(),
+            clock: clock,
+            fsync_policy: options.fsync_policy,
+            unsynced_bytes: 0,
+            reader_refs: reader_refs,
+        };
+
+        partition.recover_transactions()?;
+        // This has to happen before any segments are dropped, so that the heads include their events
+        if let Err(err) = partition.checkpoint_namespace_heads() {
+            warn!("Failed to write namespace heads for partition: {}: {:?}", partition_num, err);
+        }
+        // Segments may have expired while the server was down, and there's no reason to wait for the next tick to drop them
+        partition.expire_old_events();
+        Ok(partition)
+    }
+
+    /// Initializes a new, empty partition, creating `partition_data_dir` if it doesn't already exist
+    pub fn init_new(partition_num: ActorId,
+                    partition_data_dir: PathBuf,
+                    options: &PartitionOptions,
+                    highest_counter: HighestCounter,
+                    clock: Box<Clock>) -> io::Result<Partition> {
+
+        let compaction = CompactionTracker::new(parse_compaction_filter(options)?);
+        ::std::fs::create_dir_all(&partition_data_dir)?;
+        let encryption_keys = read_encryption_keys(options)?;
+        let archive = open_archive(options.archive.as_ref(), partition_num, &partition_data_dir, encryption_keys.clone())?;
+        let newest_segment_num = archive.as_ref().and_then(|a| a.last_segment_num()).unwrap_or_default();
+
+        Ok(Partition {
+            partition_num: partition_num,
+            partition_dir: partition_data_dir,
+            max_segment_duration: options.max_segment_duration,
+            event_retention: options.event_retention,
+            max_segment_size: options.segment_max_size_bytes,
+            max_bytes: options.max_bytes,
+            max_events: options.max_events,
+            compaction: compaction,
+            tombstones: Tombstones::new(),
+            transactions: TransactionState::new(),
+            transaction_log: None,
+            namespace_heads: NamespaceHeads::new(),
+            compress_segments: options.compress_sealed_segments,
+            encryption_keys: encryption_keys,
+            segments: VecDeque::with_capacity(4),
+            newest_segment_num: newest_segment_num,
+            archive: archive.clone(),
+            index: PartitionIndex::new(partition_num),
+            event_stream_highest_counter: highest_counter,
+            last_fsync: clock.now(),
+            clock: clock,
+            fsync_policy: options.fsync_policy,
+            unsynced_bytes: 0,
+            reader_refs: SharedReaderRefsMut::new(archive),
+        })
+    }
+
+    pub fn partition_num(&self) -> ActorId {
+        self.partition_num
+    }
+
+    /// Returns the counter of the newest event in the partition, including any events that have been archived
+    pub fn greatest_event_counter(&self) -> EventCounter {
+        let archived = self.archive.as_ref().map(|a| a.greatest_event_counter()).unwrap_or(0);
+        ::std::cmp::max(self.index.greatest_event_counter(), archived)
+    }
+
+    /// Performs all of the periodic maintenance for the partition. Expired segments are dropped, the partition is brought
+    /// back within its size limits, and sealed segments are compacted and compressed if the options call for it. Errors
+    /// are logged rather than returned, since they'll just be retried on the next tick.
+    pub fn tick(&mut self) {
+        // a partition that stops receiving appends still needs its last events to be synced within the policy's interval
+        if let Err(err) = self.fsync_if_required() {
+            error!("Failed to fsync partition: {}: {:?}", self.partition_num, err);
+        }
+        self.expire_old_events();
+        self.drop_segments_over_size_limit();
+        if let Err(err) = self.compact_segments() {
+            error!("Failed to compact segments for partition: {}: {:?}", self.partition_num, err);
+        }
+        if let Err(err) = self.compress_sealed_segments() {
+            error!("Failed to compress segments for partition: {}: {:?}", self.partition_num, err);
+        }
+        if let Some(ref archive) = self.archive {
+            if let Err(err) = archive.expire_segments(self.clock.now()) {
+                error!("Failed to remove expired archived segments for partition: {}: {:?}", self.partition_num, err);
+            }
+        }
+    }
+
+    /// Drops the oldest segments that have expired. The newest segment is never dropped, even if it's expired, since it's
+    /// the one that events are being appended to. Its events are hidden from readers instead, and the segment itself gets
+    /// dropped on a later tick, once a newer segment has been created.
+    fn expire_old_events(&mut self) {
+        let now = self.clock.now();
+        let retention = self.event_retention;
+        let droppable_count = self.segments.len().saturating_sub(1);
+        let expired_segment_index = self.segments.iter().rev().take(droppable_count).enumerate().take_while(|&(_, ref segment)| {
+            segment.is_expired(now, retention)
+        }).last().map(|(ref index, _)| *index);
+        if let Some(drop_through_index) = expired_segment_index {
+            self.drop_segments_through_index(drop_through_index);
+        }
+
+        let Partition { ref segments, ref mut index, ref reader_refs, partition_num, .. } = *self;
+        if let Some(active_segment) = segments.front() {
+            if active_segment.is_expired(now, retention) && reader_refs.contains(active_segment.segment_num) {
+                info!("Hiding the events in expired active segment: {} of partition: {}", active_segment.segment_num, partition_num);
+                reader_refs.remove_through(active_segment.segment_num);
+                if active_segment.get_event_count() > 0 {
+                    index.remove_through(active_segment.get_highest_event_counter());
+                }
+            }
+        }
+    }
+
+    /// Drops the oldest segments until the partition is back within its limits on the total number of bytes and events.
+    /// The newest segment is never dropped, since it's the one that events are being appended to.
+    fn drop_segments_over_size_limit(&mut self) {
+        let mut total_bytes: usize = self.segments.iter().map(|s| s.get_size_bytes()).sum();
+        let mut total_events: u64 = self.segments.iter().map(|s| s.get_event_count()).sum();
+        let droppable_count = self.segments.len().saturating_sub(1);
+
+        let mut drop_count = 0;
+        for segment in self.segments.iter().rev().take(droppable_count) {
+            if total_bytes <= self.max_bytes && total_events <= self.max_events {
+                break;
+            }
+            total_bytes -= segment.get_size_bytes();
+            total_events -= segment.get_event_count();
+            drop_count += 1;
+        }
+
+        if drop_count > 0 {
+            info!("partition: {} is over its size limit, dropping the oldest {} segment(s). Remaining size: {} bytes, {} events",
+                  self.partition_num, drop_count, total_bytes, total_events);
+            self.drop_segments_through_index(drop_count - 1);
+        }
+    }
+
+    /// Rewrites sealed segments to remove every event that's been deleted or aborted, as well as every event that's been superseded by
+    /// a newer event with the same namespace, for namespaces that match the compaction filter. Only the segments that the
+    /// `CompactionTracker` knows to hold such events are read. Event ids are unchanged, and readers that are already
+    /// partway through a segment will continue reading the previous version of it.
+    fn compact_segments(&mut self) -> io::Result<()> {
+        if !self.compaction.has_compactable_segments() {
+            return Ok(());
+        }
+
+        let Partition { ref mut segments, ref mut index, ref mut compaction, ref tombstones, ref transactions, ref reader_refs, partition_num, .. } = *self;
+        let is_removed = |event: &PersistentEvent| tombstones.is_deleted(event) || transactions.is_aborted(event);
+
+        // The newest segment is still being appended to, so it's never compacted
+        for segment_index in 1..segments.len() {
+            let segment_num = segments[segment_index].segment_num;
+            if !segments[segment_index].is_sealed() || !compaction.is_compactable(segment_num) {
+                continue;
+            }
+            let mut superseded = Vec::new();
+            for result in segments[segment_index].iter_from_start() {
+                let event = result?;
+                if is_removed(&event) || compaction.is_superseded(&event) {
+                    superseded.push(event.id().event_counter);
+                }
+            }
+            if superseded.is_empty() {
+                compaction.segment_removed(segment_num);
+                continue;
+            }
+
+            let compacted = {
+                let compaction = &*compaction;
+                segments[segment_index].rewrite(|event| !is_removed(event) && !compaction.is_superseded(event))?
+            };
+            info!("partition: {} compacted {} by removing {} superseded or deleted events", partition_num, compacted.segment_num, superseded.len());
+            for counter in superseded {
+                index.remove_entry(counter);
+            }
+            for result in compacted.iter_from_start() {
+                let event = result?;
+                index.append(IndexEntry::new(event.id().event_counter, compacted.segment_num, event.file_offset()));
+            }
+            reader_refs.replace(compacted.range_iter(0));
+            segments[segment_index] = compacted;
+            compaction.segment_removed(segment_num);
+        }
+        Ok(())
+    }
+
+    /// Compresses every sealed segment that isn't already compressed. Event offsets are the same in the compressed version
+    /// of a segment, so the index is unaffected, and readers that are already partway through a segment will continue
+    /// reading the uncompressed version of it.
+    fn compress_sealed_segments(&mut self) -> io::Result<()> {
+        if !self.compress_segments {
+            return Ok(());
+        }
+
+        // The newest segment is still being appended to, so it's never compressed
+        for segment_index in 1..self.segments.len() {
+            let skip = {
+                let segment = &self.segments[segment_index];
+                !segment.is_sealed() || segment.is_compressed() || segment.is_encrypted()
+            };
+            if skip {
+                continue;
+            }
+            let compressed = self.segments[segment_index].compress()?;
+            info!("partition: {} compressed {} from {} to {} bytes",
+                  self.partition_num,
+                  compressed.segment_num,
+                  self.segments[segment_index].get_size_bytes(),
+                  compressed.get_size_bytes());
+            self.reader_refs.replace(compressed.range_iter(0));
+            self.segments[segment_index] = compressed;
+        }
+        Ok(())
+    }
+
+    /// Drops the oldest segments, up through `segment_index`, where an index of 0 refers to the oldest segment. If the
+    /// partition has an archive, then each segment is moved into it before being dropped, and a segment that can't be
+    /// archived is kept in the partition until the next attempt.
+    fn drop_segments_through_index(&mut self, segment_index: usize) {
+        info!("Dropping oldest {} segment(s)", segment_index + 1);
+        let Partition { ref mut segments, ref mut index, ref mut reader_refs, ref mut compaction, ref archive, partition_num, .. } = *self;
+
+        for _ in 0..(segment_index + 1) {
+            if let Some(mut drop_segment) = segments.pop_back() {
+                if let Some(ref archive) = *archive {
+                    if let Err(err) = archive.archive(&mut drop_segment) {
+                        error!("Failed to archive {} for partition: {}: {:?}", drop_segment.segment_num, partition_num, err);
+                        segments.push_back(drop_segment);
+                        return;
+                    }
+                }
+                info!("Removing Segment: {:?} with highest_event counter: {}", drop_segment.segment_num, drop_segment.get_highest_event_counter());
+                reader_refs.remove_through(drop_segment.segment_num);
+                compaction.segment_removed(drop_segment.segment_num);
+                if drop_segment.get_event_count() > 0 {
+                    index.remove_through(drop_segment.get_highest_event_counter());
+                }
+                drop_segment.delete_on_drop();
+            }
+        }
+    }
+
+    /// Appends all of the events to the partition, and returns the id of the last one. Each event gets the next counter
+    /// from the `HighestCounter` that was passed in when the partition was initialized. Once this returns successfully, the
+    /// events are durable according to the `FsyncPolicy` and are visible to every reader.
+    pub fn append_all(&mut self, events: Vec<NewEvent>) -> io::Result<FloEventId> {
+        let event_count = events.len();
+        // reserve the range of ids for the events
+        let new_highest = self.event_stream_highest_counter.increment_and_get(event_count as u64);
+
+        let timestamp = self.clock.now();
+        let mut event_counter = new_highest - event_count as u64;
+        for new_event in events {
+            event_counter += 1;
+            let event = EventToProduce {
+                id: FloEventId::new(self.partition_num, event_counter),
+                ts: timestamp,
+                event: new_event,
+            };
+            // early return if creating segment fails or if appending fails
+            self.append(&event)?;
+            self.unsynced_bytes += PersistentEvent::get_repr_length(&event) as usize;
+        }
+        debug!("partition: {} finished appending {} events ending with counter: {}", self.partition_num, event_count, event_counter);
+        // events must be durable according to the fsync policy before the producer gets an ack
+        self.fsync_if_required()?;
+        self.drop_segments_over_size_limit();
+        Ok(FloEventId::new(self.partition_num, event_counter))
+    }
+
+    fn append(&mut self, event: &EventToProduce) -> io::Result<()> {
+        use segment::AppendResult;
+
+        let mut byte_offset: usize = 0;
+        let mut segment_num: SegmentNum = SegmentNum(0);
+
+        let event_len = PersistentEvent::get_repr_length(event) as usize;
+        if event_len > Segment::max_event_size(self.max_segment_size, self.encryption_keys.is_some()) {
+            // No point in creating a new segment if the event won't fit into it anyway
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Event {} is {} bytes, which is larger than the max segment size of {} bytes",
+                                              event.id(), event_len, self.max_segment_size)));
+        }
+
+        if let Some(ref mut segment) = self.segments.front_mut() {
+            match segment.append(event) {
+                AppendResult::Success(offset) => {
+                    byte_offset = offset;
+                    segment_num = segment.segment_num;
+                }
+                AppendResult::IoError(kind) => {
+                    return Err(kind.into());
+                }
+                other @ _ => {
+                    debug!("Event {} does not fit into {:?} due to: {:?}", event.id(), segment.segment_num, other);
+                }
+            }
+        }
+
+        if byte_offset == 0 {
+            // we weren't able to append to the last segment, so we need to seal it and create a new one
+            if let Some(ref mut segment) = self.segments.front_mut() {
+                // The index file is only an optimization, so there's no need to fail the append if it can't be written
+                if let Err(err) = segment.seal() {
+                    warn!("Failed to write index file for {} in partition: {}: {:?}", segment.segment_num, self.partition_num, err);
+                }
+            }
+            // Same goes for the namespace heads, which will just be found by reading the events if they're out of date
+            if let Err(err) = self.checkpoint_namespace_heads() {
+                warn!("Failed to write namespace heads for partition: {}: {:?}", self.partition_num, err);
+            }
+
+            segment_num = self.newest_segment_num.next();
+
+            let segment_create_time = self.clock.now();
+            let segment_end_time = segment_create_time + self.max_segment_duration;
+            let header = SegmentHeader::new(self.partition_num, event.id().event_counter, segment_create_time, segment_end_time);
+            let encryption_key = self.encryption_keys.as_ref().map(|keys| keys.current_key());
+            let new_segment = Segment::init_new(&self.partition_dir,
+                                                segment_num,
+                                                self.max_segment_size,
+                                                header,
+                                                encryption_key)?;
+            self.reader_refs.add(new_segment.range_iter(0));
+            self.segments.push_front(new_segment);
+            self.newest_segment_num = segment_num;
+
+            match self.segments.front_mut().unwrap().append(event) {
+                AppendResult::Success(offset) => {
+                    byte_offset = offset;
+                }
+                AppendResult::IoError(kind) => {
+                    return Err(kind.into());
+                }
+                other @ _ => {
+                    error!("Event {} can't fit into new segment: {:?} due to: {:?}", event.id(), segment_num, other);
+                    return Err(io::Error::new(io::ErrorKind::Other, format!("{:?}", other)));
+                }
+            }
+        }
+
+        let index_entry = IndexEntry {
+            counter: event.id().event_counter,
+            segment: segment_num,
+            file_offset: byte_offset,
+        };
+        self.index.append(index_entry);
+        self.compaction.event_appended(event, segment_num);
+        Ok(())
+    }
+
+    fn fsync_if_required(&mut self) -> io::Result<()> {
+        let elapsed_millis = (self.clock.now() - self.last_fsync).num_milliseconds();
+        let since_last_fsync = StdDuration::from_millis(::std::cmp::max(elapsed_millis, 0) as u64);
+        if self.fsync_policy.requires_fsync(since_last_fsync, self.unsynced_bytes) {
+            trace!("partition: {} fsyncing {} bytes due to policy: {:?}", self.partition_num, self.unsynced_bytes, self.fsync_policy);
+            self.fsync()?;
+        }
+        Ok(())
+    }
+
+    pub fn fsync(&mut self) -> io::Result<()> {
+        for segment in self.segments.iter_mut() {
+            segment.fsync()?
+        }
+        self.last_fsync = self.clock.now();
+        self.unsynced_bytes = 0;
+        Ok(())
+    }
+
+    /// Writes a consistent copy of this partition into `dest_dir`, which can later be opened using `init_existing`. The
+    /// partition is fsynced first, and the snapshot includes every event up to the current head. Sealed segments are hard
+    /// linked when possible, and only the written portion of the current segment is copied. Archived segments are not
+    /// included. Returns an error if `dest_dir` already contains segment files.
+    pub fn snapshot(&mut self, dest_dir: &Path) -> io::Result<PartitionSnapshot> {
+        self.fsync()?;
+        ::std::fs::create_dir_all(dest_dir)?;
+        if !get_segment_files(dest_dir)?.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{:?} already contains segment files", dest_dir)));
+        }
+
+        let mut active_segment_offset = 0;
+        // iterate oldest first, so the current segment is the last one to be written
+        for segment in self.segments.iter().rev() {
+            active_segment_offset = segment.snapshot(dest_dir)?;
+        }
+        if !self.tombstones.is_empty() {
+            write_tombstone_file(&get_tombstone_file(dest_dir), &self.tombstones)?;
+        }
+        // transactions that are still prepared get aborted when the snapshot is opened, since they weren't committed
+        // as of the snapshot
+        let transaction_records = self.transactions.to_records();
+        if !transaction_records.is_empty() {
+            write_transaction_log(&get_transaction_log_file(dest_dir), &transaction_records)?;
+        }
+        if self.namespace_heads.through_counter() > 0 {
+            write_namespace_heads_file(&get_namespace_heads_file(dest_dir), &self.namespace_heads)?;
+        }
+        let snapshot = PartitionSnapshot {
+            partition_num: self.partition_num,
+            head: self.index.greatest_event_counter(),
+            active_segment_offset: active_segment_offset,
+            segment_count: self.segments.len(),
+        };
+        info!("Created snapshot of partition: {} in {:?}: {:?}", self.partition_num, dest_dir, snapshot);
+        Ok(snapshot)
+    }
+
+    /// Marks events as deleted. The tombstones are persisted before this returns, and readers stop returning the deleted
+    /// events right away. The events themselves are removed from sealed segments on the next tick, and from the current
+    /// segment once it's been sealed. Returns an error without deleting anything if any of the counters is greater than
+    /// the partition's current head, or if the namespace glob is invalid.
+    pub fn delete(&mut self, deletion: EventDeletion) -> io::Result<()> {
+        let head = self.greatest_event_counter();
+        let start_exclusive = deletion_start_exclusive(&deletion);
+        let mut tombstones = self.tombstones.clone();
+        add_tombstones(&mut tombstones, deletion, head)?;
+        {
+            let readers = self.segments.iter().rev()
+                    .filter(|segment| segment.get_event_count() > 0 && segment.get_highest_event_counter() > start_exclusive)
+                    .map(|segment| segment.iter_from_start());
+            add_deleted_sequences(&mut tombstones, &self.tombstones, readers)?;
+        }
+        write_tombstone_file(&get_tombstone_file(&self.partition_dir), &tombstones)?;
+        info!("partition: {} updated tombstones to: {:?}", self.partition_num, tombstones);
+        self.reader_refs.set_tombstones(tombstones.clone());
+        self.tombstones = tombstones;
+        self.compaction.events_deleted(self.segments.iter().map(|s| s.segment_num));
+        if let Err(err) = self.checkpoint_namespace_heads() {
+            warn!("Failed to write namespace heads for partition: {}: {:?}", self.partition_num, err);
+        }
+        Ok(())
+    }
+
+    /// Returns the ids and producer sequences of the deleted events with counters greater than `start_exclusive`, in order.
+    /// These are recorded along with the tombstones, since the events themselves can't be read anymore.
+    pub fn deleted_producer_sequences(&self, start_exclusive: EventCounter) -> Vec<(FloEventId, ProducerSequence)> {
+        let partition_num = self.partition_num;
+        self.tombstones.sequences_after(start_exclusive).into_iter().map(|(counter, producer_sequence)| {
+            (FloEventId::new(partition_num, counter), producer_sequence)
+        }).collect()
+    }
+
+    /// Returns the counter of the newest event in each namespace, leaving out events that have been deleted or that belong
+    /// to a transaction that hasn't been committed yet. Only the events that were appended since the last segment was
+    /// sealed need to be read.
+    pub fn namespace_heads(&self) -> io::Result<HashMap<String, EventCounter>> {
+        let mut heads = self.namespace_heads.clone();
+        let after = heads.through_counter();
+        self.read_namespace_heads(&mut heads, after, EventCounter::max_value(), None)?;
+        Ok(heads.into_heads())
+    }
+
+    /// Brings the namespace heads up to date with the newest sealed segment and persists them. Events are only included up
+    /// to the start of the oldest transaction that's still unresolved, since its events may yet be aborted. Any heads that
+    /// have been deleted are replaced first, which means reading through the older events again for namespaces that had
+    /// only some of their events deleted.
+    fn checkpoint_namespace_heads(&mut self) -> io::Result<()> {
+        let mut heads = self.namespace_heads.clone();
+        let deleted_namespaces = heads.remove_deleted(&self.tombstones);
+        let mut changed = heads != self.namespace_heads;
+        if !deleted_namespaces.is_empty() {
+            let through = heads.through_counter();
+            self.read_namespace_heads(&mut heads, 0, through, Some(&deleted_namespaces))?;
+        }
+
+        // A segment that failed to seal may not have been fsynced, so the heads stop short of it
+        let sealed_through = self.segments.iter().rev()
+                .take_while(|segment| segment.is_sealed())
+                .filter(|segment| segment.get_event_count() > 0)
+                .last()
+                .map(|segment| segment.get_highest_event_counter())
+                .unwrap_or(0);
+        let through = self.transactions.resolved_through().map(|resolved| ::std::cmp::min(resolved, sealed_through)).unwrap_or(sealed_through);
+        if through > heads.through_counter() {
+            let after = heads.through_counter();
+            self.read_namespace_heads(&mut heads, after, through, None)?;
+            heads.set_through_counter(through);
+            changed = true;
+        }
+
+        self.namespace_heads = heads;
+        if changed {
+            debug!("partition: {} writing namespace heads through counter: {}", self.partition_num, self.namespace_heads.through_counter());
+            write_namespace_heads_file(&get_namespace_heads_file(&self.partition_dir), &self.namespace_heads)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the events with counters after `after` and up through `through` to the namespace heads, skipping any that
+    /// have been deleted or that belong to a transaction that hasn't been committed. If `namespaces` is set, then only
+    /// events in those namespaces are added.
+    fn read_namespace_heads(&self, heads: &mut NamespaceHeads, after: EventCounter, through: EventCounter, namespaces: Option<&[String]>) -> io::Result<()> {
+        for segment in self.segments.iter().rev() {
+            if segment.get_event_count() == 0 || segment.get_highest_event_counter() <= after {
+                continue;
+            }
+            if segment.get_first_event_counter() > through {
+                break;
+            }
+            for result in segment.iter_from_start() {
+                let event = result?;
+                let counter = event.id().event_counter;
+                if counter <= after {
+                    continue;
+                }
+                if counter > through {
+                    break;
+                }
+                let included = namespaces.map(|namespaces| namespaces.iter().any(|namespace| namespace == event.namespace())).unwrap_or(true);
+                let removed = self.tombstones.is_deleted(&event) || self.transactions.is_aborted(&event) || self.transactions.is_pending(&event);
+                if included && !removed {
+                    heads.set(event.namespace(), counter);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends the events for one partition of a transaction. A prepare record is persisted before the events are
+    /// appended, and readers stop at the first of the events until the transaction is either committed or aborted. Events
+    /// that are appended afterwards, but before the transaction is resolved, are held back as well. If the events can't
+    /// all be appended, then the transaction is aborted right away and the error is returned. Returns the id of the last
+    /// event, same as `append_all`.
+    pub fn prepare_transaction(&mut self, transaction_id: TransactionId, events: Vec<NewEvent>) -> io::Result<FloEventId> {
+        let after_counter = self.greatest_event_counter();
+        let event_count = events.len() as u64;
+        self.append_transaction_record(TransactionRecord::Prepare {
+            transaction_id: transaction_id,
+            after_counter: after_counter,
+            event_count: event_count,
+        })?;
+        self.transactions.prepare(transaction_id, after_counter, event_count);
+        self.reader_refs.set_transactions(self.transactions.clone());
+
+        match self.append_all(events) {
+            Ok(id) => {
+                self.transactions.prepared_through(transaction_id, id.event_counter);
+                Ok(id)
+            }
+            Err(err) => {
+                warn!("partition: {} failed to append events for transaction: {}, so it will be aborted: {:?}", self.partition_num, transaction_id, err);
+                if let Err(abort_err) = self.abort_transaction(transaction_id) {
+                    error!("partition: {} failed to abort transaction: {}: {:?}", self.partition_num, transaction_id, abort_err);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Persists a commit record for a prepared transaction, and then lets readers continue on to its events. If the record
+    /// can't be written, then the transaction remains prepared.
+    pub fn commit_transaction(&mut self, transaction_id: TransactionId) -> io::Result<()> {
+        if !self.transactions.is_prepared(transaction_id) {
+            return Err(transaction_not_prepared(self.partition_num, transaction_id));
+        }
+        self.append_transaction_record(TransactionRecord::Commit { transaction_id: transaction_id })?;
+        self.transactions.commit(transaction_id);
+        self.reader_refs.set_transactions(self.transactions.clone());
+        debug!("partition: {} committed transaction: {}", self.partition_num, transaction_id);
+        Ok(())
+    }
+
+    /// Aborts a prepared transaction, so that readers skip its events from then on. Readers stop waiting on the
+    /// transaction even if the abort record can't be written, since a transaction without a commit record is aborted
+    /// anyway once the partition is initialized again.
+    pub fn abort_transaction(&mut self, transaction_id: TransactionId) -> io::Result<()> {
+        let head = self.greatest_event_counter();
+        let record = self.transactions.abort(transaction_id, head).ok_or_else(|| {
+            transaction_not_prepared(self.partition_num, transaction_id)
+        })?;
+        self.reader_refs.set_transactions(self.transactions.clone());
+        self.compaction.events_deleted(self.segments.iter().map(|s| s.segment_num));
+        info!("partition: {} aborted transaction: {} with: {:?}", self.partition_num, transaction_id, record);
+        self.append_transaction_record(record)
+    }
+
+    fn append_transaction_record(&mut self, record: TransactionRecord) -> io::Result<()> {
+        if self.transaction_log.is_none() {
+            let log = TransactionLog::open(&get_transaction_log_file(&self.partition_dir))?;
+            self.transaction_log = Some(log);
+        }
+        self.transaction_log.as_mut().unwrap().append(&record)
+    }
+
+    /// Reads the transaction log when the partition is initialized. Any transaction that was prepared but never committed
+    /// is aborted, and then the log is rewritten with only the aborted transactions, since those are the only ones that
+    /// readers still need to know about.
+    fn recover_transactions(&mut self) -> io::Result<()> {
+        let path = get_transaction_log_file(&self.partition_dir);
+        let records = read_transaction_log(&path)?;
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let mut transactions = TransactionState::replay(&records);
+        for (transaction_id, after_counter, event_count) in transactions.prepared_transactions() {
+            // The events of a transaction are always the first ones appended after it was prepared
+            let mut last_counter = after_counter;
+            for _ in 0..event_count {
+                match self.index.get_next_entry(last_counter) {
+                    Some(entry) => last_counter = entry.counter,
+                    None => break,
+                }
+            }
+            transactions.prepared_through(transaction_id, last_counter);
+            let record = transactions.abort(transaction_id, last_counter);
+            warn!("partition: {} aborted transaction: {} since it was never committed: {:?}", self.partition_num, transaction_id, record);
+            self.compaction.events_deleted(self.segments.iter().map(|s| s.segment_num));
+        }
+        write_transaction_log(&path, &transactions.to_records())?;
+        self.reader_refs.set_transactions(transactions.clone());
+        self.transactions = transactions;
+        Ok(())
+    }
+
+    fn current_segment_num(&self) -> SegmentNum {
+        self.segments.front().map(|s| s.segment_num).unwrap_or(SegmentNum(0))
+    }
+
+    /// Returns the exclusive starting counter for a consumer that wants to read all the events with a timestamp greater
+    /// than or equal to `since`. If there are no such events, then the consumer will start at the end of the partition.
+    pub fn get_start_counter_since(&self, since: Timestamp) -> io::Result<EventCounter> {
+        // segments are stored newest first, and we want the oldest segment that has any events since then
+        for segment in self.segments.iter().rev() {
+            if let Some(counter) = segment.find_first_event_since(since)? {
+                debug!("partition: {} resolved start time: {:?} to event counter: {} in {}", self.partition_num, since, counter, segment.segment_num);
+                return Ok(counter - 1);
+            }
+        }
+        Ok(self.index.greatest_event_counter())
+    }
+
+    /// Returns the exclusive starting counter for a consumer that wants to read all the events with an event time greater
+    /// than or equal to `since`. Event times aren't ordered, so the consumer still has to check every event after this,
+    /// but it can skip the oldest segments when all of their event times are before `since`. Archived segments don't keep
+    /// track of their event times, so a partition with any archived segments always starts at the beginning.
+    pub fn get_start_counter_since_event_time(&self, since: Timestamp) -> io::Result<EventCounter> {
+        if self.archive.as_ref().and_then(|archive| archive.find_segment_after_counter(0)).is_some() {
+            return Ok(0);
+        }
+        for segment in self.segments.iter().rev() {
+            if segment.may_have_event_time_since(since) {
+                let start_exclusive = segment.get_first_event_counter().saturating_sub(1);
+                debug!("partition: {} resolved start event time: {:?} to event counter: {} in {}", self.partition_num, since, start_exclusive, segment.segment_num);
+                return Ok(start_exclusive);
+            }
+        }
+        Ok(self.index.greatest_event_counter())
+    }
+
+    /// Returns a reader that starts with the first event after `start_exclusive`. The reader will also return any events
+    /// that are appended after it was created, once it gets to them.
+    pub fn create_reader(&self, connection_id: ConnectionId, filter: EventFilter, start_exclusive: EventCounter) -> PartitionReader {
+        if let Some(reader) = self.create_archive_reader(start_exclusive) {
+            return PartitionReader::new(connection_id, self.partition_num, filter, Some(reader), self.reader_refs.get_reader_refs());
+        }
+
+        let current_segment_num = self.current_segment_num();
+        let index_entry: Option<IndexEntry> = self.index.get_next_entry(start_exclusive);
+        let readers = self.reader_refs.get_reader_refs();
+
+        let current_segment = match index_entry {
+            Some(entry) => {
+                readers.get_next_segment(entry.segment.previous()).map(|mut segment| {
+                    segment.set_offset(entry.file_offset);
+                    segment
+                })
+            }
+            None => {
+                // The requested event counter comes after the end of the stream, so just return the current segment
+                let mut reader = readers.get_segment(current_segment_num);
+                reader.as_mut().map(|r| r.set_offset_to_end());
+                reader
+            }
+        };
+
+        PartitionReader::new(connection_id, self.partition_num, filter, current_segment, self.reader_refs.get_reader_refs())
+    }
+
+    /// Returns a reader for the archived segment that has the first event after `start_exclusive`, if that event has been
+    /// archived. If the segment can't be fetched, then the consumer just starts with the oldest segment in the partition.
+    fn create_archive_reader(&self, start_exclusive: EventCounter) -> Option<SegmentReader> {
+        let partition_num = self.partition_num;
+        self.archive.as_ref().and_then(|archive| {
+            archive.find_segment_after_counter(start_exclusive).and_then(|segment_num| {
+                archive.fetch_from(segment_num, start_exclusive).map_err(|err| {
+                    error!("Failed to fetch archived {} for partition: {}: {:?}", segment_num, partition_num, err);
+                }).ok()
+            })
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct EventToProduce {
+    id: FloEventId,
+    ts: Timestamp,
+    event: NewEvent,
+}
+
+impl FloEvent for EventToProduce {
+    fn id(&self) -> &FloEventId {
+        &self.id
+    }
+
+    fn timestamp(&self) -> Timestamp {
+        self.ts
+    }
+
+    fn event_time(&self) -> Option<Timestamp> {
+        self.event.event_time
+    }
+
+    fn producer_sequence(&self) -> Option<ProducerSequence> {
+        self.event.producer_sequence
+    }
+
+    fn parent_id(&self) -> Option<FloEventId> {
+        self.event.parent_id
+    }
+
+    fn namespace(&self) -> &str {
+        &self.event.namespace
+    }
+
+    fn data_len(&self) -> u32 {
+        self.event.data.len() as u32
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.event.data
+    }
+
+    fn headers(&self) -> &[EventHeader] {
+        &self.event.headers
+    }
+}
+
+
+fn transaction_not_prepared(partition_num: ActorId, transaction_id: TransactionId) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("Transaction: {} is not prepared in partition: {}", transaction_id, partition_num))
+}
+
+/// Adds tombstones for all of the events in the deletion, which must not include any events after `head`
+fn add_tombstones(tombstones: &mut Tombstones, deletion: EventDeletion, head: EventCounter) -> io::Result<()> {
+    let EventDeletion { counters, namespace } = deletion;
+    for counter in counters {
+        if counter == 0 || counter > head {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Cannot delete event counter: {}, which is not in the partition", counter)));
+        }
+        tombstones.add_counter(counter);
+    }
+    if let Some(pattern) = namespace {
+        tombstones.add_namespace(&pattern, head).map_err(|description| {
+            io::Error::new(io::ErrorKind::InvalidInput, description)
+        })?;
+    }
+    Ok(())
+}
+
+/// Returns the counter that the events in the deletion all come after
+fn deletion_start_exclusive(deletion: &EventDeletion) -> EventCounter {
+    match deletion.namespace {
+        Some(_) => 0,
+        None => deletion.counters.iter().min().map(|counter| counter.saturating_sub(1)).unwrap_or(EventCounter::max_value()),
+    }
+}
+
+/// Records the producer sequence of each event that `tombstones` deletes and `previous` didn't, so that the dedupe window
+/// can still recognize the event if its producer re-sends it after a restart
+fn add_deleted_sequences<I: Iterator<Item=SegmentReader>>(tombstones: &mut Tombstones, previous: &Tombstones, readers: I) -> io::Result<()> {
+    for reader in readers {
+        for result in reader {
+            let event = result?;
+            if let Some(producer_sequence) = event.producer_sequence() {
+                if tombstones.is_deleted(&event) && !previous.is_deleted(&event) {
+                    tombstones.add_sequence(event.id().event_counter, producer_sequence);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_encryption_keys(options: &PartitionOptions) -> io::Result<Option<KeyRing>> {
+    match options.encryption_key_file {
+        Some(ref path) => KeyRing::read_key_file(path).map(Some),
+        None => Ok(None)
+    }
+}
+
+fn parse_compaction_filter(options: &PartitionOptions) -> io::Result<Option<EventFilter>> {
+    match options.compacted_namespaces {
+        Some(ref glob) => {
+            EventFilter::parse(glob).map(Some).map_err(|description| {
+                io::Error::new(io::ErrorKind::InvalidInput, description)
+            })
+        }
+        None => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use tempdir::TempDir;
+
+    use super::*;
+    use event::time::{self, SystemClock, ManualClock};
+
+    const PARTITION_NUM: ActorId = 1;
+    const CONNECTION: ConnectionId = 55;
+
+    #[test]
+    fn incomplete_segment_file_is_removed_when_partition_is_initialized() {
+        let _ = ::env_logger::init();
+
+        let options = PartitionOptions {
+            event_retention: Duration::seconds(20),
+            max_segment_duration: Duration::seconds(5),
+            segment_max_size_bytes: 1024,
+            fsync_policy: FsyncPolicy::EveryMillis(1000),
+            ..Default::default()
+        };
+        let tempdir = TempDir::new("incomplete_segment_file_is_removed").unwrap();
+
+        {
+            let mut partition = Partition::init_new(PARTITION_NUM,
+                                                    tempdir.path().to_owned(),
+                                                    &options,
+                                                    HighestCounter::zero(),
+                                                    Box::new(SystemClock)).unwrap();
+            partition.append_all(vec![new_event("/foo/bar", "the quick")]).expect("failed to append event");
+            partition.fsync().expect("failed to fsync");
+        }
+
+        // simulate the server dying right after creating the file for a new segment
+        let incomplete_file = tempdir.path().join("2.events");
+        ::std::fs::File::create(&incomplete_file).unwrap();
+
+        let mut partition = Partition::init_existing(PARTITION_NUM,
+                                                     tempdir.path().to_owned(),
+                                                     &options,
+                                                     HighestCounter::zero(),
+                                                     Box::new(SystemClock)).expect("failed to init partition");
+        assert!(!incomplete_file.exists());
+
+        let id = partition.append_all(vec![new_event("/foo/bar", "brown fox")]).expect("failed to append event");
+        assert_eq!(FloEventId::new(PARTITION_NUM, 2), id);
+
+        let count = partition.create_reader(CONNECTION, EventFilter::All, 0).map(|result| {
+            result.expect("failed to read event");
+        }).count();
+        assert_eq!(2, count);
+    }
+
+    #[test]
+    fn appended_events_are_fsynced_according_to_the_fsync_policy() {
+        let tempdir = TempDir::new("appended_events_are_fsynced").unwrap();
+
+        let mut options = PartitionOptions {
+            fsync_policy: FsyncPolicy::EveryBytes(200),
+            ..Default::default()
+        };
+        options.segment_max_size_bytes = 4096;
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                tempdir.path().join("bytes"),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).unwrap();
+
+        partition.append_all(vec![new_event("/foo/bar", "the quick")]).expect("failed to append event");
+        let unsynced = partition.unsynced_bytes;
+        assert!(unsynced > 0);
+        assert!(unsynced < 200);
+
+        let events = (0..4).map(|_| new_event("/foo/bar", "brown fox")).collect();
+        partition.append_all(events).expect("failed to append events");
+        assert_eq!(0, partition.unsynced_bytes);
+
+        options.fsync_policy = FsyncPolicy::Always;
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                tempdir.path().join("always"),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).unwrap();
+        partition.append_all(vec![new_event("/foo/bar", "the quick")]).expect("failed to append event");
+        assert_eq!(0, partition.unsynced_bytes);
+    }
+
+    #[test]
+    fn partition_is_fsynced_on_tick_once_the_interval_has_elapsed_without_any_more_appends() {
+        let tempdir = TempDir::new("partition_is_fsynced_on_tick").unwrap();
+        let options = PartitionOptions {
+            fsync_policy: FsyncPolicy::EveryMillis(1000),
+            ..Default::default()
+        };
+        let clock = ManualClock::new(time::now());
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                tempdir.path().to_owned(),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(clock.clone())).unwrap();
+
+        partition.append_all(vec![new_event("/foo/bar", "the quick")]).expect("failed to append event");
+        assert!(partition.unsynced_bytes > 0);
+
+        clock.advance(Duration::milliseconds(999));
+        partition.tick();
+        assert!(partition.unsynced_bytes > 0);
+
+        clock.advance(Duration::milliseconds(1));
+        partition.tick();
+        assert_eq!(0, partition.unsynced_bytes);
+    }
+
+    #[test]
+    fn oldest_segments_are_dropped_once_the_partition_exceeds_its_size_limits() {
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("oldest_segments_are_dropped_by_size").unwrap();
+
+        // each event is 69 bytes, so only 3 of them fit into a segment
+        let mut options = PartitionOptions {
+            segment_max_size_bytes: 256,
+            max_bytes: 600,
+            ..Default::default()
+        };
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                tempdir.path().join("bytes"),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).unwrap();
+        for _ in 0..30 {
+            partition.append_all(vec![new_event("/foo/bar", "the quick")]).expect("failed to append event");
+            let total_bytes: usize = partition.segments.iter().map(|s| s.get_size_bytes()).sum();
+            assert!(total_bytes <= 600, "partition has {} bytes", total_bytes);
+        }
+        let counters = partition.create_reader(CONNECTION, EventFilter::All, 0).map(|result| {
+            result.expect("failed to read event").id().event_counter
+        }).collect::<Vec<_>>();
+        assert_eq!(vec![25, 26, 27, 28, 29, 30], counters);
+
+        options.max_bytes = ::std::usize::MAX;
+        options.max_events = 5;
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                tempdir.path().join("events"),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).unwrap();
+        for _ in 0..30 {
+            partition.append_all(vec![new_event("/foo/bar", "the quick")]).expect("failed to append event");
+        }
+        let count = partition.create_reader(CONNECTION, EventFilter::All, 0).map(|result| {
+            result.expect("failed to read event");
+        }).count();
+        assert_eq!(3, count);
+    }
+
+    #[test]
+    fn segments_are_dropped_once_the_retention_period_has_passed_since_their_end_time() {
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("segments_are_dropped_after_retention").unwrap();
+        let options = PartitionOptions {
+            event_retention: Duration::seconds(20),
+            max_segment_duration: Duration::seconds(5),
+            ..Default::default()
+        };
+        let clock = ManualClock::new(time::now());
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                tempdir.path().to_owned(),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(clock.clone())).unwrap();
+
+        // each event goes into a new segment, since the previous one will have ended. The segments end at 5, 11, and 17 seconds
+        for _ in 0..3 {
+            partition.append_all(vec![new_event("/foo/bar", "the quick")]).expect("failed to append event");
+            clock.advance(Duration::seconds(6));
+        }
+        assert_eq!(3, partition.segments.len());
+
+        partition.expire_old_events();
+        assert_eq!(vec![1, 2, 3], read_counters(&partition));
+
+        // 25 seconds is exactly the end time plus the retention period for the first segment
+        clock.advance(Duration::seconds(7));
+        partition.expire_old_events();
+        assert_eq!(vec![1, 2, 3], read_counters(&partition));
+
+        clock.advance(Duration::seconds(1));
+        partition.tick();
+        assert_eq!(vec![2, 3], read_counters(&partition));
+
+        // the newest segment is kept after it expires, but its events are no longer readable
+        clock.advance(Duration::seconds(100));
+        partition.tick();
+        assert_eq!(1, partition.segments.len());
+        assert_eq!(Vec::<EventCounter>::new(), read_counters(&partition));
+
+        // segment numbers keep increasing, so the new segment can't be confused with one that was dropped
+        partition.append_all(vec![new_event("/foo/bar", "brown fox")]).expect("failed to append event");
+        assert_eq!(SegmentNum(4), partition.current_segment_num());
+        partition.tick();
+        assert_eq!(1, partition.segments.len());
+        assert_eq!(vec![4], read_counters(&partition));
+    }
+
+    #[test]
+    fn expired_segments_are_dropped_when_the_partition_is_initialized() {
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("expired_segments_are_dropped_on_init").unwrap();
+        let options = PartitionOptions {
+            event_retention: Duration::seconds(20),
+            max_segment_duration: Duration::seconds(5),
+            ..Default::default()
+        };
+        let clock = ManualClock::new(time::now());
+
+        // The segments end at 5 and 11 seconds
+        {
+            let mut partition = Partition::init_new(PARTITION_NUM,
+                                                    tempdir.path().to_owned(),
+                                                    &options,
+                                                    HighestCounter::zero(),
+                                                    Box::new(clock.clone())).unwrap();
+            partition.append_all(vec![new_event("/foo/bar", "the quick")]).expect("failed to append event");
+            clock.advance(Duration::seconds(6));
+            partition.append_all(vec![new_event("/foo/bar", "brown fox")]).expect("failed to append event");
+            partition.fsync().expect("failed to fsync");
+        }
+
+        clock.advance(Duration::seconds(20));
+        let partition = Partition::init_existing(PARTITION_NUM,
+                                                 tempdir.path().to_owned(),
+                                                 &options,
+                                                 HighestCounter::zero(),
+                                                 Box::new(clock.clone())).expect("failed to init partition");
+        assert_eq!(1, partition.segments.len());
+        assert_eq!(vec![2], read_counters(&partition));
+    }
+
+    #[test]
+    fn superseded_events_are_removed_from_sealed_segments_when_compacted() {
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("superseded_events_are_compacted").unwrap();
+
+        // each event is 57 bytes, so 4 of them fit into a segment
+        let options = PartitionOptions {
+            segment_max_size_bytes: 300,
+            compacted_namespaces: Some("/a/*".to_owned()),
+            ..Default::default()
+        };
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                tempdir.path().to_owned(),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).unwrap();
+        let namespaces = vec!["/a/1", "/a/2", "/b/1", "/a/1", "/a/2", "/b/1", "/a/3", "/a/1", "/a/2"];
+        for namespace in namespaces {
+            partition.append_all(vec![new_event(namespace, "x")]).expect("failed to append event");
+        }
+        assert_eq!(3, partition.segments.len());
+
+        let mut existing_reader = partition.create_reader(CONNECTION, EventFilter::All, 0);
+        let first = existing_reader.next().unwrap().expect("failed to read event");
+        assert_eq!(1, first.id().event_counter);
+
+        assert!(partition.compaction.is_compactable(SegmentNum(1)));
+        assert!(partition.compaction.is_compactable(SegmentNum(2)));
+        partition.tick();
+        // segments are only read again once a newer event supersedes one of theirs
+        assert!(!partition.compaction.has_compactable_segments());
+
+        // the reader that was already in the first segment finishes reading the old version of it
+        let remaining = existing_reader.map(|result| result.expect("failed to read event").id().event_counter).collect::<Vec<_>>();
+        assert_eq!(vec![2, 3, 4, 6, 7, 8, 9], remaining);
+
+        assert_eq!(vec![3, 6, 7, 8, 9], read_counters(&partition));
+        let next = partition.create_reader(CONNECTION, EventFilter::All, 4).next().unwrap().expect("failed to read event");
+        assert_eq!(6, next.id().event_counter);
+
+        let partition = Partition::init_existing(PARTITION_NUM,
+                                                 tempdir.path().to_owned(),
+                                                 &options,
+                                                 HighestCounter::zero(),
+                                                 Box::new(SystemClock)).expect("failed to init partition");
+        assert_eq!(vec![3, 6, 7, 8, 9], read_counters(&partition));
+    }
+
+    #[test]
+    fn sealed_segments_are_compressed_on_tick_and_read_transparently() {
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("sealed_segments_are_compressed").unwrap();
+
+        let options = PartitionOptions {
+            segment_max_size_bytes: 300,
+            compress_sealed_segments: true,
+            ..Default::default()
+        };
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                tempdir.path().to_owned(),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).unwrap();
+        for _ in 0..9 {
+            partition.append_all(vec![new_event("/foo/bar", "x")]).expect("failed to append event");
+        }
+        assert_eq!(3, partition.segments.len());
+
+        let mut existing_reader = partition.create_reader(CONNECTION, EventFilter::All, 0);
+        let first = existing_reader.next().unwrap().expect("failed to read event");
+        assert_eq!(1, first.id().event_counter);
+
+        partition.tick();
+        let compressed = partition.segments.iter().map(|s| s.is_compressed()).collect::<Vec<_>>();
+        assert_eq!(vec![false, true, true], compressed);
+
+        let remaining = existing_reader.map(|result| result.expect("failed to read event").id().event_counter).collect::<Vec<_>>();
+        assert_eq!(vec![2, 3, 4, 5, 6, 7, 8, 9], remaining);
+        assert_eq!((1..10).collect::<Vec<_>>(), read_counters(&partition));
+        let next = partition.create_reader(CONNECTION, EventFilter::All, 5).next().unwrap().expect("failed to read event");
+        assert_eq!(6, next.id().event_counter);
+
+        let mut partition = Partition::init_existing(PARTITION_NUM,
+                                                     tempdir.path().to_owned(),
+                                                     &options,
+                                                     HighestCounter::zero(),
+                                                     Box::new(SystemClock)).expect("failed to init partition");
+        assert!(partition.segments[1].is_compressed());
+        partition.append_all(vec![new_event("/foo/bar", "x")]).expect("failed to append event");
+        assert_eq!((1..11).collect::<Vec<_>>(), read_counters(&partition));
+    }
+
+    #[test]
+    fn dropped_segments_are_archived_and_read_back_by_consumers() {
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("dropped_segments_are_archived").unwrap();
+        let partition_dir = tempdir.path().join("data");
+        let archived_events_file = tempdir.path().join("archive/1.events");
+
+        // each event is 57 bytes, so 4 of them fit into a segment
+        let options = PartitionOptions {
+            segment_max_size_bytes: 300,
+            max_events: 4,
+            archive: Some(ArchiveOptions {
+                dir: tempdir.path().join("archive"),
+                retention: Duration::days(1),
+            }),
+            ..Default::default()
+        };
+        let clock = ManualClock::new(time::now());
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                partition_dir.clone(),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(clock.clone())).unwrap();
+        for _ in 0..12 {
+            partition.append_all(vec![new_event("/foo/bar", "x")]).expect("failed to append event");
+        }
+        assert_eq!(1, partition.segments.len());
+        assert!(archived_events_file.exists());
+        assert!(!partition_dir.join("1.events").exists());
+
+        assert_eq!((1..13).collect::<Vec<_>>(), read_counters(&partition));
+        let next = partition.create_reader(CONNECTION, EventFilter::All, 5).next().unwrap().expect("failed to read event");
+        assert_eq!(6, next.id().event_counter);
+
+        let mut partition = Partition::init_existing(PARTITION_NUM,
+                                                     partition_dir.clone(),
+                                                     &options,
+                                                     HighestCounter::zero(),
+                                                     Box::new(clock.clone())).expect("failed to init partition");
+        assert_eq!((1..13).collect::<Vec<_>>(), read_counters(&partition));
+
+        // archived segments are removed once the archive retention period has passed since their end time
+        clock.advance(Duration::days(3));
+        partition.tick();
+        assert!(!archived_events_file.exists());
+        assert_eq!(vec![9, 10, 11, 12], read_counters(&partition));
+    }
+
+    #[test]
+    fn snapshot_includes_every_event_up_to_the_head_and_can_be_restored() {
+        use data_dir::{restore_snapshot, get_event_steam_data_dir, get_partition_data_dir};
+
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("snapshot_can_be_restored").unwrap();
+        let snapshot_dir = tempdir.path().join("snapshot");
+        let partition_snapshot_dir = get_partition_data_dir(&get_event_steam_data_dir(&snapshot_dir, "events"), PARTITION_NUM);
+        let restored_dir = tempdir.path().join("restored");
+
+        // each event is 61 bytes, so 4 of them fit into a segment
+        let options = PartitionOptions {
+            segment_max_size_bytes: 300,
+            ..Default::default()
+        };
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                tempdir.path().join("data"),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).unwrap();
+        for _ in 0..9 {
+            partition.append_all(vec![new_event("/foo/bar", "x")]).expect("failed to append event");
+        }
+
+        let snapshot = partition.snapshot(&partition_snapshot_dir).expect("failed to create snapshot");
+        assert_eq!(9, snapshot.head);
+        assert_eq!(3, snapshot.segment_count);
+        assert_eq!(SegmentHeader::get_repr_length() + 61, snapshot.active_segment_offset);
+
+        // events appended after the snapshot aren't included in it
+        partition.append_all(vec![new_event("/foo/bar", "x")]).expect("failed to append event");
+        assert_eq!((1..11).collect::<Vec<_>>(), read_counters(&partition));
+
+        // a snapshot can't overwrite an existing one
+        let err = partition.snapshot(&partition_snapshot_dir).unwrap_err();
+        assert_eq!(io::ErrorKind::AlreadyExists, err.kind());
+
+        // whatever was left behind by a restore that failed partway through gets replaced
+        let incomplete_restore_dir = restored_dir.join(".events.restoring").join("7");
+        ::std::fs::create_dir_all(&incomplete_restore_dir).unwrap();
+
+        let restored_streams = restore_snapshot(&snapshot_dir, &restored_dir).expect("failed to restore snapshot");
+        assert_eq!(vec!["events".to_owned()], restored_streams);
+        assert!(!restored_dir.join(".events.restoring").exists());
+        assert!(!get_event_steam_data_dir(&restored_dir, "events").join("7").exists());
+        // restoring again would overwrite the existing stream
+        let err = restore_snapshot(&snapshot_dir, &restored_dir).unwrap_err();
+        assert_eq!(io::ErrorKind::AlreadyExists, err.kind());
+
+        let mut restored = Partition::init_existing(PARTITION_NUM,
+                                                    get_partition_data_dir(&get_event_steam_data_dir(&restored_dir, "events"), PARTITION_NUM),
+                                                    &options,
+                                                    HighestCounter::zero(),
+                                                    Box::new(SystemClock)).expect("failed to init partition from snapshot");
+        assert_eq!((1..10).collect::<Vec<_>>(), read_counters(&restored));
+
+        let id = restored.append_all(vec![new_event("/foo/bar", "x")]).expect("failed to append to restored partition");
+        assert_eq!(FloEventId::new(PARTITION_NUM, 10), id);
+    }
+
+    #[test]
+    fn deleted_events_are_skipped_by_readers_and_removed_from_sealed_segments() {
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("deleted_events_are_skipped").unwrap();
+        // each event is 57 bytes, so 4 of them fit into a segment
+        let options = PartitionOptions {
+            segment_max_size_bytes: 300,
+            ..Default::default()
+        };
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                tempdir.path().to_owned(),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).unwrap();
+        let events = (1..7).map(|i| {
+            let namespace = if i % 2 == 0 { "/users/2" } else { "/users/1" };
+            new_event(namespace, "x")
+        }).collect();
+        partition.append_all(events).expect("failed to append events");
+        assert_eq!(2, partition.segments.len());
+
+        let err = partition.delete(EventDeletion { counters: vec![99], namespace: None }).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+
+        partition.delete(EventDeletion {
+            counters: vec![1],
+            namespace: Some("/users/2".to_owned()),
+        }).expect("failed to delete events");
+        assert_eq!(vec![3, 5], read_counters(&partition));
+
+        partition.tick();
+        assert_eq!(1, partition.segments[1].get_event_count());
+        assert_eq!(2, partition.segments[0].get_event_count());
+        assert_eq!(vec![3, 5], read_counters(&partition));
+
+        // events that are appended after the namespace was deleted are unaffected, and ids are never reused
+        let id = partition.append_all(vec![new_event("/users/2", "x")]).expect("failed to append event");
+        assert_eq!(FloEventId::new(PARTITION_NUM, 7), id);
+        assert_eq!(vec![3, 5, 7], read_counters(&partition));
+        drop(partition);
+
+        let partition = Partition::init_existing(PARTITION_NUM,
+                                                 tempdir.path().to_owned(),
+                                                 &options,
+                                                 HighestCounter::zero(),
+                                                 Box::new(SystemClock)).expect("failed to init partition");
+        assert_eq!(vec![3, 5, 7], read_counters(&partition));
+    }
+
+    #[test]
+    fn namespace_heads_are_persisted_when_a_segment_is_sealed_and_found_after_a_restart() {
+        let tempdir = TempDir::new("namespace_heads_are_persisted").unwrap();
+        let heads_file = get_namespace_heads_file(tempdir.path());
+        // 4 of these events fit into a segment
+        let options = PartitionOptions {
+            segment_max_size_bytes: 300,
+            ..Default::default()
+        };
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                tempdir.path().to_owned(),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).unwrap();
+        let events = vec!["/a", "/b", "/a", "/b", "/a", "/b"].into_iter().map(|namespace| new_event(namespace, "x")).collect();
+        partition.append_all(events).expect("failed to append events");
+        assert_eq!(2, partition.segments.len());
+        let persisted = read_namespace_heads_file(&heads_file).expect("failed to read namespace heads");
+        assert_eq!(4, persisted.through_counter());
+        assert_eq!(&heads(&[("/a", 3), ("/b", 4)]), persisted.heads());
+        assert_eq!(heads(&[("/a", 5), ("/b", 6)]), partition.namespace_heads().unwrap());
+
+        // the persisted heads stop short of a transaction that's still prepared when the segment is sealed
+        partition.prepare_transaction(1, vec![new_event("/c", "x")]).expect("failed to prepare transaction");
+        partition.append_all(vec![new_event("/b", "x"), new_event("/a", "x")]).expect("failed to append events");
+        assert_eq!(3, partition.segments.len());
+        assert_eq!(6, read_namespace_heads_file(&heads_file).unwrap().through_counter());
+        assert_eq!(heads(&[("/a", 9), ("/b", 8)]), partition.namespace_heads().unwrap());
+        partition.commit_transaction(1).expect("failed to commit transaction");
+        assert_eq!(heads(&[("/a", 9), ("/b", 8), ("/c", 7)]), partition.namespace_heads().unwrap());
+
+        // deleting a namespace removes its head, and deleting a head by its counter goes back to the previous event
+        partition.delete(EventDeletion {
+            counters: vec![6, 8],
+            namespace: Some("/a".to_owned()),
+        }).expect("failed to delete events");
+        let expected = heads(&[("/b", 4), ("/c", 7)]);
+        assert_eq!(expected, partition.namespace_heads().unwrap());
+        let persisted = read_namespace_heads_file(&heads_file).unwrap();
+        assert_eq!(8, persisted.through_counter());
+        assert_eq!(&expected, persisted.heads());
+        drop(partition);
+
+        let partition = Partition::init_existing(PARTITION_NUM,
+                                                 tempdir.path().to_owned(),
+                                                 &options,
+                                                 HighestCounter::zero(),
+                                                 Box::new(SystemClock)).expect("failed to init partition");
+        assert_eq!(expected, partition.namespace_heads().unwrap());
+    }
+
+    #[test]
+    fn new_segments_are_encrypted_with_the_newest_key_from_the_key_file() {
+        use std::io::{Read, Write};
+
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("new_segments_are_encrypted").unwrap();
+        let partition_dir = tempdir.path().join("data");
+        let key_file = tempdir.path().join("keys");
+        write!(::std::fs::File::create(&key_file).unwrap(), "1:{}\n", "01".repeat(32)).unwrap();
+
+        // each encrypted event is 102 bytes, and the header is 48 bytes, so 2 of them fit into a segment
+        let options = PartitionOptions {
+            segment_max_size_bytes: 300,
+            compress_sealed_segments: true,
+            encryption_key_file: Some(key_file.clone()),
+            ..Default::default()
+        };
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                partition_dir.clone(),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).unwrap();
+        let events = (0..3).map(|_| new_event("/foo/bar", "secret")).collect();
+        partition.append_all(events).expect("failed to append events");
+        assert_eq!(2, partition.segments.len());
+
+        let mut contents = Vec::new();
+        ::std::fs::File::open(partition_dir.join("1.events")).unwrap().read_to_end(&mut contents).unwrap();
+        assert!(!contents.windows(6).any(|window| window == b"secret"));
+
+        // encrypted segments are never compressed, but they can still be rewritten
+        partition.delete(EventDeletion { counters: vec![1], namespace: None }).expect("failed to delete event");
+        partition.tick();
+        assert!(!partition.segments[1].is_compressed());
+        assert_eq!(1, partition.segments[1].get_event_count());
+        assert_eq!(vec![2, 3], read_counters(&partition));
+        drop(partition);
+
+        write!(::std::fs::OpenOptions::new().append(true).open(&key_file).unwrap(), "2:{}\n", "02".repeat(32)).unwrap();
+        let mut partition = Partition::init_existing(PARTITION_NUM,
+                                                     partition_dir.clone(),
+                                                     &options,
+                                                     HighestCounter::zero(),
+                                                     Box::new(SystemClock)).expect("failed to init partition");
+        partition.append_all(vec![new_event("/foo/bar", "secret")]).expect("failed to append event");
+        let key_ids = partition.segments.iter().map(|s| s.get_key_id()).collect::<Vec<_>>();
+        assert_eq!(vec![Some(2), Some(1), Some(1)], key_ids);
+        assert_eq!(vec![2, 3, 4], read_counters(&partition));
+        drop(partition);
+
+        let unencrypted_options = PartitionOptions {
+            segment_max_size_bytes: 300,
+            ..Default::default()
+        };
+        let result = Partition::init_existing(PARTITION_NUM,
+                                              partition_dir.clone(),
+                                              &unencrypted_options,
+                                              HighestCounter::zero(),
+                                              Box::new(SystemClock));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn encrypted_events_are_read_back_as_plaintext_after_the_partition_is_reopened() {
+        use std::io::Write;
+
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("encrypted_events_are_read_after_reopen").unwrap();
+        let partition_dir = tempdir.path().join("data");
+        let key_file = tempdir.path().join("keys");
+        write!(::std::fs::File::create(&key_file).unwrap(), "1:{}\n", "01".repeat(32)).unwrap();
+
+        let options = PartitionOptions {
+            segment_max_size_bytes: 300,
+            encryption_key_file: Some(key_file.clone()),
+            ..Default::default()
+        };
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                partition_dir.clone(),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).unwrap();
+        let events = vec![new_event("/foo/1", "one"), new_event("/foo/2", "two"), new_event("/foo/3", "three")];
+        partition.append_all(events).expect("failed to append events");
+        drop(partition);
+
+        let partition = Partition::init_existing(PARTITION_NUM,
+                                                 partition_dir.clone(),
+                                                 &options,
+                                                 HighestCounter::zero(),
+                                                 Box::new(SystemClock)).expect("failed to init partition");
+        let events = partition.create_reader(CONNECTION, EventFilter::All, 0).map(|result| {
+            let event = result.expect("failed to read event");
+            (event.namespace().to_owned(), event.data().to_vec())
+        }).collect::<Vec<_>>();
+        let expected = vec![
+            ("/foo/1".to_owned(), b"one".to_vec()),
+            ("/foo/2".to_owned(), b"two".to_vec()),
+            ("/foo/3".to_owned(), b"three".to_vec()),
+        ];
+        assert_eq!(expected, events);
+    }
+
+    #[test]
+    fn readers_skip_events_with_an_event_time_before_the_min_event_time() {
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("readers_skip_events_before_min_event_time").unwrap();
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                tempdir.path().to_owned(),
+                                                &PartitionOptions::default(),
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).unwrap();
+        partition.append_all(vec![
+            new_event("/foo/bar", "old").with_event_time(time::from_millis_since_epoch(1000)),
+            new_event("/foo/bar", "new").with_event_time(time::from_millis_since_epoch(5000)),
+            new_event("/foo/bar", "no event time"),
+        ]).expect("failed to append events");
+
+        let events = partition.create_reader(CONNECTION, EventFilter::All, 0)
+                .with_min_event_time(time::from_millis_since_epoch(3000))
+                .map(|result| result.expect("failed to read event"))
+                .collect::<Vec<_>>();
+
+        // the event without an event time is compared using its timestamp, which is the time it was appended
+        assert_eq!(2, events.len());
+        assert_eq!(2, events[0].id().event_counter);
+        assert_eq!(Some(time::from_millis_since_epoch(5000)), events[0].event_time());
+        assert_eq!(3, events[1].id().event_counter);
+        assert_eq!(None, events[1].event_time());
+    }
+
+    #[test]
+    fn consumers_by_event_time_start_at_the_first_segment_that_may_have_events_since_then() {
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("consumers_by_event_time_skip_segments").unwrap();
+        // each event gets its own segment
+        let options = PartitionOptions {
+            segment_max_size_bytes: 256,
+            ..Default::default()
+        };
+        let data = ::std::iter::repeat("x").take(100).collect::<String>();
+        let event_times = vec![Some(1000), Some(2000), Some(9000), Some(3000), None];
+        {
+            let mut partition = Partition::init_new(PARTITION_NUM,
+                                                    tempdir.path().to_owned(),
+                                                    &options,
+                                                    HighestCounter::zero(),
+                                                    Box::new(SystemClock)).unwrap();
+            for event_time in event_times.iter() {
+                let mut event = new_event("/foo/bar", &data);
+                event.event_time = event_time.map(time::from_millis_since_epoch);
+                partition.append_all(vec![event]).expect("failed to append event");
+            }
+            assert_eq!(5, partition.segments.len());
+            assert_eq!(2, partition.get_start_counter_since_event_time(time::from_millis_since_epoch(5000)).unwrap());
+        }
+
+        // sealed segments get their latest event times from their index files
+        let partition = Partition::init_existing(PARTITION_NUM,
+                                                 tempdir.path().to_owned(),
+                                                 &options,
+                                                 HighestCounter::zero(),
+                                                 Box::new(SystemClock)).unwrap();
+        let since = time::from_millis_since_epoch(5000);
+        let start = partition.get_start_counter_since_event_time(since).unwrap();
+        assert_eq!(2, start);
+        let counters = partition.create_reader(CONNECTION, EventFilter::All, start)
+                .with_min_event_time(since)
+                .map(|result| result.expect("failed to read event").id().event_counter)
+                .collect::<Vec<_>>();
+        assert_eq!(vec![3, 5], counters);
+
+        let future = time::now() + ::chrono::Duration::days(1);
+        assert_eq!(5, partition.get_start_counter_since_event_time(future).unwrap());
+    }
+
+    #[test]
+    fn producer_sequence_is_persisted_with_the_event() {
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("producer_sequence_is_persisted").unwrap();
+        let options = PartitionOptions::default();
+        let producer_sequence = ProducerSequence { producer_id: 7, sequence: 3 };
+        {
+            let mut partition = Partition::init_new(PARTITION_NUM,
+                                                    tempdir.path().to_owned(),
+                                                    &options,
+                                                    HighestCounter::zero(),
+                                                    Box::new(SystemClock)).unwrap();
+            partition.append_all(vec![
+                new_event("/foo/bar", "idempotent").with_producer_sequence(producer_sequence),
+                new_event("/foo/bar", "not idempotent"),
+            ]).expect("failed to append events");
+        }
+
+        let partition = Partition::init_existing(PARTITION_NUM,
+                                                 tempdir.path().to_owned(),
+                                                 &options,
+                                                 HighestCounter::zero(),
+                                                 Box::new(SystemClock)).expect("failed to init partition");
+        let sequences = partition.create_reader(CONNECTION, EventFilter::All, 0).map(|result| {
+            result.expect("failed to read event").producer_sequence()
+        }).collect::<Vec<_>>();
+        assert_eq!(vec![Some(producer_sequence), None], sequences);
+    }
+
+    #[test]
+    fn readers_stop_at_a_prepared_transaction_until_it_is_committed_and_skip_it_once_aborted() {
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("readers_stop_at_a_prepared_transaction").unwrap();
+        let options = PartitionOptions::default();
+        let mut partition = Partition::init_new(PARTITION_NUM,
+                                                tempdir.path().to_owned(),
+                                                &options,
+                                                HighestCounter::zero(),
+                                                Box::new(SystemClock)).unwrap();
+        partition.append_all(vec![new_event("/foo", "a")]).expect("failed to append event");
+        let id = partition.prepare_transaction(7, vec![new_event("/foo", "b"), new_event("/foo", "c")]).expect("failed to prepare transaction");
+        assert_eq!(FloEventId::new(PARTITION_NUM, 3), id);
+        // events appended after the transaction was prepared have to wait for it as well
+        partition.append_all(vec![new_event("/foo", "d")]).expect("failed to append event");
+
+        let mut reader = partition.create_reader(CONNECTION, EventFilter::All, 0);
+        let mut read_available = move || -> Vec<EventCounter> {
+            reader.by_ref().map(|result| result.expect("failed to read event").id().event_counter).collect()
+        };
+        assert_eq!(vec![1], read_available());
+        assert_eq!(Vec::<EventCounter>::new(), read_available());
+
+        partition.commit_transaction(7).expect("failed to commit transaction");
+        assert_eq!(vec![2, 3, 4], read_available());
+        assert_eq!(io::ErrorKind::InvalidInput, partition.commit_transaction(7).unwrap_err().kind());
+
+        partition.prepare_transaction(8, vec![new_event("/foo", "e"), new_event("/foo", "f")]).expect("failed to prepare transaction");
+        partition.abort_transaction(8).expect("failed to abort transaction");
+        partition.append_all(vec![new_event("/foo", "g")]).expect("failed to append event");
+        assert_eq!(vec![7], read_available());
+        assert_eq!(vec![1, 2, 3, 4, 7], read_counters(&partition));
+        drop(partition);
+
+        let partition = Partition::init_existing(PARTITION_NUM,
+                                                 tempdir.path().to_owned(),
+                                                 &options,
+                                                 HighestCounter::zero(),
+                                                 Box::new(SystemClock)).expect("failed to init partition");
+        assert_eq!(vec![1, 2, 3, 4, 7], read_counters(&partition));
+    }
+
+    #[test]
+    fn transaction_across_two_partitions_is_only_visible_once_a_commit_marker_is_written() {
+        use transaction::resolve_transactions;
+
+        let _ = ::env_logger::init();
+        let tempdir = TempDir::new("transaction_across_two_partitions").unwrap();
+        let options = PartitionOptions {
+            segment_max_size_bytes: 300,
+            ..Default::default()
+        };
+        let partition_dirs = vec![tempdir.path().join("1"), tempdir.path().join("2")];
+        let highest_counter = HighestCounter::zero();
+        let mut first = Partition::init_new(1, partition_dirs[0].clone(), &options, highest_counter.clone(), Box::new(SystemClock)).unwrap();
+        let mut second = Partition::init_new(2, partition_dirs[1].clone(), &options, highest_counter.clone(), Box::new(SystemClock)).unwrap();
+
+        // The second partition appends its first event, but the next one is too big for a segment, so it aborts its part
+        // of the transaction right away. The first partition then aborts its part as well
+        first.prepare_transaction(1, vec![new_event("/a", "x")]).expect("failed to prepare transaction");
+        let too_big = NewEvent::new("/b", None, vec![0; 400]);
+        let err = second.prepare_transaction(1, vec![new_event("/b", "x"), too_big]).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        first.abort_transaction(1).expect("failed to abort transaction");
+        assert_eq!(Vec::<EventCounter>::new(), read_counters(&first));
+        assert_eq!(Vec::<EventCounter>::new(), read_counters(&second));
+
+        // The server stops after the first partition commits transaction 2, but before the second one does. Neither
+        // partition commits transaction 3
+        first.prepare_transaction(2, vec![new_event("/a", "x")]).expect("failed to prepare transaction");
+        second.prepare_transaction(2, vec![new_event("/b", "x")]).expect("failed to prepare transaction");
+        first.commit_transaction(2).expect("failed to commit transaction");
+        first.prepare_transaction(3, vec![new_event("/a", "x")]).expect("failed to prepare transaction");
+        second.prepare_transaction(3, vec![new_event("/b", "x")]).expect("failed to prepare transaction");
+        second.append_all(vec![new_event("/b", "y")]).expect("failed to append event");
+        assert_eq!(vec![4], read_counters(&first));
+        assert_eq!(Vec::<EventCounter>::new(), read_counters(&second));
+        drop(first);
+        drop(second);
+
+        resolve_transactions(&partition_dirs).expect("failed to resolve transactions");
+        let first = Partition::init_existing(1, partition_dirs[0].clone(), &options, HighestCounter::zero(), Box::new(SystemClock)).unwrap();
+        let second = Partition::init_existing(2, partition_dirs[1].clone(), &options, HighestCounter::zero(), Box::new(SystemClock)).unwrap();
+        assert_eq!(vec![4], read_counters(&first));
+        assert_eq!(vec![5, 8], read_counters(&second));
+    }
+
+    fn read_counters(partition: &Partition) -> Vec<EventCounter> {
+        partition.create_reader(CONNECTION, EventFilter::All, 0).map(|result| {
+            result.expect("failed to read event").id().event_counter
+        }).collect()
+    }
+
+    fn new_event(namespace: &str, data: &str) -> NewEvent {
+        NewEvent::new(namespace, None, data)
+    }
+
+    fn heads(heads: &[(&str, EventCounter)]) -> HashMap<String, EventCounter> {
+        heads.iter().map(|&(namespace, counter)| (namespace.to_owned(), counter)).collect()
+    }
+}
@@ -0,0 +1,167 @@
+use std::io;
+use std::collections::HashMap;
+use std::path::Path;
+
+use event::{ActorId, FloEventId, EventCounter, Timestamp, ProducerSequence};
+use event_reader::{PartitionReader, EventFilter, ConnectionId};
+use transaction::TransactionId;
+use super::{Partition, MemoryPartition, NewEvent, EventDeletion, PartitionSnapshot};
+
+/// The operations that the server needs from the storage for a single partition. This allows a partition to be backed
+/// either by segment files using a `Partition`, or by a `MemoryPartition` that never touches the filesystem. See the
+/// inherent methods of `Partition` for the details of each operation.
+pub trait PartitionStorage: Send {
+    fn partition_num(&self) -> ActorId;
+
+    fn greatest_event_counter(&self) -> EventCounter;
+
+    fn tick(&mut self);
+
+    fn append_all(&mut self, events: Vec<NewEvent>) -> io::Result<FloEventId>;
+
+    fn prepare_transaction(&mut self, transaction_id: TransactionId, events: Vec<NewEvent>) -> io::Result<FloEventId>;
+
+    fn commit_transaction(&mut self, transaction_id: TransactionId) -> io::Result<()>;
+
+    fn abort_transaction(&mut self, transaction_id: TransactionId) -> io::Result<()>;
+
+    fn fsync(&mut self) -> io::Result<()>;
+
+    fn delete(&mut self, deletion: EventDeletion) -> io::Result<()>;
+
+    fn deleted_producer_sequences(&self, start_exclusive: EventCounter) -> Vec<(FloEventId, ProducerSequence)>;
+
+    fn namespace_heads(&self) -> io::Result<HashMap<String, EventCounter>>;
+
+    fn snapshot(&mut self, dest_dir: &Path) -> io::Result<PartitionSnapshot>;
+
+    fn get_start_counter_since(&self, since: Timestamp) -> io::Result<EventCounter>;
+
+    fn get_start_counter_since_event_time(&self, since: Timestamp) -> io::Result<EventCounter>;
+
+    fn create_reader(&self, connection_id: ConnectionId, filter: EventFilter, start_exclusive: EventCounter) -> PartitionReader;
+}
+
+impl PartitionStorage for Partition {
+    fn partition_num(&self) -> ActorId {
+        Partition::partition_num(self)
+    }
+
+    fn greatest_event_counter(&self) -> EventCounter {
+        Partition::greatest_event_counter(self)
+    }
+
+    fn tick(&mut self) {
+        Partition::tick(self)
+    }
+
+    fn append_all(&mut self, events: Vec<NewEvent>) -> io::Result<FloEventId> {
+        Partition::append_all(self, events)
+    }
+
+    fn prepare_transaction(&mut self, transaction_id: TransactionId, events: Vec<NewEvent>) -> io::Result<FloEventId> {
+        Partition::prepare_transaction(self, transaction_id, events)
+    }
+
+    fn commit_transaction(&mut self, transaction_id: TransactionId) -> io::Result<()> {
+        Partition::commit_transaction(self, transaction_id)
+    }
+
+    fn abort_transaction(&mut self, transaction_id: TransactionId) -> io::Result<()> {
+        Partition::abort_transaction(self, transaction_id)
+    }
+
+    fn fsync(&mut self) -> io::Result<()> {
+        Partition::fsync(self)
+    }
+
+    fn delete(&mut self, deletion: EventDeletion) -> io::Result<()> {
+        Partition::delete(self, deletion)
+    }
+
+    fn deleted_producer_sequences(&self, start_exclusive: EventCounter) -> Vec<(FloEventId, ProducerSequence)> {
+        Partition::deleted_producer_sequences(self, start_exclusive)
+    }
+
+    fn namespace_heads(&self) -> io::Result<HashMap<String, EventCounter>> {
+        Partition::namespace_heads(self)
+    }
+
+    fn snapshot(&mut self, dest_dir: &Path) -> io::Result<PartitionSnapshot> {
+        Partition::snapshot(self, dest_dir)
+    }
+
+    fn get_start_counter_since(&self, since: Timestamp) -> io::Result<EventCounter> {
+        Partition::get_start_counter_since(self, since)
+    }
+
+    fn get_start_counter_since_event_time(&self, since: Timestamp) -> io::Result<EventCounter> {
+        Partition::get_start_counter_since_event_time(self, since)
+    }
+
+    fn create_reader(&self, connection_id: ConnectionId, filter: EventFilter, start_exclusive: EventCounter) -> PartitionReader {
+        Partition::create_reader(self, connection_id, filter, start_exclusive)
+    }
+}
+
+impl PartitionStorage for MemoryPartition {
+    fn partition_num(&self) -> ActorId {
+        MemoryPartition::partition_num(self)
+    }
+
+    fn greatest_event_counter(&self) -> EventCounter {
+        MemoryPartition::greatest_event_counter(self)
+    }
+
+    fn tick(&mut self) {
+        MemoryPartition::tick(self)
+    }
+
+    fn append_all(&mut self, events: Vec<NewEvent>) -> io::Result<FloEventId> {
+        MemoryPartition::append_all(self, events)
+    }
+
+    fn prepare_transaction(&mut self, transaction_id: TransactionId, events: Vec<NewEvent>) -> io::Result<FloEventId> {
+        MemoryPartition::prepare_transaction(self, transaction_id, events)
+    }
+
+    fn commit_transaction(&mut self, transaction_id: TransactionId) -> io::Result<()> {
+        MemoryPartition::commit_transaction(self, transaction_id)
+    }
+
+    fn abort_transaction(&mut self, transaction_id: TransactionId) -> io::Result<()> {
+        MemoryPartition::abort_transaction(self, transaction_id)
+    }
+
+    fn fsync(&mut self) -> io::Result<()> {
+        MemoryPartition::fsync(self)
+    }
+
+    fn delete(&mut self, deletion: EventDeletion) -> io::Result<()> {
+        MemoryPartition::delete(self, deletion)
+    }
+
+    fn deleted_producer_sequences(&self, start_exclusive: EventCounter) -> Vec<(FloEventId, ProducerSequence)> {
+        MemoryPartition::deleted_producer_sequences(self, start_exclusive)
+    }
+
+    fn namespace_heads(&self) -> io::Result<HashMap<String, EventCounter>> {
+        MemoryPartition::namespace_heads(self)
+    }
+
+    fn snapshot(&mut self, dest_dir: &Path) -> io::Result<PartitionSnapshot> {
+        MemoryPartition::snapshot(self, dest_dir)
+    }
+
+    fn get_start_counter_since(&self, since: Timestamp) -> io::Result<EventCounter> {
+        MemoryPartition::get_start_counter_since(self, since)
+    }
+
+    fn get_start_counter_since_event_time(&self, since: Timestamp) -> io::Result<EventCounter> {
+        MemoryPartition::get_start_counter_since_event_time(self, since)
+    }
+
+    fn create_reader(&self, connection_id: ConnectionId, filter: EventFilter, start_exclusive: EventCounter) -> PartitionReader {
+        MemoryPartition::create_reader(self, connection_id, filter, start_exclusive)
+    }
+}
@@ -0,0 +1,110 @@
+
+use std::io;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use segment::{Segment, KeyRing, migrate_segment_file, get_index_file, remove_index_file, COMPACTION_FILE_EXTENSION, MIGRATION_FILE_EXTENSION, COMPRESSION_FILE_EXTENSION};
+use event::ActorId;
+use partition::{SegmentNum, DATA_FILE_EXTENSION};
+use index::PartitionIndex;
+use archive::ArchiveRef;
+
+#[derive(Debug)]
+pub struct SegmentFile {
+    pub segment_num: SegmentNum,
+    pub path: PathBuf,
+}
+
+impl SegmentFile {
+    pub fn init_segment(&self, max_segment_size: usize, keys: Option<&KeyRing>, partition_index: &mut PartitionIndex) -> io::Result<Segment> {
+        let start_time = Instant::now();
+        debug!("initializing {:?}", self);
+        let segment = Segment::init_from_existing_file(&self.path, self.segment_num, max_segment_size, keys, partition_index)?;
+        debug!("Finished initializing {:?} in {:?}", self, start_time.elapsed());
+        Ok(segment)
+    }
+}
+
+/// If the server dies right after creating a new segment file, then the newest segment file may not have been
+/// initialized yet. A segment like that can't contain any events, so it's safe to just remove it.
+pub fn remove_incomplete_segment_file(segment_files: &mut Vec<SegmentFile>) -> io::Result<()> {
+    let is_incomplete = match segment_files.last() {
+        Some(newest) => !Segment::is_initialized_file(&newest.path)?,
+        None => false,
+    };
+
+    if is_incomplete {
+        let incomplete = segment_files.pop().unwrap();
+        warn!("Removing incomplete segment file: {:?}", incomplete.path);
+        fs::remove_file(&incomplete.path)?;
+    }
+    Ok(())
+}
+
+/// Removes any temporary files that were left behind if the server died in the middle of compacting, migrating, or
+/// compressing a segment. The original segment file is only replaced once the new version is complete, so these are always safe to remove.
+pub fn remove_incomplete_temp_files(partition_dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(partition_dir)? {
+        let path = entry?.path();
+        let is_temp_file = path.extension().map(|ext| {
+            ext == COMPACTION_FILE_EXTENSION || ext == MIGRATION_FILE_EXTENSION || ext == COMPRESSION_FILE_EXTENSION
+        }).unwrap_or(false);
+        if is_temp_file {
+            warn!("Removing incomplete temporary segment file: {:?}", path);
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+/// If the server dies after a segment has been archived, but before its local files were deleted, then the segment will
+/// still be in the partition directory. The archive has the authoritative copy, so the local files are just removed.
+pub fn remove_archived_segment_files(segment_files: &mut Vec<SegmentFile>, archive: &ArchiveRef) -> io::Result<()> {
+    while segment_files.first().map(|file| archive.contains(file.segment_num)).unwrap_or(false) {
+        let archived = segment_files.remove(0);
+        warn!("Removing local copy of archived segment file: {:?}", archived.path);
+        remove_index_file(&get_index_file(&archived.path))?;
+        fs::remove_file(&archived.path)?;
+    }
+    Ok(())
+}
+
+/// Upgrades any segment files that were written using an older version of the segment format, so that they can be opened
+pub fn migrate_segment_files(segment_files: &[SegmentFile], partition_num: ActorId) -> io::Result<()> {
+    for segment_file in segment_files {
+        migrate_segment_file(&segment_file.path, partition_num)?;
+    }
+    Ok(())
+}
+
+pub fn get_segment_files(partition_dir: &Path) -> io::Result<Vec<SegmentFile>> {
+    let dir_entries = fs::read_dir(partition_dir)?;
+
+    let mut segments = Vec::with_capacity(8);
+
+    for entry in dir_entries {
+        let dir_entry = entry?;
+        if let Ok(string) = dir_entry.file_name().into_string() {
+            if string.ends_with(DATA_FILE_EXTENSION) {
+                let segment_num_str = string.split_terminator(DATA_FILE_EXTENSION).next();
+
+                let segment_num = segment_num_str.and_then(|num_str| {
+                    num_str.parse::<u64>().ok()
+                }).ok_or_else(|| {
+                    let message = format!("Invalid events filename: {}", string);
+                    io::Error::new(io::ErrorKind::Other, message)
+                })?; // early return if any of this business fails
+
+                segments.push(SegmentFile {
+                    path: dir_entry.path(),
+                    segment_num: SegmentNum::new(segment_num),
+                });
+            }
+        }
+    }
+
+    segments.sort_by_key(|sf| sf.segment_num);
+    Ok(segments)
+}
+
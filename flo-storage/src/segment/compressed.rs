@@ -0,0 +1,328 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::fmt::{self, Debug};
+
+use byteorder::{ByteOrder, BigEndian};
+use crc::crc32;
+use memmap::{Mmap, Protection};
+use snap;
+
+use event::{FloEvent, EventCounter};
+use super::header::SegmentHeader;
+use super::mmap::{MmapInner, MmapRef, SegmentDeleter};
+use super::persistent_event::PersistentEvent;
+
+/// Extension for the temporary file that a segment is written into while it's being compressed
+pub const COMPRESSION_FILE_EXTENSION: &'static str = "compressing";
+
+/// Events are grouped into blocks of at least this many uncompressed bytes, and each block is compressed independently.
+/// Blocks always end on an event boundary, so reading any single event only requires decompressing one block.
+const TARGET_BLOCK_SIZE: usize = 64 * 1024;
+
+/// start offset (u64), file offset (u64), compressed length (u32), uncompressed length (u32)
+const BLOCK_INDEX_ENTRY_LEN: usize = 24;
+
+/// block index offset (u64), last event counter (u64), block count (u32), block index checksum (u32)
+const FOOTER_LEN: usize = 24;
+
+/// Describes where a single block is. Offsets of events within a compressed segment are the same as they were in the
+/// uncompressed version, so that index entries are unaffected by compression. `start_offset` is the offset of the first
+/// event in the block, while `file_offset` is where the compressed bytes are actually stored in the file.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct BlockIndexEntry {
+    start_offset: usize,
+    file_offset: usize,
+    compressed_len: usize,
+    uncompressed_len: usize,
+}
+
+impl BlockIndexEntry {
+    fn end_offset(&self) -> usize {
+        self.start_offset + self.uncompressed_len
+    }
+
+    fn contains(&self, offset: usize) -> bool {
+        offset >= self.start_offset && offset < self.end_offset()
+    }
+
+    fn read_from(buffer: &[u8]) -> BlockIndexEntry {
+        BlockIndexEntry {
+            start_offset: BigEndian::read_u64(&buffer[0..8]) as usize,
+            file_offset: BigEndian::read_u64(&buffer[8..16]) as usize,
+            compressed_len: BigEndian::read_u32(&buffer[16..20]) as usize,
+            uncompressed_len: BigEndian::read_u32(&buffer[20..24]) as usize,
+        }
+    }
+
+    fn write_to(&self, buffer: &mut [u8]) {
+        BigEndian::write_u64(&mut buffer[0..8], self.start_offset as u64);
+        BigEndian::write_u64(&mut buffer[8..16], self.file_offset as u64);
+        BigEndian::write_u32(&mut buffer[16..20], self.compressed_len as u32);
+        BigEndian::write_u32(&mut buffer[20..24], self.uncompressed_len as u32);
+    }
+}
+
+/// Writes a compressed copy of a segment to `file_path`. The file starts with the segment header, which is the same as
+/// the original except that it's marked as compressed. That's followed by the compressed blocks, then the block index,
+/// and finally a fixed size footer that says where to find the block index.
+pub fn write_compressed_file<I>(file_path: &Path, header: &SegmentHeader, events: I) -> io::Result<()>
+        where I: Iterator<Item=io::Result<PersistentEvent>> {
+
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(file_path)?;
+    let mut header_buffer = vec![0; SegmentHeader::get_repr_length()];
+    let mut compressed_header = header.clone();
+    compressed_header.compressed = true;
+    compressed_header.write_to(&mut header_buffer)?;
+    file.write_all(&header_buffer)?;
+
+    let mut encoder = snap::Encoder::new();
+    let mut blocks = Vec::new();
+    let mut block_buffer = Vec::with_capacity(TARGET_BLOCK_SIZE);
+    let mut block_start = SegmentHeader::get_repr_length();
+    let mut file_offset = header_buffer.len();
+    let mut last_event_counter = 0;
+
+    for result in events {
+        let event = result?;
+        if block_buffer.is_empty() {
+            block_start = event.file_offset();
+        }
+        block_buffer.extend_from_slice(event.raw_bytes());
+        last_event_counter = event.id().event_counter;
+
+        if block_buffer.len() >= TARGET_BLOCK_SIZE {
+            let block = write_block(&mut file, &mut encoder, &block_buffer, block_start, file_offset)?;
+            file_offset += block.compressed_len;
+            blocks.push(block);
+            block_buffer.clear();
+        }
+    }
+    if !block_buffer.is_empty() {
+        let block = write_block(&mut file, &mut encoder, &block_buffer, block_start, file_offset)?;
+        file_offset += block.compressed_len;
+        blocks.push(block);
+    }
+
+    let mut index_buffer = vec![0; blocks.len() * BLOCK_INDEX_ENTRY_LEN];
+    for (block, dst) in blocks.iter().zip(index_buffer.chunks_mut(BLOCK_INDEX_ENTRY_LEN)) {
+        block.write_to(dst);
+    }
+    let mut footer = [0; FOOTER_LEN];
+    BigEndian::write_u64(&mut footer[0..8], file_offset as u64);
+    BigEndian::write_u64(&mut footer[8..16], last_event_counter);
+    BigEndian::write_u32(&mut footer[16..20], blocks.len() as u32);
+    BigEndian::write_u32(&mut footer[20..24], crc32::checksum_ieee(&index_buffer));
+
+    file.write_all(&index_buffer)?;
+    file.write_all(&footer)?;
+    file.sync_all()
+}
+
+fn write_block(file: &mut File, encoder: &mut snap::Encoder, block: &[u8], start_offset: usize, file_offset: usize) -> io::Result<BlockIndexEntry> {
+    let compressed = encoder.compress_vec(block).map_err(|err| {
+        io::Error::new(io::ErrorKind::Other, format!("Failed to compress block: {}", err))
+    })?;
+    file.write_all(&compressed)?;
+    trace!("wrote block starting at offset: {} with {} bytes compressed to {}", start_offset, block.len(), compressed.len());
+    Ok(BlockIndexEntry {
+        start_offset: start_offset,
+        file_offset: file_offset,
+        compressed_len: compressed.len(),
+        uncompressed_len: block.len(),
+    })
+}
+
+
+/// A segment file that's been compressed. These are read-only, and are only ever accessed through a `CompressedReader`.
+pub struct CompressedFile {
+    mmap: Mmap,
+    file_path: PathBuf,
+    file_len: usize,
+    blocks: Vec<BlockIndexEntry>,
+    last_event_counter: EventCounter,
+    delete: Mutex<Option<SegmentDeleter>>,
+}
+
+pub type CompressedFileRef = Arc<CompressedFile>;
+
+impl CompressedFile {
+    pub fn open(file_path: &Path) -> io::Result<CompressedFile> {
+        let file = File::open(file_path)?;
+        let file_len = file.metadata()?.len() as usize;
+        if file_len < SegmentHeader::get_repr_length() + FOOTER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Compressed segment file is too small to hold a header and footer"));
+        }
+        let mmap = Mmap::open(&file, Protection::Read)?;
+
+        let (blocks, last_event_counter) = {
+            let data = unsafe { mmap.as_slice() };
+            let footer = &data[(file_len - FOOTER_LEN)..];
+            let index_offset = BigEndian::read_u64(&footer[0..8]) as usize;
+            let last_event_counter = BigEndian::read_u64(&footer[8..16]);
+            let block_count = BigEndian::read_u32(&footer[16..20]) as usize;
+            let expected_crc = BigEndian::read_u32(&footer[20..24]);
+
+            let index_end = index_offset + block_count * BLOCK_INDEX_ENTRY_LEN;
+            if index_offset < SegmentHeader::get_repr_length() || index_end != file_len - FOOTER_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid block index position in compressed segment"));
+            }
+            let index_buffer = &data[index_offset..index_end];
+            if crc32::checksum_ieee(index_buffer) != expected_crc {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Block index checksum mismatch in compressed segment"));
+            }
+
+            let blocks = index_buffer.chunks(BLOCK_INDEX_ENTRY_LEN).map(BlockIndexEntry::read_from).collect::<Vec<_>>();
+            if blocks.iter().any(|block| block.file_offset + block.compressed_len > index_offset) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Block extends past the end of the compressed segment"));
+            }
+            (blocks, last_event_counter)
+        };
+        debug!("Opened compressed segment: {:?} with {} blocks", file_path, blocks.len());
+
+        Ok(CompressedFile {
+            mmap: mmap,
+            file_path: file_path.to_owned(),
+            file_len: file_len,
+            blocks: blocks,
+            last_event_counter: last_event_counter,
+            delete: Mutex::new(None),
+        })
+    }
+
+    /// Returns the offset just past the end of the last event, which is the same as it was before the segment was compressed
+    pub fn end_offset(&self) -> usize {
+        self.blocks.last().map(|block| block.end_offset()).unwrap_or(SegmentHeader::get_repr_length())
+    }
+
+    pub fn last_event_counter(&self) -> EventCounter {
+        self.last_event_counter
+    }
+
+    /// The size of the compressed file on disk
+    pub fn file_len(&self) -> usize {
+        self.file_len
+    }
+
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    pub fn delete_on_drop(&self) {
+        let mut delete = self.delete.lock().unwrap();
+        *delete = Some(SegmentDeleter(self.file_path.clone()));
+    }
+
+    fn find_block(&self, offset: usize) -> Option<BlockIndexEntry> {
+        let index = match self.blocks.binary_search_by_key(&offset, |block| block.start_offset) {
+            Ok(exact) => exact,
+            Err(0) => return None,
+            Err(insert_index) => insert_index - 1,
+        };
+        let block = self.blocks[index];
+        if block.contains(offset) {
+            Some(block)
+        } else {
+            None
+        }
+    }
+
+    fn read_block(&self, block: &BlockIndexEntry) -> io::Result<MmapRef> {
+        let compressed = unsafe {
+            &self.mmap.as_slice()[block.file_offset..(block.file_offset + block.compressed_len)]
+        };
+        trace!("decompressing block starting at offset: {} from {:?}", block.start_offset, self.file_path);
+        MmapInner::with_contents(block.start_offset, block.uncompressed_len, |dst| {
+            let len = snap::Decoder::new().decompress(compressed, dst).map_err(|err| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Failed to decompress block: {}", err))
+            })?;
+            if len != dst.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Decompressed block length does not match the block index"));
+            }
+            Ok(())
+        })
+    }
+}
+
+impl Debug for CompressedFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let delete = self.delete.lock().unwrap().is_some();
+        f.debug_struct("CompressedFile")
+            .field("file_path", &self.file_path)
+            .field("file_len", &self.file_len)
+            .field("block_count", &self.blocks.len())
+            .field("delete", &delete)
+            .finish()
+    }
+}
+
+unsafe impl Sync for CompressedFile {}
+
+
+/// Reads events from a compressed segment, decompressing one block at a time. The events that are returned are
+/// identical to the ones that were read from the segment before it was compressed, including their offsets.
+#[derive(Clone, Debug)]
+pub struct CompressedReader {
+    file: CompressedFileRef,
+    current_offset: usize,
+    current_block: Option<(BlockIndexEntry, MmapRef)>,
+}
+
+impl CompressedReader {
+    pub fn new(file: CompressedFileRef, start_offset: usize) -> CompressedReader {
+        CompressedReader {
+            file: file,
+            current_offset: start_offset,
+            current_block: None,
+        }
+    }
+
+    pub fn read_next(&mut self) -> Option<io::Result<PersistentEvent>> {
+        if self.is_exhausted() {
+            return None;
+        }
+
+        let offset = self.current_offset;
+        let result = self.get_block(offset).and_then(|block| PersistentEvent::read(&block, offset));
+        if let Ok(event) = result.as_ref() {
+            self.current_offset += event.total_repr_len();
+        }
+        Some(result)
+    }
+
+    pub fn set_offset(&mut self, new_offset: usize) {
+        self.current_offset = new_offset;
+    }
+
+    pub fn set_offset_to_end(&mut self) {
+        self.current_offset = self.file.end_offset();
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.current_offset >= self.file.end_offset()
+    }
+
+    fn get_block(&mut self, offset: usize) -> io::Result<MmapRef> {
+        if let Some((ref entry, ref block)) = self.current_block {
+            if entry.contains(offset) {
+                return Ok(block.clone());
+            }
+        }
+
+        let entry = self.file.find_block(offset).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("No block contains offset: {} in {:?}", offset, self.file.file_path()))
+        })?;
+        let block = self.file.read_block(&entry)?;
+        self.current_block = Some((entry, block.clone()));
+        Ok(block)
+    }
+}
+
+impl Iterator for CompressedReader {
+    type Item = io::Result<PersistentEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_next()
+    }
+}
@@ -0,0 +1,285 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+use std::fmt::{self, Debug};
+
+use byteorder::{ByteOrder, BigEndian};
+use ring::aead::{self, SealingKey, OpeningKey, AES_256_GCM};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use event::FloEvent;
+use super::mmap::{MmapInner, MmapRef};
+use super::persistent_event::PersistentEvent;
+
+/// Identifies one of the keys in a key file. The id is stored in the header of every segment that's encrypted with it
+pub type KeyId = u32;
+
+/// Every encrypted event in a segment starts with its total length, followed by these marker bytes
+pub const ENCRYPTED_RECORD_MARKER: &'static [u8; 8] = b"FLO_ENC\n";
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// total length (u32), marker, nonce
+const RECORD_PREFIX_LEN: usize = 4 + 8 + NONCE_LEN;
+
+/// The number of bytes that encrypting an event adds to it
+pub const RECORD_OVERHEAD: usize = RECORD_PREFIX_LEN + TAG_LEN;
+
+/// A single AES-256-GCM key. Each event is sealed individually, using a random nonce, so that readers can still start at
+/// any event in the segment. The offset of the event within the segment is included as associated data, so an encrypted
+/// event that gets moved to a different position in the file will fail to decrypt.
+pub struct EncryptionKey {
+    id: KeyId,
+    sealing_key: SealingKey,
+    opening_key: OpeningKey,
+    rng: SystemRandom,
+}
+
+pub type EncryptionKeyRef = Arc<EncryptionKey>;
+
+impl EncryptionKey {
+    pub fn new(id: KeyId, key_bytes: &[u8]) -> io::Result<EncryptionKey> {
+        if id == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Encryption key ids must be greater than 0"));
+        }
+        if key_bytes.len() != KEY_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Encryption key: {} must be {} bytes, but was {} bytes", id, KEY_LEN, key_bytes.len())));
+        }
+        let sealing_key = SealingKey::new(&AES_256_GCM, key_bytes).map_err(|_| invalid_key(id))?;
+        let opening_key = OpeningKey::new(&AES_256_GCM, key_bytes).map_err(|_| invalid_key(id))?;
+        Ok(EncryptionKey {
+            id: id,
+            sealing_key: sealing_key,
+            opening_key: opening_key,
+            rng: SystemRandom::new(),
+        })
+    }
+
+    pub fn id(&self) -> KeyId {
+        self.id
+    }
+
+    /// Encrypts the event and writes it to `dst`, which must be exactly `PersistentEvent::get_repr_length(event) +
+    /// RECORD_OVERHEAD` bytes long. `offset` is the position of the event within the segment. The event is encrypted in a
+    /// separate buffer, so that its plaintext is never written into the segment file, even temporarily.
+    pub fn encrypt_event<E: FloEvent>(&self, event: &E, offset: usize, dst: &mut [u8]) -> io::Result<()> {
+        let event_len = PersistentEvent::get_repr_length(event) as usize;
+        let record_len = event_len + RECORD_OVERHEAD;
+        if dst.len() != record_len {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Destination length does not match the encrypted event length"));
+        }
+
+        let mut buffer = vec![0; record_len];
+        BigEndian::write_u32(&mut buffer[0..4], record_len as u32);
+        buffer[4..12].copy_from_slice(ENCRYPTED_RECORD_MARKER);
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "Failed to generate a nonce")
+        })?;
+        buffer[12..RECORD_PREFIX_LEN].copy_from_slice(&nonce);
+        unsafe {
+            PersistentEvent::write_unchecked(event, &mut buffer[RECORD_PREFIX_LEN..]);
+        }
+
+        let ad = associated_data(&buffer[..12], offset);
+        aead::seal_in_place(&self.sealing_key, &nonce, &ad, &mut buffer[RECORD_PREFIX_LEN..], TAG_LEN).map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, format!("Failed to encrypt event: {}", event.id()))
+        })?;
+        dst.copy_from_slice(&buffer);
+        Ok(())
+    }
+
+    /// Decrypts the event that starts at `offset` in the segment. Returns the event, which keeps the same offset, along
+    /// with the encrypted length, which is where the next event starts.
+    pub fn decrypt_event(&self, segment: &MmapRef, offset: usize) -> io::Result<(PersistentEvent, usize)> {
+        let (record_len, plaintext) = {
+            let src = segment.get_read_slice(offset);
+            if src.len() < RECORD_OVERHEAD {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "buffer is not large enough"));
+            }
+            if &src[4..12] != ENCRYPTED_RECORD_MARKER {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid marker bytes"));
+            }
+            let record_len = BigEndian::read_u32(&src[0..4]) as usize;
+            if record_len < RECORD_OVERHEAD || record_len > src.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "event extends past the end of the segment"));
+            }
+
+            let ad = associated_data(&src[..12], offset);
+            let nonce = &src[12..RECORD_PREFIX_LEN];
+            let mut buffer = src[RECORD_PREFIX_LEN..record_len].to_vec();
+            let plaintext_len = aead::open_in_place(&self.opening_key, nonce, &ad, 0, &mut buffer).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("failed to decrypt event with key: {}", self.id))
+            })?.len();
+            buffer.truncate(plaintext_len);
+            (record_len, buffer)
+        };
+
+        let region = MmapInner::with_contents(offset, plaintext.len(), |dst| {
+            dst.copy_from_slice(&plaintext);
+            Ok(())
+        })?;
+        PersistentEvent::read(&region, offset).map(|event| (event, record_len))
+    }
+}
+
+impl Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // never include the key itself
+        f.debug_struct("EncryptionKey")
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+fn invalid_key(id: KeyId) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid encryption key: {}", id))
+}
+
+fn associated_data(record_prefix: &[u8], offset: usize) -> [u8; 20] {
+    let mut ad = [0; 20];
+    ad[..12].copy_from_slice(record_prefix);
+    BigEndian::write_u64(&mut ad[12..], offset as u64);
+    ad
+}
+
+
+/// All of the keys from a key file. New segments are always encrypted using the key with the greatest id, while older
+/// segments are decrypted using whichever key their header says they were encrypted with. Keys are rotated by adding
+/// a new key with a greater id to the file, and the old key can be removed once every segment that uses it is gone.
+#[derive(Clone, Debug)]
+pub struct KeyRing {
+    keys: Vec<EncryptionKeyRef>,
+}
+
+impl KeyRing {
+    pub fn new(mut keys: Vec<EncryptionKey>) -> io::Result<KeyRing> {
+        if keys.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "At least one encryption key is required"));
+        }
+        keys.sort_by_key(|key| key.id);
+        if keys.windows(2).any(|pair| pair[0].id == pair[1].id) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Encryption key ids must be unique"));
+        }
+        Ok(KeyRing {
+            keys: keys.into_iter().map(Arc::new).collect(),
+        })
+    }
+
+    /// Reads a key file, which has one key per line in the form `<id>:<key>`, where the id is a positive integer and
+    /// the key is 32 bytes written as 64 hex characters. Blank lines and lines starting with `#` are ignored.
+    pub fn read_key_file(path: &Path) -> io::Result<KeyRing> {
+        let mut contents = String::new();
+        File::open(path)?.read_to_string(&mut contents)?;
+
+        let mut keys = Vec::new();
+        for (line_num, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let key = parse_key_line(line).and_then(|(id, key_bytes)| EncryptionKey::new(id, &key_bytes)).map_err(|err| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Invalid key file: {:?} at line {}: {}", path, line_num + 1, err))
+            })?;
+            keys.push(key);
+        }
+        KeyRing::new(keys).map_err(|err| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Invalid key file: {:?}: {}", path, err))
+        })
+    }
+
+    /// Returns the key that new segments should be encrypted with
+    pub fn current_key(&self) -> EncryptionKeyRef {
+        self.keys.last().unwrap().clone()
+    }
+
+    pub fn get(&self, id: KeyId) -> Option<EncryptionKeyRef> {
+        self.keys.iter().find(|key| key.id == id).cloned()
+    }
+}
+
+fn parse_key_line(line: &str) -> io::Result<(KeyId, Vec<u8>)> {
+    let mut parts = line.splitn(2, ':');
+    let id = parts.next().and_then(|id| id.trim().parse::<KeyId>().ok()).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "expected a numeric key id")
+    })?;
+    let hex = parts.next().map(|hex| hex.trim()).unwrap_or("");
+    if hex.len() % 2 != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "key must be an even number of hex characters"));
+    }
+    let mut key_bytes = Vec::with_capacity(hex.len() / 2);
+    for i in 0..(hex.len() / 2) {
+        let byte = u8::from_str_radix(&hex[(i * 2)..(i * 2 + 2)], 16).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "key must only contain hex characters")
+        })?;
+        key_bytes.push(byte);
+    }
+    Ok((id, key_bytes))
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::path::PathBuf;
+    use memmap::{Mmap, Protection};
+    use tempdir::TempDir;
+
+    use super::*;
+    use segment::mmap::MmapAppender;
+    use event::{OwnedFloEvent, FloEventId, time};
+
+    #[test]
+    fn newest_key_is_used_and_older_keys_are_still_available() {
+        let tempdir = TempDir::new("newest_key_is_used").unwrap();
+        let path = tempdir.path().join("keys");
+        let mut file = File::create(&path).unwrap();
+        writeln!(file, "# rotated on 2017-06-01").unwrap();
+        writeln!(file, "2:{}", "ab".repeat(32)).unwrap();
+        writeln!(file, "").unwrap();
+        writeln!(file, "1:{}", "0f".repeat(32)).unwrap();
+
+        let subject = KeyRing::read_key_file(&path).expect("failed to read key file");
+        assert_eq!(2, subject.current_key().id());
+        assert_eq!(Some(1), subject.get(1).map(|key| key.id()));
+        assert!(subject.get(3).is_none());
+
+        File::create(&path).unwrap().write_all(b"1:abcd\n").unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, KeyRing::read_key_file(&path).unwrap_err().kind());
+    }
+
+    #[test]
+    fn encrypted_event_is_read_back_and_tampering_is_detected() {
+        let key = Arc::new(EncryptionKey::new(7, &[42; KEY_LEN]).unwrap());
+        let mmap = Mmap::anonymous(1024, Protection::ReadWrite).unwrap();
+        let mut appender = MmapAppender::with_encryption_key(mmap, 0, PathBuf::new(), Some(key.clone()));
+        let input = OwnedFloEvent::new(FloEventId::new(3, 4), None, time::from_millis_since_epoch(999), "/foo/bar".to_owned(), b"secret".to_vec());
+
+        let offset = appender.append(&input).unwrap().expect("append returned none");
+        let written = appender.written_bytes().to_vec();
+        assert_eq!(PersistentEvent::get_repr_length(&input) as usize + RECORD_OVERHEAD, written.len());
+        assert!(!written.windows(6).any(|window| window == b"secret"));
+
+        let result = appender.reader(offset).read_next().unwrap().expect("failed to read event");
+        assert_eq!(input, result.to_owned());
+        assert_eq!(offset, result.file_offset());
+
+        let wrong_key = EncryptionKey::new(7, &[43; KEY_LEN]).unwrap();
+        let region = MmapInner::with_contents(0, written.len(), |dst| {
+            dst.copy_from_slice(&written);
+            Ok(())
+        }).unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, wrong_key.decrypt_event(&region, 0).unwrap_err().kind());
+
+        let region = MmapInner::with_contents(0, written.len(), |dst| {
+            dst.copy_from_slice(&written);
+            dst[30] ^= 0x01;
+            Ok(())
+        }).unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, key.decrypt_event(&region, 0).unwrap_err().kind());
+    }
+}
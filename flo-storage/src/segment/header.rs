@@ -0,0 +1,278 @@
+use std::io;
+
+use byteorder::{ByteOrder, BigEndian};
+use memmap::Mmap;
+
+use event::{Timestamp, ActorId, EventCounter, FloEvent, time};
+use super::encryption::{KeyId, RECORD_OVERHEAD};
+use super::persistent_event::PersistentEvent;
+
+/// Every segment file that uses a versioned header starts with these bytes
+pub const SEGMENT_MAGIC: &'static [u8; 8] = b"FLO_SEG\n";
+
+/// Segments that were created before the header was versioned. These start right away with the create and end times.
+pub const LEGACY_FORMAT_VERSION: u32 = 0;
+
+/// The version of the segment format that's used for all newly created segments that aren't encrypted
+pub const CURRENT_FORMAT_VERSION: u32 = 1;
+
+/// Encrypted segments use this version, which adds the id of the encryption key to the header. Unencrypted segments are
+/// still written using version 1, so that turning on encryption doesn't change the format of any other segment.
+pub const ENCRYPTED_FORMAT_VERSION: u32 = 2;
+
+/// Set in the flags of a segment whose events have been compressed
+const FLAG_COMPRESSED: u16 = 0x01;
+
+const LEGACY_HEADER_LEN: usize = 16;
+const V1_HEADER_LEN: usize = 40;
+const V2_HEADER_LEN: usize = 48;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SegmentHeader {
+    pub version: u32,
+    pub partition_num: ActorId,
+    /// No event in the segment will have a counter lower than this one
+    pub first_event_counter: EventCounter,
+    pub create_time: Timestamp,
+    pub end_time: Timestamp,
+    /// true if the events following the header are stored in compressed blocks
+    pub compressed: bool,
+    /// The id of the key that every event in the segment is encrypted with, or `None` if events are stored in plaintext
+    pub key_id: Option<KeyId>,
+}
+
+
+impl SegmentHeader {
+
+    /// Creates a header using the current format version
+    pub fn new(partition_num: ActorId, first_event_counter: EventCounter, create_time: Timestamp, end_time: Timestamp) -> SegmentHeader {
+        SegmentHeader {
+            version: CURRENT_FORMAT_VERSION,
+            partition_num: partition_num,
+            first_event_counter: first_event_counter,
+            create_time: create_time,
+            end_time: end_time,
+            compressed: false,
+            key_id: None,
+        }
+    }
+
+    /// Returns this header, changed to describe a segment whose events are encrypted with the given key
+    pub fn with_key_id(mut self, key_id: KeyId) -> SegmentHeader {
+        self.version = ENCRYPTED_FORMAT_VERSION;
+        self.key_id = Some(key_id);
+        self
+    }
+
+    pub fn read(mmap: &Mmap) -> io::Result<SegmentHeader> {
+        let data = unsafe { mmap.as_slice() };
+        SegmentHeader::read_from(data)
+    }
+
+    /// Reads a header of any known version from the start of `data`
+    pub fn read_from(data: &[u8]) -> io::Result<SegmentHeader> {
+        if data.len() < SEGMENT_MAGIC.len() || &data[..SEGMENT_MAGIC.len()] != SEGMENT_MAGIC {
+            return SegmentHeader::read_legacy(data);
+        }
+
+        if data.len() < 12 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Segment file length is smaller than header length"));
+        }
+        let version = BigEndian::read_u32(&data[8..12]);
+        trace!("reading header with format version: {}", version);
+        match version {
+            1 => SegmentHeader::read_v1(data),
+            2 => SegmentHeader::read_v2(data),
+            other => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unsupported segment format version: {}", other)))
+        }
+    }
+
+    fn read_legacy(data: &[u8]) -> io::Result<SegmentHeader> {
+        if data.len() < LEGACY_HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Segment file length is smaller than header length"));
+        }
+
+        let create_ts_milis = BigEndian::read_u64(&data[0..8]);
+        let end_ts_millis = BigEndian::read_u64(&data[8..16]);
+        trace!("reading legacy header from start: {}, end: {}", create_ts_milis, end_ts_millis);
+
+        Ok(SegmentHeader {
+            version: LEGACY_FORMAT_VERSION,
+            partition_num: 0,
+            first_event_counter: 0,
+            create_time: time::from_millis_since_epoch(create_ts_milis),
+            end_time: time::from_millis_since_epoch(end_ts_millis),
+            compressed: false,
+            key_id: None,
+        })
+    }
+
+    fn read_v1(data: &[u8]) -> io::Result<SegmentHeader> {
+        if data.len() < V1_HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Segment file length is smaller than header length"));
+        }
+
+        let partition_num = BigEndian::read_u16(&data[12..14]);
+        let flags = BigEndian::read_u16(&data[14..16]);
+        let first_event_counter = BigEndian::read_u64(&data[16..24]);
+        let create_ts_millis = BigEndian::read_u64(&data[24..32]);
+        let end_ts_millis = BigEndian::read_u64(&data[32..40]);
+
+        Ok(SegmentHeader {
+            version: 1,
+            partition_num: partition_num,
+            first_event_counter: first_event_counter,
+            create_time: time::from_millis_since_epoch(create_ts_millis),
+            end_time: time::from_millis_since_epoch(end_ts_millis),
+            compressed: flags & FLAG_COMPRESSED != 0,
+            key_id: None,
+        })
+    }
+
+    /// Version 2 is the same as version 1, followed by the key id and 4 reserved bytes. A key id of 0 means that the
+    /// segment isn't encrypted, although those segments are normally written using version 1.
+    fn read_v2(data: &[u8]) -> io::Result<SegmentHeader> {
+        if data.len() < V2_HEADER_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Segment file length is smaller than header length"));
+        }
+        let mut header = SegmentHeader::read_v1(data)?;
+        let key_id = BigEndian::read_u32(&data[40..44]);
+        header.version = ENCRYPTED_FORMAT_VERSION;
+        header.key_id = if key_id == 0 { None } else { Some(key_id) };
+        Ok(header)
+    }
+
+    pub fn write(&self, mmap: &mut Mmap) -> io::Result<()> {
+        let dst = unsafe { mmap.as_mut_slice() };
+        self.write_to(dst)
+    }
+
+    /// Writes the header to the start of `dst`. Headers are always written using the current format version, unless
+    /// the segment is encrypted, in which case the encrypted format version is used
+    pub fn write_to(&self, dst: &mut [u8]) -> io::Result<()> {
+        let version = if self.key_id.is_some() { ENCRYPTED_FORMAT_VERSION } else { CURRENT_FORMAT_VERSION };
+        if dst.len() < SegmentHeader::get_repr_length_for_version(version) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Destination length is smaller than header length"));
+        }
+
+        dst[0..8].copy_from_slice(SEGMENT_MAGIC);
+        BigEndian::write_u32(&mut dst[8..12], version);
+        BigEndian::write_u16(&mut dst[12..14], self.partition_num);
+        let flags = if self.compressed { FLAG_COMPRESSED } else { 0 };
+        BigEndian::write_u16(&mut dst[14..16], flags);
+        BigEndian::write_u64(&mut dst[16..24], self.first_event_counter);
+        BigEndian::write_u64(&mut dst[24..32], time::millis_since_epoch(self.create_time));
+        BigEndian::write_u64(&mut dst[32..40], time::millis_since_epoch(self.end_time));
+        if let Some(key_id) = self.key_id {
+            BigEndian::write_u32(&mut dst[40..44], key_id);
+            BigEndian::write_u32(&mut dst[44..48], 0);
+        }
+
+        trace!("wrote header {:?}", self);
+        Ok(())
+    }
+
+    /// The length of the header in the current format version, which is also the offset of the first event in an
+    /// unencrypted segment
+    pub fn get_repr_length() -> usize {
+        V1_HEADER_LEN
+    }
+
+    /// The length of a header in the given format version
+    pub fn get_repr_length_for_version(version: u32) -> usize {
+        match version {
+            LEGACY_FORMAT_VERSION => LEGACY_HEADER_LEN,
+            ENCRYPTED_FORMAT_VERSION => V2_HEADER_LEN,
+            _ => V1_HEADER_LEN
+        }
+    }
+
+    /// The length of this header, which is also the offset of the first event in the segment
+    pub fn repr_len(&self) -> usize {
+        SegmentHeader::get_repr_length_for_version(self.version)
+    }
+
+    /// Returns true if the segment was written using a format that must be migrated before it can be opened
+    pub fn requires_migration(&self) -> bool {
+        self.version == LEGACY_FORMAT_VERSION
+    }
+
+    /// The number of bytes that the given event takes up in this segment, including any encryption overhead
+    pub fn get_record_len<E: FloEvent>(&self, event: &E) -> usize {
+        let event_len = PersistentEvent::get_repr_length(event) as usize;
+        if self.key_id.is_some() {
+            event_len + RECORD_OVERHEAD
+        } else {
+            event_len
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use event::time;
+
+    #[test]
+    fn header_is_written_and_read_back_using_the_current_version() {
+        let header = SegmentHeader::new(7, 12345, time::from_millis_since_epoch(1000), time::from_millis_since_epoch(9000));
+        let mut buffer = [0; 64];
+        header.write_to(&mut buffer).expect("failed to write header");
+
+        assert_eq!(SEGMENT_MAGIC, &buffer[..8]);
+        let result = SegmentHeader::read_from(&buffer).expect("failed to read header");
+        assert_eq!(header, result);
+        assert_eq!(CURRENT_FORMAT_VERSION, result.version);
+        assert!(!result.compressed);
+    }
+
+    #[test]
+    fn compressed_flag_is_written_and_read_back() {
+        let mut header = SegmentHeader::new(7, 12345, time::from_millis_since_epoch(1000), time::from_millis_since_epoch(9000));
+        header.compressed = true;
+        let mut buffer = [0; 64];
+        header.write_to(&mut buffer).expect("failed to write header");
+
+        let result = SegmentHeader::read_from(&buffer).expect("failed to read header");
+        assert_eq!(header, result);
+    }
+
+    #[test]
+    fn key_id_is_written_and_read_back_using_the_encrypted_version() {
+        let header = SegmentHeader::new(7, 12345, time::from_millis_since_epoch(1000), time::from_millis_since_epoch(9000))
+                .with_key_id(3);
+        let mut buffer = [0; 64];
+        header.write_to(&mut buffer).expect("failed to write header");
+
+        let result = SegmentHeader::read_from(&buffer).expect("failed to read header");
+        assert_eq!(header, result);
+        assert_eq!(ENCRYPTED_FORMAT_VERSION, result.version);
+        assert_eq!(Some(3), result.key_id);
+        assert_eq!(48, result.repr_len());
+        assert!(!result.requires_migration());
+    }
+
+    #[test]
+    fn legacy_header_is_read_when_there_are_no_magic_bytes() {
+        let mut buffer = [0; 16];
+        BigEndian::write_u64(&mut buffer[0..8], 1000);
+        BigEndian::write_u64(&mut buffer[8..16], 9000);
+
+        let result = SegmentHeader::read_from(&buffer).expect("failed to read header");
+        assert_eq!(LEGACY_FORMAT_VERSION, result.version);
+        assert_eq!(time::from_millis_since_epoch(1000), result.create_time);
+        assert_eq!(time::from_millis_since_epoch(9000), result.end_time);
+    }
+
+    #[test]
+    fn unknown_format_version_returns_an_error() {
+        let header = SegmentHeader::new(7, 12345, time::from_millis_since_epoch(1000), time::from_millis_since_epoch(9000));
+        let mut buffer = [0; 64];
+        header.write_to(&mut buffer).expect("failed to write header");
+        BigEndian::write_u32(&mut buffer[8..12], 99);
+
+        let err = SegmentHeader::read_from(&buffer).unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+    }
+}
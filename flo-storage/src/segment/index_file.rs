@@ -0,0 +1,233 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+use crc::crc32::checksum_ieee;
+
+use event::EventCounter;
+use super::time_index::{TimeIndexEntry, UNKNOWN_EVENT_TIME};
+
+const INDEX_FILE_EXTENSION: &'static str = "index";
+const INDEX_FILE_MARKER: &'static [u8] = b"FLO_IX2\n";
+/// Index files written before the latest event time was added to the header. These are still read, but the latest event
+/// time of their segments is unknown
+const V1_INDEX_FILE_MARKER: &'static [u8] = b"FLO_IDX\n";
+
+// 8 for the marker, 8 for the end offset, 8 for the number of entries, 8 for the number of time entries, 8 for the latest
+// event time
+const HEADER_LEN: usize = 40;
+const V1_HEADER_LEN: usize = 32;
+// 8 for the event counter, 8 for the file offset
+const ENTRY_LEN: usize = 16;
+// 8 for the timestamp, 8 for the event counter, 8 for the file offset
+const TIME_ENTRY_LEN: usize = 24;
+const CHECKSUM_LEN: usize = 4;
+
+/// The contents of the index file that gets written alongside a segment once it's sealed. This has an entry for every
+/// event in the segment, so that the partition index can be rebuilt without having to read all the events.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SegmentIndexData {
+    /// The offset just past the end of the last event in the segment
+    pub end_offset: usize,
+    /// The counter and file offset of every event in the segment, in the order they were written
+    pub entries: Vec<(EventCounter, usize)>,
+    /// The entries of the segment's `TimeIndex`
+    pub time_entries: Vec<TimeIndexEntry>,
+    /// The latest event time in the segment, from its `TimeIndex`
+    pub latest_event_time_millis: u64,
+}
+
+pub fn get_index_file(events_file: &Path) -> PathBuf {
+    events_file.with_extension(INDEX_FILE_EXTENSION)
+}
+
+/// Writes the index file. The data is written to a temporary file first and then renamed, so that a partially written
+/// file is never mistaken for a complete one.
+pub fn write_index_file(path: &Path, data: &SegmentIndexData) -> io::Result<()> {
+    let data_len = (data.entries.len() * ENTRY_LEN) + (data.time_entries.len() * TIME_ENTRY_LEN);
+    let mut buffer = Vec::with_capacity(HEADER_LEN + data_len + CHECKSUM_LEN);
+    buffer.extend_from_slice(INDEX_FILE_MARKER);
+    buffer.write_u64::<BigEndian>(data.end_offset as u64)?;
+    buffer.write_u64::<BigEndian>(data.entries.len() as u64)?;
+    buffer.write_u64::<BigEndian>(data.time_entries.len() as u64)?;
+    buffer.write_u64::<BigEndian>(data.latest_event_time_millis)?;
+    for &(counter, offset) in data.entries.iter() {
+        buffer.write_u64::<BigEndian>(counter)?;
+        buffer.write_u64::<BigEndian>(offset as u64)?;
+    }
+    for time_entry in data.time_entries.iter() {
+        buffer.write_u64::<BigEndian>(time_entry.timestamp_millis)?;
+        buffer.write_u64::<BigEndian>(time_entry.counter)?;
+        buffer.write_u64::<BigEndian>(time_entry.file_offset as u64)?;
+    }
+    let checksum = checksum_ieee(&buffer);
+    buffer.write_u32::<BigEndian>(checksum)?;
+
+    let temp_path = path.with_extension("index.tmp");
+    {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, path)
+}
+
+/// Reads the index file at the given path. Returns `Ok(None)` if there is no index file, and an error if the file
+/// is corrupted.
+pub fn read_index_file(path: &Path) -> io::Result<Option<SegmentIndexData>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    parse_index_data(&buffer).map(Some)
+}
+
+/// Removes the index file, if there is one
+pub fn remove_index_file(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        other @ _ => other,
+    }
+}
+
+fn parse_index_data(buffer: &[u8]) -> io::Result<SegmentIndexData> {
+    let header_len = match buffer.get(..8) {
+        Some(INDEX_FILE_MARKER) => HEADER_LEN,
+        Some(V1_INDEX_FILE_MARKER) => V1_HEADER_LEN,
+        _ => return Err(invalid_index("missing index file header")),
+    };
+    if buffer.len() < header_len + CHECKSUM_LEN {
+        return Err(invalid_index("missing index file header"));
+    }
+
+    let checksum_start = buffer.len() - CHECKSUM_LEN;
+    let expected_checksum = BigEndian::read_u32(&buffer[checksum_start..]);
+    if checksum_ieee(&buffer[..checksum_start]) != expected_checksum {
+        return Err(invalid_index("checksum mismatch"));
+    }
+
+    let end_offset = BigEndian::read_u64(&buffer[8..16]) as usize;
+    let entry_count = BigEndian::read_u64(&buffer[16..24]) as usize;
+    let time_entry_count = BigEndian::read_u64(&buffer[24..32]) as usize;
+    let latest_event_time_millis = if header_len == HEADER_LEN {
+        BigEndian::read_u64(&buffer[32..40])
+    } else {
+        UNKNOWN_EVENT_TIME
+    };
+    let time_entries_start = header_len + (entry_count * ENTRY_LEN);
+    if time_entries_start + (time_entry_count * TIME_ENTRY_LEN) != checksum_start {
+        return Err(invalid_index("mismatched entry count"));
+    }
+
+    let entries = buffer[header_len..time_entries_start].chunks(ENTRY_LEN).map(|entry| {
+        let counter = BigEndian::read_u64(&entry[..8]);
+        let offset = BigEndian::read_u64(&entry[8..]) as usize;
+        (counter, offset)
+    }).collect();
+
+    let time_entries = buffer[time_entries_start..checksum_start].chunks(TIME_ENTRY_LEN).map(|entry| {
+        TimeIndexEntry {
+            timestamp_millis: BigEndian::read_u64(&entry[..8]),
+            counter: BigEndian::read_u64(&entry[8..16]),
+            file_offset: BigEndian::read_u64(&entry[16..]) as usize,
+        }
+    }).collect();
+
+    Ok(SegmentIndexData {
+        end_offset: end_offset,
+        entries: entries,
+        time_entries: time_entries,
+        latest_event_time_millis: latest_event_time_millis,
+    })
+}
+
+fn invalid_index(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Invalid segment index file: {}", message))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn index_data() -> SegmentIndexData {
+        SegmentIndexData {
+            end_offset: 999,
+            entries: vec![(1, 16), (4, 77), (5, 200)],
+            time_entries: vec![
+                TimeIndexEntry { timestamp_millis: 5000, counter: 1, file_offset: 16 },
+                TimeIndexEntry { timestamp_millis: 7000, counter: 5, file_offset: 200 },
+            ],
+            latest_event_time_millis: 8000,
+        }
+    }
+
+    #[test]
+    fn index_file_is_written_and_read_back() {
+        let tmpdir = TempDir::new("index_file_round_trip").unwrap();
+        let path = get_index_file(&tmpdir.path().join("3.events"));
+        assert_eq!(tmpdir.path().join("3.index"), path);
+
+        let data = index_data();
+        write_index_file(&path, &data).expect("failed to write index file");
+
+        let result = read_index_file(&path).expect("failed to read index file");
+        assert_eq!(Some(data), result);
+    }
+
+    #[test]
+    fn index_file_without_a_latest_event_time_is_read_with_an_unknown_one() {
+        let data = index_data();
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(V1_INDEX_FILE_MARKER);
+        buffer.write_u64::<BigEndian>(data.end_offset as u64).unwrap();
+        buffer.write_u64::<BigEndian>(data.entries.len() as u64).unwrap();
+        buffer.write_u64::<BigEndian>(data.time_entries.len() as u64).unwrap();
+        for &(counter, offset) in data.entries.iter() {
+            buffer.write_u64::<BigEndian>(counter).unwrap();
+            buffer.write_u64::<BigEndian>(offset as u64).unwrap();
+        }
+        for time_entry in data.time_entries.iter() {
+            buffer.write_u64::<BigEndian>(time_entry.timestamp_millis).unwrap();
+            buffer.write_u64::<BigEndian>(time_entry.counter).unwrap();
+            buffer.write_u64::<BigEndian>(time_entry.file_offset as u64).unwrap();
+        }
+        let checksum = checksum_ieee(&buffer);
+        buffer.write_u32::<BigEndian>(checksum).unwrap();
+
+        let result = parse_index_data(&buffer).expect("failed to parse index file");
+        assert_eq!(UNKNOWN_EVENT_TIME, result.latest_event_time_millis);
+        assert_eq!(data.entries, result.entries);
+        assert_eq!(data.time_entries, result.time_entries);
+    }
+
+    #[test]
+    fn reading_a_missing_index_file_returns_none() {
+        let tmpdir = TempDir::new("index_file_missing").unwrap();
+        let result = read_index_file(&tmpdir.path().join("1.index")).expect("failed to read index file");
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn corrupted_index_file_returns_error() {
+        let data = index_data();
+        let tmpdir = TempDir::new("index_file_corrupt").unwrap();
+        let path = tmpdir.path().join("1.index");
+        write_index_file(&path, &data).expect("failed to write index file");
+
+        let mut bytes = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+
+        let mut corrupt = bytes.clone();
+        corrupt[HEADER_LEN + 3] = 9;
+        assert!(parse_index_data(&corrupt).is_err());
+
+        let truncated = &bytes[..(bytes.len() - ENTRY_LEN)];
+        assert!(parse_index_data(truncated).is_err());
+    }
+}
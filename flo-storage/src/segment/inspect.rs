@@ -0,0 +1,248 @@
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use memmap::{Mmap, Protection};
+
+use event::{FloEvent, EventCounter};
+use super::header::{SegmentHeader, CURRENT_FORMAT_VERSION};
+use super::mmap::MmapAppender;
+use super::compressed::{CompressedFile, CompressedReader};
+use super::index_file::{get_index_file, remove_index_file};
+use super::persistent_event::PersistentEvent;
+use super::encryption::KeyRing;
+use super::get_encryption_key;
+
+/// The results of reading through every event in a segment file
+#[derive(Debug, PartialEq, Clone)]
+pub struct SegmentInspection {
+    pub header: SegmentHeader,
+    /// The size of the file on disk, which is larger than `events_end` for segments that haven't been compressed
+    pub file_len: usize,
+    pub event_count: u64,
+    pub first_event_counter: Option<EventCounter>,
+    pub last_event_counter: Option<EventCounter>,
+    /// The offset just past the end of the last valid event
+    pub events_end: usize,
+    pub corrupt_tail: Option<CorruptTail>,
+}
+
+/// A region following the last valid event in a segment that holds something other than zeroes
+#[derive(Debug, PartialEq, Clone)]
+pub struct CorruptTail {
+    pub offset: usize,
+    pub len: usize,
+    /// describes the error from reading an event at `offset`
+    pub error: String,
+}
+
+/// Reads and validates every event in a segment file, without modifying the file. The given function is called with
+/// each valid event, in order. Reading stops at the first event that fails validation, and everything from there on is
+/// reported as a corrupt tail. Segment files that need to be migrated to the current format can't be inspected. If the
+/// segment is encrypted, then `keys` must contain the key that it was encrypted with.
+pub fn inspect_segment_file<F: FnMut(&PersistentEvent)>(file_path: &Path, keys: Option<&KeyRing>, on_event: F) -> io::Result<SegmentInspection> {
+    let file = File::open(file_path)?;
+    let file_len = file.metadata()?.len() as usize;
+    if file_len < SegmentHeader::get_repr_length() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Segment file length is smaller than header length"));
+    }
+    let mmap = Mmap::open(&file, Protection::Read)?;
+    let header = SegmentHeader::read(&mmap)?;
+    if header.requires_migration() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Segment file: {:?} uses format version {}, and must be migrated to version {} before it can be inspected",
+                                          file_path, header.version, CURRENT_FORMAT_VERSION)));
+    }
+    let encryption_key = get_encryption_key(file_path, &header, keys)?;
+
+    if header.compressed {
+        let compressed = Arc::new(CompressedFile::open(file_path)?);
+        let end_offset = compressed.end_offset();
+        let reader = CompressedReader::new(compressed, header.repr_len());
+        let mut inspection = read_events(reader, header, file_len, on_event);
+        if let Some(ref mut tail) = inspection.corrupt_tail {
+            tail.len = end_offset - tail.offset;
+        }
+        Ok(inspection)
+    } else {
+        let header_len = header.repr_len();
+        let mut appender = MmapAppender::with_encryption_key(mmap, file_len, file_path.to_owned(), encryption_key);
+        let mut inspection = read_events(appender.reader(header_len), header, file_len, on_event);
+
+        // Segment files are zero-filled past the last event, so a read error is only corruption if there's data after it
+        let last_counter = inspection.last_event_counter.unwrap_or(0);
+        appender.set_head(inspection.events_end, last_counter);
+        let torn_len = appender.get_torn_tail_len(file_len);
+        if torn_len == 0 {
+            inspection.corrupt_tail = None;
+        } else if let Some(ref mut tail) = inspection.corrupt_tail {
+            tail.len = torn_len;
+        }
+        Ok(inspection)
+    }
+}
+
+/// Zeroes out the corrupt tail of a segment file, if it has one, so that the segment ends with the last valid event.
+/// The index file for the segment is removed, since it may refer to events that no longer exist. Compressed segments
+/// are never appended to, so they can't have a torn write, and corruption in them can't be repaired.
+pub fn truncate_corrupt_tail(file_path: &Path, keys: Option<&KeyRing>) -> io::Result<Option<CorruptTail>> {
+    let inspection = inspect_segment_file(file_path, keys, |_| {})?;
+    let tail = match inspection.corrupt_tail {
+        Some(tail) => tail,
+        None => return Ok(None),
+    };
+    if inspection.header.compressed {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("Segment file: {:?} is compressed, and can't be repaired", file_path)));
+    }
+
+    let file = OpenOptions::new().read(true).write(true).open(file_path)?;
+    let mmap = Mmap::open(&file, Protection::ReadWrite)?;
+    let mut appender = MmapAppender::new(mmap, tail.offset, file_path.to_owned());
+    appender.zero_tail(tail.len)?;
+    remove_index_file(&get_index_file(file_path))?;
+    warn!("Truncated segment file: {:?} by zeroing {} bytes starting at offset: {}", file_path, tail.len, tail.offset);
+    Ok(Some(tail))
+}
+
+fn read_events<I, F>(events: I, header: SegmentHeader, file_len: usize, mut on_event: F) -> SegmentInspection
+        where I: Iterator<Item=io::Result<PersistentEvent>>, F: FnMut(&PersistentEvent) {
+
+    let events_start = header.repr_len();
+    let mut inspection = SegmentInspection {
+        header: header,
+        file_len: file_len,
+        event_count: 0,
+        first_event_counter: None,
+        last_event_counter: None,
+        events_end: events_start,
+        corrupt_tail: None,
+    };
+    for result in events {
+        match result {
+            Ok(event) => {
+                let counter = event.id().event_counter;
+                inspection.event_count += 1;
+                inspection.first_event_counter = inspection.first_event_counter.or(Some(counter));
+                inspection.last_event_counter = Some(counter);
+                inspection.events_end = event.file_offset() + inspection.header.get_record_len(&event);
+                on_event(&event);
+            }
+            Err(err) => {
+                inspection.corrupt_tail = Some(CorruptTail {
+                    offset: inspection.events_end,
+                    len: 0,
+                    error: format!("{}", err),
+                });
+                break;
+            }
+        }
+    }
+    inspection
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{Read, Write, Seek, SeekFrom};
+    use tempdir::TempDir;
+    use chrono::Duration;
+
+    use event::{time, OwnedFloEvent, FloEventId};
+    use partition::SegmentNum;
+    use segment::{Segment, SegmentHeader, KeyRing, EncryptionKey};
+
+    fn write_segment(file_path: &Path, event_count: u64) -> usize {
+        let create_time = time::now();
+        let header = SegmentHeader::new(1, 1, create_time, create_time + Duration::seconds(60));
+        let mut segment = Segment::init_new(file_path.parent().unwrap(), SegmentNum::new(1), 4096, header, None).unwrap();
+        let mut end = 0;
+        for counter in 1..(event_count + 1) {
+            let event = OwnedFloEvent::new(FloEventId::new(1, counter), None, time::now(), "/foo".to_owned(), vec![1, 2, 3]);
+            assert!(segment.append(&event).is_success());
+            end = SegmentHeader::get_repr_length() + counter as usize * PersistentEvent::get_repr_length(&event) as usize;
+        }
+        segment.fsync().unwrap();
+        end
+    }
+
+    #[test]
+    fn valid_segment_is_inspected_without_modifying_it() {
+        let tmpdir = TempDir::new("inspect_valid_segment").unwrap();
+        let file_path = tmpdir.path().join("1.events");
+        let events_end = write_segment(&file_path, 3);
+
+        let mut counters = Vec::new();
+        let inspection = inspect_segment_file(&file_path, None, |event| counters.push(event.id().event_counter)).expect("failed to inspect segment");
+        assert_eq!(vec![1, 2, 3], counters);
+        assert_eq!(3, inspection.event_count);
+        assert_eq!(Some(1), inspection.first_event_counter);
+        assert_eq!(Some(3), inspection.last_event_counter);
+        assert_eq!(events_end, inspection.events_end);
+        assert!(inspection.corrupt_tail.is_none());
+        assert_eq!(None, truncate_corrupt_tail(&file_path, None).expect("failed to truncate"));
+    }
+
+    #[test]
+    fn encrypted_segment_is_inspected_using_the_key_from_the_key_ring() {
+        let tmpdir = TempDir::new("inspect_encrypted_segment").unwrap();
+        let file_path = tmpdir.path().join("1.events");
+        let keys = KeyRing::new(vec![EncryptionKey::new(1, &[7; 32]).unwrap()]).unwrap();
+        let create_time = time::now();
+        let header = SegmentHeader::new(1, 1, create_time, create_time + Duration::seconds(60));
+        let events_end = {
+            let mut segment = Segment::init_new(tmpdir.path(), SegmentNum::new(1), 4096, header, Some(keys.current_key())).unwrap();
+            for counter in 1..3 {
+                let event = OwnedFloEvent::new(FloEventId::new(1, counter), None, time::now(), "/foo".to_owned(), b"secret".to_vec());
+                assert!(segment.append(&event).is_success());
+            }
+            segment.fsync().unwrap();
+            segment.get_size_bytes()
+        };
+
+        assert!(inspect_segment_file(&file_path, None, |_| {}).is_err());
+
+        let mut data = Vec::new();
+        let inspection = inspect_segment_file(&file_path, Some(&keys), |event| data.push(event.data().to_vec())).expect("failed to inspect segment");
+        assert_eq!(vec![b"secret".to_vec(), b"secret".to_vec()], data);
+        assert_eq!(Some(1), inspection.header.key_id);
+        assert_eq!(events_end, inspection.events_end);
+        assert!(inspection.corrupt_tail.is_none());
+        assert_eq!(None, truncate_corrupt_tail(&file_path, Some(&keys)).expect("failed to truncate"));
+    }
+
+    #[test]
+    fn corrupt_tail_is_reported_and_then_truncated() {
+        let tmpdir = TempDir::new("inspect_corrupt_segment").unwrap();
+        let file_path = tmpdir.path().join("1.events");
+        write_segment(&file_path, 3);
+        let second_event_offset = {
+            let mut offsets = Vec::new();
+            inspect_segment_file(&file_path, None, |event| offsets.push(event.file_offset())).unwrap();
+            offsets[1]
+        };
+
+        // flip a byte in the data of the second event, so that its checksum no longer matches
+        {
+            let mut file = OpenOptions::new().read(true).write(true).open(&file_path).unwrap();
+            file.seek(SeekFrom::Start(second_event_offset as u64 + 53)).unwrap();
+            let mut byte = [0];
+            file.read_exact(&mut byte).unwrap();
+            file.seek(SeekFrom::Start(second_event_offset as u64 + 53)).unwrap();
+            file.write_all(&[byte[0] ^ 0xFF]).unwrap();
+        }
+
+        let inspection = inspect_segment_file(&file_path, None, |_| {}).expect("failed to inspect segment");
+        assert_eq!(1, inspection.event_count);
+        let tail = inspection.corrupt_tail.expect("expected a corrupt tail");
+        assert_eq!(second_event_offset, tail.offset);
+        assert!(tail.len > 0);
+
+        assert_eq!(Some(tail), truncate_corrupt_tail(&file_path, None).expect("failed to truncate"));
+        let inspection = inspect_segment_file(&file_path, None, |_| {}).expect("failed to inspect segment");
+        assert_eq!(1, inspection.event_count);
+        assert!(inspection.corrupt_tail.is_none());
+    }
+}
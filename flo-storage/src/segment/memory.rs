@@ -0,0 +1,138 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use memmap::{Mmap, Protection};
+use chrono::Duration;
+
+use event::{Timestamp, FloEvent, EventCounter};
+use partition::{get_events_file, SegmentNum};
+use super::mmap::MmapAppender;
+use super::{AppendResult, EventReader, Segment, SegmentHeader, SegmentReader, PersistentEvent};
+
+/// A segment that only exists in memory. Events are written in exactly the same format as they are in a segment file, into
+/// an anonymous mapping that's sized for the maximum segment size, so readers work the same way for both. Since there's no
+/// file, nothing is ever fsynced, and the events are gone once the segment and all of its readers are dropped.
+pub struct MemorySegment {
+    pub segment_num: SegmentNum,
+    appender: MmapAppender,
+    header: SegmentHeader,
+    max_length_bytes: usize,
+    event_count: u64,
+    /// The latest event time of any event in the segment, using the timestamp of events that don't have an event time
+    latest_event_time: Option<Timestamp>,
+}
+
+impl MemorySegment {
+
+    pub fn new(segment_num: SegmentNum, max_size: usize, header: SegmentHeader) -> io::Result<MemorySegment> {
+        debug!("initializing new in-memory segment: {:?}, max_size: {}, header: {:?}", segment_num, max_size, header);
+        let mut mmap = Mmap::anonymous(max_size, Protection::ReadWrite)?;
+        header.write(&mut mmap)?;
+        let start_position = SegmentHeader::get_repr_length();
+
+        Ok(MemorySegment {
+            segment_num: segment_num,
+            appender: MmapAppender::new(mmap, start_position, PathBuf::new()),
+            header: header,
+            max_length_bytes: max_size,
+            event_count: 0,
+            latest_event_time: None,
+        })
+    }
+
+    /// A segment is expired once the `retention` period has passed since its end time, same as with a `Segment`
+    pub fn is_expired(&self, now: Timestamp, retention: Duration) -> bool {
+        now > self.header.end_time && now - self.header.end_time > retention
+    }
+
+    pub fn get_highest_event_counter(&self) -> EventCounter {
+        self.appender.last_event_counter
+    }
+
+    /// Returns the number of bytes used by this segment, including the header
+    pub fn get_size_bytes(&self) -> usize {
+        self.appender.get_file_position()
+    }
+
+    pub fn get_event_count(&self) -> u64 {
+        self.event_count
+    }
+
+    /// Returns the counter from the segment header, which is no greater than the counter of the first event in the segment
+    pub fn get_first_event_counter(&self) -> EventCounter {
+        self.header.first_event_counter
+    }
+
+    pub fn append<E: FloEvent>(&mut self, event: &E) -> AppendResult {
+        if event.timestamp() > self.header.end_time {
+            return AppendResult::TimeOutOfRange;
+        }
+
+        let event_len = PersistentEvent::get_repr_length(event) as usize;
+        if event_len > Segment::max_event_size(self.max_length_bytes, false) {
+            return AppendResult::EventTooBig;
+        }
+        if self.appender.get_file_position() + event_len > self.max_length_bytes {
+            return AppendResult::SegmentFull;
+        }
+
+        match self.appender.append(event) {
+            Ok(Some(offset)) => {
+                self.event_count += 1;
+                let event_time = event.event_time().unwrap_or_else(|| event.timestamp());
+                if self.latest_event_time.map(|latest| event_time > latest).unwrap_or(true) {
+                    self.latest_event_time = Some(event_time);
+                }
+                AppendResult::Success(offset)
+            }
+            Ok(None) => AppendResult::SegmentFull,
+            Err(io_err) => AppendResult::IoError(io_err.kind()),
+        }
+    }
+
+    /// Returns the counter of the first event in this segment with a timestamp greater than or equal to `since`, or
+    /// `None` if every event in this segment is older than that. In-memory segments don't keep a time index, so this
+    /// always scans from the start of the segment.
+    pub fn find_first_event_since(&self, since: Timestamp) -> io::Result<Option<EventCounter>> {
+        if since > self.header.end_time {
+            return Ok(None);
+        }
+        for result in self.iter_from_start() {
+            let event = result?;
+            if event.timestamp() >= since {
+                return Ok(Some(event.id().event_counter));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns false if every event in this segment has an event time before `since`, same as with a `Segment`
+    pub fn may_have_event_time_since(&self, since: Timestamp) -> bool {
+        self.latest_event_time.map(|latest| latest >= since).unwrap_or(false)
+    }
+
+    pub fn range_iter(&self, start_offset: usize) -> SegmentReader {
+        let start = ::std::cmp::max(start_offset, SegmentHeader::get_repr_length());
+        SegmentReader {
+            segment_id: self.segment_num,
+            reader: EventReader::Mapped(self.appender.reader(start)),
+        }
+    }
+
+    pub fn iter_from_start(&self) -> SegmentReader {
+        self.range_iter(SegmentHeader::get_repr_length())
+    }
+
+    /// Writes the segment into a new segment file in `dest_dir`, which can later be opened the same as any other segment
+    /// file. Returns the offset just past the end of the last event in the segment.
+    pub fn write_to(&self, dest_dir: &Path) -> io::Result<usize> {
+        let dest_file = get_events_file(dest_dir, self.segment_num);
+        let bytes = self.appender.written_bytes();
+        debug!("Writing {} bytes of in-memory {} to {:?}", bytes.len(), self.segment_num, dest_file);
+        let mut dest = OpenOptions::new().write(true).create_new(true).open(&dest_file)?;
+        dest.write_all(bytes)?;
+        dest.sync_all()?;
+        Ok(bytes.len())
+    }
+}
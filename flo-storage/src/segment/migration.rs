@@ -0,0 +1,120 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write, Seek, SeekFrom};
+use std::path::Path;
+
+use event::ActorId;
+use super::header::{SegmentHeader, CURRENT_FORMAT_VERSION, ENCRYPTED_FORMAT_VERSION};
+use super::index_file::{get_index_file, remove_index_file};
+use super::persistent_event::read_event_id;
+
+/// Extension for the temporary file that a segment is written into while it's being migrated
+pub const MIGRATION_FILE_EXTENSION: &'static str = "migrating";
+
+/// Upgrades a segment file that was written using an older version of the segment format to the current version. The
+/// upgraded segment is written to a temporary file, which is then renamed over the original. Returns true if the file
+/// was upgraded, or false if it was already using the current version. Encrypted segments were never written using an
+/// older version, so they never need to be upgraded.
+pub fn migrate_segment_file(file_path: &Path, partition_num: ActorId) -> io::Result<bool> {
+    let mut file = File::open(file_path)?;
+    let mut header_buffer = vec![0; SegmentHeader::get_repr_length_for_version(ENCRYPTED_FORMAT_VERSION)];
+    let header_len = read_up_to(&mut file, &mut header_buffer)?;
+    let old_header = SegmentHeader::read_from(&header_buffer[..header_len])?;
+    if !old_header.requires_migration() {
+        return Ok(false);
+    }
+
+    // The layout of the events themselves hasn't changed, so they just need to be moved to make room for the new header
+    let events_start = SegmentHeader::get_repr_length_for_version(old_header.version) as u64;
+    file.seek(SeekFrom::Start(events_start))?;
+    let mut first_event_buffer = [0; 22];
+    let first_event_len = read_up_to(&mut file, &mut first_event_buffer)?;
+    let first_event_counter = read_event_id(&first_event_buffer[..first_event_len]).map(|id| id.event_counter).unwrap_or(0);
+    file.seek(SeekFrom::Start(events_start))?;
+
+    let new_header = SegmentHeader::new(partition_num, first_event_counter, old_header.create_time, old_header.end_time);
+    let mut new_header_buffer = vec![0; SegmentHeader::get_repr_length()];
+    new_header.write_to(&mut new_header_buffer)?;
+
+    let temp_path = file_path.with_extension(MIGRATION_FILE_EXTENSION);
+    {
+        let mut temp_file = OpenOptions::new().write(true).create(true).truncate(true).open(&temp_path)?;
+        temp_file.write_all(&new_header_buffer)?;
+        io::copy(&mut file, &mut temp_file)?;
+        temp_file.sync_all()?;
+    }
+
+    // All the event offsets have changed, so the index file is no longer valid
+    remove_index_file(&get_index_file(file_path))?;
+    fs::rename(&temp_path, file_path)?;
+    info!("Migrated segment file: {:?} from format version {} to {}", file_path, old_header.version, CURRENT_FORMAT_VERSION);
+    Ok(true)
+}
+
+fn read_up_to(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        let count = file.read(&mut buffer[total..])?;
+        if count == 0 {
+            break;
+        }
+        total += count;
+    }
+    Ok(total)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+    use byteorder::{ByteOrder, BigEndian};
+
+    use event::{time, FloEvent, OwnedFloEvent, FloEventId};
+    use partition::SegmentNum;
+    use index::PartitionIndex;
+    use segment::{Segment, PersistentEvent};
+    use super::super::header::LEGACY_FORMAT_VERSION;
+
+    #[test]
+    fn legacy_segment_file_is_migrated_to_the_current_format() {
+        let tmpdir = TempDir::new("legacy_segment_is_migrated").unwrap();
+        let file_path = tmpdir.path().join("1.events");
+        let create_time = time::from_millis_since_epoch(1000);
+        let end_time = time::now() + ::chrono::Duration::seconds(60);
+
+        // write a segment the way it was before the header was versioned
+        let events = (4..7).map(|counter| {
+            OwnedFloEvent::new(FloEventId::new(3, counter), None, time::now(), "/foo/bar".to_owned(), vec![1, 2, 3])
+        }).collect::<Vec<_>>();
+        let events_len = events.iter().map(|e| PersistentEvent::get_repr_length(e) as usize).sum::<usize>();
+        let mut legacy = vec![0; 16 + events_len + 100];
+        BigEndian::write_u64(&mut legacy[0..8], time::millis_since_epoch(create_time));
+        BigEndian::write_u64(&mut legacy[8..16], time::millis_since_epoch(end_time));
+        let mut offset = 16;
+        for event in events.iter() {
+            unsafe {
+                PersistentEvent::write_unchecked(event, &mut legacy[offset..]);
+            }
+            offset += PersistentEvent::get_repr_length(event) as usize;
+        }
+        File::create(&file_path).unwrap().write_all(&legacy).unwrap();
+        assert_eq!(LEGACY_FORMAT_VERSION, SegmentHeader::read_from(&legacy).unwrap().version);
+
+        let mut index = PartitionIndex::new(3);
+        assert!(Segment::init_from_existing_file(&file_path, SegmentNum::new(1), 4096, None, &mut index).is_err());
+
+        assert!(migrate_segment_file(&file_path, 3).expect("failed to migrate segment"));
+        assert!(!tmpdir.path().join("1.migrating").exists());
+        // a second migration does nothing
+        assert!(!migrate_segment_file(&file_path, 3).expect("failed to migrate segment"));
+
+        let mut migrated = Vec::new();
+        File::open(&file_path).unwrap().read_to_end(&mut migrated).unwrap();
+        let header = SegmentHeader::read_from(&migrated).unwrap();
+        assert_eq!(SegmentHeader::new(3, 4, create_time, header.end_time), header);
+
+        let segment = Segment::init_from_existing_file(&file_path, SegmentNum::new(1), 4096, None, &mut index).expect("failed to open migrated segment");
+        let counters = segment.iter_from_start().map(|result| result.unwrap().id().event_counter).collect::<Vec<_>>();
+        assert_eq!(vec![4, 5, 6], counters);
+    }
+}
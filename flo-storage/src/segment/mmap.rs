@@ -0,0 +1,652 @@
+
+
+use std::cell::UnsafeCell;
+use std::io;
+use std::sync::atomic::{AtomicUsize, fence, Ordering};
+use std::sync::{Arc, Mutex};
+use std::fmt::{self, Debug};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, BigEndian};
+use memmap::{Mmap, Protection};
+
+use segment::PersistentEvent;
+use event::{FloEvent, EventCounter};
+use super::encryption::{EncryptionKeyRef, ENCRYPTED_RECORD_MARKER, RECORD_OVERHEAD};
+use super::persistent_event::is_event_marker;
+
+
+
+pub struct MmapInner {
+    region: UnsafeCell<Mmap>,
+    delete: Mutex<Option<SegmentDeleter>>,
+    /// The offset within the segment that the start of the region corresponds to. This is 0 for regions that map a segment
+    /// file, and the start of the block for regions that hold a block that was decompressed from a compressed segment.
+    base_offset: usize,
+    head: AtomicUsize,
+}
+
+
+impl MmapInner {
+    /// Creates a read-only region of `len` bytes, which is filled in by `init`. The region holds the part of a segment
+    /// starting at `base_offset`, so events in it are read using the same offsets that they have in the segment.
+    pub fn with_contents<F>(base_offset: usize, len: usize, init: F) -> io::Result<MmapRef> where F: FnOnce(&mut [u8]) -> io::Result<()> {
+        let mut mmap = Mmap::anonymous(len, Protection::ReadWrite)?;
+        init(unsafe { mmap.as_mut_slice() })?;
+        Ok(Arc::new(MmapInner {
+            region: UnsafeCell::new(mmap),
+            delete: Mutex::new(None),
+            base_offset: base_offset,
+            head: AtomicUsize::new(base_offset + len),
+        }))
+    }
+
+    pub fn get_read_slice(&self, offset: usize) -> &[u8] {
+        let end = self.head.load(Ordering::Relaxed);
+        if offset > end || offset < self.base_offset {
+            panic!("Range out of bounds! requested offset: {}, region_start: {}, region_len: {}", offset, self.base_offset, end);
+        }
+        unsafe {
+            &self.region_ref().as_slice()[(offset - self.base_offset)..(end - self.base_offset)]
+        }
+    }
+
+    unsafe fn get_write_slice(&self, start_offset: usize) -> &mut [u8] {
+        let mmap = &mut *self.region.get();
+        &mut mmap.as_mut_slice()[start_offset..]
+    }
+
+    unsafe fn flush(&self, start: usize, end: usize) -> io::Result<()> {
+        let mmap = &mut *self.region.get();
+        mmap.flush_range(start, end - start)
+    }
+
+    fn region_ref(&self) -> &Mmap {
+        unsafe { &*self.region.get() }
+    }
+
+}
+
+pub type MmapRef = Arc<MmapInner>;
+
+impl Debug for MmapInner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let region_ptr = self.region_ref().ptr();
+        let region_end = self.head.load(Ordering::Relaxed);
+        let delete = self.delete.lock().unwrap().is_some();
+        f.debug_struct("MmapInner")
+            .field("region_ptr", &region_ptr)
+            .field("base_offset", &self.base_offset)
+            .field("region_len", &region_end)
+            .field("delete", &delete)
+            .finish()
+    }
+}
+//TODO: might not actually be needed
+unsafe impl Sync for MmapInner {}
+
+#[derive(Debug)]
+pub struct SegmentDeleter(pub PathBuf);
+
+impl Drop for SegmentDeleter {
+    fn drop(&mut self) {
+        info!("About to delete segment file: {:?}", self.0);
+        let result = ::std::fs::remove_file(&self.0);
+        if let Err(err) = result {
+            error!("Error deleting segment file: {:?} - {:?}", self.0, err);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MmapAppender {
+    inner: MmapRef,
+    file_path: PathBuf,
+    encryption_key: Option<EncryptionKeyRef>,
+    pub last_event_counter: EventCounter,
+}
+
+
+impl MmapAppender {
+    pub fn new(mmap: Mmap, start_position: usize, file_path: PathBuf) -> MmapAppender {
+        MmapAppender::with_encryption_key(mmap, start_position, file_path, None)
+    }
+
+    /// Creates an appender that encrypts every event it writes using the given key, and whose readers decrypt them
+    pub fn with_encryption_key(mmap: Mmap, start_position: usize, file_path: PathBuf, encryption_key: Option<EncryptionKeyRef>) -> MmapAppender {
+        let inner = MmapInner {
+            region: UnsafeCell::new(mmap),
+            delete: Mutex::new(None),
+            base_offset: 0,
+            head: AtomicUsize::new(start_position),
+        };
+        MmapAppender {
+            inner: Arc::new(inner),
+            file_path,
+            encryption_key,
+            last_event_counter: 0,
+        }
+    }
+
+
+    /// Finds the end of the events in an existing segment file by reading through all of them. The appender must have
+    /// been created with its head at the end of the file. The mmap may be longer than the file, and reading the region
+    /// past the end of it is an error. The given function is called with each event that's found, starting with the one
+    /// at `header_len`.
+    pub fn scan_to_end<F: FnMut(&PersistentEvent)>(&mut self, header_len: usize, mut on_event: F) {
+        // Files are allocated in chunks, so the number of bytes in the file will be more than what's actually been written to.
+        // We use a reader to figure out where the end of the events is
+        let mut reader = self.reader(header_len);
+
+        let mut event_count = 0;
+        let mut highest_counter = 0;
+        while let Some(Ok(event)) = reader.next() {
+            on_event(&event);
+            event_count += 1;
+            highest_counter = event.id().event_counter;
+        }
+        let head = reader.current_offset;
+        debug!("initialized appender to existing segment with {} events and total len: {}", event_count, head);
+        // reset the head to the actual value, now that we know what it is
+        self.set_head(head, highest_counter);
+    }
+
+    /// Sets the position of the end of the last event, for when it's already known
+    pub fn set_head(&mut self, head: usize, last_event_counter: EventCounter) {
+        self.last_event_counter = last_event_counter;
+        self.inner.head.store(head, Ordering::SeqCst);
+    }
+
+    /// Returns the number of bytes following the head that were written by a partially completed append. This will
+    /// be 0 unless the server died in the middle of writing an event. Only the region up to `file_len` is examined.
+    pub fn get_torn_tail_len(&self, file_len: usize) -> usize {
+        let head = self.get_file_position();
+        if head >= file_len {
+            return 0;
+        }
+        let tail = unsafe {
+            &self.inner.region_ref().as_slice()[head..file_len]
+        };
+        torn_region_len(tail)
+    }
+
+    /// Returns the offset of the first valid event between the head and `end`, if there is one. Events are only ever
+    /// appended at the head, so an invalid region that's followed by a valid event can't be from a partially completed
+    /// append, and must be corruption instead. The head is moved while looking for events, so this must only be called
+    /// before anything is reading from the segment.
+    pub fn find_valid_event_after_head(&mut self, end: usize) -> Option<usize> {
+        let head = self.get_file_position();
+        let last_event_counter = self.last_event_counter;
+        self.set_head(end, last_event_counter);
+        let found = {
+            let region = unsafe {
+                &self.inner.region_ref().as_slice()[..end]
+            };
+            let mut reader = self.reader(head);
+            ((head + 1)..end.saturating_sub(11)).find(|&offset| {
+                let marker = &region[(offset + 4)..(offset + 12)];
+                if !is_event_marker(marker) && marker != ENCRYPTED_RECORD_MARKER {
+                    return false;
+                }
+                reader.set_offset(offset);
+                match reader.read_next() {
+                    Some(Ok(_)) => true,
+                    _ => false,
+                }
+            })
+        };
+        self.set_head(head, last_event_counter);
+        found
+    }
+
+    /// Zeroes out `len` bytes starting at the head and flushes them to disk, so that they can never be read as an event
+    pub fn zero_tail(&mut self, len: usize) -> io::Result<()> {
+        let head = self.get_file_position();
+        unsafe {
+            {
+                let tail = &mut self.inner.get_write_slice(head)[..len];
+                ::std::ptr::write_bytes(tail.as_mut_ptr(), 0, len);
+            }
+            let mmap = &mut *self.inner.region.get();
+            mmap.flush_range(head, len)
+        }
+    }
+
+    pub fn delete_on_drop(&mut self) {
+        let path = self.file_path.clone();
+        let mut delete = self.inner.delete.lock().unwrap();
+        *delete = Some(SegmentDeleter(path));
+    }
+
+    pub fn append<E: FloEvent>(&mut self, event: &E) -> io::Result<Option<usize>> {
+        unsafe {
+            let mut event_len = PersistentEvent::get_repr_length(event) as usize;
+            if self.encryption_key.is_some() {
+                event_len += RECORD_OVERHEAD;
+            }
+            let start_offset = self.inner.head.load(Ordering::Relaxed);
+            debug!("will write event: {} starting at offset: {}", event.id(), start_offset);
+            let write_slice = self.inner.get_write_slice(start_offset);
+
+            if write_slice.len() < event_len {
+                return Ok(None);
+            }
+
+            match self.encryption_key {
+                Some(ref key) => key.encrypt_event(event, start_offset, &mut write_slice[..event_len])?,
+                None => PersistentEvent::write_unchecked(event, write_slice),
+            }
+            self.inner.head.fetch_add(event_len, Ordering::SeqCst);
+            self.last_event_counter = event.id().event_counter;
+
+            Ok(Some(start_offset))
+        }
+    }
+
+    /// Synchronously flushes the given byte range of the file to disk
+    pub fn flush_range(&mut self, start: usize, end: usize) -> io::Result<()> {
+        if end > start {
+            unsafe {
+                self.inner.flush(start, end)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_file_position(&self) -> usize {
+        self.inner.head.load(Ordering::SeqCst)
+    }
+
+    pub fn file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    pub fn encryption_key(&self) -> Option<&EncryptionKeyRef> {
+        self.encryption_key.as_ref()
+    }
+
+    /// Returns everything from the start of the region up to the end of the last event, including the segment header
+    pub fn written_bytes(&self) -> &[u8] {
+        self.inner.get_read_slice(0)
+    }
+
+    pub fn reader(&self, start_offset: usize) -> MmapReader {
+        MmapReader {
+            inner: self.inner.clone(),
+            encryption_key: self.encryption_key.clone(),
+            current_offset: start_offset,
+        }
+    }
+
+}
+
+
+const TORN_REGION_SCAN_CHUNK_SIZE: usize = 4096;
+
+/// Determines the extent of a partially written event at the start of `tail`. Segment files are zero-filled when they're
+/// allocated, so anything that isn't zeroed out must have come from a write that didn't complete. Writes to the mmap are not
+/// guaranteed to make it to disk in order, though, so the torn region may have holes in it. If the event header made it
+/// to disk, we use the length it claims, whether it's a plain or an encrypted event. Otherwise, we keep going until we find a chunk that's entirely zeroed out.
+fn torn_region_len(tail: &[u8]) -> usize {
+    use std::cmp::{min, max};
+
+    let mut end = 0;
+    if tail.len() >= 12 && (is_event_marker(&tail[4..12]) || &tail[4..12] == ENCRYPTED_RECORD_MARKER) {
+        let claimed_len = BigEndian::read_u32(&tail[..4]) as usize;
+        if claimed_len <= tail.len() {
+            end = claimed_len;
+        }
+    }
+
+    let mut chunk_start = 0;
+    while chunk_start < tail.len() {
+        let chunk_end = min(chunk_start + TORN_REGION_SCAN_CHUNK_SIZE, tail.len());
+        match tail[chunk_start..chunk_end].iter().rposition(|b| *b != 0) {
+            Some(pos) => {
+                end = max(end, chunk_start + pos + 1);
+            }
+            None if chunk_start >= end => break,
+            None => { }
+        }
+        chunk_start = chunk_end;
+    }
+    end
+}
+
+#[derive(Clone, Debug)]
+pub struct MmapReader {
+    inner: MmapRef,
+    encryption_key: Option<EncryptionKeyRef>,
+    current_offset: usize,
+}
+
+
+
+impl MmapReader {
+    pub fn read_next(&mut self) -> Option<io::Result<PersistentEvent>> {
+        // This bit synchronizes with the store to the `head` value in the Appender
+        let current_head = self.inner.head.load(Ordering::Relaxed);
+        fence(Ordering::Acquire);
+
+        if self.current_offset >= current_head {
+            return None;
+        }
+
+        let result = match self.encryption_key {
+            Some(ref key) => key.decrypt_event(&self.inner, self.current_offset),
+            None => PersistentEvent::read(&self.inner, self.current_offset).map(|event| {
+                let len = event.total_repr_len();
+                (event, len)
+            })
+        };
+
+        Some(result.map(|(event, record_len)| {
+            self.current_offset += record_len;
+            event
+        }))
+    }
+
+    pub fn set_offset(&mut self, new_offset: usize) {
+        self.current_offset = new_offset;
+    }
+
+    pub fn set_offset_to_end(&mut self) {
+        let max = self.get_current_head();
+        self.set_offset(max);
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        let head = self.get_current_head();
+        self.current_offset >= head
+    }
+
+    fn get_current_head(&self) -> usize {
+        self.inner.head.load(Ordering::Relaxed)
+    }
+}
+
+impl Iterator for MmapReader {
+    type Item = io::Result<PersistentEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_next()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use super::*;
+    use memmap::{Mmap, Protection};
+    use event::{OwnedFloEvent, FloEvent, FloEventId, time};
+
+    fn anon_mmap() -> MmapAppender {
+        let mmap = Mmap::anonymous(1024, Protection::ReadWrite).unwrap();
+        MmapAppender::new(mmap, 0, PathBuf::new())
+    }
+
+    #[test]
+    fn write_and_read_an_event_with_zero_lengh_namespace_and_data() {
+        let mut subject = anon_mmap();
+        let mut reader = subject.reader(0);
+        let input = OwnedFloEvent::new(
+            FloEventId::new(3, 4),
+            Some(FloEventId::new(3, 3)),
+            time::from_millis_since_epoch(999),
+            "".to_owned(),
+            Vec::new());
+
+        subject.append(&input).unwrap();
+        let result = reader.read_next().expect("reader returned none").expect("failed to read event");
+        assert_eq!(input, result.to_owned());
+    }
+
+    #[test]
+    fn read_event_returns_error_when_namespace_length_is_too_large() {
+        assert_read_err("namespace length too large", |buf| {
+            buf[41] = 56;
+        })
+    }
+
+    #[test]
+    fn read_event_returns_error_when_namespace_length_is_too_small() {
+        assert_read_err("mismatched lengths", |buf| {
+            buf[43] = 7; //make the namespace length 7 instead of 8
+        })
+    }
+
+    #[test]
+    fn read_event_returns_error_when_data_length_is_too_large() {
+        assert_read_err("mismatched lengths", |buf| {
+            buf[55] = 6; //make the data length 6 instead of 5
+        })
+    }
+
+    #[test]
+    fn read_event_returns_error_when_data_length_is_too_small() {
+        assert_read_err("mismatched lengths", |buf| {
+            buf[55] = 4; //make the data length 4 instead of 5
+        })
+    }
+
+    #[test]
+    fn read_event_returns_error_when_namespace_is_corrupted() {
+        assert_read_err("checksum mismatch", |buf| {
+            buf[45] = b'g'; // "/foo/bar" becomes "/goo/bar"
+        })
+    }
+
+    #[test]
+    fn read_event_returns_error_when_data_is_corrupted() {
+        assert_read_err("checksum mismatch", |buf| {
+            buf[58] ^= 0x01;
+        })
+    }
+
+    #[test]
+    fn read_event_returns_error_when_stored_checksum_is_corrupted() {
+        assert_read_err("checksum mismatch", |buf| {
+            buf[62] ^= 0x80;
+        })
+    }
+
+    #[test]
+    fn checksum_errors_are_distinguishable_from_other_read_errors() {
+        use segment::is_checksum_error;
+
+        let mut subject = anon_mmap();
+        let input = OwnedFloEvent::new(
+            FloEventId::new(3, 4),
+            None,
+            time::from_millis_since_epoch(999),
+            "/foo/bar".to_owned(),
+            vec![1, 2, 3, 4, 5]);
+        subject.append(&input).unwrap();
+        unsafe {
+            subject.inner.get_write_slice(0)[45] = b'g';
+        }
+        let err = subject.reader(0).read_next().unwrap().unwrap_err();
+        assert!(is_checksum_error(&err));
+
+        unsafe {
+            subject.inner.get_write_slice(0)[55] = 4;
+        }
+        let err = subject.reader(0).read_next().unwrap().unwrap_err();
+        assert!(!is_checksum_error(&err));
+    }
+
+    #[test]
+    fn torn_region_len_is_zero_when_tail_is_all_zeros() {
+        let tail = vec![0; 10_000];
+        assert_eq!(0, torn_region_len(&tail));
+    }
+
+    #[test]
+    fn torn_region_len_uses_the_length_claimed_by_a_partially_written_event() {
+        let input = OwnedFloEvent::new(
+            FloEventId::new(3, 4),
+            None,
+            time::from_millis_since_epoch(999),
+            "/foo/bar".to_owned(),
+            vec![1, 2, 3, 4, 5]);
+        let len = PersistentEvent::get_repr_length(&input) as usize;
+        let mut tail = vec![0; 10_000];
+        unsafe {
+            PersistentEvent::write_unchecked(&input, &mut tail[..]);
+        }
+        // zero out the end of the event, as though it never made it to disk
+        for b in tail[20..len].iter_mut() {
+            *b = 0;
+        }
+        assert_eq!(len, torn_region_len(&tail));
+    }
+
+    #[test]
+    fn torn_region_len_includes_non_zero_bytes_with_no_valid_header() {
+        let mut tail = vec![0; 10_000];
+        tail[3] = 8;
+        tail[5000] = 9;
+        assert_eq!(5001, torn_region_len(&tail));
+
+        // the chunk between 4096 and 8192 is all zeros, so the scan stops there
+        let mut tail = vec![0; 20_000];
+        tail[7] = 8;
+        tail[9000] = 9;
+        assert_eq!(8, torn_region_len(&tail));
+    }
+
+    #[test]
+    fn write_an_event_and_read_it_back() {
+        let mut subject = anon_mmap();
+        let mut reader = subject.reader(0);
+        let input = OwnedFloEvent::new(
+            FloEventId::new(3, 4),
+            Some(FloEventId::new(3, 3)),
+            time::from_millis_since_epoch(999),
+            "/foo/bar".to_owned(),
+            vec![1, 2, 3, 4, 5]);
+
+        subject.append(&input).unwrap();
+        let result = reader.read_next().expect("reader returned none").expect("failed to read event");
+        assert_eq!(input, result.to_owned());
+
+        let len = PersistentEvent::get_repr_length(&input);
+        assert_eq!(len, PersistentEvent::get_repr_length(&result));
+    }
+
+    #[test]
+    fn write_an_event_with_headers_and_read_it_back() {
+        let mut subject = anon_mmap();
+        let mut reader = subject.reader(0);
+        let input = OwnedFloEvent::new(
+            FloEventId::new(3, 4),
+            None,
+            time::from_millis_since_epoch(999),
+            "/foo/bar".to_owned(),
+            vec![1, 2, 3, 4, 5])
+                .with_header("content-type", "application/octet-stream")
+                .with_header("empty", Vec::new());
+
+        subject.append(&input).unwrap();
+        let result = reader.read_next().expect("reader returned none").expect("failed to read event");
+        assert_eq!(input, result.to_owned());
+        assert_eq!(Some(&b"application/octet-stream"[..]), result.header("content-type"));
+        assert_eq!(&[1u8, 2, 3, 4, 5][..], result.data());
+
+        // 4 for the length of the extended section, 24 for the event time and producer sequence, then 6 + name + value
+        // for each header
+        let expected_len = 65 + 4 + 24 + (6 + 12 + 24) + (6 + 5);
+        assert_eq!(expected_len, PersistentEvent::get_repr_length(&input));
+        assert_eq!(expected_len as usize, result.total_repr_len());
+    }
+
+    #[test]
+    fn write_an_event_with_an_event_time_and_read_it_back() {
+        let mut subject = anon_mmap();
+        let mut reader = subject.reader(0);
+        let input = OwnedFloEvent::new(
+            FloEventId::new(3, 4),
+            None,
+            time::from_millis_since_epoch(999),
+            "/foo/bar".to_owned(),
+            vec![1, 2, 3, 4, 5])
+                .with_event_time(time::from_millis_since_epoch(555));
+
+        subject.append(&input).unwrap();
+        let result = reader.read_next().expect("reader returned none").expect("failed to read event");
+        assert_eq!(input, result.to_owned());
+        assert_eq!(Some(time::from_millis_since_epoch(555)), result.event_time());
+        assert!(result.headers().is_empty());
+        assert_eq!(&[1u8, 2, 3, 4, 5][..], result.data());
+
+        let expected_len = 65 + 4 + 24;
+        assert_eq!(expected_len, PersistentEvent::get_repr_length(&input));
+        assert_eq!(expected_len as usize, result.total_repr_len());
+    }
+
+    #[test]
+    fn write_many_events_then_read_back() {
+        let mut subject = anon_mmap();
+        let input1 = OwnedFloEvent::new(
+            FloEventId::new(3, 4),
+            Some(FloEventId::new(3, 3)),
+            time::from_millis_since_epoch(999),
+            "/foo/bar".to_owned(),
+            vec![1, 2, 3, 4, 5]);
+        let input2 = OwnedFloEvent::new(
+            FloEventId::new(3, 5),
+            Some(FloEventId::new(3, 3)),
+            time::from_millis_since_epoch(999),
+            "/foo/bar".to_owned(),
+            vec![1, 2, 3, 4, 5]);
+        let input3 = OwnedFloEvent::new(
+            FloEventId::new(3, 7),
+            Some(FloEventId::new(3, 3)),
+            time::from_millis_since_epoch(999),
+            "/foo/bar".to_owned(),
+            vec![1, 2, 3, 4, 5]);
+
+        let _off_1 = subject.append(&input1).unwrap().expect("write returned none");
+        let off_2 = subject.append(&input2).unwrap().expect("write returned none");
+        let off_3 = subject.append(&input3).unwrap().expect("write returned none");
+
+        let read_all = subject.reader(0)
+            .map(|result| result.expect("failed to read event").to_owned())
+            .collect::<Vec<OwnedFloEvent>>();
+        let expected = vec![input1.clone(), input2.clone(), input3.clone()];
+        assert_eq!(expected, read_all);
+
+        let read_2 = subject.reader(off_2).next().unwrap().expect("failed to read event 2");
+        let read2_offset = read_2.file_offset();
+        assert_eq!(off_2, read2_offset);
+        assert_eq!(input2, read_2.to_owned());
+
+        let read_3 = subject.reader(off_3).next().unwrap().expect("failed to read event 3");
+        assert_eq!(input3, read_3.to_owned());
+    }
+
+    fn assert_read_err<F: Fn(&mut [u8])>(expected_description: &str, modify_buffer_fun: F) {
+        let mut subject = anon_mmap();
+        let mut reader = subject.reader(0);
+
+        let input = OwnedFloEvent::new(
+            FloEventId::new(3, 4),
+            Some(FloEventId::new(3, 3)),
+            time::from_millis_since_epoch(999),
+            "/foo/bar".to_owned(),
+            vec![1, 2, 3, 4, 5]);
+
+        subject.append(&input).unwrap();
+
+        unsafe {
+            let buff = subject.inner.get_write_slice(0);
+            modify_buffer_fun(buff);
+        }
+
+        let err_result = reader.read_next().expect("read next returned none");
+        assert!(err_result.is_err());
+        let io_err = err_result.unwrap_err();
+        let message = io_err.to_string();
+        assert!(message.starts_with(expected_description), "expected error: '{}' but got: '{}'", expected_description, message);
+    }
+}
@@ -0,0 +1,1283 @@
+mod persistent_event;
+mod mmap;
+mod header;
+mod index_file;
+mod time_index;
+mod migration;
+mod compressed;
+mod inspect;
+mod memory;
+mod encryption;
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use memmap::{Mmap, Protection};
+use chrono::Duration;
+
+use self::mmap::{MmapAppender};
+use partition::{get_events_file, SegmentNum};
+use index::{PartitionIndex, IndexEntry};
+use event::{time, Timestamp, FloEvent, EventCounter};
+use self::mmap::{MmapReader};
+use self::compressed::{CompressedFile, CompressedFileRef, CompressedReader, write_compressed_file};
+
+pub use self::persistent_event::{PersistentEvent, is_checksum_error};
+pub use self::header::SegmentHeader;
+use self::header::{CURRENT_FORMAT_VERSION, ENCRYPTED_FORMAT_VERSION};
+pub use self::migration::{migrate_segment_file, MIGRATION_FILE_EXTENSION};
+pub use self::compressed::COMPRESSION_FILE_EXTENSION;
+pub use self::inspect::{SegmentInspection, CorruptTail, inspect_segment_file, truncate_corrupt_tail};
+pub use self::index_file::{get_index_file, remove_index_file};
+pub use self::memory::MemorySegment;
+pub use self::encryption::{KeyRing, KeyId, EncryptionKey, EncryptionKeyRef};
+use self::encryption::RECORD_OVERHEAD;
+use self::index_file::{SegmentIndexData, read_index_file, write_index_file};
+use self::time_index::TimeIndex;
+
+/// Segment files are allocated in chunks of this many bytes as they fill up, rather than allocating the maximum size up
+/// front. The entire maximum size is still mapped into memory when the segment is opened, though. That way the mapping
+/// never needs to change once readers have references to it, and only the pages that are backed by the file are touched.
+const FILE_ALLOCATION_CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Extension for the temporary file that a segment is rewritten into during compaction
+pub const COMPACTION_FILE_EXTENSION: &'static str = "compacting";
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AppendResult {
+    Success(usize),
+    /// The event is larger than the maximum size of a segment, so it can never be appended to any segment
+    EventTooBig,
+    /// There's not enough space left in this segment for the event, or the segment has been sealed, so the event must be
+    /// appended to a new segment
+    SegmentFull,
+    TimeOutOfRange,
+    IoError(io::ErrorKind),
+}
+
+impl AppendResult {
+    #[cfg(test)]
+    pub fn is_success(&self) -> bool {
+        match *self {
+            AppendResult::Success(_) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Where the events in a segment are actually stored
+enum SegmentData {
+    /// The segment file is mapped into memory as-is, which is required in order to append to it
+    Mapped(MmapAppender),
+    /// A sealed segment that's been compressed. Events are decompressed one block at a time as they're read
+    Compressed(CompressedFileRef),
+}
+
+impl SegmentData {
+    fn reader(&self, start_offset: usize) -> EventReader {
+        match *self {
+            SegmentData::Mapped(ref appender) => EventReader::Mapped(appender.reader(start_offset)),
+            SegmentData::Compressed(ref file) => EventReader::Compressed(CompressedReader::new(file.clone(), start_offset)),
+        }
+    }
+
+    /// Returns the offset just past the end of the last event
+    fn end_offset(&self) -> usize {
+        match *self {
+            SegmentData::Mapped(ref appender) => appender.get_file_position(),
+            SegmentData::Compressed(ref file) => file.end_offset(),
+        }
+    }
+
+    fn file_path(&self) -> &Path {
+        match *self {
+            SegmentData::Mapped(ref appender) => appender.file_path(),
+            SegmentData::Compressed(ref file) => file.file_path(),
+        }
+    }
+
+    fn encryption_key(&self) -> Option<EncryptionKeyRef> {
+        match *self {
+            SegmentData::Mapped(ref appender) => appender.encryption_key().cloned(),
+            // compressed segments are never encrypted
+            SegmentData::Compressed(_) => None,
+        }
+    }
+}
+
+pub struct Segment {
+    pub segment_num: SegmentNum,
+    data: SegmentData,
+    segment_file: File,
+    allocated_length_bytes: usize,
+    last_flush_range_end: usize,
+    max_length_bytes: usize,
+    header: SegmentHeader,
+    time_index: TimeIndex,
+    event_count: u64,
+    index_file_path: PathBuf,
+    /// entries for the events in this segment that haven't yet been written to an index file
+    pending_index_entries: Vec<(EventCounter, usize)>,
+    /// true once the segment has been sealed and its index file written
+    is_sealed: bool,
+}
+
+impl Segment {
+
+    /// A segment is expired once the `retention` period has passed since its end time, since that's when the newest
+    /// event that could possibly be in it becomes older than the retention period
+    pub fn is_expired(&self, now: Timestamp, retention: Duration) -> bool {
+        now > self.header.end_time && now - self.header.end_time > retention
+    }
+
+    pub fn delete_on_drop(&mut self) {
+        info!("Segment: {:?} will delete on drop", self.segment_num);
+        // The index file isn't used by readers, so it can go right away
+        if let Err(err) = remove_index_file(&self.index_file_path) {
+            error!("Error deleting index file: {:?} - {:?}", self.index_file_path, err);
+        }
+        match self.data {
+            SegmentData::Mapped(ref mut appender) => appender.delete_on_drop(),
+            SegmentData::Compressed(ref file) => file.delete_on_drop(),
+        }
+    }
+
+    pub fn get_highest_event_counter(&self) -> EventCounter {
+        match self.data {
+            SegmentData::Mapped(ref appender) => appender.last_event_counter,
+            SegmentData::Compressed(ref file) => file.last_event_counter(),
+        }
+    }
+
+    /// Returns the number of bytes used by this segment, including the header. For a compressed segment, this is the
+    /// size of the compressed file.
+    pub fn get_size_bytes(&self) -> usize {
+        match self.data {
+            SegmentData::Mapped(ref appender) => appender.get_file_position(),
+            SegmentData::Compressed(ref file) => file.file_len(),
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.header.compressed
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.header.key_id.is_some()
+    }
+
+    /// Returns the id of the key that the events in this segment are encrypted with
+    pub fn get_key_id(&self) -> Option<KeyId> {
+        self.header.key_id
+    }
+
+    pub fn get_event_count(&self) -> u64 {
+        self.event_count
+    }
+
+    pub fn append<E: FloEvent>(&mut self, event: &E) -> AppendResult {
+        if event.timestamp() > self.header.end_time {
+            return AppendResult::TimeOutOfRange;
+        }
+
+        let event_len = PersistentEvent::get_repr_length(event) as usize;
+        if event_len > Segment::max_event_size(self.max_length_bytes, self.is_encrypted()) {
+            return AppendResult::EventTooBig;
+        }
+
+        let required_len = self.data.end_offset() + self.header.get_record_len(event);
+        if self.is_sealed || required_len > self.max_length_bytes {
+            return AppendResult::SegmentFull;
+        }
+        if required_len > self.allocated_length_bytes {
+            if let Err(io_err) = self.grow_file(required_len) {
+                return AppendResult::IoError(io_err.kind());
+            }
+        }
+
+        let append_result = match self.data {
+            SegmentData::Mapped(ref mut appender) => appender.append(event),
+            // compressed segments are always sealed
+            SegmentData::Compressed(_) => Ok(None),
+        };
+        match append_result {
+            Ok(Some(offset)) => {
+                self.pending_index_entries.push((event.id().event_counter, offset));
+                self.time_index.add(event.timestamp(), event.event_time(), event.id().event_counter, offset);
+                self.event_count += 1;
+                AppendResult::Success(offset)
+            }
+            Ok(None) => AppendResult::SegmentFull,
+            Err(io_err) => AppendResult::IoError(io_err.kind()),
+        }
+    }
+
+    /// Returns the size of the largest event that could fit into an empty segment with the given max size. Encrypted
+    /// segments have a larger header, and each event in them takes up some extra space.
+    pub fn max_event_size(max_segment_size: usize, encrypted: bool) -> usize {
+        if encrypted {
+            let overhead = SegmentHeader::get_repr_length_for_version(ENCRYPTED_FORMAT_VERSION) + RECORD_OVERHEAD;
+            max_segment_size.saturating_sub(overhead)
+        } else {
+            max_segment_size.saturating_sub(SegmentHeader::get_repr_length())
+        }
+    }
+
+    /// Extends the file by at least one allocation chunk, so that it's at least `required_len` bytes long
+    fn grow_file(&mut self, required_len: usize) -> io::Result<()> {
+        use std::cmp::{min, max};
+
+        let new_len = min(self.max_length_bytes, max(required_len, self.allocated_length_bytes + FILE_ALLOCATION_CHUNK_SIZE));
+        debug!("Growing {} from {} to {} bytes", self.segment_num, self.allocated_length_bytes, new_len);
+        allocate_file(&self.segment_file, new_len)?;
+        self.allocated_length_bytes = new_len;
+        Ok(())
+    }
+
+    pub fn get_end_time(&self) -> Timestamp {
+        self.header.end_time
+    }
+
+    /// Returns the counter from the segment header, which is no greater than the counter of the first event in the segment
+    pub fn get_first_event_counter(&self) -> EventCounter {
+        self.header.first_event_counter
+    }
+
+    pub fn file_path(&self) -> &Path {
+        self.data.file_path()
+    }
+
+    pub fn index_file_path(&self) -> &Path {
+        &self.index_file_path
+    }
+
+    /// Returns the counter of the first event in this segment with a timestamp greater than or equal to `since`, or
+    /// `None` if every event in this segment is older than that.
+    pub fn find_first_event_since(&self, since: Timestamp) -> io::Result<Option<EventCounter>> {
+        // event timestamps are only stored with millisecond precision, so `since` needs to be truncated to match them
+        let since = time::from_millis_since_epoch(time::millis_since_epoch(since));
+        if since > self.header.end_time {
+            // events can never be appended to a segment after its end time
+            return Ok(None);
+        }
+
+        let start_offset = if since <= self.header.create_time {
+            self.header.repr_len()
+        } else {
+            self.time_index.get_scan_start_offset(since).unwrap_or(self.header.repr_len())
+        };
+
+        for result in self.range_iter(start_offset) {
+            let event = result?;
+            if event.timestamp() >= since {
+                return Ok(Some(event.id().event_counter));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns false if every event in this segment has an event time before `since`, using the timestamp for events
+    /// without an event time
+    pub fn may_have_event_time_since(&self, since: Timestamp) -> bool {
+        self.time_index.may_have_event_time_since(since)
+    }
+
+    pub fn range_iter(&self, start_offset: usize) -> SegmentReader {
+        let start = ::std::cmp::max(start_offset, self.header.repr_len());
+        trace!("creating range iter starting at offset: {}", start);
+        SegmentReader {
+            segment_id: self.segment_num,
+            reader: self.data.reader(start)
+        }
+    }
+
+    pub fn iter_from_start(&self) -> SegmentReader {
+        self.range_iter(self.header.repr_len())
+    }
+
+    /// Flushes everything that's been written since the last fsync to disk. Does nothing if nothing's been written
+    pub fn fsync(&mut self) -> io::Result<()> {
+        if let SegmentData::Mapped(ref mut appender) = self.data {
+            let head = appender.get_file_position();
+            if head > self.last_flush_range_end {
+                trace!("fsyncing {:?} from offset: {} to {}", self.segment_num, self.last_flush_range_end, head);
+                appender.flush_range(self.last_flush_range_end, head)?;
+                self.last_flush_range_end = head;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_sealed(&self) -> bool {
+        self.is_sealed
+    }
+
+    /// Called once no more events will be appended to this segment. Flushes the segment and writes out its index file,
+    /// so that the next time the segment is opened the events don't all need to be read in order to rebuild the index.
+    pub fn seal(&mut self) -> io::Result<()> {
+        if self.is_sealed {
+            return Ok(());
+        }
+        self.fsync()?;
+        let index_data = SegmentIndexData {
+            end_offset: self.data.end_offset(),
+            entries: ::std::mem::replace(&mut self.pending_index_entries, Vec::new()),
+            time_entries: self.time_index.entries().to_vec(),
+            latest_event_time_millis: self.time_index.latest_event_time_millis(),
+        };
+        debug!("Sealing {} with {} events", self.segment_num, index_data.entries.len());
+        let result = write_index_file(&self.index_file_path, &index_data);
+        if result.is_ok() {
+            self.is_sealed = true;
+        } else {
+            self.pending_index_entries = index_data.entries;
+        }
+        result
+    }
+
+    /// Creates a new version of this segment that contains only the events for which `retain` returns true. The new
+    /// version is written to a temporary file and then renamed over the existing one, so anything that's still reading
+    /// from this version can continue to do so. Events keep their original ids and timestamps, and are encrypted using
+    /// the same key as before. The returned segment is already sealed.
+    pub fn rewrite<F: FnMut(&PersistentEvent) -> bool>(&self, mut retain: F) -> io::Result<Segment> {
+        let mut retained = Vec::new();
+        let mut retained_len = 0;
+        for result in self.iter_from_start() {
+            let event = result?;
+            if retain(&event) {
+                retained_len += self.header.get_record_len(&event);
+                retained.push(event);
+            }
+        }
+
+        let file_path = self.data.file_path().to_owned();
+        let temp_path = file_path.with_extension(COMPACTION_FILE_EXTENSION);
+        let file_len = self.header.repr_len() + retained_len;
+        debug!("Rewriting {} with {} events into {:?}", self.segment_num, retained.len(), temp_path);
+
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&temp_path)?;
+        allocate_file(&file, file_len)?;
+        let mut mmap = Mmap::open_with_offset(&file, Protection::ReadWrite, 0, file_len)?;
+        // The header is unchanged, since events can still only have counters greater than or equal to its first_event_counter.
+        // The rewritten segment is never compressed, though, even if this one was
+        let mut header = self.header.clone();
+        header.compressed = false;
+        header.write(&mut mmap)?;
+
+        let mut appender = MmapAppender::with_encryption_key(mmap, header.repr_len(), file_path.clone(), self.data.encryption_key());
+        let mut pending_index_entries = Vec::with_capacity(retained.len());
+        let mut time_index = TimeIndex::new();
+        for event in retained.iter() {
+            let offset = appender.append(event)?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Other, format!("Event {} does not fit into rewritten {}", event.id(), self.segment_num))
+            })?;
+            pending_index_entries.push((event.id().event_counter, offset));
+            time_index.add(event.timestamp(), event.event_time(), event.id().event_counter, offset);
+        }
+        appender.flush_range(0, file_len)?;
+
+        // Remove the old index file first, so that it can't be mistaken for the index of the new file
+        remove_index_file(&self.index_file_path)?;
+        ::std::fs::rename(&temp_path, &file_path)?;
+
+        let mut segment = Segment {
+            data: SegmentData::Mapped(appender),
+            segment_file: file,
+            segment_num: self.segment_num,
+            allocated_length_bytes: file_len,
+            last_flush_range_end: file_len,
+            max_length_bytes: file_len,
+            header: header,
+            time_index: time_index,
+            event_count: retained.len() as u64,
+            index_file_path: self.index_file_path.clone(),
+            pending_index_entries: pending_index_entries,
+            is_sealed: false,
+        };
+        segment.seal()?;
+        Ok(segment)
+    }
+
+    /// Creates a compressed version of this segment, which must already be sealed. Like `rewrite`, the compressed version
+    /// is written to a temporary file that's then renamed over the existing one, so anything that's still reading from this
+    /// version can continue to do so. Events keep their original offsets, so the index file and any index entries that
+    /// refer to this segment remain valid. Encrypted segments can't be compressed, since the compressed blocks would
+    /// hold the events in plaintext.
+    pub fn compress(&self) -> io::Result<Segment> {
+        if !self.is_sealed {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} must be sealed before it can be compressed", self.segment_num)));
+        }
+        if self.is_encrypted() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is encrypted, and cannot be compressed", self.segment_num)));
+        }
+
+        let file_path = self.data.file_path().to_owned();
+        let temp_path = file_path.with_extension(COMPRESSION_FILE_EXTENSION);
+        debug!("Compressing {} with {} events into {:?}", self.segment_num, self.event_count, temp_path);
+        write_compressed_file(&temp_path, &self.header, self.iter_from_start())?;
+        ::std::fs::rename(&temp_path, &file_path)?;
+
+        let file = File::open(&file_path)?;
+        let compressed = Arc::new(CompressedFile::open(&file_path)?);
+        let mut header = self.header.clone();
+        header.compressed = true;
+        let end_offset = compressed.end_offset();
+
+        Ok(Segment {
+            segment_file: file,
+            segment_num: self.segment_num,
+            allocated_length_bytes: compressed.file_len(),
+            last_flush_range_end: end_offset,
+            max_length_bytes: end_offset,
+            header: header,
+            time_index: self.time_index.clone(),
+            event_count: self.event_count,
+            index_file_path: self.index_file_path.clone(),
+            pending_index_entries: Vec::new(),
+            is_sealed: true,
+            data: SegmentData::Compressed(compressed),
+        })
+    }
+
+    /// Discards anything that was written after the last valid event in the segment, which can happen if the server
+    /// dies halfway through writing an event. The torn region is zeroed out so that it can safely be appended over.
+    /// Returns the number of bytes that were discarded. If there are any valid events after the invalid region, then it
+    /// must be corruption rather than a torn write, so an error is returned without discarding anything.
+    pub fn recover_tail(&mut self) -> io::Result<usize> {
+        let appender = match self.data {
+            SegmentData::Mapped(ref mut appender) => appender,
+            // compressed segments are only ever written all at once, and are renamed into place once they're complete
+            SegmentData::Compressed(_) => return Ok(0),
+        };
+        let torn_len = appender.get_torn_tail_len(self.allocated_length_bytes);
+        if torn_len > 0 {
+            let invalid_offset = appender.get_file_position();
+            if let Some(valid_offset) = appender.find_valid_event_after_head(invalid_offset + torn_len) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("{} has an invalid event at offset: {}, followed by a valid event at offset: {}. \
+                                                   Nothing was discarded, since this is corruption rather than a partially written event. \
+                                                   Use `flo-admin verify` to inspect the segment file: {:?}",
+                                                  self.segment_num, invalid_offset, valid_offset, appender.file_path())));
+            }
+            warn!("{} has a partially written event at offset: {}; discarding {} bytes. Last valid event counter is: {}",
+                  self.segment_num,
+                  appender.get_file_position(),
+                  torn_len,
+                  appender.last_event_counter);
+            appender.zero_tail(torn_len)?;
+        }
+        Ok(torn_len)
+    }
+
+    /// Writes a copy of this segment into `dest_dir`, using the same file names. Sealed segments are never modified in
+    /// place, so their files are hard linked when possible. For the segment that's still being appended to, only the
+    /// portion that's been written so far is copied, so the caller should fsync first. Returns the offset just past the
+    /// end of the last event in the segment.
+    pub fn snapshot(&self, dest_dir: &Path) -> io::Result<usize> {
+        let end_offset = self.data.end_offset();
+        let dest_file = dest_dir.join(file_name(self.file_path())?);
+        if self.is_sealed {
+            debug!("Linking sealed {} into {:?}", self.segment_num, dest_file);
+            link_or_copy(self.file_path(), &dest_file)?;
+            if self.index_file_path.exists() {
+                let dest_index = dest_dir.join(file_name(&self.index_file_path)?);
+                link_or_copy(&self.index_file_path, &dest_index)?;
+            }
+        } else {
+            debug!("Copying the first {} bytes of {} into {:?}", end_offset, self.segment_num, dest_file);
+            let source = File::open(self.file_path())?;
+            let mut dest = OpenOptions::new().write(true).create_new(true).open(&dest_file)?;
+            let copied = io::copy(&mut io::Read::take(source, end_offset as u64), &mut dest)?;
+            if copied < end_offset as u64 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                          format!("Only copied {} of {} bytes from {}", copied, end_offset, self.segment_num)));
+            }
+            dest.sync_all()?;
+        }
+        Ok(end_offset)
+    }
+
+    /// Returns false if the file at the given path is too small to even hold a segment header. This happens if the
+    /// server dies right after creating a new segment file, before it could be initialized.
+    pub fn is_initialized_file(file_path: &Path) -> io::Result<bool> {
+        let file_len = ::std::fs::metadata(file_path)?.len() as usize;
+        Ok(file_len >= SegmentHeader::get_repr_length())
+    }
+
+    /// Opens an existing segment file. Segments that were created with a larger max size than the current one will keep
+    /// their original max size. If the segment is encrypted, then `keys` must contain the key that it was encrypted with.
+    pub fn init_from_existing_file(file_path: &Path, segment_num: SegmentNum, max_size: usize, keys: Option<&KeyRing>, index: &mut PartitionIndex) -> io::Result<Segment> {
+        let file = OpenOptions::new().read(true).write(true).open(&file_path)?;
+        let file_len = file.metadata()?.len() as usize;
+        let max_length = ::std::cmp::max(file_len, max_size);
+        let mmap = Mmap::open_with_offset(&file, Protection::ReadWrite, 0, max_length)?;
+        let header = SegmentHeader::read(&mmap)?;
+        if header.requires_migration() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                      format!("Segment file: {:?} uses format version {}, and must be migrated to version {} before it can be opened",
+                                              file_path, header.version, CURRENT_FORMAT_VERSION)));
+        }
+        if header.compressed {
+            return Segment::init_compressed(file, file_path, segment_num, header, index);
+        }
+        let encryption_key = get_encryption_key(file_path, &header, keys)?;
+
+        let index_file_path = get_index_file(file_path);
+        let mut mmap_appender = MmapAppender::with_encryption_key(mmap, file_len, file_path.to_owned(), encryption_key);
+        let mut pending_index_entries = Vec::new();
+        let mut time_index = TimeIndex::new();
+        let event_count;
+        let valid_index_data = read_valid_index_file(&index_file_path, &header, file_len, |offset| mmap_appender.reader(offset).read_next());
+        let is_sealed = match valid_index_data {
+            Some(index_data) => {
+                let last_counter = index_data.entries.last().map(|&(counter, _)| counter).unwrap_or(0);
+                mmap_appender.set_head(index_data.end_offset, last_counter);
+                append_index_entries(index, segment_num, &index_data.entries);
+                time_index = TimeIndex::from_entries(index_data.time_entries, index_data.latest_event_time_millis);
+                event_count = index_data.entries.len() as u64;
+                true
+            }
+            None => {
+                mmap_appender.scan_to_end(header.repr_len(), |event| {
+                    pending_index_entries.push((event.id().event_counter, event.file_offset()));
+                    time_index.add(event.timestamp(), event.event_time(), event.id().event_counter, event.file_offset());
+                });
+                append_index_entries(index, segment_num, &pending_index_entries);
+                event_count = pending_index_entries.len() as u64;
+                false
+            }
+        };
+        let current_position = mmap_appender.get_file_position();
+
+        let segment = Segment {
+            data: SegmentData::Mapped(mmap_appender),
+            segment_file: file,
+            segment_num: segment_num,
+            allocated_length_bytes: file_len,
+            last_flush_range_end: current_position,
+            max_length_bytes: max_length,
+            header: header,
+            time_index: time_index,
+            event_count: event_count,
+            index_file_path: index_file_path,
+            pending_index_entries: pending_index_entries,
+            is_sealed: is_sealed,
+        };
+
+        Ok(segment)
+    }
+
+    /// Opens an existing segment file that was compressed. Compressed segments are always sealed, so the index file is
+    /// rewritten right away if it needs to be rebuilt.
+    fn init_compressed(file: File, file_path: &Path, segment_num: SegmentNum, header: SegmentHeader, index: &mut PartitionIndex) -> io::Result<Segment> {
+        let compressed = Arc::new(CompressedFile::open(file_path)?);
+        let end_offset = compressed.end_offset();
+        let index_file_path = get_index_file(file_path);
+
+        let valid_index_data = read_valid_index_file(&index_file_path, &header, end_offset, |offset| {
+            CompressedReader::new(compressed.clone(), offset).read_next()
+        });
+        let index_data = match valid_index_data {
+            Some(index_data) => index_data,
+            None => {
+                let mut entries = Vec::new();
+                let mut time_index = TimeIndex::new();
+                for result in CompressedReader::new(compressed.clone(), header.repr_len()) {
+                    let event = result?;
+                    entries.push((event.id().event_counter, event.file_offset()));
+                    time_index.add(event.timestamp(), event.event_time(), event.id().event_counter, event.file_offset());
+                }
+                let index_data = SegmentIndexData {
+                    end_offset: end_offset,
+                    entries: entries,
+                    time_entries: time_index.entries().to_vec(),
+                    latest_event_time_millis: time_index.latest_event_time_millis(),
+                };
+                if let Err(err) = write_index_file(&index_file_path, &index_data) {
+                    warn!("Failed to write index file for compressed {}: {:?}", segment_num, err);
+                }
+                index_data
+            }
+        };
+        append_index_entries(index, segment_num, &index_data.entries);
+
+        Ok(Segment {
+            segment_file: file,
+            segment_num: segment_num,
+            allocated_length_bytes: compressed.file_len(),
+            last_flush_range_end: end_offset,
+            max_length_bytes: end_offset,
+            header: header,
+            time_index: TimeIndex::from_entries(index_data.time_entries, index_data.latest_event_time_millis),
+            event_count: index_data.entries.len() as u64,
+            index_file_path: index_file_path,
+            pending_index_entries: Vec::new(),
+            is_sealed: true,
+            data: SegmentData::Compressed(compressed),
+        })
+    }
+
+    /// Creates a new segment file. If an encryption key is given, then every event in the segment is encrypted with it
+    pub fn init_new(dir_path: &Path, segment_num: SegmentNum, max_size: usize, header: SegmentHeader, encryption_key: Option<EncryptionKeyRef>) -> io::Result<Segment> {
+        let header = match encryption_key {
+            Some(ref key) => header.with_key_id(key.id()),
+            None => header,
+        };
+        let file_path = get_events_file(dir_path, segment_num);
+        debug!("initializing new segment: {:?} at path: {:?}, max_size: {}, header: {:?}", segment_num, file_path, max_size, header);
+        let file = OpenOptions::new().read(true).write(true).create(true).open(&file_path)?;
+
+        // Only the first chunk of the file is allocated now, and the rest gets allocated as the segment fills up. We map the
+        // maximum file size right away, though, since changing the mapping would require ensuring that there are no
+        // existing borrows of it in any other threads
+        let initial_len = ::std::cmp::min(max_size, FILE_ALLOCATION_CHUNK_SIZE);
+        allocate_file(&file, initial_len)?;
+
+        let mut mmap = Mmap::open_with_offset(&file, Protection::ReadWrite, 0, max_size)?;
+        header.write(&mut mmap)?;
+
+        let start_position = header.repr_len();
+        let index_file_path = get_index_file(&file_path);
+        // In case a previous segment with the same number left its index file behind
+        remove_index_file(&index_file_path)?;
+
+        Ok(Segment {
+            data: SegmentData::Mapped(MmapAppender::with_encryption_key(mmap, start_position, file_path, encryption_key)),
+            segment_file: file,
+            segment_num: segment_num,
+            allocated_length_bytes: initial_len,
+            last_flush_range_end: 0,
+            max_length_bytes: max_size,
+            header: header,
+            time_index: TimeIndex::new(),
+            event_count: 0,
+            index_file_path: index_file_path,
+            pending_index_entries: Vec::new(),
+            is_sealed: false,
+        })
+    }
+
+}
+
+/// Finds the key that the segment was encrypted with, if it's encrypted at all
+fn get_encryption_key(file_path: &Path, header: &SegmentHeader, keys: Option<&KeyRing>) -> io::Result<Option<EncryptionKeyRef>> {
+    let key_id = match header.key_id {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    keys.and_then(|keys| keys.get(key_id)).map(Some).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput,
+                       format!("Segment file: {:?} is encrypted using key: {}, which is not in the key file", file_path, key_id))
+    })
+}
+
+fn append_index_entries(index: &mut PartitionIndex, segment_num: SegmentNum, entries: &[(EventCounter, usize)]) {
+    for &(counter, offset) in entries.iter() {
+        index.append(IndexEntry::new(counter, segment_num, offset));
+    }
+}
+
+/// Reads the index file for a segment, and checks that it agrees with the segment file. `read_event_at` reads the event
+/// at the given offset, and must be able to read up to `data_len`. Returns None if the index file is missing, corrupted,
+/// or out of date with the segment, in which case the segment must be scanned instead.
+fn read_valid_index_file<F>(index_file_path: &Path, header: &SegmentHeader, data_len: usize, read_event_at: F) -> Option<SegmentIndexData>
+        where F: Fn(usize) -> Option<io::Result<PersistentEvent>> {
+    let index_data = match read_index_file(index_file_path) {
+        Ok(Some(data)) => data,
+        Ok(None) => {
+            debug!("No index file exists at: {:?}", index_file_path);
+            return None;
+        }
+        Err(err) => {
+            warn!("Ignoring index file: {:?} due to error: {}", index_file_path, err);
+            return None;
+        }
+    };
+
+    let end_offset = index_data.end_offset;
+    let header_len = header.repr_len();
+    // the last event in the index must be in the segment, and there must not be any events after it
+    let last_event_matches = match index_data.entries.last() {
+        Some(&(last_counter, last_offset)) => is_event_ending_at(&read_event_at, header, last_counter, last_offset, end_offset),
+        None => end_offset == header_len,
+    };
+    let is_valid = end_offset >= header_len &&
+            end_offset <= data_len &&
+            last_event_matches &&
+            !has_event_at(&read_event_at, end_offset, data_len);
+
+    if is_valid {
+        Some(index_data)
+    } else {
+        warn!("Ignoring index file: {:?} because it is out of date with the segment", index_file_path);
+        None
+    }
+}
+
+fn is_event_ending_at<F>(read_event_at: &F, header: &SegmentHeader, counter: EventCounter, offset: usize, end_offset: usize) -> bool
+        where F: Fn(usize) -> Option<io::Result<PersistentEvent>> {
+    if offset < header.repr_len() || offset >= end_offset {
+        return false;
+    }
+    match read_event_at(offset) {
+        Some(Ok(event)) => event.id().event_counter == counter && offset + header.get_record_len(&event) == end_offset,
+        _ => false
+    }
+}
+
+fn has_event_at<F>(read_event_at: &F, offset: usize, data_len: usize) -> bool
+        where F: Fn(usize) -> Option<io::Result<PersistentEvent>> {
+    offset < data_len && read_event_at(offset).map(|result| result.is_ok()).unwrap_or(false)
+}
+
+
+/// Ensures that the file is allocated up to `len` bytes. On linux, the disk space is actually reserved, so that running
+/// out of space shows up as an error here instead of as a SIGBUS when writing to the mmap.
+#[cfg(target_os = "linux")]
+fn allocate_file(file: &File, len: usize) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let result = unsafe {
+        ::libc::posix_fallocate(file.as_raw_fd(), 0, len as ::libc::off_t)
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(result))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocate_file(file: &File, len: usize) -> io::Result<()> {
+    file.set_len(len as u64)
+}
+
+
+fn file_name(path: &Path) -> io::Result<&::std::ffi::OsStr> {
+    path.file_name().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} does not have a file name", path))
+    })
+}
+
+/// Hard links `source` to `dest`, falling back to copying it if they're on different filesystems
+fn link_or_copy(source: &Path, dest: &Path) -> io::Result<()> {
+    if let Err(err) = ::std::fs::hard_link(source, dest) {
+        if err.kind() == io::ErrorKind::AlreadyExists {
+            return Err(err);
+        }
+        debug!("Failed to link {:?} to {:?}, so it will be copied instead: {:?}", source, dest, err);
+        ::std::fs::copy(source, dest)?;
+    }
+    Ok(())
+}
+
+
+/// Reads events from either an uncompressed or a compressed segment, so that consumers never need to know the difference
+#[derive(Clone, Debug)]
+enum EventReader {
+    Mapped(MmapReader),
+    Compressed(CompressedReader),
+}
+
+#[derive(Clone, Debug)]
+pub struct SegmentReader {
+    pub segment_id: SegmentNum,
+    reader: EventReader,
+}
+
+impl SegmentReader {
+    pub fn read_next(&mut self) -> Option<io::Result<PersistentEvent>> {
+        match self.reader {
+            EventReader::Mapped(ref mut reader) => reader.read_next(),
+            EventReader::Compressed(ref mut reader) => reader.read_next(),
+        }
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        match self.reader {
+            EventReader::Mapped(ref reader) => reader.is_exhausted(),
+            EventReader::Compressed(ref reader) => reader.is_exhausted(),
+        }
+    }
+
+    pub fn set_offset_to_end(&mut self) {
+        match self.reader {
+            EventReader::Mapped(ref mut reader) => reader.set_offset_to_end(),
+            EventReader::Compressed(ref mut reader) => reader.set_offset_to_end(),
+        }
+    }
+
+    pub fn set_offset(&mut self, new_offset: usize) {
+        match self.reader {
+            EventReader::Mapped(ref mut reader) => reader.set_offset(new_offset),
+            EventReader::Compressed(ref mut reader) => reader.set_offset(new_offset),
+        }
+    }
+}
+
+impl Iterator for SegmentReader {
+    type Item = io::Result<PersistentEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_next()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+    use tempdir::TempDir;
+
+    use super::*;
+    use event::*;
+    use index::PartitionIndex;
+
+    fn future_time(seconds_in_future: i64) -> Timestamp {
+        time::now() + Duration::seconds(seconds_in_future)
+    }
+
+    fn test_header(end_time: Timestamp) -> SegmentHeader {
+        SegmentHeader::new(1, 1, time::now(), end_time)
+    }
+
+    #[test]
+    fn write_one_event_to_segment_and_read_it_back() {
+        let _ = ::env_logger::init();
+
+        let tmpdir = TempDir::new("write_events_to_segment").unwrap();
+        let event = event(1);
+        let segment_num = SegmentNum::new(1);
+
+        {
+            let mut subject = Segment::init_new(tmpdir.path(), segment_num, 4096, test_header(future_time(2)), None)
+                    .expect("failed to initialize segment");
+
+            let result = subject.append(&event);
+            assert_eq!(AppendResult::Success(SegmentHeader::get_repr_length()), result);
+
+            let mut iter = subject.iter_from_start();
+
+            let event_result = iter.next().expect("next returned None").expect("failed to read event");
+
+            assert_events_eq(&event, &event_result);
+            assert!(iter.next().is_none());
+        }
+
+        let mut index = PartitionIndex::new(1);
+
+        let segment_file = tmpdir.path().join("1.events");
+        let subject = Segment::init_from_existing_file(&segment_file, segment_num, 4096, None, &mut index)
+                .expect("failed to init segment from existing file");
+
+        let mut iter = subject.iter_from_start();
+        let event_result = iter.next().expect("next returned none").expect("failed to read event");
+        assert_events_eq(&event, &event_result);
+        assert!(iter.next().is_none());
+    }
+
+    #[test]
+    fn write_multiple_events_and_read_them_back() {
+        let tmpdir = TempDir::new("write_events_to_segment").unwrap();
+
+        let mut subject = Segment::init_new(tmpdir.path(), SegmentNum::new(1), 4096, test_header(future_time(2)), None)
+                .expect("failed to initialize segment");
+
+        let input_events: Vec<OwnedFloEvent> = (1..11).map(|i| event(i)).collect();
+
+        for event in input_events.iter() {
+            let write_result = subject.append(event);
+            assert!(write_result.is_success());
+        }
+
+        let read_results: Vec<io::Result<PersistentEvent>> = subject.iter_from_start().collect();
+
+        for (expected, actual) in input_events.iter().zip(read_results.iter()) {
+            assert_events_eq(expected, actual.as_ref().expect("failed to read event"));
+        }
+        assert_eq!(10, read_results.len());
+    }
+
+    #[test]
+    fn read_after_write() {
+        let tmpdir = TempDir::new("read_after_write").unwrap();
+        let mut subject = Segment::init_new(tmpdir.path(), SegmentNum::new(1), 4096, test_header(future_time(2)), None)
+                .expect("failed to initialize segment");
+
+        let mut reader = subject.iter_from_start();
+        assert!(reader.next().is_none());
+
+        let event = event(1);
+
+        let append_result = subject.append(&event);
+        assert!(append_result.is_success());
+
+        // make sure we can read the new event
+        reader.next().expect("next returned none").expect("next returned error");
+    }
+
+    #[test]
+    fn corrupt_event_followed_by_valid_events_is_an_error_instead_of_being_discarded() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let tmpdir = TempDir::new("recover_corrupt_event").unwrap();
+        let segment_num = SegmentNum::new(1);
+        let segment_file = tmpdir.path().join("1.events");
+
+        let corrupt_offset = {
+            let mut subject = Segment::init_new(tmpdir.path(), segment_num, 4096, test_header(future_time(2)), None)
+                    .expect("failed to initialize segment");
+            assert!(subject.append(&event(1)).is_success());
+            let offset = subject.data.end_offset();
+            assert!(subject.append(&event(2)).is_success());
+            assert!(subject.append(&event(3)).is_success());
+            subject.fsync().expect("failed to fsync");
+            offset
+        };
+
+        // flip a single bit in the last byte of the second event
+        let corrupt_byte_offset = corrupt_offset + PersistentEvent::get_repr_length(&event(2)) as usize - 1;
+        let mut file = OpenOptions::new().read(true).write(true).open(&segment_file).unwrap();
+        let mut byte = [0; 1];
+        file.seek(SeekFrom::Start(corrupt_byte_offset as u64)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        byte[0] ^= 0x01;
+        file.seek(SeekFrom::Start(corrupt_byte_offset as u64)).unwrap();
+        file.write_all(&byte).unwrap();
+        drop(file);
+
+        let mut index = PartitionIndex::new(1);
+        let mut subject = Segment::init_from_existing_file(&segment_file, segment_num, 4096, None, &mut index)
+                .expect("failed to init segment from existing file");
+        assert_eq!(1, subject.get_highest_event_counter());
+        let err = subject.recover_tail().unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+        drop(subject);
+
+        // fixing the corrupted bit shows that the events after it were left alone
+        let mut file = OpenOptions::new().write(true).open(&segment_file).unwrap();
+        byte[0] ^= 0x01;
+        file.seek(SeekFrom::Start(corrupt_byte_offset as u64)).unwrap();
+        file.write_all(&byte).unwrap();
+        drop(file);
+        let mut index = PartitionIndex::new(1);
+        let subject = Segment::init_from_existing_file(&segment_file, segment_num, 4096, None, &mut index)
+                .expect("failed to init segment from existing file");
+        assert_eq!(3, subject.iter_from_start().count());
+    }
+
+    #[test]
+    fn partially_written_event_is_discarded_when_recovering_segment() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        let tmpdir = TempDir::new("recover_torn_write").unwrap();
+        let segment_num = SegmentNum::new(1);
+        let segment_file = tmpdir.path().join("1.events");
+
+        let torn_write_offset = {
+            let mut subject = Segment::init_new(tmpdir.path(), segment_num, 4096, test_header(future_time(2)), None)
+                    .expect("failed to initialize segment");
+            assert!(subject.append(&event(1)).is_success());
+            assert!(subject.append(&event(2)).is_success());
+            subject.fsync().expect("failed to fsync");
+            subject.data.end_offset()
+        };
+
+        // simulate the server dying halfway through writing the third event
+        let torn_event = event(3);
+        let torn_event_len = PersistentEvent::get_repr_length(&torn_event) as usize;
+        {
+            let mut buffer = vec![0; torn_event_len];
+            unsafe {
+                PersistentEvent::write_unchecked(&torn_event, &mut buffer[..]);
+            }
+            let mut file = OpenOptions::new().write(true).open(&segment_file).unwrap();
+            file.seek(SeekFrom::Start(torn_write_offset as u64)).unwrap();
+            file.write_all(&buffer[..(torn_event_len / 2)]).unwrap();
+        }
+
+        {
+            let mut index = PartitionIndex::new(1);
+            let mut subject = Segment::init_from_existing_file(&segment_file, segment_num, 4096, None, &mut index)
+                    .expect("failed to init segment from existing file");
+            assert_eq!(torn_write_offset, subject.data.end_offset());
+            assert_eq!(2, subject.get_highest_event_counter());
+
+            let discarded = subject.recover_tail().expect("failed to recover segment");
+            assert_eq!(torn_event_len, discarded);
+            assert_eq!(2, subject.iter_from_start().count());
+
+            // the segment should be appendable again
+            assert_eq!(AppendResult::Success(torn_write_offset), subject.append(&torn_event));
+            subject.fsync().expect("failed to fsync");
+        }
+
+        let mut index = PartitionIndex::new(1);
+        let mut subject = Segment::init_from_existing_file(&segment_file, segment_num, 4096, None, &mut index)
+                .expect("failed to init segment from existing file");
+        assert_eq!(0, subject.recover_tail().expect("failed to recover segment"));
+        let events = subject.iter_from_start().map(|r| r.expect("failed to read event")).collect::<Vec<_>>();
+        assert_eq!(3, events.len());
+        assert_events_eq(&torn_event, &events[2]);
+    }
+
+    #[test]
+    fn append_returns_segment_full_once_the_max_size_is_reached() {
+        let tmpdir = TempDir::new("segment_full").unwrap();
+        let event_len = PersistentEvent::get_repr_length(&event(1)) as usize;
+        let max_size = SegmentHeader::get_repr_length() + (event_len * 3) + 10;
+        let mut subject = Segment::init_new(tmpdir.path(), SegmentNum::new(1), max_size, test_header(future_time(2)), None)
+                .expect("failed to initialize segment");
+
+        for i in 1..4 {
+            assert!(subject.append(&event(i)).is_success());
+        }
+        assert_eq!(AppendResult::SegmentFull, subject.append(&event(4)));
+
+        let too_big = OwnedFloEvent::new(FloEventId::new(1, 5), None, time::now(), "/foo/bar".to_owned(), vec![0; max_size]);
+        assert_eq!(AppendResult::EventTooBig, subject.append(&too_big));
+    }
+
+    #[test]
+    fn segment_file_is_allocated_in_chunks_as_it_grows() {
+        let tmpdir = TempDir::new("segment_file_growth").unwrap();
+        let max_size = (FILE_ALLOCATION_CHUNK_SIZE * 2) + 1000;
+        let segment_file = tmpdir.path().join("1.events");
+        let file_len = || ::std::fs::metadata(&segment_file).unwrap().len() as usize;
+
+        let mut subject = Segment::init_new(tmpdir.path(), SegmentNum::new(1), max_size, test_header(future_time(2)), None)
+                .expect("failed to initialize segment");
+        assert_eq!(FILE_ALLOCATION_CHUNK_SIZE, file_len());
+
+        subject.grow_file(FILE_ALLOCATION_CHUNK_SIZE + 1).expect("failed to grow file");
+        assert_eq!(FILE_ALLOCATION_CHUNK_SIZE * 2, file_len());
+
+        // the last chunk is truncated to the max segment size
+        subject.grow_file(FILE_ALLOCATION_CHUNK_SIZE * 2 + 1).expect("failed to grow file");
+        assert_eq!(max_size, file_len());
+
+        assert!(subject.append(&event(1)).is_success());
+        subject.fsync().expect("failed to fsync");
+        drop(subject);
+
+        let mut index = PartitionIndex::new(1);
+        let subject = Segment::init_from_existing_file(&segment_file, SegmentNum::new(1), 1024, None, &mut index)
+                .expect("failed to init segment from existing file");
+        assert_eq!(max_size, subject.max_length_bytes);
+        assert_eq!(1, subject.iter_from_start().count());
+    }
+
+    #[test]
+    fn sealed_segment_is_initialized_from_its_index_file() {
+        let tmpdir = TempDir::new("sealed_segment_index_file").unwrap();
+        let segment_num = SegmentNum::new(1);
+        let segment_file = tmpdir.path().join("1.events");
+
+        let expected_entries = {
+            let mut subject = Segment::init_new(tmpdir.path(), segment_num, 4096, test_header(future_time(2)), None)
+                    .expect("failed to initialize segment");
+            let entries = (1..4).map(|i| {
+                match subject.append(&event(i * 2)) {
+                    AppendResult::Success(offset) => IndexEntry::new(i * 2, segment_num, offset),
+                    other @ _ => panic!("failed to append event: {:?}", other)
+                }
+            }).collect::<Vec<_>>();
+            subject.seal().expect("failed to seal segment");
+            assert!(subject.is_sealed());
+            assert_eq!(AppendResult::SegmentFull, subject.append(&event(7)));
+            entries
+        };
+        assert!(tmpdir.path().join("1.index").exists());
+
+        let mut index = PartitionIndex::new(1);
+        let subject = Segment::init_from_existing_file(&segment_file, segment_num, 4096, None, &mut index)
+                .expect("failed to init segment from existing file");
+        assert!(subject.is_sealed());
+        assert_eq!(6, subject.get_highest_event_counter());
+        assert_eq!(3, subject.iter_from_start().count());
+        for (i, expected) in expected_entries.into_iter().enumerate() {
+            assert_eq!(Some(expected), index.get_next_entry(i as EventCounter * 2));
+        }
+    }
+
+    #[test]
+    fn segment_is_scanned_when_its_index_file_is_out_of_date() {
+        let tmpdir = TempDir::new("stale_segment_index_file").unwrap();
+        let segment_num = SegmentNum::new(1);
+        let segment_file = tmpdir.path().join("1.events");
+
+        {
+            let mut subject = Segment::init_new(tmpdir.path(), segment_num, 4096, test_header(future_time(2)), None)
+                    .expect("failed to initialize segment");
+            assert!(subject.append(&event(1)).is_success());
+            let first_event_end = subject.data.end_offset();
+            assert!(subject.append(&event(2)).is_success());
+            subject.fsync().expect("failed to fsync");
+
+            // index file that only includes the first event
+            let stale_data = SegmentIndexData {
+                end_offset: first_event_end,
+                entries: vec![(1, SegmentHeader::get_repr_length())],
+                time_entries: Vec::new(),
+                latest_event_time_millis: 0,
+            };
+            write_index_file(&get_index_file(&segment_file), &stale_data).expect("failed to write index file");
+        }
+
+        let mut index = PartitionIndex::new(1);
+        let subject = Segment::init_from_existing_file(&segment_file, segment_num, 4096, None, &mut index)
+                .expect("failed to init segment from existing file");
+        assert!(!subject.is_sealed());
+        assert_eq!(2, subject.get_highest_event_counter());
+        assert_eq!(2, index.greatest_event_counter());
+        assert_eq!(2, subject.iter_from_start().count());
+    }
+
+    fn assert_events_eq<L: FloEvent, R: FloEvent>(lhs: &L, rhs: &R) {
+        assert_eq!(lhs.id(), rhs.id());
+        assert_eq!(lhs.parent_id(), rhs.parent_id());
+        assert_eq!(time::millis_since_epoch(lhs.timestamp()), time::millis_since_epoch(rhs.timestamp()));
+        assert_eq!(lhs.namespace(), rhs.namespace());
+        assert_eq!(lhs.data_len(), rhs.data_len());
+        assert_eq!(lhs.data(), rhs.data());
+    }
+
+    #[test]
+    fn first_event_since_a_given_time_is_found_using_the_time_index() {
+        let tmpdir = TempDir::new("segment_find_event_since").unwrap();
+        let start = time::now();
+        let mut subject = Segment::init_new(tmpdir.path(), SegmentNum::new(1), 4096, test_header(future_time(30)), None)
+                .expect("failed to initialize segment");
+
+        for (counter, millis) in vec![(1, 0), (2, 500), (3, 1500), (4, 3000), (5, 3200)] {
+            let mut event = event(counter);
+            event.timestamp = start + Duration::milliseconds(millis);
+            assert!(subject.append(&event).is_success());
+        }
+
+        let find = |millis: i64| subject.find_first_event_since(start + Duration::milliseconds(millis)).expect("failed to find event");
+        assert_eq!(Some(1), find(-5000));
+        assert_eq!(Some(1), find(0));
+        assert_eq!(Some(3), find(501));
+        assert_eq!(Some(3), find(1500));
+        assert_eq!(Some(5), find(3100));
+        assert_eq!(None, find(3201));
+        assert_eq!(None, find(60_000));
+    }
+
+    #[test]
+    fn segment_is_only_expired_once_the_retention_period_has_passed_since_its_end_time() {
+        let tmpdir = TempDir::new("segment_is_expired").unwrap();
+        let create_time = time::now();
+        let end_time = create_time + Duration::seconds(10);
+        let subject = Segment::init_new(tmpdir.path(), SegmentNum::new(1), 4096, SegmentHeader::new(1, 1, create_time, end_time), None)
+                .expect("failed to initialize segment");
+
+        let retention = Duration::seconds(30);
+        assert!(!subject.is_expired(create_time, retention));
+        assert!(!subject.is_expired(end_time, retention));
+        assert!(!subject.is_expired(end_time + retention, retention));
+        assert!(subject.is_expired(end_time + retention + Duration::milliseconds(1), retention));
+        assert!(!subject.is_expired(end_time + Duration::days(9999), Duration::max_value()));
+    }
+
+    #[test]
+    fn rewritten_segment_contains_only_the_retained_events() {
+        let tmpdir = TempDir::new("segment_rewrite").unwrap();
+        let segment_num = SegmentNum::new(1);
+        let mut subject = Segment::init_new(tmpdir.path(), segment_num, 4096, test_header(future_time(2)), None)
+                .expect("failed to initialize segment");
+        for i in 1..6 {
+            assert!(subject.append(&event(i)).is_success());
+        }
+        subject.seal().expect("failed to seal segment");
+        let old_reader = subject.iter_from_start();
+
+        let rewritten = subject.rewrite(|event| event.id().event_counter % 2 == 1).expect("failed to rewrite segment");
+        assert!(rewritten.is_sealed());
+        assert_eq!(3, rewritten.get_event_count());
+        assert!(!tmpdir.path().join("1.compacting").exists());
+        let counters = rewritten.iter_from_start().map(|r| r.unwrap().id().event_counter).collect::<Vec<_>>();
+        assert_eq!(vec![1, 3, 5], counters);
+
+        // readers of the previous version are unaffected
+        assert_eq!(5, old_reader.count());
+        drop(subject);
+
+        let mut index = PartitionIndex::new(1);
+        let reopened = Segment::init_from_existing_file(&tmpdir.path().join("1.events"), segment_num, 4096, None, &mut index)
+                .expect("failed to reopen segment");
+        assert!(reopened.is_sealed());
+        let counters = reopened.iter_from_start().map(|r| r.unwrap().id().event_counter).collect::<Vec<_>>();
+        assert_eq!(vec![1, 3, 5], counters);
+        assert_eq!(Some(IndexEntry::new(3, segment_num, rewritten.iter_from_start().nth(1).unwrap().unwrap().file_offset())),
+                   index.get_next_entry(1));
+    }
+
+    #[test]
+    fn compressed_segment_returns_the_same_events_as_the_original() {
+        let tmpdir = TempDir::new("segment_compress").unwrap();
+        let segment_num = SegmentNum::new(1);
+        let max_size = 1024 * 1024;
+        let mut subject = Segment::init_new(tmpdir.path(), segment_num, max_size, test_header(future_time(2)), None)
+                .expect("failed to initialize segment");
+        // enough events to span multiple blocks
+        for i in 1..201 {
+            let data = format!("{{\"counter\": {}, \"message\": \"{}\"}}", i, "lorem ipsum ".repeat(100)).into_bytes();
+            let event = OwnedFloEvent::new(FloEventId::new(1, i), None, time::now(), "/foo/bar".to_owned(), data);
+            assert!(subject.append(&event).is_success());
+        }
+        subject.seal().expect("failed to seal segment");
+        let original = subject.iter_from_start().map(|r| r.unwrap()).collect::<Vec<_>>();
+
+        let compressed = subject.compress().expect("failed to compress segment");
+        assert!(compressed.is_compressed());
+        assert!(compressed.get_size_bytes() < subject.get_size_bytes() / 4);
+        assert_eq!(subject.get_highest_event_counter(), compressed.get_highest_event_counter());
+        assert!(!tmpdir.path().join("1.compressing").exists());
+
+        let result = compressed.iter_from_start().map(|r| r.unwrap()).collect::<Vec<_>>();
+        assert_eq!(original, result);
+        let original_offsets = original.iter().map(|e| e.file_offset()).collect::<Vec<_>>();
+        let result_offsets = result.iter().map(|e| e.file_offset()).collect::<Vec<_>>();
+        assert_eq!(original_offsets, result_offsets);
+
+        // readers can start from the offset of any event
+        let mut reader = compressed.range_iter(original[150].file_offset());
+        assert_eq!(original[150], reader.next().unwrap().unwrap());
+        reader.set_offset_to_end();
+        assert!(reader.is_exhausted());
+        drop(subject);
+        drop(compressed);
+
+        // reopening works with or without the index file
+        for &remove_index in [false, true].iter() {
+            if remove_index {
+                ::std::fs::remove_file(tmpdir.path().join("1.index")).unwrap();
+            }
+            let mut index = PartitionIndex::new(1);
+            let reopened = Segment::init_from_existing_file(&tmpdir.path().join("1.events"), segment_num, max_size, None, &mut index)
+                    .expect("failed to reopen segment");
+            assert!(reopened.is_compressed());
+            assert!(reopened.is_sealed());
+            assert_eq!(200, reopened.get_event_count());
+            assert_eq!(Some(IndexEntry::new(101, segment_num, original[100].file_offset())), index.get_next_entry(100));
+            assert_eq!(Some(original[199].timestamp()), reopened.iter_from_start().last().map(|r| r.unwrap().timestamp()));
+        }
+    }
+
+    fn event(counter: EventCounter) -> OwnedFloEvent {
+        OwnedFloEvent::new(
+            FloEventId::new(1, counter),
+            None,
+            time::now(),
+            "/foo/bar".to_owned(),
+            vec![1, 2, 3, 4, 5]
+        )
+    }
+}
+
+
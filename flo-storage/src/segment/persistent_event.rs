@@ -0,0 +1,434 @@
+use std::io::{self, Write};
+use std::error::Error;
+use std::fmt::{self, Display};
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+use crc::crc32;
+
+use event::{FloEvent, OwnedFloEvent, FloEventId, Timestamp, EventHeader, ProducerSequence, time};
+use segment::mmap::{MmapRef};
+
+
+
+/// Marks the start of an event that has no headers, event time, or producer sequence
+const EVENT_MARKER: &'static [u8; 8] = b"FLO_EVT\n";
+/// Marks the start of an event that has headers, an event time, or a producer sequence. These events have an extended
+/// section in between the namespace and the data. Events without either are always written using `EVENT_MARKER`, so they're laid out exactly
+/// the same way as events that were written before the extended section existed.
+const EXTENDED_EVENT_MARKER: &'static [u8; 8] = b"FLO_EVX\n";
+
+#[derive(Debug)]
+pub struct PersistentEvent {
+    id: FloEventId,
+    file_offset: usize,
+    raw_data: MmapRef,
+    /// Headers are parsed when the event is read, since `FloEvent::headers` returns them as owned pairs
+    headers: Vec<EventHeader>,
+}
+
+
+impl PersistentEvent {
+
+    pub fn get_repr_length<E: FloEvent>(event: &E) -> u32 {
+        // Don't change this function without also changing `write_event` below!
+        //
+        // 4 for total_size +     start = 0
+        // 8 for header marker +  start = 4
+        // 10 for id +            start = 12
+        // 10 for parent_id +     start = 22
+        // 8 for timestamp +      start = 32
+        // 4 for namespace.len +  start = 40
+        // x for namespace +      start = 44
+        // 4 for data.len +       start = 44 + x = ?
+        // y for data             start = 48 + x = ?
+        // 4 for crc              start = 48 + x + y
+        //
+        // = 52 + x + y
+        //
+        // Events with headers, an event time, or a producer sequence have the extended section right after the namespace. It starts with
+        // the length of the rest of the section, so it takes up 4 + e bytes. See `get_extended_section_len`.
+        let extended_len = if has_extended_section(event) {
+            4 + get_extended_section_len(event)
+        } else {
+            0
+        };
+        EVENT_OVERHEAD + event.namespace().len() as u32 + extended_len + event.data_len()
+    }
+
+    pub fn total_repr_len(&self) -> usize {
+        PersistentEvent::get_repr_length(self) as usize
+    }
+
+    pub unsafe fn write_unchecked<E: FloEvent>(event: &E, buffer: &mut [u8]) {
+        let len = PersistentEvent::get_repr_length(event);
+        write_event_unchecked(buffer, event, len);
+    }
+
+    pub fn read(mmap: &MmapRef, start_offset: usize) -> io::Result<Self> {
+        let result = {
+            let buffer = mmap.get_read_slice(start_offset);
+            PersistentEvent::validate(buffer)
+        };
+        result.map(|(id, headers)| {
+            PersistentEvent {
+                id: id,
+                file_offset: start_offset,
+                raw_data: mmap.clone(),
+                headers: headers,
+            }
+        })
+    }
+
+    pub fn file_offset(&self) -> usize {
+        self.file_offset
+    }
+
+    /// Returns the serialized form of the entire event, exactly as it's stored in the segment
+    pub fn raw_bytes(&self) -> &[u8] {
+        self.as_buf(0, self.total_repr_len())
+    }
+
+    fn validate(buffer: &[u8]) -> io::Result<(FloEventId, Vec<EventHeader>)> {
+        if buffer.len() < EVENT_OVERHEAD as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "buffer is not large enough"));
+        }
+
+        let total_len = BigEndian::read_u32(&buffer[..4]);
+
+        let header_bytes = &buffer[4..12];
+        let is_extended = if header_bytes == EVENT_MARKER {
+            false
+        } else if header_bytes == EXTENDED_EVENT_MARKER {
+            true
+        } else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid marker bytes"));
+        };
+
+        let partition_buf = &buffer[12..14];
+        let partition_num = BigEndian::read_u16(partition_buf);
+        let counter_buf = &buffer[14..22];
+        let counter = BigEndian::read_u64(counter_buf);
+
+        // check the namespace and data lengths to ensure that they line up OK
+        let ns_len = BigEndian::read_u32(&buffer[40..44]);
+
+        if ns_len as usize + EVENT_OVERHEAD as usize > buffer.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "namespace length too large"));
+        }
+
+        // the extended section, if there is one, includes its own 4 byte length
+        let mut extended_len = 0usize;
+        if is_extended {
+            let extended_len_pos = 44usize + ns_len as usize;
+            extended_len = 4 + BigEndian::read_u32(&buffer[extended_len_pos..(extended_len_pos + 4)]) as usize;
+            if extended_len < 4 + EXTENDED_SECTION_MIN_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "extended section length too small"));
+            }
+            if ns_len as usize + extended_len + EVENT_OVERHEAD as usize > buffer.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "extended section length too large"));
+            }
+        }
+
+        let data_len_pos = 44usize + ns_len as usize + extended_len;
+        let data_len_buf = &buffer[data_len_pos..(data_len_pos + 4)];
+        let data_len = BigEndian::read_u32(data_len_buf);
+
+        if total_len as u64 != EVENT_OVERHEAD as u64 + ns_len as u64 + extended_len as u64 + data_len as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "mismatched lengths"));
+        }
+
+        if total_len as usize > buffer.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "event extends past the end of the segment"));
+        }
+
+        let crc_pos = total_len as usize - 4;
+        let expected = BigEndian::read_u32(&buffer[crc_pos..(crc_pos + 4)]);
+        let actual = crc32::checksum_ieee(&buffer[..crc_pos]);
+        if expected != actual {
+            let id = FloEventId::new(partition_num, counter);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, ChecksumMismatch { id, expected, actual }));
+        }
+
+        let headers = if is_extended {
+            let headers_start = 48 + ns_len as usize + EXTENDED_SECTION_MIN_LEN;
+            read_headers(&buffer[headers_start..data_len_pos])?
+        } else {
+            Vec::new()
+        };
+
+        Ok((FloEventId::new(partition_num, counter), headers))
+    }
+
+    fn as_buf(&self, start: usize, len: usize) -> &[u8] {
+        &self.raw_data.get_read_slice(self.file_offset + start)[..len]
+    }
+
+    fn namespace_len(&self) -> u32 {
+        let buf = self.as_buf(40, 4);
+        BigEndian::read_u32(buf)
+    }
+
+    fn is_extended(&self) -> bool {
+        self.as_buf(4, 8) == EXTENDED_EVENT_MARKER
+    }
+
+    /// Returns the offset of the data length, relative to the start of the event
+    fn data_len_pos(&self) -> usize {
+        let extended_pos = 44 + self.namespace_len() as usize;
+        if self.is_extended() {
+            let extended_len = BigEndian::read_u32(self.as_buf(extended_pos, 4)) as usize;
+            extended_pos + 4 + extended_len
+        } else {
+            extended_pos
+        }
+    }
+}
+
+impl PartialEq for PersistentEvent {
+    fn eq(&self, other: &PersistentEvent) -> bool {
+        self.id() == other.id() &&
+                self.parent_id() == other.parent_id() &&
+                self.namespace() == other.namespace() &&
+                self.timestamp() == other.timestamp() &&
+                self.event_time() == other.event_time() &&
+                self.producer_sequence() == other.producer_sequence() &&
+                self.headers() == other.headers() &&
+                self.data() == other.data()
+    }
+}
+
+impl FloEvent for PersistentEvent {
+    fn id(&self) -> &FloEventId {
+        &self.id
+    }
+
+    fn timestamp(&self) -> Timestamp {
+        let buf = self.as_buf(32, 8);
+        let as_u64 = BigEndian::read_u64(buf);
+        time::from_millis_since_epoch(as_u64)
+    }
+
+    fn event_time(&self) -> Option<Timestamp> {
+        if !self.is_extended() {
+            return None;
+        }
+        let event_time_pos = 48 + self.namespace_len() as usize;
+        let as_u64 = BigEndian::read_u64(self.as_buf(event_time_pos, 8));
+        if as_u64 > 0 {
+            Some(time::from_millis_since_epoch(as_u64))
+        } else {
+            None
+        }
+    }
+
+    fn producer_sequence(&self) -> Option<ProducerSequence> {
+        if !self.is_extended() {
+            return None;
+        }
+        let producer_id_pos = 56 + self.namespace_len() as usize;
+        let producer_id = BigEndian::read_u64(self.as_buf(producer_id_pos, 8));
+        if producer_id > 0 {
+            Some(ProducerSequence {
+                producer_id: producer_id,
+                sequence: BigEndian::read_u64(self.as_buf(producer_id_pos + 8, 8)),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn parent_id(&self) -> Option<FloEventId> {
+        let buf = self.as_buf(22, 10);
+        let partition = BigEndian::read_u16(&buf[0..2]);
+        let counter = BigEndian::read_u64(&buf[2..]);
+        if counter > 0 {
+            Some(FloEventId::new(partition, counter))
+        } else {
+            None
+        }
+    }
+
+    fn namespace(&self) -> &str {
+        let ns_len = self.namespace_len() as usize;
+        let ns_buf = self.as_buf(44, ns_len);
+        unsafe {
+            ::std::str::from_utf8_unchecked(ns_buf)
+        }
+    }
+
+    fn data_len(&self) -> u32 {
+        let data_len_buf = self.as_buf(self.data_len_pos(), 4);
+        BigEndian::read_u32(data_len_buf)
+    }
+
+    fn data(&self) -> &[u8] {
+        let data_len_pos = self.data_len_pos();
+        let data_len = BigEndian::read_u32(self.as_buf(data_len_pos, 4)) as usize;
+        self.as_buf(data_len_pos + 4, data_len)
+    }
+
+    fn headers(&self) -> &[EventHeader] {
+        &self.headers
+    }
+
+    fn to_owned(&self) -> OwnedFloEvent {
+        let id = *self.id();
+        let parent_id = self.parent_id();
+        let timestamp = self.timestamp();
+        let namespace = self.namespace().to_owned();
+        let data = self.data().to_owned();
+        let mut owned = OwnedFloEvent::new(id, parent_id, timestamp, namespace, data);
+        owned.event_time = self.event_time();
+        owned.headers = self.headers.clone();
+        owned
+    }
+}
+
+
+/// Reads just the id from the start of a serialized event, without validating the rest of it. Returns `None` if the
+/// buffer doesn't start with an event.
+pub fn read_event_id(buffer: &[u8]) -> Option<FloEventId> {
+    if buffer.len() < 22 || !is_event_marker(&buffer[4..12]) {
+        return None;
+    }
+    let partition_num = BigEndian::read_u16(&buffer[12..14]);
+    let counter = BigEndian::read_u64(&buffer[14..22]);
+    Some(FloEventId::new(partition_num, counter))
+}
+
+/// Returns true if the given bytes are one of the markers that every serialized event has right after its length
+pub fn is_event_marker(bytes: &[u8]) -> bool {
+    bytes == EVENT_MARKER || bytes == EXTENDED_EVENT_MARKER
+}
+
+/// Returns true if the given error was caused by an event whose checksum did not match its contents
+pub fn is_checksum_error(err: &io::Error) -> bool {
+    err.get_ref().map(|inner| inner.is::<ChecksumMismatch>()).unwrap_or(false)
+}
+
+/// The error returned when reading an event whose stored crc does not match the crc computed from its header and body.
+/// This indicates that the segment file has been corrupted.
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    pub id: FloEventId,
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "checksum mismatch for event: {}, expected: {:08x}, actual: {:08x}", self.id, self.expected, self.actual)
+    }
+}
+
+impl Error for ChecksumMismatch {
+    fn description(&self) -> &str {
+        "checksum mismatch"
+    }
+}
+
+/// The number of bytes in each persisted event that are used for things other than the namespace, extended section,
+/// and data
+const EVENT_OVERHEAD: u32 = 52;
+
+/// The extended section always starts with the event time, followed by the producer id and sequence number. Each of these
+/// is 0 if the event doesn't have one
+const EXTENDED_SECTION_MIN_LEN: usize = 24;
+
+fn has_extended_section<E: FloEvent>(event: &E) -> bool {
+    event.event_time().is_some() || event.producer_sequence().is_some() || !event.headers().is_empty()
+}
+
+/// The length of the extended section, not including the 4 bytes for the length itself. The extended section has 8 bytes
+/// each for the event time, producer id, and sequence number, followed by each header as a u16 name length, the name, a
+/// u32 value length, and the value
+fn get_extended_section_len<E: FloEvent>(event: &E) -> u32 {
+    let headers_len: u32 = event.headers().iter().map(|&(ref name, ref value)| 6 + name.len() as u32 + value.len() as u32).sum();
+    EXTENDED_SECTION_MIN_LEN as u32 + headers_len
+}
+
+fn read_headers(mut buffer: &[u8]) -> io::Result<Vec<EventHeader>> {
+    let mut headers = Vec::new();
+    while !buffer.is_empty() {
+        if buffer.len() < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated header name"));
+        }
+        let name_end = 2 + BigEndian::read_u16(&buffer[..2]) as usize;
+        if buffer.len() < name_end + 4 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated header name"));
+        }
+        let name = ::std::str::from_utf8(&buffer[2..name_end]).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, "header name is not valid utf-8")
+        })?.to_owned();
+        let value_end = name_end + 4 + BigEndian::read_u32(&buffer[name_end..(name_end + 4)]) as usize;
+        if buffer.len() < value_end {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated header value"));
+        }
+        headers.push((name, buffer[(name_end + 4)..value_end].to_vec()));
+        buffer = &buffer[value_end..];
+    }
+    Ok(headers)
+}
+
+/// private function to write the event. `total_size` must match the actual size of the data to be written
+fn write_event_unchecked<E: FloEvent>(buffer: &mut [u8], event: &E, total_size: u32) {
+    // Don't change this function without also changing `get_repr_len` above!
+    //
+    // 4 for total_size +     start = 0
+    // 8 for header marker +  start = 4
+    // 10 for id +            start = 12
+    // 10 for parent_id +     start = 22
+    // 8 for timestamp +      start = 32
+    // 4 for namespace.len +  start = 40
+    // x for namespace +      start = 44
+    // 4 for data.len +       start = 44 + x = ?
+    // y for data             start = 48 + x = ?
+    // 4 for crc              start = 48 + x + y
+    //
+    // = 52 + x + y
+    //
+    // plus 4 + e for the extended section after the namespace, if the event has headers, an event time, or a producer sequence
+
+    let is_extended = has_extended_section(event);
+    let crc_pos = {
+        let mut writer = io::Cursor::new(&mut buffer[..]);
+        write_event_fields(&mut writer, event, total_size, is_extended).expect("buffer is too small for event");
+        writer.position() as usize
+    };
+
+    // the crc covers everything in the event that comes before it
+    let crc = crc32::checksum_ieee(&buffer[..crc_pos]);
+    BigEndian::write_u32(&mut buffer[crc_pos..(crc_pos + 4)], crc);
+    debug_assert_eq!(total_size as usize, crc_pos + 4);
+}
+
+fn write_event_fields<E: FloEvent, W: Write>(writer: &mut W, event: &E, total_size: u32, is_extended: bool) -> io::Result<()> {
+    use event::time::millis_since_epoch;
+
+    let marker = if is_extended { EXTENDED_EVENT_MARKER } else { EVENT_MARKER };
+    writer.write_u32::<BigEndian>(total_size)?;
+    writer.write_all(marker)?;
+    writer.write_u16::<BigEndian>(event.id().actor)?;
+    writer.write_u64::<BigEndian>(event.id().event_counter)?;
+    writer.write_u16::<BigEndian>(event.parent_id().map(|e| e.actor).unwrap_or(0))?;
+    writer.write_u64::<BigEndian>(event.parent_id().map(|e| e.event_counter).unwrap_or(0))?;
+    writer.write_u64::<BigEndian>(millis_since_epoch(event.timestamp()))?;
+    writer.write_u32::<BigEndian>(event.namespace().len() as u32)?;
+    writer.write_all(event.namespace().as_bytes())?;
+    if is_extended {
+        writer.write_u32::<BigEndian>(get_extended_section_len(event))?;
+        writer.write_u64::<BigEndian>(event.event_time().map(millis_since_epoch).unwrap_or(0))?;
+        writer.write_u64::<BigEndian>(event.producer_sequence().map(|seq| seq.producer_id).unwrap_or(0))?;
+        writer.write_u64::<BigEndian>(event.producer_sequence().map(|seq| seq.sequence).unwrap_or(0))?;
+        for &(ref name, ref value) in event.headers().iter() {
+            writer.write_u16::<BigEndian>(name.len() as u16)?;
+            writer.write_all(name.as_bytes())?;
+            writer.write_u32::<BigEndian>(value.len() as u32)?;
+            writer.write_all(value)?;
+        }
+    }
+    writer.write_u32::<BigEndian>(event.data_len())?;
+    writer.write_all(event.data())
+}
+
+
@@ -0,0 +1,150 @@
+use event::{EventCounter, Timestamp, time};
+
+/// The minimum number of milliseconds between entries in a `TimeIndex`
+const TIME_INDEX_INTERVAL_MILLIS: u64 = 1000;
+
+/// Used as the latest event time of a segment whose index was written before event times were tracked
+pub const UNKNOWN_EVENT_TIME: u64 = ::std::u64::MAX;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TimeIndexEntry {
+    pub timestamp_millis: u64,
+    pub counter: EventCounter,
+    pub file_offset: usize,
+}
+
+/// A sparse index of event timestamps within a single segment. An entry is added for the first event in the segment, and
+/// then for the first event after each interval of `TIME_INDEX_INTERVAL_MILLIS`. This allows finding the events since a
+/// given time by only reading the events within a single interval, without keeping an entry for every event in memory.
+/// Event times aren't ordered, so the index only keeps the latest event time in the segment, which is enough to skip the
+/// segments that can't have any events since a given event time.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TimeIndex {
+    entries: Vec<TimeIndexEntry>,
+    /// The latest event time of any event in the segment, using the timestamp of events that don't have an event time
+    latest_event_time_millis: u64,
+}
+
+impl TimeIndex {
+    pub fn new() -> TimeIndex {
+        TimeIndex::from_entries(Vec::new(), 0)
+    }
+
+    pub fn from_entries(entries: Vec<TimeIndexEntry>, latest_event_time_millis: u64) -> TimeIndex {
+        TimeIndex {
+            entries: entries,
+            latest_event_time_millis: latest_event_time_millis,
+        }
+    }
+
+    pub fn entries(&self) -> &[TimeIndexEntry] {
+        &self.entries
+    }
+
+    pub fn latest_event_time_millis(&self) -> u64 {
+        self.latest_event_time_millis
+    }
+
+    /// Called for every event that's added to the segment, in order
+    pub fn add(&mut self, timestamp: Timestamp, event_time: Option<Timestamp>, counter: EventCounter, file_offset: usize) {
+        let timestamp_millis = time::millis_since_epoch(timestamp);
+        let event_time_millis = event_time.map(time::millis_since_epoch).unwrap_or(timestamp_millis);
+        if event_time_millis > self.latest_event_time_millis {
+            self.latest_event_time_millis = event_time_millis;
+        }
+
+        let add_entry = self.entries.last().map(|last| {
+            timestamp_millis >= last.timestamp_millis + TIME_INDEX_INTERVAL_MILLIS
+        }).unwrap_or(true);
+
+        if add_entry {
+            self.entries.push(TimeIndexEntry {
+                timestamp_millis: timestamp_millis,
+                counter: counter,
+                file_offset: file_offset,
+            });
+        }
+    }
+
+    /// Returns the offset of the last entry with a timestamp that's strictly before the given time. Every event before
+    /// that offset is guaranteed to be older than `since`, so the segment can be read starting from there to find the
+    /// first event since that time. Returns `None` if there's no such entry, meaning the whole segment must be read.
+    pub fn get_scan_start_offset(&self, since: Timestamp) -> Option<usize> {
+        let since_millis = time::millis_since_epoch(since);
+        // entries are always in strictly increasing order of timestamp, since they can only be added once the interval has passed
+        let index = match self.entries.binary_search_by_key(&since_millis, |entry| entry.timestamp_millis) {
+            Ok(exact) => exact,
+            Err(insert_index) => insert_index,
+        };
+
+        if index > 0 {
+            Some(self.entries[index - 1].file_offset)
+        } else {
+            None
+        }
+    }
+
+    /// Returns false if every event in the segment has an event time before `since`, so that consumers looking for event
+    /// times since then can skip the whole segment
+    pub fn may_have_event_time_since(&self, since: Timestamp) -> bool {
+        self.latest_event_time_millis >= time::millis_since_epoch(since)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ts(millis: u64) -> Timestamp {
+        time::from_millis_since_epoch(millis)
+    }
+
+    #[test]
+    fn entries_are_only_added_once_the_interval_has_passed() {
+        let mut subject = TimeIndex::new();
+        subject.add(ts(5000), None, 1, 16);
+        subject.add(ts(5999), None, 2, 80);
+        subject.add(ts(6000), None, 3, 160);
+        subject.add(ts(6500), None, 4, 240);
+        subject.add(ts(9000), None, 5, 320);
+
+        let counters = subject.entries().iter().map(|e| e.counter).collect::<Vec<_>>();
+        assert_eq!(vec![1, 3, 5], counters);
+    }
+
+    #[test]
+    fn scan_start_offset_is_the_last_entry_before_the_given_time() {
+        let mut subject = TimeIndex::new();
+        subject.add(ts(5000), None, 1, 16);
+        subject.add(ts(6000), None, 3, 160);
+        subject.add(ts(9000), None, 5, 320);
+
+        assert_eq!(None, subject.get_scan_start_offset(ts(4000)));
+        assert_eq!(None, subject.get_scan_start_offset(ts(5000)));
+        assert_eq!(Some(16), subject.get_scan_start_offset(ts(5001)));
+        assert_eq!(Some(16), subject.get_scan_start_offset(ts(6000)));
+        assert_eq!(Some(160), subject.get_scan_start_offset(ts(8999)));
+        assert_eq!(Some(320), subject.get_scan_start_offset(ts(99999)));
+    }
+
+    #[test]
+    fn segment_may_have_event_times_since_its_latest_event_time_or_timestamp() {
+        let mut subject = TimeIndex::new();
+        subject.add(ts(5000), Some(ts(9000)), 1, 16);
+        subject.add(ts(6000), Some(ts(2000)), 2, 160);
+        subject.add(ts(7000), None, 3, 320);
+        assert_eq!(9000, subject.latest_event_time_millis());
+        assert!(subject.may_have_event_time_since(ts(9000)));
+        assert!(!subject.may_have_event_time_since(ts(9001)));
+
+        let unknown = TimeIndex::from_entries(subject.entries().to_vec(), UNKNOWN_EVENT_TIME);
+        assert!(unknown.may_have_event_time_since(ts(99999)));
+    }
+
+    #[test]
+    fn scan_start_offset_is_none_when_index_is_empty() {
+        let subject = TimeIndex::new();
+        assert_eq!(None, subject.get_scan_start_offset(ts(4000)));
+    }
+}
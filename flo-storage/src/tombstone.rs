@@ -0,0 +1,277 @@
+use std::collections::{BTreeSet, BTreeMap};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+use crc::crc32::checksum_ieee;
+
+use event::{FloEvent, EventCounter, ProducerSequence};
+use event_reader::NamespaceGlob;
+
+const TOMBSTONE_FILE_NAME: &'static str = "tombstones";
+const TOMBSTONE_FILE_MARKER: &'static [u8] = b"FLO_TMB\n";
+const CHECKSUM_LEN: usize = 4;
+
+/// Deletes every event in a namespace that matches the glob, as long as the event was already in the partition when
+/// the namespace was deleted. Events that are appended later are unaffected.
+#[derive(Debug, PartialEq, Clone)]
+struct NamespaceTombstone {
+    pattern: String,
+    glob: NamespaceGlob,
+    through_counter: EventCounter,
+}
+
+/// The events that have been deleted from a partition. Deleted events are skipped by every reader right away, but they
+/// may remain in the segment files until the segment gets rewritten. Event ids are never reused, so a deleted event just
+/// leaves a gap in the sequence of counters. The producer sequences of deleted events are kept as well, since the events
+/// can't be read anymore when the dedupe window gets rebuilt after a restart.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Tombstones {
+    counters: BTreeSet<EventCounter>,
+    namespaces: Vec<NamespaceTombstone>,
+    sequences: BTreeMap<EventCounter, ProducerSequence>,
+}
+
+impl Tombstones {
+    pub fn new() -> Tombstones {
+        Tombstones {
+            counters: BTreeSet::new(),
+            namespaces: Vec::new(),
+            sequences: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty() && self.namespaces.is_empty()
+    }
+
+    pub fn is_deleted<E: FloEvent>(&self, event: &E) -> bool {
+        self.contains(event.id().event_counter, event.namespace())
+    }
+
+    /// Returns true if the event with the given counter and namespace has been deleted
+    pub fn contains(&self, counter: EventCounter, namespace: &str) -> bool {
+        self.counters.contains(&counter) || self.is_namespace_deleted(counter, namespace)
+    }
+
+    /// Returns true if every event in the namespace, up through the given counter, has been deleted
+    pub fn is_namespace_deleted(&self, counter: EventCounter, namespace: &str) -> bool {
+        self.namespaces.iter().any(|tombstone| {
+            counter <= tombstone.through_counter && tombstone.glob.matches(namespace)
+        })
+    }
+
+    pub fn add_counter(&mut self, counter: EventCounter) {
+        self.counters.insert(counter);
+    }
+
+    /// Deletes every event with a namespace matching `pattern` and a counter no greater than `through_counter`
+    pub fn add_namespace(&mut self, pattern: &str, through_counter: EventCounter) -> Result<(), String> {
+        let glob = NamespaceGlob::new(pattern)?;
+        self.namespaces.push(NamespaceTombstone {
+            pattern: pattern.to_owned(),
+            glob: glob,
+            through_counter: through_counter,
+        });
+        Ok(())
+    }
+
+    /// Records the producer sequence of a deleted event
+    pub fn add_sequence(&mut self, counter: EventCounter, producer_sequence: ProducerSequence) {
+        self.sequences.insert(counter, producer_sequence);
+    }
+
+    /// Returns the producer sequences of the deleted events with counters greater than `start_exclusive`, in order
+    pub fn sequences_after(&self, start_exclusive: EventCounter) -> Vec<(EventCounter, ProducerSequence)> {
+        self.sequences.range((start_exclusive + 1)..).map(|(counter, producer_sequence)| (*counter, *producer_sequence)).collect()
+    }
+}
+
+pub fn get_tombstone_file(partition_dir: &Path) -> PathBuf {
+    partition_dir.join(TOMBSTONE_FILE_NAME)
+}
+
+/// Writes all of the tombstones for a partition. The data is written to a temporary file first and then renamed, so that
+/// a partially written file never replaces a complete one.
+pub fn write_tombstone_file(path: &Path, tombstones: &Tombstones) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(TOMBSTONE_FILE_MARKER);
+    buffer.write_u64::<BigEndian>(tombstones.counters.len() as u64)?;
+    for counter in tombstones.counters.iter() {
+        buffer.write_u64::<BigEndian>(*counter)?;
+    }
+    buffer.write_u64::<BigEndian>(tombstones.namespaces.len() as u64)?;
+    for tombstone in tombstones.namespaces.iter() {
+        buffer.write_u64::<BigEndian>(tombstone.through_counter)?;
+        buffer.write_u32::<BigEndian>(tombstone.pattern.len() as u32)?;
+        buffer.extend_from_slice(tombstone.pattern.as_bytes());
+    }
+    buffer.write_u64::<BigEndian>(tombstones.sequences.len() as u64)?;
+    for (counter, producer_sequence) in tombstones.sequences.iter() {
+        buffer.write_u64::<BigEndian>(*counter)?;
+        buffer.write_u64::<BigEndian>(producer_sequence.producer_id)?;
+        buffer.write_u64::<BigEndian>(producer_sequence.sequence)?;
+    }
+    let checksum = checksum_ieee(&buffer);
+    buffer.write_u32::<BigEndian>(checksum)?;
+
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, path)
+}
+
+/// Reads the tombstone file at the given path. Returns an empty set of tombstones if there is no file, and an error if
+/// the file is corrupted, since silently ignoring it would resurrect deleted events.
+pub fn read_tombstone_file(path: &Path) -> io::Result<Tombstones> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Tombstones::new()),
+        Err(err) => return Err(err),
+    };
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    parse_tombstones(&buffer).map_err(|description| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("Invalid tombstone file: {:?}: {}", path, description))
+    })
+}
+
+fn parse_tombstones(buffer: &[u8]) -> Result<Tombstones, String> {
+    if buffer.len() < TOMBSTONE_FILE_MARKER.len() + CHECKSUM_LEN || &buffer[..TOMBSTONE_FILE_MARKER.len()] != TOMBSTONE_FILE_MARKER {
+        return Err("missing tombstone file marker".to_owned());
+    }
+    let data_end = buffer.len() - CHECKSUM_LEN;
+    if checksum_ieee(&buffer[..data_end]) != BigEndian::read_u32(&buffer[data_end..]) {
+        return Err("checksum mismatch".to_owned());
+    }
+
+    let mut reader = BufferReader::new(&buffer[..data_end], TOMBSTONE_FILE_MARKER.len());
+    let mut tombstones = Tombstones::new();
+    let counter_count = reader.read_u64()?;
+    for _ in 0..counter_count {
+        let counter = reader.read_u64()?;
+        tombstones.add_counter(counter);
+    }
+    let namespace_count = reader.read_u64()?;
+    for _ in 0..namespace_count {
+        let through_counter = reader.read_u64()?;
+        let pattern_len = reader.read_u32()? as usize;
+        let pattern = String::from_utf8(reader.read_bytes(pattern_len)?.to_vec()).map_err(|err| {
+            format!("invalid namespace pattern: {}", err)
+        })?;
+        tombstones.add_namespace(&pattern, through_counter)?;
+    }
+    // Files written before producer sequences were added end after the namespaces
+    if reader.is_at_end() {
+        return Ok(tombstones);
+    }
+    let sequence_count = reader.read_u64()?;
+    for _ in 0..sequence_count {
+        let counter = reader.read_u64()?;
+        let producer_id = reader.read_u64()?;
+        let sequence = reader.read_u64()?;
+        tombstones.add_sequence(counter, ProducerSequence { producer_id, sequence });
+    }
+    Ok(tombstones)
+}
+
+/// Reads big endian values from a buffer, returning an error instead of panicking if the buffer ends too soon
+pub struct BufferReader<'a> {
+    buffer: &'a [u8],
+    position: usize,
+}
+
+impl <'a> BufferReader<'a> {
+    pub fn new(buffer: &'a [u8], position: usize) -> BufferReader<'a> {
+        BufferReader {
+            buffer: buffer,
+            position: position,
+        }
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.position >= self.buffer.len()
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.position + len;
+        if end > self.buffer.len() {
+            return Err("unexpected end of file".to_owned());
+        }
+        let bytes = &self.buffer[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, String> {
+        self.read_bytes(8).map(BigEndian::read_u64)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        self.read_bytes(4).map(BigEndian::read_u32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+    use event::{OwnedFloEvent, FloEventId, time};
+
+    fn event(counter: EventCounter, namespace: &str) -> OwnedFloEvent {
+        OwnedFloEvent::new(FloEventId::new(1, counter), None, time::now(), namespace.to_owned(), Vec::new())
+    }
+
+    #[test]
+    fn namespace_tombstone_only_deletes_events_up_through_its_counter() {
+        let mut subject = Tombstones::new();
+        subject.add_counter(3);
+        subject.add_namespace("/users/42/**/*", 10).expect("failed to add namespace");
+
+        assert!(subject.is_deleted(&event(3, "/foo")));
+        assert!(subject.is_deleted(&event(7, "/users/42/profile")));
+        assert!(!subject.is_deleted(&event(7, "/users/43/profile")));
+        assert!(!subject.is_deleted(&event(11, "/users/42/profile")));
+    }
+
+    #[test]
+    fn tombstones_are_written_and_read_back() {
+        let tempdir = TempDir::new("tombstones_are_written_and_read_back").unwrap();
+        let path = get_tombstone_file(tempdir.path());
+        assert_eq!(Tombstones::new(), read_tombstone_file(&path).expect("failed to read missing file"));
+
+        let mut tombstones = Tombstones::new();
+        tombstones.add_counter(5);
+        tombstones.add_counter(99);
+        tombstones.add_namespace("/users/42/**/*", 77).expect("failed to add namespace");
+        tombstones.add_sequence(5, ProducerSequence { producer_id: 3, sequence: 12 });
+        write_tombstone_file(&path, &tombstones).expect("failed to write tombstones");
+        assert_eq!(tombstones, read_tombstone_file(&path).expect("failed to read tombstones"));
+
+        let mut bytes = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        bytes[10] ^= 0xFF;
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, read_tombstone_file(&path).unwrap_err().kind());
+    }
+
+    #[test]
+    fn tombstone_files_without_producer_sequences_are_still_read() {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(TOMBSTONE_FILE_MARKER);
+        buffer.write_u64::<BigEndian>(1).unwrap();
+        buffer.write_u64::<BigEndian>(4).unwrap();
+        buffer.write_u64::<BigEndian>(0).unwrap();
+        let checksum = checksum_ieee(&buffer);
+        buffer.write_u32::<BigEndian>(checksum).unwrap();
+
+        let mut expected = Tombstones::new();
+        expected.add_counter(4);
+        assert_eq!(Ok(expected), parse_tombstones(&buffer));
+    }
+}
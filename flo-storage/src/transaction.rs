@@ -0,0 +1,471 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+use crc::crc32::checksum_ieee;
+
+use event::{FloEvent, EventCounter};
+
+const TRANSACTION_LOG_FILE_NAME: &'static str = "transactions";
+const TRANSACTION_LOG_MARKER: &'static [u8] = b"FLO_TXN\n";
+/// kind (1) + transaction id (8) + two counters (16) + checksum (4)
+const RECORD_LEN: usize = 29;
+const CHECKSUM_LEN: usize = 4;
+
+const PREPARE: u8 = 1;
+const COMMIT: u8 = 2;
+const ABORT: u8 = 3;
+
+/// Identifies a transaction among the ones that a partition has not yet resolved
+pub type TransactionId = u64;
+
+/// A single entry in a partition's transaction log
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TransactionRecord {
+    /// Written before any of the transaction's events are appended. The events are the first `event_count` events in the
+    /// partition with counters greater than `after_counter`
+    Prepare {
+        transaction_id: TransactionId,
+        after_counter: EventCounter,
+        event_count: u64,
+    },
+    /// Written once every partition in the transaction has prepared its events, which makes them visible to readers
+    Commit {
+        transaction_id: TransactionId,
+    },
+    /// The events with counters greater than `after_counter`, through `last_counter`, belong to a transaction that was
+    /// never committed, so readers skip them
+    Abort {
+        transaction_id: TransactionId,
+        after_counter: EventCounter,
+        last_counter: EventCounter,
+    },
+}
+
+impl TransactionRecord {
+    fn transaction_id(&self) -> TransactionId {
+        match *self {
+            TransactionRecord::Prepare {transaction_id, ..} => transaction_id,
+            TransactionRecord::Commit {transaction_id} => transaction_id,
+            TransactionRecord::Abort {transaction_id, ..} => transaction_id,
+        }
+    }
+}
+
+/// A transaction that has been prepared in a partition, but not yet committed or aborted
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct PreparedTransaction {
+    transaction_id: TransactionId,
+    after_counter: EventCounter,
+    event_count: u64,
+    /// set once the events have been appended
+    last_counter: Option<EventCounter>,
+}
+
+/// A range of event counters that belong to an aborted transaction
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct AbortedTransaction {
+    transaction_id: TransactionId,
+    after_counter: EventCounter,
+    last_counter: EventCounter,
+}
+
+/// The transactions in a partition that readers need to know about. Readers stop at the first event after the start of
+/// any prepared transaction until that transaction is either committed or aborted, and they skip the events of aborted
+/// transactions. Committed transactions don't need to be kept here at all, since their events are just regular events.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TransactionState {
+    prepared: Vec<PreparedTransaction>,
+    aborted: Vec<AbortedTransaction>,
+}
+
+impl TransactionState {
+    pub fn new() -> TransactionState {
+        TransactionState {
+            prepared: Vec::new(),
+            aborted: Vec::new(),
+        }
+    }
+
+    /// Builds the state by applying each record from a transaction log in order. Any transaction that was prepared but
+    /// never committed or aborted is left in the prepared state.
+    pub fn replay(records: &[TransactionRecord]) -> TransactionState {
+        let mut state = TransactionState::new();
+        for record in records {
+            match *record {
+                TransactionRecord::Prepare {transaction_id, after_counter, event_count} => {
+                    state.prepare(transaction_id, after_counter, event_count);
+                }
+                TransactionRecord::Commit {transaction_id} => {
+                    state.commit(transaction_id);
+                }
+                TransactionRecord::Abort {transaction_id, after_counter, last_counter} => {
+                    state.prepared.retain(|prepared| prepared.transaction_id != transaction_id);
+                    state.add_aborted(transaction_id, after_counter, last_counter);
+                }
+            }
+        }
+        state
+    }
+
+    /// Returns true if the event belongs to a transaction that was aborted
+    pub fn is_aborted<E: FloEvent>(&self, event: &E) -> bool {
+        let counter = event.id().event_counter;
+        self.aborted.iter().any(|aborted| counter > aborted.after_counter && counter <= aborted.last_counter)
+    }
+
+    /// Returns true if the event comes after the start of a transaction that hasn't been committed or aborted yet. Such
+    /// events may or may not belong to the transaction, but either way they can't be read until it's resolved.
+    pub fn is_uncommitted<E: FloEvent>(&self, event: &E) -> bool {
+        let counter = event.id().event_counter;
+        self.prepared.iter().any(|prepared| counter > prepared.after_counter)
+    }
+
+    /// Returns true if the event belongs to a transaction that hasn't been committed or aborted yet
+    pub fn is_pending<E: FloEvent>(&self, event: &E) -> bool {
+        let counter = event.id().event_counter;
+        self.prepared.iter().any(|prepared| {
+            counter > prepared.after_counter && counter <= prepared.last_counter.unwrap_or(EventCounter::max_value())
+        })
+    }
+
+    /// Returns the counter that the oldest unresolved transaction was prepared after, if there are any. Every event up
+    /// through this counter is either committed or aborted.
+    pub fn resolved_through(&self) -> Option<EventCounter> {
+        self.prepared.iter().map(|prepared| prepared.after_counter).min()
+    }
+
+    pub fn is_prepared(&self, transaction_id: TransactionId) -> bool {
+        self.prepared.iter().any(|prepared| prepared.transaction_id == transaction_id)
+    }
+
+    /// Returns the id, start, and event count of every transaction that is prepared but not yet resolved
+    pub fn prepared_transactions(&self) -> Vec<(TransactionId, EventCounter, u64)> {
+        self.prepared.iter().map(|prepared| (prepared.transaction_id, prepared.after_counter, prepared.event_count)).collect()
+    }
+
+    pub fn prepare(&mut self, transaction_id: TransactionId, after_counter: EventCounter, event_count: u64) {
+        self.prepared.push(PreparedTransaction {
+            transaction_id: transaction_id,
+            after_counter: after_counter,
+            event_count: event_count,
+            last_counter: None,
+        });
+    }
+
+    /// Records the counter of the last event in a prepared transaction, once all of its events have been appended
+    pub fn prepared_through(&mut self, transaction_id: TransactionId, last_counter: EventCounter) {
+        if let Some(prepared) = self.prepared.iter_mut().find(|prepared| prepared.transaction_id == transaction_id) {
+            prepared.last_counter = Some(last_counter);
+        }
+    }
+
+    /// Removes the transaction from the prepared ones, so that its events become visible. Returns false if the
+    /// transaction was not prepared
+    pub fn commit(&mut self, transaction_id: TransactionId) -> bool {
+        let prepared_count = self.prepared.len();
+        self.prepared.retain(|prepared| prepared.transaction_id != transaction_id);
+        self.prepared.len() < prepared_count
+    }
+
+    /// Marks a prepared transaction as aborted, and returns the record for it. If the transaction's events were never
+    /// completely appended, then it's assumed to end with `head`, since nothing else could have been appended after it.
+    /// Returns `None` if the transaction was not prepared.
+    pub fn abort(&mut self, transaction_id: TransactionId, head: EventCounter) -> Option<TransactionRecord> {
+        let position = self.prepared.iter().position(|prepared| prepared.transaction_id == transaction_id);
+        position.map(|index| {
+            let prepared = self.prepared.remove(index);
+            let last_counter = prepared.last_counter.unwrap_or(head);
+            self.add_aborted(transaction_id, prepared.after_counter, last_counter);
+            TransactionRecord::Abort {
+                transaction_id: transaction_id,
+                after_counter: prepared.after_counter,
+                last_counter: last_counter,
+            }
+        })
+    }
+
+    fn add_aborted(&mut self, transaction_id: TransactionId, after_counter: EventCounter, last_counter: EventCounter) {
+        // a transaction that aborted before appending any events has nothing for readers to skip
+        if last_counter > after_counter {
+            self.aborted.push(AbortedTransaction {
+                transaction_id: transaction_id,
+                after_counter: after_counter,
+                last_counter: last_counter,
+            });
+        }
+    }
+
+    /// Returns the smallest set of records that reproduces this state when replayed
+    pub fn to_records(&self) -> Vec<TransactionRecord> {
+        let aborted = self.aborted.iter().map(|aborted| {
+            TransactionRecord::Abort {
+                transaction_id: aborted.transaction_id,
+                after_counter: aborted.after_counter,
+                last_counter: aborted.last_counter,
+            }
+        });
+        let prepared = self.prepared.iter().map(|prepared| {
+            TransactionRecord::Prepare {
+                transaction_id: prepared.transaction_id,
+                after_counter: prepared.after_counter,
+                event_count: prepared.event_count,
+            }
+        });
+        aborted.chain(prepared).collect()
+    }
+}
+
+pub fn get_transaction_log_file(partition_dir: &Path) -> PathBuf {
+    partition_dir.join(TRANSACTION_LOG_FILE_NAME)
+}
+
+/// The transaction log of a single partition, which records are appended to as transactions are prepared and resolved.
+/// Each record is fsynced before `append` returns.
+pub struct TransactionLog {
+    file: File,
+}
+
+impl TransactionLog {
+    /// Opens the log at the given path for appending, creating it if it doesn't exist
+    pub fn open(path: &Path) -> io::Result<TransactionLog> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(TRANSACTION_LOG_MARKER)?;
+        }
+        Ok(TransactionLog { file: file })
+    }
+
+    pub fn append(&mut self, record: &TransactionRecord) -> io::Result<()> {
+        let mut buffer = Vec::with_capacity(RECORD_LEN);
+        serialize_record(record, &mut buffer)?;
+        self.file.write_all(&buffer)?;
+        self.file.sync_data()
+    }
+}
+
+/// Replaces the transaction log with one that contains only the given records. The data is written to a temporary file
+/// first and then renamed, so that a partially written file never replaces a complete one.
+pub fn write_transaction_log(path: &Path, records: &[TransactionRecord]) -> io::Result<()> {
+    let mut buffer = Vec::with_capacity(TRANSACTION_LOG_MARKER.len() + records.len() * RECORD_LEN);
+    buffer.extend_from_slice(TRANSACTION_LOG_MARKER);
+    for record in records {
+        serialize_record(record, &mut buffer)?;
+    }
+
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+    }
+    fs::rename(&temp_path, path)
+}
+
+/// Reads every record from the transaction log at the given path. Returns no records if there is no file. A record at the
+/// end of the file that's incomplete or fails its checksum is ignored, since it was being appended when the server
+/// stopped and was never acknowledged. Any other invalid record is an error.
+pub fn read_transaction_log(path: &Path) -> io::Result<Vec<TransactionRecord>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    parse_records(&buffer).map_err(|description| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("Invalid transaction log: {:?}: {}", path, description))
+    })
+}
+
+/// Commits any transaction that's still prepared in one partition but was committed by another. Each partition commits a
+/// transaction only after every partition has prepared it, so a commit record in any partition means that the transaction
+/// must be committed everywhere. Any transactions that are still prepared afterwards are aborted once their partition is
+/// initialized. This must be called with the directories of every partition in an event stream before any of them are
+/// initialized.
+pub fn resolve_transactions(partition_dirs: &[PathBuf]) -> io::Result<()> {
+    let mut logs = Vec::with_capacity(partition_dirs.len());
+    let mut committed = HashSet::new();
+    for partition_dir in partition_dirs {
+        let path = get_transaction_log_file(partition_dir);
+        let records = read_transaction_log(&path)?;
+        for record in records.iter() {
+            if let TransactionRecord::Commit {transaction_id} = *record {
+                committed.insert(transaction_id);
+            }
+        }
+        logs.push((path, records));
+    }
+
+    for (path, records) in logs {
+        let state = TransactionState::replay(&records);
+        for (transaction_id, _, _) in state.prepared_transactions() {
+            if committed.contains(&transaction_id) {
+                info!("Committing transaction: {} in {:?} since it was committed by another partition", transaction_id, path);
+                TransactionLog::open(&path)?.append(&TransactionRecord::Commit { transaction_id: transaction_id })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn serialize_record(record: &TransactionRecord, buffer: &mut Vec<u8>) -> io::Result<()> {
+    let start = buffer.len();
+    let (kind, first, second) = match *record {
+        TransactionRecord::Prepare {after_counter, event_count, ..} => (PREPARE, after_counter, event_count),
+        TransactionRecord::Commit {..} => (COMMIT, 0, 0),
+        TransactionRecord::Abort {after_counter, last_counter, ..} => (ABORT, after_counter, last_counter),
+    };
+    buffer.push(kind);
+    buffer.write_u64::<BigEndian>(record.transaction_id())?;
+    buffer.write_u64::<BigEndian>(first)?;
+    buffer.write_u64::<BigEndian>(second)?;
+    let checksum = checksum_ieee(&buffer[start..]);
+    buffer.write_u32::<BigEndian>(checksum)
+}
+
+fn parse_records(buffer: &[u8]) -> Result<Vec<TransactionRecord>, String> {
+    if buffer.len() < TRANSACTION_LOG_MARKER.len() || &buffer[..TRANSACTION_LOG_MARKER.len()] != TRANSACTION_LOG_MARKER {
+        return Err("missing transaction log marker".to_owned());
+    }
+
+    let mut records = Vec::new();
+    let mut position = TRANSACTION_LOG_MARKER.len();
+    while position + RECORD_LEN <= buffer.len() {
+        let is_last = position + RECORD_LEN * 2 > buffer.len();
+        let record_bytes = &buffer[position..(position + RECORD_LEN)];
+        let data_end = RECORD_LEN - CHECKSUM_LEN;
+        if checksum_ieee(&record_bytes[..data_end]) != BigEndian::read_u32(&record_bytes[data_end..]) {
+            if is_last {
+                warn!("Ignoring incomplete record at the end of the transaction log at offset: {}", position);
+                break;
+            }
+            return Err(format!("checksum mismatch for record at offset: {}", position));
+        }
+
+        let transaction_id = BigEndian::read_u64(&record_bytes[1..9]);
+        let first = BigEndian::read_u64(&record_bytes[9..17]);
+        let second = BigEndian::read_u64(&record_bytes[17..25]);
+        let record = match record_bytes[0] {
+            PREPARE => TransactionRecord::Prepare { transaction_id: transaction_id, after_counter: first, event_count: second },
+            COMMIT => TransactionRecord::Commit { transaction_id: transaction_id },
+            ABORT => TransactionRecord::Abort { transaction_id: transaction_id, after_counter: first, last_counter: second },
+            other => return Err(format!("invalid record kind: {} at offset: {}", other, position)),
+        };
+        records.push(record);
+        position += RECORD_LEN;
+    }
+    if position < buffer.len() {
+        warn!("Ignoring {} bytes of an incomplete record at the end of the transaction log", buffer.len() - position);
+    }
+    Ok(records)
+}
+
+
+#[cfg(test)]
+mod test {
+    use tempdir::TempDir;
+
+    use super::*;
+    use event::{OwnedFloEvent, FloEventId, time};
+
+    fn event(counter: EventCounter) -> OwnedFloEvent {
+        OwnedFloEvent::new(FloEventId::new(1, counter), None, time::now(), "/foo".to_owned(), Vec::new())
+    }
+
+    fn prepare(transaction_id: TransactionId, after_counter: EventCounter, event_count: u64) -> TransactionRecord {
+        TransactionRecord::Prepare {
+            transaction_id: transaction_id,
+            after_counter: after_counter,
+            event_count: event_count,
+        }
+    }
+
+    #[test]
+    fn readers_stop_at_prepared_transactions_and_skip_aborted_ones() {
+        let mut subject = TransactionState::new();
+        subject.prepare(1, 3, 2);
+        subject.prepare(2, 6, 1);
+        assert!(!subject.is_uncommitted(&event(3)));
+        assert!(subject.is_uncommitted(&event(4)));
+        assert_eq!(Some(3), subject.resolved_through());
+
+        subject.prepared_through(1, 5);
+        assert!(subject.is_pending(&event(5)));
+        assert!(!subject.is_pending(&event(6)));
+        assert_eq!(Some(TransactionRecord::Abort { transaction_id: 1, after_counter: 3, last_counter: 5 }), subject.abort(1, 9));
+        assert!(subject.is_aborted(&event(4)));
+        assert!(subject.is_aborted(&event(5)));
+        assert!(!subject.is_aborted(&event(6)));
+        assert!(!subject.is_uncommitted(&event(6)));
+        assert!(subject.is_uncommitted(&event(7)));
+
+        assert!(subject.commit(2));
+        assert!(!subject.commit(2));
+        assert!(!subject.is_uncommitted(&event(7)));
+        assert_eq!(None, subject.resolved_through());
+        assert_eq!(None, subject.abort(2, 9));
+    }
+
+    #[test]
+    fn transaction_log_is_read_back_and_an_incomplete_last_record_is_ignored() {
+        let tempdir = TempDir::new("transaction_log_is_read_back").unwrap();
+        let path = get_transaction_log_file(tempdir.path());
+        assert_eq!(Vec::<TransactionRecord>::new(), read_transaction_log(&path).expect("failed to read missing log"));
+
+        let records = vec![
+            prepare(1, 3, 2),
+            TransactionRecord::Commit { transaction_id: 1 },
+            prepare(2, 7, 1),
+            TransactionRecord::Abort { transaction_id: 2, after_counter: 7, last_counter: 8 },
+        ];
+        {
+            let mut log = TransactionLog::open(&path).expect("failed to open log");
+            for record in records.iter() {
+                log.append(record).expect("failed to append record");
+            }
+        }
+        assert_eq!(records, read_transaction_log(&path).expect("failed to read log"));
+
+        let mut bytes = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut bytes).unwrap();
+        let torn_len = bytes.len() - 3;
+        File::create(&path).unwrap().write_all(&bytes[..torn_len]).unwrap();
+        assert_eq!(&records[..3], &read_transaction_log(&path).expect("failed to read torn log")[..]);
+
+        bytes[TRANSACTION_LOG_MARKER.len() + 2] ^= 0xFF;
+        File::create(&path).unwrap().write_all(&bytes).unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, read_transaction_log(&path).unwrap_err().kind());
+    }
+
+    #[test]
+    fn transaction_that_was_committed_by_any_partition_is_committed_by_every_partition() {
+        let tempdir = TempDir::new("transaction_committed_by_any_partition").unwrap();
+        let partition_dirs = (1..4).map(|i| tempdir.path().join(format!("{}", i))).collect::<Vec<_>>();
+        for dir in partition_dirs.iter() {
+            fs::create_dir_all(dir).unwrap();
+        }
+
+        // the server stopped after partition 1 committed transaction 1, but before partition 2 did, and none of the
+        // partitions committed transaction 2
+        let logs = vec![
+            vec![prepare(1, 0, 2), TransactionRecord::Commit { transaction_id: 1 }, prepare(2, 5, 1)],
+            vec![prepare(1, 0, 1), prepare(2, 4, 1)],
+            vec![],
+        ];
+        for (dir, records) in partition_dirs.iter().zip(logs.iter()) {
+            write_transaction_log(&get_transaction_log_file(dir), records).unwrap();
+        }
+
+        resolve_transactions(&partition_dirs).expect("failed to resolve transactions");
+
+        let states = partition_dirs.iter().map(|dir| {
+            TransactionState::replay(&read_transaction_log(&get_transaction_log_file(dir)).unwrap())
+        }).collect::<Vec<_>>();
+        assert_eq!(vec![(2, 5, 1)], states[0].prepared_transactions());
+        assert_eq!(vec![(2, 4, 1)], states[1].prepared_transactions());
+        assert!(states[2].prepared_transactions().is_empty());
+    }
+}